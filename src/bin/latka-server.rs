@@ -0,0 +1,79 @@
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use latka::cluster::Broker;
+use latka::cluster::manager::{LogManager, RECOVERY_THREADS_PER_DIR};
+use latka::partition::config::PartitionConfig;
+use latka::server::{Server, ServerConfig};
+
+const USAGE: &str = "usage: latka-server [--id N] [--host HOST] [--port PORT] [--rack RACK] [--log-dir DIR]... [--num-partitions N] \
+                     [--num-recovery-threads-per-data-dir N] [--controller] [--controller-quorum-voters ID@HOST:PORT,...] [--controller-addr HOST:PORT,...]";
+
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("latka-server: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut id: u32 = 0;
+    let mut host = String::from("127.0.0.1");
+    let mut port: u16 = 9092;
+    let mut rack: Option<String> = None;
+    let mut log_dirs: Vec<PathBuf> = vec![];
+    let mut recovery_threads = RECOVERY_THREADS_PER_DIR;
+    let mut config = ServerConfig::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--id" => id = parse(&value()?)?,
+            "--host" => host = value()?,
+            "--port" => port = parse(&value()?)?,
+            "--rack" => rack = Some(value()?),
+            "--log-dir" => log_dirs.push(PathBuf::from(value()?)),
+            "--num-recovery-threads-per-data-dir" => recovery_threads = parse(&value()?)?,
+            "--num-partitions" => config.num_partitions = parse(&value()?)?,
+            "--controller" => config.controller = true,
+            "--controller-quorum-voters" => config.controller_quorum_voters = parse_voters(&value()?)?,
+            "--controller-addr" => config.controller_addr = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(())
+            },
+            _ => return Err(format!("unknown argument {}\n{}", arg, USAGE)),
+        }
+    }
+    if log_dirs.is_empty() {
+        log_dirs.push(env::temp_dir().join("latka-logs"));
+    }
+
+    let logs = Arc::new(LogManager::open_with_recovery_threads(log_dirs, PartitionConfig::default(), recovery_threads).map_err(|e| e.to_string())?);
+    let _tasks = LogManager::start(logs.clone(), Duration::from_secs(30));
+    let broker = Broker::new(id, &host, port);
+    let broker = match &rack {
+        Some(rack) => broker.with_rack(rack),
+        None => broker,
+    };
+    let server = Server::bind(broker, logs, config).map_err(|e| e.to_string())?;
+    println!("latka-server: broker {} listening on {}", id, server.broker().addr());
+    server.serve().map_err(|e| e.to_string())
+}
+
+fn parse<T: std::str::FromStr>(raw: &str) -> Result<T, String> {
+    raw.parse().map_err(|_| format!("{:?} isn't a valid value", raw))
+}
+
+fn parse_voters(raw: &str) -> Result<Vec<(u32, String)>, String> {
+    // 1@host:9092,2@host:9093
+    raw.split(',').map(|voter| match voter.split_once('@') {
+        Some((id, addr)) => Ok((parse(id)?, String::from(addr))),
+        None => Err(format!("{:?} isn't ID@HOST:PORT", voter)),
+    }).collect()
+}
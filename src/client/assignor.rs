@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use crate::{Result};
+use crate::kafka::codec::{Decoder, Encoder};
+
+// each topic's partitions
+pub type Assignment = BTreeMap<String, Vec<u32>>;
+
+const SUBSCRIPTION_VERSION: i16 = 2;
+const ASSIGNMENT_VERSION: i16 = 0;
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Subscription {
+    pub topics: Vec<String>,
+    // what the member held going into the rebalance, sticky assignors keep it
+    pub owned: Assignment,
+    // the generation the member owned them in, -1 for none
+    pub generation: i32,
+}
+
+impl Subscription {
+    pub fn new(topics: &[&str]) -> Subscription {
+        Subscription{ topics: topics.iter().map(|t| String::from(*t)).collect(), owned: Assignment::new(), generation: -1 }
+    }
+
+    // kafka's consumer protocol, owned partitions came in v1 and the generation in v2
+    //
+    //   version i16 | [topic string] | user_data bytes | [topic string | [partition i32]] | generation i32
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.i16(SUBSCRIPTION_VERSION);
+        enc.array_len(self.topics.len());
+        for topic in &self.topics {
+            enc.string(topic);
+        }
+        enc.bytes(None);
+        encode_partitions(&mut enc, &self.owned);
+        enc.i32(self.generation);
+        enc.into_vec()
+    }
+
+    pub fn from_slice(raw: &[u8]) -> Result<Subscription> {
+        let mut dec = Decoder::new(raw);
+        let version = dec.i16()?;
+        let topics: Result<Vec<String>> = (0..dec.array_len()?.unwrap_or(0)).map(|_| dec.string()).collect();
+        let _user_data = dec.bytes()?;
+        let owned = if version >= 1 { decode_partitions(&mut dec)? } else { Assignment::new() };
+        let generation = if version >= 2 { dec.i32()? } else { -1 };
+        Ok(Subscription{ topics: topics?, owned, generation })
+    }
+}
+
+//   version i16 | [topic string | [partition i32]] | user_data bytes
+pub fn encode_assignment(assignment: &Assignment) -> Vec<u8> {
+    let mut enc = Encoder::new();
+    enc.i16(ASSIGNMENT_VERSION);
+    encode_partitions(&mut enc, assignment);
+    enc.bytes(None);
+    enc.into_vec()
+}
+
+pub fn decode_assignment(raw: &[u8]) -> Result<Assignment> {
+    // a member the leader left out gets an empty assignment
+    if raw.is_empty() { return Ok(Assignment::new()) }
+    let mut dec = Decoder::new(raw);
+    let _version = dec.i16()?;
+    decode_partitions(&mut dec)
+}
+
+fn encode_partitions(enc: &mut Encoder, partitions: &Assignment) {
+    enc.array_len(partitions.len());
+    for (topic, partitions) in partitions {
+        enc.string(topic);
+        enc.array_len(partitions.len());
+        for partition in partitions {
+            enc.i32(*partition as i32);
+        }
+    }
+}
+
+fn decode_partitions(dec: &mut Decoder) -> Result<Assignment> {
+    let mut assignment = Assignment::new();
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        let topic = dec.string()?;
+        let partitions: Result<Vec<u32>> = (0..dec.array_len()?.unwrap_or(0)).map(|_| Ok(dec.i32()? as u32)).collect();
+        assignment.insert(topic, partitions?);
+    }
+    Ok(assignment)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebalanceProtocol {
+    // every member gives up all its partitions before joining again
+    Eager,
+    // members keep what they're assigned again and only give up what moves,
+    // which then takes a second rebalance to hand over
+    Cooperative,
+}
+
+// Decides which member consumes what. The group leader runs the assignor the
+// group agreed on (by name, the protocol name in JoinGroup) over every
+// member's subscription and each subscribed topic's partition count.
+pub trait PartitionAssignor: Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn protocol(&self) -> RebalanceProtocol { RebalanceProtocol::Eager }
+
+    fn assign(&self, partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment>;
+}
+
+fn empty_assignments(subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+    subscriptions.keys().map(|member| (member.clone(), Assignment::new())).collect()
+}
+
+fn subscribers<'a>(topic: &str, subscriptions: &'a BTreeMap<String, Subscription>) -> Vec<&'a String> {
+    subscriptions.iter().filter(|(_, s)| s.topics.iter().any(|t| t == topic)).map(|(member, _)| member).collect()
+}
+
+fn all_partitions(partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> Vec<(String, u32)> {
+    // every partition of a topic somebody subscribes to, in order
+    let mut all = vec![];
+    for (topic, count) in partitions {
+        if subscribers(topic, subscriptions).is_empty() { continue }
+        all.extend((0..*count).map(|p| (topic.clone(), p)));
+    }
+    all
+}
+
+fn add(assignments: &mut BTreeMap<String, Assignment>, member: &str, topic: &str, partition: u32) {
+    let partitions = assignments.get_mut(member).unwrap().entry(String::from(topic)).or_default();
+    partitions.push(partition);
+    partitions.sort_unstable();
+}
+
+
+// Each topic on its own: its partitions in consecutive runs over the sorted
+// members, the first ones get one more when they don't divide evenly
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RangeAssignor;
+
+impl PartitionAssignor for RangeAssignor {
+    fn name(&self) -> &str { "range" }
+
+    fn assign(&self, partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+        let mut assignments = empty_assignments(subscriptions);
+        for (topic, count) in partitions {
+            let members = subscribers(topic, subscriptions);
+            let mut next = 0;
+            for (i, member) in members.iter().enumerate() {
+                let share = count / members.len() as u32 + if (i as u32) < count % members.len() as u32 { 1 } else { 0 };
+                if share == 0 { continue }
+                assignments.get_mut(*member).unwrap().insert(topic.clone(), (next..next + share).collect());
+                next += share;
+            }
+        }
+        assignments
+    }
+}
+
+
+// Every partition of every topic dealt out in turn, skipping members that
+// don't subscribe to the partition's topic
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundRobinAssignor;
+
+impl PartitionAssignor for RoundRobinAssignor {
+    fn name(&self) -> &str { "roundrobin" }
+
+    fn assign(&self, partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+        let mut assignments = empty_assignments(subscriptions);
+        let members: Vec<&String> = subscriptions.keys().collect();
+        let mut next = 0;
+        for (topic, partition) in all_partitions(partitions, subscriptions) {
+            let subscribed = subscribers(&topic, subscriptions);
+            while !subscribed.contains(&members[next % members.len()]) { next += 1 }
+            add(&mut assignments, members[next % members.len()], &topic, partition);
+            next += 1;
+        }
+        assignments
+    }
+}
+
+
+// Balanced like round robin, but members keep the partitions they owned as
+// far as the balance allows, so a rebalance moves as little as it can
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StickyAssignor;
+
+impl StickyAssignor {
+    fn previous_owners(subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<(String, u32), String> {
+        // two members claiming a partition (one missed a rebalance) goes to
+        // whoever owned it in the later generation
+        let mut owners: BTreeMap<(String, u32), (String, i32)> = BTreeMap::new();
+        for (member, subscription) in subscriptions {
+            for (topic, partitions) in &subscription.owned {
+                for partition in partitions {
+                    let claim = (member.clone(), subscription.generation);
+                    let owner = owners.entry((topic.clone(), *partition)).or_insert_with(|| claim.clone());
+                    if claim.1 > owner.1 { *owner = claim }
+                }
+            }
+        }
+        owners.into_iter().map(|(tp, (member, _))| (tp, member)).collect()
+    }
+
+    fn sticky(partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+        let all = all_partitions(partitions, subscriptions);
+        let mut owned: BTreeMap<&String, Vec<(String, u32)>> = subscriptions.keys().map(|m| (m, vec![])).collect();
+        let mut unassigned: BTreeSet<(String, u32)> = all.iter().cloned().collect();
+        for (tp, member) in Self::previous_owners(subscriptions) {
+            // gone partitions and topics the member unsubscribed from are dropped
+            if !unassigned.contains(&tp) || !subscriptions[&member].topics.contains(&tp.0) { continue }
+            unassigned.remove(&tp);
+            owned.get_mut(&member).unwrap().push(tp);
+        }
+
+        // the members holding the most keep one over the even share
+        let floor = all.len() / subscriptions.len();
+        let mut extra = all.len() % subscriptions.len();
+        let mut by_owned: Vec<&String> = owned.keys().cloned().collect();
+        by_owned.sort_by_key(|m| std::cmp::Reverse(owned[m].len()));
+        for member in by_owned {
+            let quota = floor + if extra > 0 && owned[member].len() > floor { extra -= 1; 1 } else { 0 };
+            let held = owned.get_mut(member).unwrap();
+            while held.len() > quota {
+                unassigned.insert(held.pop().unwrap());
+            }
+        }
+
+        // and the rest go to whoever subscribed holds the fewest
+        for (topic, partition) in unassigned {
+            let member = subscribers(&topic, subscriptions).into_iter().min_by_key(|m| owned[m].len()).unwrap();
+            owned.get_mut(member).unwrap().push((topic, partition));
+        }
+
+        let mut assignments = empty_assignments(subscriptions);
+        for (member, held) in owned {
+            for (topic, partition) in held {
+                add(&mut assignments, member, &topic, partition);
+            }
+        }
+        assignments
+    }
+}
+
+impl PartitionAssignor for StickyAssignor {
+    fn name(&self) -> &str { "sticky" }
+
+    fn assign(&self, partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+        StickyAssignor::sticky(partitions, subscriptions)
+    }
+}
+
+
+// The sticky assignment, but a partition moving between members is left out
+// until its owner has given it up. The owner sees it missing from its
+// assignment, revokes it and joins again, and the next rebalance hands it on.
+// Everyone else keeps consuming throughout.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CooperativeStickyAssignor;
+
+impl PartitionAssignor for CooperativeStickyAssignor {
+    fn name(&self) -> &str { "cooperative-sticky" }
+
+    fn protocol(&self) -> RebalanceProtocol { RebalanceProtocol::Cooperative }
+
+    fn assign(&self, partitions: &BTreeMap<String, u32>, subscriptions: &BTreeMap<String, Subscription>) -> BTreeMap<String, Assignment> {
+        let owners = StickyAssignor::previous_owners(subscriptions);
+        let mut assignments = StickyAssignor::sticky(partitions, subscriptions);
+        for (member, assignment) in assignments.iter_mut() {
+            for (topic, held) in assignment.iter_mut() {
+                held.retain(|p| owners.get(&(topic.clone(), *p)).is_none_or(|owner| owner == member));
+            }
+            assignment.retain(|_, held| !held.is_empty());
+        }
+        assignments
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribe(members: &[(&str, &[&str])]) -> BTreeMap<String, Subscription> {
+        members.iter().map(|(member, topics)| (String::from(*member), Subscription::new(topics))).collect()
+    }
+
+    fn owning(subscriptions: &mut BTreeMap<String, Subscription>, member: &str, topic: &str, partitions: &[u32]) {
+        let subscription = subscriptions.get_mut(member).unwrap();
+        subscription.owned.insert(String::from(topic), partitions.to_vec());
+        subscription.generation = 1;
+    }
+
+    fn held(assignments: &BTreeMap<String, Assignment>, member: &str, topic: &str) -> Vec<u32> {
+        assignments[member].get(topic).cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn it_assigns_ranges_and_round_robin() {
+        let partitions: BTreeMap<String, u32> = vec![(String::from("events"), 3), (String::from("clicks"), 2)].into_iter().collect();
+        let subscriptions = subscribe(&[("a", &["events", "clicks"]), ("b", &["events", "clicks"]), ("c", &["events"])]);
+
+        let ranges = RangeAssignor.assign(&partitions, &subscriptions);
+        assert_eq!((held(&ranges, "a", "events"), held(&ranges, "b", "events"), held(&ranges, "c", "events")), (vec![0], vec![1], vec![2]));
+        assert_eq!((held(&ranges, "a", "clicks"), held(&ranges, "b", "clicks")), (vec![0], vec![1]));
+
+        // clicks 0, clicks 1, events 0, events 1, events 2
+        let dealt = RoundRobinAssignor.assign(&partitions, &subscriptions);
+        assert_eq!((held(&dealt, "a", "clicks"), held(&dealt, "b", "clicks")), (vec![0], vec![1]));
+        assert_eq!((held(&dealt, "c", "events"), held(&dealt, "a", "events"), held(&dealt, "b", "events")), (vec![0], vec![1], vec![2]));
+
+        let raw = Subscription{ generation: 4, ..subscriptions["a"].clone() }.to_vec();
+        assert_eq!(Subscription::from_slice(&raw).unwrap().generation, 4);
+        assert_eq!(decode_assignment(&encode_assignment(&ranges["a"])).unwrap(), ranges["a"]);
+    }
+
+    #[test]
+    fn it_keeps_owned_partitions() {
+        let partitions: BTreeMap<String, u32> = vec![(String::from("events"), 6)].into_iter().collect();
+        let mut subscriptions = subscribe(&[("a", &["events"]), ("b", &["events"]), ("c", &["events"])]);
+        owning(&mut subscriptions, "a", "events", &[0, 1, 2]);
+        owning(&mut subscriptions, "b", "events", &[3, 4, 5]);
+
+        let sticky = StickyAssignor.assign(&partitions, &subscriptions);
+        assert_eq!((held(&sticky, "a", "events"), held(&sticky, "b", "events")), (vec![0, 1], vec![3, 4]));
+        assert_eq!(held(&sticky, "c", "events"), vec![2, 5], "only what has to move moves");
+
+        // the new member gets nothing until the others have given it up
+        let cooperative = CooperativeStickyAssignor.assign(&partitions, &subscriptions);
+        assert_eq!((held(&cooperative, "a", "events"), held(&cooperative, "b", "events")), (vec![0, 1], vec![3, 4]));
+        assert!(cooperative["c"].is_empty());
+        owning(&mut subscriptions, "a", "events", &[0, 1]);
+        owning(&mut subscriptions, "b", "events", &[3, 4]);
+        assert_eq!(CooperativeStickyAssignor.assign(&partitions, &subscriptions), sticky);
+
+        // a stale claim loses to the later generation's owner
+        subscriptions.get_mut("c").unwrap().owned.insert(String::from("events"), vec![0]);
+        let sticky = StickyAssignor.assign(&partitions, &subscriptions);
+        assert!(held(&sticky, "a", "events").contains(&0));
+    }
+}
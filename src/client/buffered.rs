@@ -0,0 +1,730 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Offset, Result};
+use crate::client::{PendingRecord, is_retriable};
+use crate::client::transport::{Transport, EmbeddedTransport, BrokerTransport};
+use crate::cluster::manager::{LogManager};
+use crate::cluster::partitioner::{Partitioner, DefaultPartitioner};
+use crate::kafka::{errors};
+use crate::partition::producer::{ProducerBatch, next_sequence};
+use crate::partition::segment::{now_ms};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acks {
+    // don't wait for the broker at all
+    None,
+    // the leader appended it
+    Leader,
+    // every in-sync replica has it
+    All,
+}
+
+impl Acks {
+    pub fn as_i16(self) -> i16 {
+        match self {
+            Acks::None => 0,
+            Acks::Leader => 1,
+            Acks::All => -1,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct ProducerConfig {
+    // batch.size, a partition's batch is sent once it holds this many bytes
+    pub batch_size: usize,
+    // linger.ms, how long a batch waits to fill before it's sent anyway
+    pub linger: Duration,
+    pub acks: Acks,
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    // buffer.memory, bytes of records waiting to be sent before send() blocks
+    pub buffer_memory: usize,
+    // max.block.ms, how long send() blocks for buffer memory
+    pub max_block: Duration,
+    // enable.idempotence, batches carry a producer id and sequences so the
+    // broker drops the duplicates retries would otherwise write
+    pub idempotence: bool,
+    // transactional.id, records are sent inside begin/commit_transaction and
+    // a new producer with the same id fences this one
+    pub transactional_id: Option<String>,
+    // transaction.timeout.ms, the coordinator aborts a transaction left open longer
+    pub transaction_timeout: Duration,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> ProducerConfig {
+        ProducerConfig{
+            batch_size: 16 * 1024,
+            linger: Duration::from_millis(5),
+            acks: Acks::All,
+            retries: 5,
+            retry_backoff: Duration::from_millis(100),
+            buffer_memory: 32 * 1024 * 1024,
+            max_block: Duration::from_secs(60),
+            idempotence: true,
+            transactional_id: None,
+            transaction_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ProducerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.batch_size == 0 {
+            return Err(Error::InvalidConfig(String::from("batch.size must be positive")))
+        }
+        if self.buffer_memory < self.batch_size {
+            return Err(Error::InvalidConfig(String::from("buffer.memory must hold at least one batch")))
+        }
+        if self.idempotence && self.acks != Acks::All {
+            return Err(Error::InvalidConfig(String::from("enable.idempotence needs acks=all")))
+        }
+        if self.transactional_id.is_some() && !self.idempotence {
+            return Err(Error::InvalidConfig(String::from("transactional.id needs enable.idempotence")))
+        }
+        Ok(())
+    }
+
+    fn transaction_timeout_ms(&self) -> u32 {
+        self.transaction_timeout.as_millis().min(i32::MAX as u128) as u32
+    }
+}
+
+
+// Bounds the bytes of records waiting to be sent, send() blocks when it's used up
+struct BufferPool {
+    capacity: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> BufferPool {
+        BufferPool{ capacity, used: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    fn acquire(&self, size: usize, max_block: Duration) -> Result<()> {
+        if size > self.capacity {
+            return Err(Error::MessageTooLarge{ size: size as u64, max: self.capacity as u64 })
+        }
+        let deadline = Instant::now() + max_block;
+        let mut used = self.used.lock().unwrap();
+        while *used + size > self.capacity {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(format!("no buffer memory for {} bytes within max.block.ms", size)))
+            }
+            used = self.freed.wait_timeout(used, deadline - now).unwrap().0;
+        }
+        *used += size;
+        Ok(())
+    }
+
+    fn release(&self, size: usize) {
+        *self.used.lock().unwrap() -= size;
+        self.freed.notify_all();
+    }
+}
+
+
+// Resolves once the record's batch was sent: its offset, or None with acks=0
+pub struct SendHandle {
+    rx: Receiver<Result<Option<Offset>>>,
+}
+
+impl SendHandle {
+    pub fn wait(self) -> Result<Option<Offset>> {
+        match self.rx.recv() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "producer closed before sending"))),
+        }
+    }
+
+    pub fn try_wait(&self) -> Option<Result<Option<Offset>>> {
+        match self.rx.try_recv() {
+            Ok(res) => Some(res),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "producer closed before sending"))))
+            },
+        }
+    }
+}
+
+
+struct Pending {
+    record: PendingRecord,
+    done: Sender<Result<Option<Offset>>>,
+}
+
+struct Batch {
+    records: Vec<Pending>,
+    bytes: usize,
+    created: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    batches: BTreeMap<(String, u32), Batch>,
+    // records accepted by send() and not yet answered
+    unsent: usize,
+    flushing: usize,
+    closed: bool,
+}
+
+struct Shared {
+    config: ProducerConfig,
+    state: Mutex<State>,
+    // wakes the sender thread
+    wake: Condvar,
+    // wakes flush() when records are answered
+    sent: Condvar,
+    pool: BufferPool,
+    transport: Mutex<Box<dyn Transport>>,
+    producer_id: Mutex<Option<(i64, i16)>>,
+    transaction: Mutex<Transaction>,
+}
+
+
+// The open transaction of a transactional producer
+#[derive(Default)]
+struct Transaction {
+    open: bool,
+    // partitions the coordinator was told about
+    partitions: HashSet<(String, u32)>,
+    // the first batch that failed, the transaction can only be aborted
+    error: Option<Error>,
+}
+
+
+// The sender thread's idempotence bookkeeping, the next sequence of every
+// partition written to under the current producer id
+#[derive(Default)]
+struct Sequences {
+    producer: Option<(i64, i16)>,
+    next: HashMap<(String, u32), i32>,
+    // a batch failed for good, its sequences may or may not be in the log
+    reset: bool,
+}
+
+
+// Accumulates records per partition and sends them in batches from a
+// background thread, once a batch reaches batch.size or has lingered for
+// linger.ms. One batch is in flight at a time, so retries keep ordering.
+pub struct BufferedProducer {
+    shared: Arc<Shared>,
+    partitioner: Box<dyn Partitioner>,
+    partitions: HashMap<String, u32>,
+    sender: Option<JoinHandle<()>>,
+}
+
+impl BufferedProducer {
+    pub fn embedded(logs: Arc<LogManager>, config: ProducerConfig) -> Result<BufferedProducer> {
+        BufferedProducer::with_transport(EmbeddedTransport::new(logs), config)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: ProducerConfig) -> Result<BufferedProducer> {
+        BufferedProducer::with_transport(BrokerTransport::connect(addr)?, config)
+    }
+
+    pub fn with_transport<T: Transport + 'static>(mut transport: T, config: ProducerConfig) -> Result<BufferedProducer> {
+        config.validate()?;
+        let mut sequences = Sequences::default();
+        if config.idempotence {
+            let timeout_ms = config.transaction_timeout_ms();
+            sequences.producer = Some(transport.init_producer_id(config.transactional_id.as_deref(), timeout_ms)?);
+        }
+        let shared = Arc::new(Shared{
+            pool: BufferPool::new(config.buffer_memory),
+            config,
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+            sent: Condvar::new(),
+            transport: Mutex::new(Box::new(transport)),
+            producer_id: Mutex::new(sequences.producer),
+            transaction: Mutex::new(Transaction::default()),
+        });
+        let sending = shared.clone();
+        let sender = thread::Builder::new()
+            .name(String::from("latka-producer"))
+            .spawn(move || run_sender(sending, sequences))?;
+        Ok(BufferedProducer{
+            shared,
+            partitioner: Box::new(DefaultPartitioner::new()),
+            partitions: HashMap::new(),
+            sender: Some(sender),
+        })
+    }
+
+    // the producer id and epoch batches are sent with, None without idempotence
+    pub fn producer_id(&self) -> Option<(i64, i16)> { *self.shared.producer_id.lock().unwrap() }
+
+    pub fn set_partitioner<P: Partitioner + 'static>(&mut self, partitioner: P) {
+        self.partitioner = Box::new(partitioner);
+    }
+
+    pub fn partitions(&mut self, topic: &str) -> Result<u32> {
+        if let Some(count) = self.partitions.get(topic) { return Ok(*count) }
+        let count = self.shared.transport.lock().unwrap().partitions(topic)?;
+        if count == 0 {
+            return Err(Error::Remote{
+                code: errors::UNKNOWN_TOPIC_OR_PARTITION,
+                message: format!("topic {} doesn't exist", topic),
+            })
+        }
+        self.partitions.insert(String::from(topic), count);
+        Ok(count)
+    }
+
+    pub fn send(&mut self, topic: &str, key: Option<&[u8]>, value: &[u8]) -> Result<SendHandle> {
+        let count = self.partitions(topic)?;
+        let mut partition = self.partitioner.partition(topic, key, value, count);
+        if self.batch_is_full(topic, partition) {
+            // sticky partitioners move on once the batch they filled is done
+            self.partitioner.on_new_batch(topic, partition, count);
+            partition = self.partitioner.partition(topic, key, value, count);
+        }
+        self.send_to(topic, partition, key, value)
+    }
+
+    pub fn send_to(&mut self, topic: &str, partition: u32, key: Option<&[u8]>, value: &[u8]) -> Result<SendHandle> {
+        if self.shared.config.transactional_id.is_some() && !self.shared.transaction.lock().unwrap().open {
+            return Err(Error::InvalidTxnState(String::from("send outside of a transaction")))
+        }
+        let record = PendingRecord{ timestamp: now_ms(), key: key.map(|k| k.to_vec()), value: Some(value.to_vec()) };
+        let size = record.size();
+        self.shared.pool.acquire(size, self.shared.config.max_block)?;
+
+        let (done, rx) = mpsc::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            self.shared.pool.release(size);
+            return Err(Error::InvalidConfig(String::from("producer is closed")))
+        }
+        let batch = state.batches.entry((String::from(topic), partition)).or_insert_with(|| Batch{
+            records: vec![],
+            bytes: 0,
+            created: Instant::now(),
+        });
+        batch.records.push(Pending{ record, done });
+        batch.bytes += size;
+        state.unsent += 1;
+        self.shared.wake.notify_one();
+        Ok(SendHandle{ rx })
+    }
+
+    fn batch_is_full(&self, topic: &str, partition: u32) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        match state.batches.get(&(String::from(topic), partition)) {
+            Some(batch) => batch.bytes >= self.shared.config.batch_size,
+            None => false,
+        }
+    }
+
+    pub fn flush(&self) {
+        // sends every batch now and waits for the answers
+        let mut state = self.shared.state.lock().unwrap();
+        state.flushing += 1;
+        self.shared.wake.notify_one();
+        while state.unsent > 0 {
+            state = self.shared.sent.wait(state).unwrap();
+        }
+        state.flushing -= 1;
+    }
+
+    pub fn begin_transaction(&mut self) -> Result<()> {
+        if self.shared.config.transactional_id.is_none() {
+            return Err(Error::InvalidTxnState(String::from("producer has no transactional.id")))
+        }
+        let mut transaction = self.shared.transaction.lock().unwrap();
+        if transaction.open {
+            return Err(Error::InvalidTxnState(String::from("a transaction is already open")))
+        }
+        transaction.open = true;
+        Ok(())
+    }
+
+    pub fn commit_transaction(&mut self) -> Result<()> {
+        // sends what's buffered first, a transaction with a failed batch has
+        // to be aborted instead
+        self.flush();
+        if let Some(e) = &self.shared.transaction.lock().unwrap().error {
+            return Err(e.clone())
+        }
+        self.end_transaction(true)
+    }
+
+    pub fn abort_transaction(&mut self) -> Result<()> {
+        self.flush();
+        self.end_transaction(false)?;
+        if self.shared.transaction.lock().unwrap().error.take().is_some() {
+            // a failed batch leaves the sequences in doubt, a new epoch starts them over
+            let timeout_ms = self.shared.config.transaction_timeout_ms();
+            let producer = self.shared.transport.lock().unwrap()
+                .init_producer_id(self.shared.config.transactional_id.as_deref(), timeout_ms)?;
+            *self.shared.producer_id.lock().unwrap() = Some(producer);
+        }
+        Ok(())
+    }
+
+    fn end_transaction(&mut self, commit: bool) -> Result<()> {
+        let transactional_id = match &self.shared.config.transactional_id {
+            Some(id) => id,
+            None => return Err(Error::InvalidTxnState(String::from("producer has no transactional.id"))),
+        };
+        if !self.shared.transaction.lock().unwrap().open {
+            return Err(Error::InvalidTxnState(String::from("no transaction is open")))
+        }
+        let (producer_id, epoch) = self.producer_id().unwrap();
+        self.shared.transport.lock().unwrap().end_txn(transactional_id, producer_id, epoch, commit)?;
+        let mut transaction = self.shared.transaction.lock().unwrap();
+        transaction.open = false;
+        transaction.partitions.clear();
+        Ok(())
+    }
+
+    pub fn close(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.wake.notify_one();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+    }
+}
+
+impl Drop for BufferedProducer {
+    fn drop(&mut self) { self.close() }
+}
+
+fn run_sender(shared: Arc<Shared>, mut sequences: Sequences) {
+    loop {
+        let ready = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                let now = Instant::now();
+                let force = state.closed || state.flushing > 0;
+                let config = &shared.config;
+                let keys: Vec<(String, u32)> = state.batches.iter()
+                    .filter(|(_, b)| force || b.bytes >= config.batch_size || now >= b.created + config.linger)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                if !keys.is_empty() {
+                    break keys.into_iter().map(|key| {
+                        let batch = state.batches.remove(&key).unwrap();
+                        (key, batch)
+                    }).collect::<Vec<_>>()
+                }
+                if state.closed { return }
+                // sleep until the oldest batch has lingered long enough
+                let linger = state.batches.values().map(|b| (b.created + config.linger) - now).min();
+                state = match linger {
+                    Some(timeout) => shared.wake.wait_timeout(state, timeout).unwrap().0,
+                    None => shared.wake.wait(state).unwrap(),
+                };
+            }
+        };
+        for ((topic, partition), batch) in ready {
+            send_batch(&shared, &mut sequences, &topic, partition, batch);
+        }
+    }
+}
+
+fn send_batch(shared: &Shared, sequences: &mut Sequences, topic: &str, partition: u32, batch: Batch) {
+    let records: Vec<PendingRecord> = batch.records.iter().map(|p| p.record.clone()).collect();
+    let acks = shared.config.acks.as_i16();
+    let transactional_id = shared.config.transactional_id.as_deref();
+    if sequences.reset {
+        // start over under a fresh producer id rather than guess which
+        // sequences the broker has seen
+        let timeout_ms = shared.config.transaction_timeout_ms();
+        if let Ok(producer) = shared.transport.lock().unwrap().init_producer_id(transactional_id, timeout_ms) {
+            *shared.producer_id.lock().unwrap() = Some(producer);
+            sequences.reset = false;
+        }
+    }
+    let current = *shared.producer_id.lock().unwrap();
+    if current != sequences.producer {
+        sequences.producer = current;
+        sequences.next.clear();
+    }
+    let producer = sequences.producer.map(|(producer_id, epoch)| {
+        let next = sequences.next.entry((String::from(topic), partition)).or_insert(0);
+        let batch = ProducerBatch{
+            producer_id,
+            epoch,
+            base_sequence: *next,
+            count: records.len() as i32,
+            transactional: transactional_id.is_some(),
+        };
+        *next = next_sequence(*next, batch.count);
+        batch
+    });
+
+    // retries resend the same sequences, so a batch that did make it the first
+    // time around is answered with its offset instead of being appended again
+    let mut attempt = 0;
+    let res = loop {
+        let res = add_to_transaction(shared, topic, partition)
+            .and_then(|()| shared.transport.lock().unwrap().send_batch(topic, partition, acks, producer, &records));
+        match res {
+            Err(ref e) if is_retriable(e) && attempt < shared.config.retries => {
+                attempt += 1;
+                thread::sleep(shared.config.retry_backoff);
+            },
+            res => break res,
+        }
+    };
+    if let Err(e) = &res {
+        // a transactional producer keeps its epoch until the transaction is aborted
+        match transactional_id {
+            Some(_) => { shared.transaction.lock().unwrap().error.get_or_insert_with(|| e.clone()); },
+            None if producer.is_some() => sequences.reset = true,
+            None => (),
+        }
+    }
+
+    let count = batch.records.len();
+    for (i, pending) in batch.records.into_iter().enumerate() {
+        let _ = pending.done.send(match &res {
+            Ok(base) => Ok(base.map(|base| base + i as Offset)),
+            Err(e) => Err(e.clone()),
+        });
+    }
+    shared.pool.release(batch.bytes);
+    shared.state.lock().unwrap().unsent -= count;
+    shared.sent.notify_all();
+}
+
+fn add_to_transaction(shared: &Shared, topic: &str, partition: u32) -> Result<()> {
+    // the coordinator has to know about a partition before the transaction
+    // writes to it, so the marker gets there on commit or abort
+    let transactional_id = match &shared.config.transactional_id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let key = (String::from(topic), partition);
+    if shared.transaction.lock().unwrap().partitions.contains(&key) { return Ok(()) }
+    let (producer_id, epoch) = shared.producer_id.lock().unwrap().unwrap();
+    shared.transport.lock().unwrap().add_partition_to_txn(transactional_id, producer_id, epoch, topic, partition)?;
+    shared.transaction.lock().unwrap().partitions.insert(key);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tempfile::tempdir;
+    use super::*;
+    use crate::client::Connection;
+    use crate::client::tests::{start_broker};
+    use crate::cluster::transaction::{TransactionCoordinator};
+    use crate::partition::segment::{MaxBytes};
+
+    fn logs(dir: &std::path::Path) -> Arc<LogManager> {
+        let logs = LogManager::open(vec![dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap();
+        logs.create("events", 0, None).unwrap();
+        logs.create("events", 1, None).unwrap();
+        Arc::new(logs)
+    }
+
+    fn lingering() -> ProducerConfig {
+        ProducerConfig{ linger: Duration::from_secs(60), ..ProducerConfig::default() }
+    }
+
+    #[test]
+    fn it_batches_embedded() {
+        let tmp = tempdir().unwrap();
+        let logs = logs(tmp.path());
+        let mut producer = BufferedProducer::embedded(logs.clone(), lingering()).unwrap();
+        let handles: Vec<SendHandle> = (0..3)
+            .map(|_| producer.send_to("events", 1, None, b"YELLOW SUBMARINE").unwrap())
+            .collect();
+        assert!(handles[0].try_wait().is_none(), "still lingering");
+        assert_eq!(logs.get("events", 1).unwrap().lock().unwrap().log_end_offset(), 0);
+
+        producer.flush();
+        let offsets: Vec<Option<Offset>> = handles.into_iter().map(|h| h.wait().unwrap()).collect();
+        assert_eq!(offsets, vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(logs.get("events", 1).unwrap().lock().unwrap().log_end_offset(), 3);
+    }
+
+    #[test]
+    fn it_sends_full_batches_without_lingering() {
+        let tmp = tempdir().unwrap();
+        let config = ProducerConfig{ batch_size: 16, ..lingering() };
+        let mut producer = BufferedProducer::embedded(logs(tmp.path()), config).unwrap();
+        let handle = producer.send_to("events", 0, None, b"YELLOW SUBMARINE").unwrap();
+        assert_eq!(handle.wait().unwrap(), Some(0));
+    }
+
+    #[test]
+    fn it_sends_after_linger() {
+        let tmp = tempdir().unwrap();
+        let config = ProducerConfig{ linger: Duration::from_millis(20), ..ProducerConfig::default() };
+        let mut producer = BufferedProducer::embedded(logs(tmp.path()), config).unwrap();
+        let start = Instant::now();
+        let handle = producer.send("events", Some(b"key"), b"XX").unwrap();
+        assert!(handle.wait().unwrap().is_some());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn it_applies_backpressure() {
+        let tmp = tempdir().unwrap();
+        let config = ProducerConfig{
+            batch_size: 64,
+            buffer_memory: 64,
+            max_block: Duration::from_millis(20),
+            ..lingering()
+        };
+        let mut producer = BufferedProducer::embedded(logs(tmp.path()), config).unwrap();
+        let err = producer.send_to("events", 0, None, &[0; 100]).err().unwrap();
+        assert!(matches!(err, Error::MessageTooLarge{ .. }), "bigger than the whole pool");
+
+        let first = producer.send_to("events", 0, None, &[0; 40]).unwrap();
+        let err = producer.send_to("events", 0, None, &[0; 40]).err().unwrap();
+        assert!(matches!(err, Error::Timeout(_)), "pool is used up until the batch is sent");
+        producer.flush();
+        assert_eq!(first.wait().unwrap(), Some(0));
+        assert!(producer.send_to("events", 0, None, &[0; 40]).is_ok(), "memory was released");
+    }
+
+    struct Flaky {
+        failures: Arc<AtomicU32>,
+        inner: EmbeddedTransport,
+    }
+
+    impl Transport for Flaky {
+        fn partitions(&mut self, topic: &str) -> Result<u32> { self.inner.partitions(topic) }
+        fn init_producer_id(&mut self, transactional_id: Option<&str>, timeout_ms: u32) -> Result<(i64, i16)> {
+            self.inner.init_producer_id(transactional_id, timeout_ms)
+        }
+        fn send_batch(
+            &mut self,
+            topic: &str,
+            partition: u32,
+            acks: i16,
+            producer: Option<ProducerBatch>,
+            records: &[PendingRecord],
+        ) -> Result<Option<Offset>> {
+            // lost responses: the batch is appended but the producer hears an error
+            let appended = self.inner.send_batch(topic, partition, acks, producer, records);
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset")))
+            }
+            appended
+        }
+    }
+
+    #[test]
+    fn it_retries_retriable_errors() {
+        let tmp = tempdir().unwrap();
+        let logs = logs(tmp.path());
+        let config = ProducerConfig{ retries: 2, retry_backoff: Duration::from_millis(1), ..lingering() };
+        let failures = Arc::new(AtomicU32::new(2));
+        let transport = Flaky{ failures: failures.clone(), inner: EmbeddedTransport::new(logs.clone()) };
+        let mut producer = BufferedProducer::with_transport(transport, config).unwrap();
+        let handle = producer.send_to("events", 0, None, b"XX").unwrap();
+        producer.flush();
+        assert_eq!(handle.wait().unwrap(), Some(0), "the retries were deduplicated");
+        assert_eq!(logs.get("events", 0).unwrap().lock().unwrap().log_end_offset(), 1);
+
+        failures.store(3, Ordering::SeqCst);
+        let handle = producer.send_to("events", 0, None, b"XX").unwrap();
+        producer.flush();
+        assert!(matches!(handle.wait(), Err(Error::Io(_))), "out of retries");
+        let first_id = producer.producer_id().unwrap().0;
+
+        let handle = producer.send_to("events", 0, None, b"XX").unwrap();
+        producer.flush();
+        assert_eq!(handle.wait().unwrap(), Some(2));
+        assert_ne!(producer.producer_id().unwrap().0, first_id, "a failed batch resets the producer id");
+    }
+
+    #[test]
+    fn it_duplicates_retries_without_idempotence() {
+        let tmp = tempdir().unwrap();
+        let logs = logs(tmp.path());
+        let config = ProducerConfig{
+            retries: 1,
+            retry_backoff: Duration::from_millis(1),
+            idempotence: false,
+            ..lingering()
+        };
+        let transport = Flaky{ failures: Arc::new(AtomicU32::new(1)), inner: EmbeddedTransport::new(logs.clone()) };
+        let mut producer = BufferedProducer::with_transport(transport, config).unwrap();
+        assert_eq!(producer.producer_id(), None);
+        let handle = producer.send_to("events", 0, None, b"XX").unwrap();
+        producer.flush();
+        assert_eq!(handle.wait().unwrap(), Some(1));
+        assert_eq!(logs.get("events", 0).unwrap().lock().unwrap().log_end_offset(), 2);
+    }
+
+    #[test]
+    fn it_sends_in_transactions() {
+        let tmp = tempdir().unwrap();
+        let logs = logs(tmp.path());
+        let transactions = Arc::new(TransactionCoordinator::open(logs.clone()).unwrap());
+        let config = ProducerConfig{ transactional_id: Some(String::from("tx")), ..lingering() };
+        let transport = EmbeddedTransport::with_coordinator(logs.clone(), transactions.clone());
+        let mut producer = BufferedProducer::with_transport(transport, config.clone()).unwrap();
+        assert!(matches!(producer.send_to("events", 0, None, b"XX"), Err(Error::InvalidTxnState(_))));
+
+        producer.begin_transaction().unwrap();
+        producer.send_to("events", 0, None, b"aborted").unwrap();
+        producer.abort_transaction().unwrap();
+        producer.begin_transaction().unwrap();
+        producer.send_to("events", 0, None, b"committed").unwrap();
+        producer.send_to("events", 1, None, b"committed").unwrap();
+        producer.commit_transaction().unwrap();
+        let events0 = logs.get("events", 0).unwrap();
+        let (messages, next) = events0.lock().unwrap().read_committed(0, 1024).unwrap();
+        assert_eq!(messages.iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![2]);
+        assert_eq!(next, 4);
+
+        // a new producer with the same transactional id fences this one
+        producer.begin_transaction().unwrap();
+        let transport = EmbeddedTransport::with_coordinator(logs.clone(), transactions);
+        let _zombie_killer = BufferedProducer::with_transport(transport, config).unwrap();
+        let handle = producer.send_to("events", 0, None, b"zombie").unwrap();
+        assert!(matches!(producer.commit_transaction(), Err(Error::ProducerFenced{ .. })));
+        assert!(handle.wait().is_err());
+    }
+
+    #[test]
+    fn it_sends_to_a_broker() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 1);
+        let mut producer = BufferedProducer::connect(addr, lingering()).unwrap();
+        assert!(producer.producer_id().is_some());
+        let first = producer.send("events", None, b"one").unwrap();
+        let second = producer.send("events", None, b"two").unwrap();
+        producer.flush();
+        assert_eq!((first.wait().unwrap(), second.wait().unwrap()), (Some(0), Some(1)));
+
+        let config = ProducerConfig{ acks: Acks::None, idempotence: false, ..lingering() };
+        assert!(matches!(ProducerConfig{ idempotence: true, ..config.clone() }.validate(), Err(Error::InvalidConfig(_))));
+        let mut fire_and_forget = BufferedProducer::connect(addr, config).unwrap();
+        let handle = fire_and_forget.send("events", None, b"three").unwrap();
+        fire_and_forget.close();
+        assert_eq!(handle.wait().unwrap(), None, "acks=0 never learns the offset");
+
+        let mut conn = Connection::connect(addr).unwrap();
+        for _ in 0..100 {
+            if conn.list_offsets("events", 0).unwrap().1 == 3 { break }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(conn.list_offsets("events", 0).unwrap(), (0, 3));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use crate::{Offset, Result};
+use crate::client::{Connection, ConsumerRecord};
+use crate::partition::transaction::{Isolation};
+
+const DEFAULT_MAX_BYTES: u32 = 1024 * 1024;
+const DEFAULT_MAX_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartFrom {
+    Earliest,
+    Latest,
+}
+
+
+// Reads every partition of one topic, keeping its own position in each
+pub struct Consumer {
+    conn: Connection,
+    topic: String,
+    positions: BTreeMap<u32, Offset>,
+    max_bytes: u32,
+    min_bytes: u32,
+    max_wait: Duration,
+    isolation: Isolation,
+}
+
+impl Consumer {
+    pub fn connect<A: ToSocketAddrs>(addr: A, topic: &str, start: StartFrom) -> Result<Consumer> {
+        let mut conn = Connection::connect(addr)?;
+        let partitions = conn.metadata(topic, false)?;
+        let mut consumer = Consumer{
+            conn,
+            topic: String::from(topic),
+            positions: BTreeMap::new(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            min_bytes: 1,
+            max_wait: DEFAULT_MAX_WAIT,
+            isolation: Isolation::ReadUncommitted,
+        };
+        consumer.assign(&partitions, start)?;
+        Ok(consumer)
+    }
+
+    pub fn assign(&mut self, partitions: &[u32], start: StartFrom) -> Result<()> {
+        // reads only these partitions from now on, ones already assigned
+        // carry on from their position and the rest from their start or end
+        let mut positions = BTreeMap::new();
+        for partition in partitions {
+            if let Some(position) = self.positions.get(partition) {
+                positions.insert(*partition, *position);
+                continue
+            }
+            let (log_start, log_end) = self.conn.list_offsets(&self.topic, *partition)?;
+            let position = match start {
+                StartFrom::Earliest => log_start,
+                StartFrom::Latest => log_end,
+            };
+            positions.insert(*partition, position);
+        }
+        self.positions = positions;
+        Ok(())
+    }
+
+    pub fn assignment(&self) -> Vec<u32> { self.positions.keys().cloned().collect() }
+    pub fn positions(&self) -> Vec<(u32, Offset)> { self.positions.iter().map(|(p, o)| (*p, *o)).collect() }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u32) { self.max_bytes = max_bytes }
+    pub fn set_min_bytes(&mut self, min_bytes: u32) { self.min_bytes = min_bytes }
+    pub fn set_max_wait(&mut self, max_wait: Duration) { self.max_wait = max_wait }
+    pub fn set_isolation(&mut self, isolation: Isolation) { self.isolation = isolation }
+    pub fn topic(&self) -> &str { &self.topic }
+    pub fn position(&self, partition: u32) -> Option<Offset> { self.positions.get(&partition).cloned() }
+    pub fn seek(&mut self, partition: u32, offset: Offset) { self.positions.insert(partition, offset); }
+
+    pub fn commit(&mut self, group: &str) -> Result<()> {
+        // stores every partition's position as the group's committed offset
+        let offsets: Vec<(u32, Offset, Option<&str>)> = self.positions.iter().map(|(p, o)| (*p, *o, None)).collect();
+        self.conn.commit_offsets(group, -1, "", &self.topic, &offsets)
+    }
+
+    pub fn seek_to_committed(&mut self, group: &str) -> Result<()> {
+        // picks up where the group left off, partitions it never committed in
+        // keep their position
+        for (partition, committed) in self.conn.fetch_offsets(group, &self.topic)? {
+            if let Some(position) = self.positions.get_mut(&partition) { *position = committed.offset }
+        }
+        Ok(())
+    }
+
+    pub fn poll(&mut self) -> Result<Vec<ConsumerRecord>> {
+        // one fetch across every partition, the broker holds it until min_bytes
+        // arrive or max_wait passes, so an idle consumer doesn't spin
+        let positions = self.positions();
+        let max_wait_ms = self.max_wait.as_millis().min(i32::MAX as u128) as u32;
+        let fetched = self.conn.fetch_wait(&self.topic, &positions, self.max_bytes, self.min_bytes, max_wait_ms, self.isolation)?;
+        let mut records = vec![];
+        for (partition, res) in fetched {
+            let fetched = res?;
+            self.positions.insert(partition, fetched.next_offset);
+            records.extend(fetched.records);
+        }
+        Ok(records)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+    use tempfile::tempdir;
+    use super::*;
+    use crate::Error;
+    use crate::client::{BufferedProducer, Connection, Producer, ProducerConfig};
+    use crate::kafka::{errors};
+    use crate::client::tests::{start_broker};
+
+    #[test]
+    fn it_polls_what_was_sent() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 2);
+        let mut producer = Producer::connect(addr).unwrap();
+        producer.send_to("events", 0, None, b"zero").unwrap();
+        producer.send_to("events", 1, None, b"one").unwrap();
+
+        let mut latest = Consumer::connect(addr, "events", StartFrom::Latest).unwrap();
+        let mut consumer = Consumer::connect(addr, "events", StartFrom::Earliest).unwrap();
+        consumer.set_max_wait(Duration::from_millis(10));
+        let values: Vec<Vec<u8>> = consumer.poll().unwrap().into_iter().filter_map(|r| r.value).collect();
+        assert_eq!(values, vec![b"zero".to_vec(), b"one".to_vec()]);
+        assert!(consumer.poll().unwrap().is_empty(), "caught up");
+
+        producer.send_to("events", 1, Some(b"k"), b"two").unwrap();
+        let polled = consumer.poll().unwrap();
+        assert_eq!((polled[0].partition, polled[0].offset), (1, 1));
+        assert_eq!(polled[0].key, Some(b"k".to_vec()));
+        assert_eq!(latest.poll().unwrap().len(), 1, "only what came after connecting");
+
+        consumer.seek(0, 0);
+        assert_eq!(consumer.poll().unwrap().len(), 1);
+        assert_eq!(consumer.position(0), Some(1));
+    }
+
+    #[test]
+    fn it_resumes_from_committed_offsets() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 2);
+        let mut producer = Producer::connect(addr).unwrap();
+        for value in &[b"one", b"two"] {
+            producer.send_to("events", 0, None, *value).unwrap();
+        }
+
+        let mut consumer = Consumer::connect(addr, "events", StartFrom::Earliest).unwrap();
+        consumer.set_max_wait(Duration::from_millis(10));
+        assert_eq!(consumer.poll().unwrap().len(), 2);
+        consumer.commit("workers").unwrap();
+
+        producer.send_to("events", 0, None, b"three").unwrap();
+        let mut restarted = Consumer::connect(addr, "events", StartFrom::Earliest).unwrap();
+        restarted.set_max_wait(Duration::from_millis(10));
+        restarted.seek_to_committed("workers").unwrap();
+        let polled = restarted.poll().unwrap();
+        assert_eq!(polled.len(), 1);
+        assert_eq!(polled[0].value, Some(b"three".to_vec()));
+        assert_eq!(restarted.position(1), Some(0));
+
+        let mut conn = Connection::connect(addr).unwrap();
+        let committed = conn.fetch_offsets("workers", "events").unwrap();
+        assert_eq!(committed.iter().map(|(p, c)| (*p, c.offset)).collect::<Vec<_>>(), vec![(0, 2), (1, 0)]);
+        assert!(conn.fetch_offsets("nobody", "events").unwrap().is_empty());
+        let err = conn.commit_offsets("workers", -1, "", "events", &[(5, 0, None)]).unwrap_err();
+        assert!(matches!(err, Error::Remote{ code: errors::UNKNOWN_TOPIC_OR_PARTITION, .. }), "{}", err);
+    }
+
+    #[test]
+    fn it_reads_committed() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 1);
+        let config = ProducerConfig{ transactional_id: Some(String::from("tx")), ..ProducerConfig::default() };
+        let mut producer = BufferedProducer::connect(addr, config).unwrap();
+        producer.partitions("events").unwrap();
+        let mut consumer = Consumer::connect(addr, "events", StartFrom::Earliest).unwrap();
+        consumer.set_isolation(Isolation::ReadCommitted);
+        consumer.set_max_wait(Duration::from_millis(10));
+
+        producer.begin_transaction().unwrap();
+        producer.send("events", None, b"aborted").unwrap();
+        producer.abort_transaction().unwrap();
+        producer.begin_transaction().unwrap();
+        producer.send("events", None, b"open").unwrap();
+        producer.flush();
+        assert!(consumer.poll().unwrap().is_empty());
+        assert_eq!(consumer.position(0), Some(2), "moved past the aborted transaction");
+
+        producer.commit_transaction().unwrap();
+        let polled = consumer.poll().unwrap();
+        assert_eq!(polled.len(), 1);
+        assert_eq!((polled[0].offset, polled[0].value.clone()), (2, Some(b"open".to_vec())));
+        assert_eq!(consumer.position(0), Some(4), "past the commit marker");
+    }
+
+    #[test]
+    fn it_long_polls_until_an_append() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 2);
+        let mut producer = Producer::connect(addr).unwrap();
+        producer.partitions("events").unwrap();
+
+        let mut consumer = Consumer::connect(addr, "events", StartFrom::Latest).unwrap();
+        consumer.set_max_wait(Duration::from_secs(30));
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            producer.send_to("events", 1, None, b"late").unwrap();
+        });
+        let start = Instant::now();
+        let polled = consumer.poll().unwrap();
+        assert_eq!(polled.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(30), "woken by the append");
+        handle.join().unwrap();
+
+        consumer.set_max_wait(Duration::from_millis(50));
+        let start = Instant::now();
+        assert!(consumer.poll().unwrap().is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50), "waited out max_wait");
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap};
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+use crate::client::{Connection, ConsumerRecord};
+use crate::client::assignor::{PartitionAssignor, RebalanceProtocol, Subscription};
+use crate::client::assignor::{CooperativeStickyAssignor, RangeAssignor, decode_assignment, encode_assignment};
+use crate::client::consumer::{Consumer, StartFrom};
+use crate::cluster::group::{JoinRequest};
+
+const PROTOCOL_TYPE: &str = "consumer";
+
+
+#[derive(Debug, Clone)]
+pub struct GroupConfig {
+    // session.timeout.ms, the coordinator drops a member it doesn't hear from for this long
+    pub session_timeout: Duration,
+    // max.poll.interval.ms, how long the group waits for members to join again
+    pub rebalance_timeout: Duration,
+    // heartbeat.interval.ms, sent from poll() so keep polls shorter than the session
+    pub heartbeat_interval: Duration,
+    // auto.offset.reset, where partitions without a committed offset start
+    pub start: StartFrom,
+    pub client_id: String,
+    // partition.assignment.strategy, most preferred first. The group uses the
+    // one most members prefer out of those they all have
+    pub assignors: Vec<Arc<dyn PartitionAssignor>>,
+}
+
+impl Default for GroupConfig {
+    fn default() -> GroupConfig {
+        GroupConfig{
+            session_timeout: Duration::from_secs(10),
+            rebalance_timeout: Duration::from_secs(60),
+            heartbeat_interval: Duration::from_secs(3),
+            start: StartFrom::Earliest,
+            client_id: String::from("latka"),
+            assignors: vec![Arc::new(RangeAssignor), Arc::new(CooperativeStickyAssignor)],
+        }
+    }
+}
+
+
+// A Consumer whose partitions are shared out among every member of a group.
+// Members join through the group coordinator, the leader assigns partitions,
+// and poll() heartbeats and joins again whenever the group rebalances.
+pub struct GroupConsumer {
+    consumer: Consumer,
+    coordinator: Connection,
+    group: String,
+    config: GroupConfig,
+    member_id: String,
+    generation: i32,
+    // how the assignor the group last agreed on rebalances
+    protocol: RebalanceProtocol,
+    last_heartbeat: Instant,
+    joined: bool,
+}
+
+impl GroupConsumer {
+    pub fn connect<A: ToSocketAddrs + Copy>(addr: A, group: &str, topic: &str, config: GroupConfig) -> Result<GroupConsumer> {
+        if config.assignors.is_empty() {
+            return Err(Error::InvalidConfig(String::from("a group consumer needs an assignor")))
+        }
+        let mut consumer = Consumer::connect(addr, topic, config.start)?;
+        consumer.assign(&[], config.start)?;
+        Ok(GroupConsumer{
+            consumer,
+            coordinator: Connection::connect(addr)?,
+            group: String::from(group),
+            config,
+            member_id: String::new(),
+            generation: -1,
+            protocol: RebalanceProtocol::Eager,
+            last_heartbeat: Instant::now(),
+            joined: false,
+        })
+    }
+
+    pub fn consumer(&mut self) -> &mut Consumer { &mut self.consumer }
+    pub fn member_id(&self) -> &str { &self.member_id }
+    pub fn generation(&self) -> i32 { self.generation }
+    pub fn assignment(&self) -> Vec<u32> { self.consumer.assignment() }
+
+    pub fn poll(&mut self) -> Result<Vec<ConsumerRecord>> {
+        if !self.joined {
+            self.join()?;
+        } else if self.last_heartbeat.elapsed() >= self.config.heartbeat_interval {
+            match self.coordinator.heartbeat(&self.group, self.generation, &self.member_id) {
+                Ok(()) => self.last_heartbeat = Instant::now(),
+                Err(Error::RebalanceInProgress) => self.join()?,
+                Err(Error::IllegalGeneration{ .. }) | Err(Error::UnknownMember(_)) => {
+                    // the group moved on without this member, its partitions
+                    // may be someone else's already so nothing is committed
+                    self.consumer.assign(&[], self.config.start)?;
+                    self.member_id.clear();
+                    self.join()?;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        self.consumer.poll()
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        let offsets: Vec<_> = self.consumer.positions().into_iter().map(|(p, o)| (p, o, None)).collect();
+        if offsets.is_empty() { return Ok(()) }
+        let topic = String::from(self.consumer.topic());
+        self.coordinator.commit_offsets(&self.group, self.generation, &self.member_id, &topic, &offsets)
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        // leaving straight away lets the rest rebalance without waiting out the session
+        self.commit()?;
+        self.coordinator.leave_group(&self.group, &self.member_id)
+    }
+
+    fn assignor(&self, name: &str) -> Result<Arc<dyn PartitionAssignor>> {
+        match self.config.assignors.iter().find(|a| a.name() == name) {
+            Some(assignor) => Ok(assignor.clone()),
+            None => Err(Error::InconsistentGroupProtocol(format!("no {} assignor", name))),
+        }
+    }
+
+    fn assign(&mut self, members: &[(String, Vec<u8>)], assignor: &dyn PartitionAssignor) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut subscriptions = BTreeMap::new();
+        let mut partitions = BTreeMap::new();
+        for (member, metadata) in members {
+            let subscription = Subscription::from_slice(metadata)?;
+            for topic in &subscription.topics {
+                if !partitions.contains_key(topic) {
+                    partitions.insert(topic.clone(), self.coordinator.metadata(topic, false)?.len() as u32);
+                }
+            }
+            subscriptions.insert(member.clone(), subscription);
+        }
+        let assignments = assignor.assign(&partitions, &subscriptions);
+        Ok(assignments.iter().map(|(member, assignment)| (member.clone(), encode_assignment(assignment))).collect())
+    }
+
+    fn join(&mut self) -> Result<()> {
+        let topic = String::from(self.consumer.topic());
+        if self.protocol == RebalanceProtocol::Eager {
+            // hand over where this member got to before its partitions move
+            self.commit()?;
+            self.consumer.assign(&[], self.config.start)?;
+        }
+        let (mut assignment, protocol) = loop {
+            let mut subscription = Subscription::new(&[&topic]);
+            let owned = self.consumer.assignment();
+            if !owned.is_empty() {
+                subscription.owned.insert(topic.clone(), owned);
+                subscription.generation = self.generation;
+            }
+            let req = JoinRequest{
+                member_id: self.member_id.clone(),
+                client_id: self.config.client_id.clone(),
+                protocol_type: String::from(PROTOCOL_TYPE),
+                protocols: self.config.assignors.iter().map(|a| (String::from(a.name()), subscription.to_vec())).collect(),
+                session_timeout: self.config.session_timeout,
+                rebalance_timeout: self.config.rebalance_timeout,
+            };
+            let joined = match self.coordinator.join_group(&self.group, &req) {
+                Ok(joined) => joined,
+                Err(Error::UnknownMember(_)) => {
+                    self.member_id.clear();
+                    continue
+                },
+                Err(e) => return Err(e),
+            };
+            self.member_id = joined.member_id.clone();
+            self.generation = joined.generation;
+            let assignor = self.assignor(&joined.protocol)?;
+            let assignments = match joined.leader == joined.member_id {
+                true => self.assign(&joined.members, assignor.as_ref())?,
+                false => vec![],
+            };
+            match self.coordinator.sync_group(&self.group, self.generation, &self.member_id, &assignments) {
+                Ok(assignment) => break (decode_assignment(&assignment)?, assignor.protocol()),
+                Err(Error::RebalanceInProgress) | Err(Error::IllegalGeneration{ .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        self.protocol = protocol;
+
+        // partitions this member keeps carry on where they are, cooperative
+        // ones it has to give up are committed and the group joined again
+        // so they can be handed on
+        let assigned = assignment.remove(&topic).unwrap_or_default();
+        let owned = self.consumer.assignment();
+        let revoked = owned.iter().any(|p| !assigned.contains(p));
+        if revoked { self.commit()? }
+        self.consumer.assign(&assigned, self.config.start)?;
+        for (partition, committed) in self.coordinator.fetch_offsets(&self.group, &topic)? {
+            if assigned.contains(&partition) && !owned.contains(&partition) {
+                self.consumer.seek(partition, committed.offset)
+            }
+        }
+        self.joined = !revoked;
+        self.last_heartbeat = Instant::now();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use tempfile::tempdir;
+    use super::*;
+    use crate::client::Producer;
+    use crate::client::tests::{start_broker};
+
+    #[test]
+    fn it_shares_partitions_among_members() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 4);
+        let mut producer = Producer::connect(addr).unwrap();
+        for partition in 0..4 {
+            producer.send_to("events", partition, None, b"XX").unwrap();
+        }
+        let config = GroupConfig{ heartbeat_interval: Duration::from_millis(10), ..GroupConfig::default() };
+
+        let mut first = GroupConsumer::connect(addr, "workers", "events", config.clone()).unwrap();
+        first.consumer().set_max_wait(Duration::from_millis(10));
+        assert_eq!(first.poll().unwrap().len(), 4);
+        assert_eq!(first.assignment(), vec![0, 1, 2, 3]);
+
+        // the second member's join blocks until the first one joins again
+        let joining = thread::spawn(move || {
+            let mut second = GroupConsumer::connect(addr, "workers", "events", config).unwrap();
+            second.consumer().set_max_wait(Duration::from_millis(10));
+            let polled = second.poll().unwrap();
+            (second, polled)
+        });
+        while first.generation() < 2 {
+            first.poll().unwrap();
+        }
+        let (second, polled) = joining.join().unwrap();
+        assert!(polled.is_empty(), "the first member committed before giving up its partitions");
+        assert_eq!(first.assignment(), vec![0, 1]);
+        assert_eq!(second.assignment(), vec![2, 3]);
+
+        second.close().unwrap();
+        while first.assignment().len() < 4 {
+            first.poll().unwrap();
+        }
+        producer.send_to("events", 3, None, b"YY").unwrap();
+        let polled = first.poll().unwrap();
+        assert_eq!((polled.len(), polled[0].partition), (1, 3));
+    }
+
+    #[test]
+    fn it_rebalances_cooperatively() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 4);
+        let mut producer = Producer::connect(addr).unwrap();
+        for partition in 0..4 {
+            producer.send_to("events", partition, None, b"XX").unwrap();
+        }
+        let config = GroupConfig{
+            heartbeat_interval: Duration::from_millis(10),
+            assignors: vec![Arc::new(CooperativeStickyAssignor)],
+            ..GroupConfig::default()
+        };
+
+        let mut first = GroupConsumer::connect(addr, "workers", "events", config.clone()).unwrap();
+        first.consumer().set_max_wait(Duration::from_millis(10));
+        assert_eq!(first.poll().unwrap().len(), 4);
+
+        let joining = thread::spawn(move || {
+            let mut second = GroupConsumer::connect(addr, "workers", "events", config).unwrap();
+            second.consumer().set_max_wait(Duration::from_millis(10));
+            let mut polled = vec![];
+            while second.assignment().is_empty() {
+                polled.extend(second.poll().unwrap());
+            }
+            (second, polled)
+        });
+
+        // the first member keeps consuming what it isn't giving up the whole time
+        let mut kept = first.assignment();
+        while first.generation() < 3 {
+            first.poll().unwrap();
+            kept.retain(|p| first.assignment().contains(p));
+        }
+        let (second, polled) = joining.join().unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(first.assignment(), kept);
+        assert_eq!(second.assignment().len(), 2);
+        assert!(second.assignment().iter().all(|p| !kept.contains(p)));
+        assert!(polled.is_empty(), "the revoked partitions were committed before being handed on");
+        assert_eq!(second.generation(), 3, "it took a second rebalance to hand them on");
+    }
+}
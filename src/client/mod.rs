@@ -0,0 +1,628 @@
+pub mod assignor;
+pub mod buffered;
+pub mod consumer;
+pub mod group;
+pub mod producer;
+pub mod transport;
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{Error, Offset, Result};
+use crate::cluster::Broker;
+use crate::cluster::controller::{self, LeaderAndIsrResponse};
+use crate::cluster::raft::{self, AppendRequest, AppendResponse, SnapshotRequest, VoteRequest, VoteResponse};
+use crate::cluster::group::{JoinRequest, JoinResult};
+use crate::cluster::manager::{TopicPartitionId};
+use crate::cluster::offsets::{CommittedOffset};
+use crate::cluster::replica::{PartitionState, ReplicaFetch};
+use crate::partition::epoch::{EpochEndOffset, EpochEntry};
+use crate::kafka::{errors};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::native;
+use crate::partition::producer::{ProducerBatch};
+use crate::partition::record::{Record, NO_PRODUCER_ID};
+use crate::partition::transaction::{Isolation};
+
+pub use crate::client::assignor::{PartitionAssignor, RangeAssignor, RoundRobinAssignor, StickyAssignor, CooperativeStickyAssignor};
+pub use crate::client::buffered::{Acks, BufferedProducer, ProducerConfig, SendHandle};
+pub use crate::client::consumer::{Consumer, StartFrom};
+pub use crate::client::group::{GroupConfig, GroupConsumer};
+pub use crate::client::producer::{Producer};
+pub use crate::client::transport::{Transport, EmbeddedTransport, BrokerTransport};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerRecord {
+    pub topic: String,
+    pub partition: u32,
+    pub offset: Offset,
+    pub timestamp: u64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+// a record waiting in a producer batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRecord {
+    pub timestamp: u64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl PendingRecord {
+    pub fn size(&self) -> usize {
+        // what it holds of the producer's buffer memory
+        8 + self.key.as_ref().map_or(0, |k| k.len()) + self.value.as_ref().map_or(0, |v| v.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchResponse {
+    pub high_watermark: Offset,
+    // where to fetch from next, past the last record when read_committed
+    // left aborted records out
+    pub next_offset: Offset,
+    pub records: Vec<ConsumerRecord>,
+}
+
+
+pub fn is_retriable(err: &Error) -> bool {
+    // worth sending again: the connection dropped or the broker expects to
+    // recover (unknown partition while a topic is created, leadership moving)
+    match err {
+        Error::Io(_) | Error::Timeout(_) | Error::NotLeader(_) | Error::LeaderNotAvailable(_) => true,
+        Error::Remote{ code, .. } => matches!(*code,
+            errors::UNKNOWN_TOPIC_OR_PARTITION
+            | errors::LEADER_NOT_AVAILABLE
+            | errors::NOT_LEADER_FOR_PARTITION
+            | errors::REQUEST_TIMED_OUT
+            | errors::NOT_ENOUGH_REPLICAS
+            | errors::NOT_ENOUGH_REPLICAS_AFTER_APPEND),
+        _ => false,
+    }
+}
+
+
+// One connection to a broker speaking the native protocol, requests are
+// answered in order
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Connection> {
+        Connection::open(TcpStream::connect(addr)?)
+    }
+
+    pub fn connect_timeout(addr: &str, timeout: Duration) -> Result<Connection> {
+        // gives up on a broker that doesn't answer within timeout, connecting included
+        let resolved = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::InvalidConfig(format!("no address for {}", addr)))?;
+        let mut conn = Connection::open(TcpStream::connect_timeout(&resolved, timeout)?)?;
+        conn.set_timeout(Some(timeout))?;
+        Ok(conn)
+    }
+
+    fn open(mut stream: TcpStream) -> Result<Connection> {
+        stream.set_nodelay(true)?;
+        stream.write_all(&native::MAGIC)?;
+        Ok(Connection{ stream })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        // how long a call waits on the broker before failing with an io error
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn write_request(&mut self, op: i8, body: Encoder) -> Result<()> {
+        let mut frame = Encoder::new();
+        frame.i8(op);
+        frame.raw(body.as_slice());
+        self.stream.write_u32::<BigEndian>(frame.len() as u32)?;
+        self.stream.write_all(frame.as_slice())?;
+        Ok(())
+    }
+
+    fn call<T, F>(&mut self, op: i8, body: Encoder, parse: F) -> Result<T>
+    where F: FnOnce(&mut Decoder) -> Result<T> {
+        self.write_request(op, body)?;
+        let size = self.stream.read_u32::<BigEndian>()?;
+        let mut response = vec![0; size as usize];
+        self.stream.read_exact(&mut response)?;
+        let mut dec = Decoder::new(&response);
+        native::decode_status(&mut dec)?;
+        parse(&mut dec)
+    }
+
+    pub fn metadata(&mut self, topic: &str, create: bool) -> Result<Vec<u32>> {
+        let mut body = Encoder::new();
+        body.string(topic);
+        body.boolean(create);
+        self.call(native::METADATA, body, |dec| {
+            let len = dec.array_len()?.unwrap_or(0);
+            (0..len).map(|_| Ok(dec.i32()? as u32)).collect()
+        })
+    }
+
+    pub fn produce(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        acks: i16,
+        key: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) -> Result<Option<Offset>> {
+        // the record's offset, None with acks=0 when the broker doesn't answer
+        let mut body = Encoder::new();
+        body.string(topic);
+        body.i32(partition as i32);
+        body.i16(acks);
+        body.bytes(key);
+        body.bytes(value);
+        if acks == 0 {
+            self.write_request(native::PRODUCE, body)?;
+            return Ok(None)
+        }
+        self.call(native::PRODUCE, body, |dec| Ok(Some(dec.i64()? as Offset)))
+    }
+
+    pub fn init_producer_id(&mut self, transactional_id: Option<&str>, timeout_ms: u32) -> Result<(i64, i16)> {
+        let mut body = Encoder::new();
+        body.nullable_string(transactional_id);
+        body.i32(timeout_ms.min(i32::MAX as u32) as i32);
+        self.call(native::INIT_PRODUCER_ID, body, |dec| Ok((dec.i64()?, dec.i16()?)))
+    }
+
+    pub fn add_partitions_to_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, partitions: &[(&str, u32)]) -> Result<()> {
+        let mut body = Encoder::new();
+        body.string(transactional_id);
+        body.i64(producer_id);
+        body.i16(epoch);
+        body.array_len(partitions.len());
+        for (topic, partition) in partitions {
+            body.string(topic);
+            body.i32(*partition as i32);
+        }
+        self.call(native::ADD_PARTITIONS_TO_TXN, body, |_| Ok(()))
+    }
+
+    pub fn end_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, commit: bool) -> Result<()> {
+        let mut body = Encoder::new();
+        body.string(transactional_id);
+        body.i64(producer_id);
+        body.i16(epoch);
+        body.boolean(commit);
+        self.call(native::END_TXN, body, |_| Ok(()))
+    }
+
+    pub fn produce_batch(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        acks: i16,
+        producer: Option<ProducerBatch>,
+        records: &[PendingRecord],
+    ) -> Result<Option<Offset>> {
+        // the offset of the first record, None with acks=0 since the broker doesn't answer
+        let mut body = Encoder::new();
+        body.string(topic);
+        body.i32(partition as i32);
+        body.i16(acks);
+        match producer {
+            Some(producer) => {
+                body.i64(producer.producer_id);
+                body.i16(producer.epoch);
+                body.i32(producer.base_sequence);
+                body.boolean(producer.transactional);
+            },
+            None => {
+                body.i64(NO_PRODUCER_ID);
+                body.i16(-1);
+                body.i32(-1);
+                body.boolean(false);
+            },
+        }
+        body.array_len(records.len());
+        for record in records {
+            body.i64(record.timestamp as i64);
+            body.bytes(record.key.as_deref());
+            body.bytes(record.value.as_deref());
+        }
+        if acks == 0 {
+            self.write_request(native::PRODUCE_BATCH, body)?;
+            return Ok(None)
+        }
+        self.call(native::PRODUCE_BATCH, body, |dec| Ok(Some(dec.i64()? as Offset)))
+    }
+
+    pub fn fetch(&mut self, topic: &str, partition: u32, offset: Offset, max_bytes: u32) -> Result<FetchResponse> {
+        // returns straight away, even with nothing to read
+        let mut fetched = self.fetch_wait(topic, &[(partition, offset)], max_bytes, 0, 0, Isolation::ReadUncommitted)?;
+        match fetched.pop() {
+            Some((_, res)) => res,
+            None => Err(Error::InvalidRequest(String::from("fetch response has no partitions"))),
+        }
+    }
+
+    pub fn fetch_wait(
+        &mut self,
+        topic: &str,
+        positions: &[(u32, Offset)],
+        max_bytes: u32,
+        min_bytes: u32,
+        max_wait_ms: u32,
+        isolation: Isolation,
+    ) -> Result<Vec<(u32, Result<FetchResponse>)>> {
+        // the broker holds on to the request until min_bytes are there to read
+        // across the partitions, or max_wait_ms passes
+        let mut body = Encoder::new();
+        body.string(topic);
+        body.i32(max_wait_ms as i32);
+        body.i32(min_bytes as i32);
+        body.i8(isolation.as_i8());
+        body.array_len(positions.len());
+        for (partition, offset) in positions {
+            body.i32(*partition as i32);
+            body.i64(*offset as i64);
+            body.i32(max_bytes as i32);
+        }
+        self.call(native::FETCH, body, |dec| {
+            let len = dec.array_len()?.unwrap_or(0);
+            let mut fetched = Vec::with_capacity(len);
+            for _ in 0..len {
+                let partition = dec.i32()? as u32;
+                if let Err(e) = native::decode_status(dec) {
+                    fetched.push((partition, Err(e)));
+                    continue
+                }
+                let high_watermark = dec.i64()? as Offset;
+                let next_offset = dec.i64()? as Offset;
+                let records = native::decode_messages(dec)?.into_iter()
+                    .map(|message| {
+                        // anything appended as a bare payload reads as a value
+                        let record = Record::from_slice(&message.payload)
+                            .unwrap_or_else(|_| Record::new(0, None, Some(&message.payload)));
+                        ConsumerRecord{
+                            topic: String::from(topic),
+                            partition,
+                            offset: message.offset,
+                            timestamp: record.timestamp,
+                            key: record.key,
+                            value: record.value,
+                        }
+                    })
+                    .collect();
+                fetched.push((partition, Ok(FetchResponse{ high_watermark, next_offset, records })));
+            }
+            Ok(fetched)
+        })
+    }
+
+    pub fn commit_offsets(
+        &mut self,
+        group: &str,
+        generation: i32,
+        member_id: &str,
+        topic: &str,
+        offsets: &[(u32, Offset, Option<&str>)],
+    ) -> Result<()> {
+        // (partition, offset, metadata), committed together. Group members commit
+        // under their generation, anyone else with generation -1 and no member id
+        let mut body = Encoder::new();
+        body.string(group);
+        body.i32(generation);
+        body.string(member_id);
+        body.string(topic);
+        body.array_len(offsets.len());
+        for (partition, offset, metadata) in offsets {
+            body.i32(*partition as i32);
+            body.i64(*offset as i64);
+            body.nullable_string(*metadata);
+        }
+        self.call(native::OFFSET_COMMIT, body, |_| Ok(()))
+    }
+
+    pub fn fetch_offsets(&mut self, group: &str, topic: &str) -> Result<Vec<(u32, CommittedOffset)>> {
+        // the group's committed offsets in the topic's partitions, those it
+        // never committed in are left out
+        let mut body = Encoder::new();
+        body.string(group);
+        body.string(topic);
+        self.call(native::OFFSET_FETCH, body, |dec| {
+            let len = dec.array_len()?.unwrap_or(0);
+            (0..len).map(|_| Ok((dec.i32()? as u32, CommittedOffset{
+                offset: dec.i64()? as Offset,
+                metadata: dec.nullable_string()?,
+                commit_timestamp: dec.i64()? as u64,
+            }))).collect()
+        })
+    }
+
+    pub fn join_group(&mut self, group: &str, req: &JoinRequest) -> Result<JoinResult> {
+        // blocks until the group's next generation is formed
+        let mut body = Encoder::new();
+        body.string(group);
+        body.string(&req.member_id);
+        body.string(&req.client_id);
+        body.string(&req.protocol_type);
+        body.i32(req.session_timeout.as_millis().min(i32::MAX as u128) as i32);
+        body.i32(req.rebalance_timeout.as_millis().min(i32::MAX as u128) as i32);
+        body.array_len(req.protocols.len());
+        for (name, metadata) in &req.protocols {
+            body.string(name);
+            body.bytes(Some(metadata));
+        }
+        self.call(native::JOIN_GROUP, body, |dec| {
+            let mut joined = JoinResult{
+                generation: dec.i32()?,
+                protocol: dec.string()?,
+                leader: dec.string()?,
+                member_id: dec.string()?,
+                members: vec![],
+            };
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                joined.members.push((dec.string()?, dec.bytes()?.unwrap_or_default().to_vec()));
+            }
+            Ok(joined)
+        })
+    }
+
+    pub fn sync_group(&mut self, group: &str, generation: i32, member_id: &str, assignments: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+        // the leader sends everyone's assignment, each member gets back its own
+        let mut body = Encoder::new();
+        body.string(group);
+        body.i32(generation);
+        body.string(member_id);
+        body.array_len(assignments.len());
+        for (id, assignment) in assignments {
+            body.string(id);
+            body.bytes(Some(assignment));
+        }
+        self.call(native::SYNC_GROUP, body, |dec| Ok(dec.bytes()?.unwrap_or_default().to_vec()))
+    }
+
+    pub fn heartbeat(&mut self, group: &str, generation: i32, member_id: &str) -> Result<()> {
+        let mut body = Encoder::new();
+        body.string(group);
+        body.i32(generation);
+        body.string(member_id);
+        self.call(native::HEARTBEAT, body, |_| Ok(()))
+    }
+
+    pub fn leave_group(&mut self, group: &str, member_id: &str) -> Result<()> {
+        let mut body = Encoder::new();
+        body.string(group);
+        body.string(member_id);
+        self.call(native::LEAVE_GROUP, body, |_| Ok(()))
+    }
+
+    pub fn list_offsets(&mut self, topic: &str, partition: u32) -> Result<(Offset, Offset)> {
+        // the log start offset and the high watermark
+        let mut body = Encoder::new();
+        body.string(topic);
+        body.i32(partition as i32);
+        self.call(native::LIST_OFFSETS, body, |dec| Ok((dec.i64()? as Offset, dec.i64()? as Offset)))
+    }
+
+    pub fn replica_fetch(
+        &mut self,
+        replica_id: u32,
+        positions: &[(TopicPartitionId, i32, Offset)],
+        max_wait_ms: u32,
+        max_bytes: u32,
+    ) -> Result<Vec<(TopicPartitionId, Result<ReplicaFetch>)>> {
+        // a follower copying the leader's log, each partition with the leader
+        // epoch the follower knows and where its copy ends. The leader waits
+        // for something to copy
+        let mut body = Encoder::new();
+        body.i32(replica_id as i32);
+        body.i32(max_wait_ms as i32);
+        body.i32(max_bytes as i32);
+        body.array_len(positions.len());
+        for (tp, leader_epoch, offset) in positions {
+            body.string(&tp.topic);
+            body.i32(tp.partition as i32);
+            body.i32(*leader_epoch);
+            body.i64(*offset as i64);
+        }
+        self.call(native::REPLICA_FETCH, body, |dec| {
+            let len = dec.array_len()?.unwrap_or(0);
+            let mut fetched = Vec::with_capacity(len);
+            for _ in 0..len {
+                let tp = TopicPartitionId::new(&dec.string()?, dec.i32()? as u32);
+                if let Err(e) = native::decode_status(dec) {
+                    fetched.push((tp, Err(e)));
+                    continue
+                }
+                let log_end_offset = dec.i64()? as Offset;
+                let high_watermark = dec.i64()? as Offset;
+                let mut epochs = vec![];
+                for _ in 0..dec.array_len()?.unwrap_or(0) {
+                    epochs.push(EpochEntry{ epoch: dec.i32()?, start_offset: dec.i64()? as Offset });
+                }
+                let messages = native::decode_messages(dec)?;
+                fetched.push((tp, Ok(ReplicaFetch{ log_end_offset, high_watermark, epochs, messages })));
+            }
+            Ok(fetched)
+        })
+    }
+
+    pub fn offsets_for_leader_epoch(
+        &mut self,
+        replica_id: u32,
+        requests: &[(TopicPartitionId, i32, i32)],
+    ) -> Result<Vec<(TopicPartitionId, Result<EpochEndOffset>)>> {
+        // (partition, current leader epoch, epoch) to the largest epoch at or
+        // below epoch the leader has and where it ends in the leader's log
+        let mut body = Encoder::new();
+        body.i32(replica_id as i32);
+        body.array_len(requests.len());
+        for (tp, current_leader_epoch, epoch) in requests {
+            body.string(&tp.topic);
+            body.i32(tp.partition as i32);
+            body.i32(*current_leader_epoch);
+            body.i32(*epoch);
+        }
+        self.call(native::OFFSETS_FOR_LEADER_EPOCH, body, |dec| {
+            let len = dec.array_len()?.unwrap_or(0);
+            let mut answers = Vec::with_capacity(len);
+            for _ in 0..len {
+                let tp = TopicPartitionId::new(&dec.string()?, dec.i32()? as u32);
+                match native::decode_status(dec) {
+                    Ok(()) => answers.push((tp, Ok((dec.i32()?, dec.i64()? as Offset)))),
+                    Err(e) => answers.push((tp, Err(e))),
+                }
+            }
+            Ok(answers)
+        })
+    }
+
+    pub fn leader_and_isr(
+        &mut self,
+        controller_id: u32,
+        controller_epoch: i32,
+        brokers: &[Broker],
+        states: &[(TopicPartitionId, PartitionState)],
+    ) -> Result<LeaderAndIsrResponse> {
+        // the controller's leaders and isrs for partitions the broker has a
+        // replica of, and where to find the leaders. Doubles as the
+        // controller's check the broker is alive
+        let mut body = Encoder::new();
+        body.i32(controller_id as i32);
+        body.i32(controller_epoch);
+        controller::encode_brokers(&mut body, brokers);
+        body.array_len(states.len());
+        for (tp, state) in states {
+            body.string(&tp.topic);
+            body.i32(tp.partition as i32);
+            controller::encode_state(&mut body, state);
+        }
+        self.call(native::LEADER_AND_ISR, body, |dec| {
+            let mut errors = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                let tp = TopicPartitionId::new(&dec.string()?, dec.i32()? as u32);
+                if let Err(e) = native::decode_status(dec) { errors.push((tp, e)) }
+            }
+            let mut alter_isr = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                let tp = TopicPartitionId::new(&dec.string()?, dec.i32()? as u32);
+                alter_isr.push((tp, controller::decode_state(dec)?));
+            }
+            Ok(LeaderAndIsrResponse{ errors, alter_isr })
+        })
+    }
+
+    pub fn reassign_partitions(&mut self, moves: &[(TopicPartitionId, Vec<u32>)]) -> Result<Vec<(TopicPartitionId, Result<()>)>> {
+        // to the controller, each partition's replicas moved to the brokers
+        // given. The move goes on in the background
+        let mut body = Encoder::new();
+        body.array_len(moves.len());
+        for (tp, target) in moves {
+            body.string(&tp.topic);
+            body.i32(tp.partition as i32);
+            body.array_len(target.len());
+            for id in target {
+                body.i32(*id as i32);
+            }
+        }
+        self.call(native::REASSIGN_PARTITIONS, body, |dec| {
+            let mut started = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                let tp = TopicPartitionId::new(&dec.string()?, dec.i32()? as u32);
+                started.push((tp, native::decode_status(dec)));
+            }
+            Ok(started)
+        })
+    }
+
+    pub fn register_broker(&mut self, broker: &Broker) -> Result<i64> {
+        // to the controller, the broker's epoch comes back
+        let mut body = Encoder::new();
+        controller::encode_broker(&mut body, broker);
+        self.call(native::BROKER_REGISTRATION, body, |dec| dec.i64())
+    }
+
+    pub fn broker_heartbeat(&mut self, broker_id: u32, epoch: i64) -> Result<Vec<Broker>> {
+        // to the controller, answered with the live brokers
+        let mut body = Encoder::new();
+        body.i32(broker_id as i32);
+        body.i64(epoch);
+        self.call(native::BROKER_HEARTBEAT, body, controller::decode_brokers)
+    }
+
+    pub fn raft_vote(&mut self, req: &VoteRequest) -> Result<VoteResponse> {
+        let mut body = Encoder::new();
+        raft::encode_vote_request(&mut body, req);
+        self.call(native::RAFT_VOTE, body, raft::decode_vote_response)
+    }
+
+    pub fn raft_append(&mut self, req: &AppendRequest) -> Result<AppendResponse> {
+        let mut body = Encoder::new();
+        raft::encode_append_request(&mut body, req);
+        self.call(native::RAFT_APPEND, body, raft::decode_append_response)
+    }
+
+    pub fn raft_install_snapshot(&mut self, req: &SnapshotRequest) -> Result<i32> {
+        // answered with the member's term
+        let mut body = Encoder::new();
+        raft::encode_snapshot_request(&mut body, req);
+        self.call(native::RAFT_SNAPSHOT, body, |dec| dec.i32())
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+    use crate::cluster::Broker;
+    use crate::cluster::manager::{LogManager};
+    use crate::partition::segment::{MaxBytes};
+    use crate::server::{Server, ServerConfig};
+
+    pub fn start_broker(log_dir: &Path, num_partitions: u32) -> SocketAddr {
+        let logs = Arc::new(LogManager::open(vec![log_dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+        let config = ServerConfig{
+            num_partitions,
+            group_min_session_timeout: Duration::from_millis(1),
+            ..ServerConfig::default()
+        };
+        let server = Server::bind(Broker::new(0, "127.0.0.1", 0), logs, config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+        addr
+    }
+
+    #[test]
+    fn it_speaks_the_native_protocol() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut conn = Connection::connect(start_broker(tmp.path(), 2)).unwrap();
+
+        assert_eq!(conn.metadata("events", false).unwrap(), Vec::<u32>::new());
+        assert_eq!(conn.metadata("events", true).unwrap(), vec![0, 1]);
+        assert_eq!(conn.produce("events", 1, 1, Some(b"k"), Some(b"v")).unwrap(), Some(0));
+        assert_eq!(conn.produce("events", 1, -1, None, Some(b"w")).unwrap(), Some(1));
+        assert_eq!(conn.list_offsets("events", 1).unwrap(), (0, 2));
+
+        let fetched = conn.fetch("events", 1, 1, 1024).unwrap();
+        assert_eq!(fetched.high_watermark, 2);
+        assert_eq!(fetched.records.len(), 1);
+        assert_eq!((fetched.records[0].offset, fetched.records[0].value.clone()), (1, Some(b"w".to_vec())));
+
+        let err = conn.fetch("events", 7, 0, 1024).unwrap_err();
+        assert!(matches!(err, Error::Remote{ code: errors::UNKNOWN_TOPIC_OR_PARTITION, .. }), "{}", err);
+        let err = conn.fetch("events", 1, 9, 1024).unwrap_err();
+        assert!(matches!(err, Error::Remote{ code: errors::OFFSET_OUT_OF_RANGE, .. }), "{}", err);
+        assert!(conn.list_offsets("events", 0).is_ok(), "connection survives errors");
+    }
+}
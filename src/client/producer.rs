@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+
+use crate::{Error, Offset, Result};
+use crate::client::{Acks, Connection};
+use crate::cluster::partitioner::{Partitioner, DefaultPartitioner};
+use crate::kafka::{errors};
+
+
+// Sends one record at a time over a native connection, picking partitions
+// with a Partitioner the same way Topic::send does locally
+pub struct Producer {
+    conn: Connection,
+    partitioner: Box<dyn Partitioner>,
+    partitions: HashMap<String, u32>,
+    // Leader unless set, All waits for every in-sync replica
+    acks: Acks,
+}
+
+impl Producer {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Producer> {
+        Ok(Producer{
+            conn: Connection::connect(addr)?,
+            partitioner: Box::new(DefaultPartitioner::new()),
+            partitions: HashMap::new(),
+            acks: Acks::Leader,
+        })
+    }
+
+    pub fn set_acks(&mut self, acks: Acks) -> Result<()> {
+        // every send waits for its offset, fire and forget is BufferedProducer's
+        if acks == Acks::None {
+            return Err(Error::InvalidConfig(String::from("a Producer needs acks from the broker")))
+        }
+        self.acks = acks;
+        Ok(())
+    }
+
+    pub fn set_partitioner<P: Partitioner + 'static>(&mut self, partitioner: P) {
+        self.partitioner = Box::new(partitioner);
+    }
+
+    pub fn partitions(&mut self, topic: &str) -> Result<u32> {
+        // asking creates the topic if the broker allows it
+        if let Some(count) = self.partitions.get(topic) { return Ok(*count) }
+        let count = self.conn.metadata(topic, true)?.len() as u32;
+        if count == 0 {
+            return Err(Error::Remote{
+                code: errors::UNKNOWN_TOPIC_OR_PARTITION,
+                message: format!("topic {} doesn't exist", topic),
+            })
+        }
+        self.partitions.insert(String::from(topic), count);
+        Ok(count)
+    }
+
+    pub fn send(&mut self, topic: &str, key: Option<&[u8]>, value: &[u8]) -> Result<Offset> {
+        let count = self.partitions(topic)?;
+        let partition = self.partitioner.partition(topic, key, value, count);
+        let offset = self.send_to(topic, partition, key, value)?;
+        // every send is its own batch
+        self.partitioner.on_new_batch(topic, partition, count);
+        Ok(offset)
+    }
+
+    pub fn send_to(&mut self, topic: &str, partition: u32, key: Option<&[u8]>, value: &[u8]) -> Result<Offset> {
+        self.partitions(topic)?;
+        let offset = self.conn.produce(topic, partition, self.acks.as_i16(), key, Some(value))?;
+        offset.ok_or_else(|| Error::InvalidRequest(String::from("the broker didn't answer with an offset")))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+    use crate::client::tests::{start_broker};
+    use crate::cluster::partitioner::{to_positive, murmur2};
+
+    #[test]
+    fn it_sends_to_the_keyed_partition() {
+        let tmp = tempdir().unwrap();
+        let addr = start_broker(tmp.path(), 4);
+        let mut producer = Producer::connect(addr).unwrap();
+
+        assert_eq!(producer.send("events", Some(b"foobar"), b"one").unwrap(), 0);
+        assert_eq!(producer.send("events", Some(b"foobar"), b"two").unwrap(), 1);
+        assert_eq!(producer.partitions("events").unwrap(), 4);
+
+        let partition = to_positive(murmur2(b"foobar")) % 4;
+        let mut conn = Connection::connect(addr).unwrap();
+        assert_eq!(conn.list_offsets("events", partition).unwrap(), (0, 2));
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use crate::{Error, Offset, Result};
+use crate::client::{Connection, PendingRecord};
+use crate::cluster::manager::{LogManager, TopicPartitionId};
+use crate::cluster::transaction::{TransactionCoordinator};
+use crate::partition::producer::{ProducerBatch, next_sequence};
+use crate::partition::record::{Record};
+use crate::partition::transaction::{TRANSACTIONAL};
+
+
+// Where a BufferedProducer's batches go. `send_batch` appends the records to
+// one partition back to back and returns the first one's offset, None when
+// acks=0 means nobody waits to find out. Batches from an idempotent producer
+// carry its id, epoch and first sequence. Transactions need a coordinator
+// behind the transport, without one the transaction calls fail.
+pub trait Transport: Send {
+    fn partitions(&mut self, topic: &str) -> Result<u32>;
+    fn init_producer_id(&mut self, transactional_id: Option<&str>, timeout_ms: u32) -> Result<(i64, i16)>;
+    fn send_batch(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        acks: i16,
+        producer: Option<ProducerBatch>,
+        records: &[PendingRecord],
+    ) -> Result<Option<Offset>>;
+
+    fn add_partition_to_txn(&mut self, transactional_id: &str, _producer_id: i64, _epoch: i16, _topic: &str, _partition: u32) -> Result<()> {
+        Err(Error::InvalidTxnState(format!("no transaction coordinator for {}", transactional_id)))
+    }
+
+    fn end_txn(&mut self, transactional_id: &str, _producer_id: i64, _epoch: i16, _commit: bool) -> Result<()> {
+        Err(Error::InvalidTxnState(format!("no transaction coordinator for {}", transactional_id)))
+    }
+}
+
+
+// Appends in-process through a LogManager's partitions
+pub struct EmbeddedTransport {
+    logs: Arc<LogManager>,
+    transactions: Option<Arc<TransactionCoordinator>>,
+}
+
+impl EmbeddedTransport {
+    pub fn new(logs: Arc<LogManager>) -> EmbeddedTransport { EmbeddedTransport{ logs, transactions: None } }
+
+    pub fn with_coordinator(logs: Arc<LogManager>, transactions: Arc<TransactionCoordinator>) -> EmbeddedTransport {
+        EmbeddedTransport{ logs, transactions: Some(transactions) }
+    }
+
+    fn coordinator(&self, transactional_id: &str) -> Result<&TransactionCoordinator> {
+        match &self.transactions {
+            Some(transactions) => Ok(transactions),
+            None => Err(Error::InvalidTxnState(format!("no transaction coordinator for {}", transactional_id))),
+        }
+    }
+}
+
+impl Transport for EmbeddedTransport {
+    fn partitions(&mut self, topic: &str) -> Result<u32> {
+        Ok(self.logs.partitions_of(topic).len() as u32)
+    }
+
+    fn init_producer_id(&mut self, transactional_id: Option<&str>, timeout_ms: u32) -> Result<(i64, i16)> {
+        match transactional_id {
+            Some(id) => self.coordinator(id)?.init_producer_id(id, timeout_ms),
+            None => Ok((self.logs.next_producer_id()?, 0)),
+        }
+    }
+
+    fn send_batch(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        acks: i16,
+        producer: Option<ProducerBatch>,
+        records: &[PendingRecord],
+    ) -> Result<Option<Offset>> {
+        let shared = match self.logs.get(topic, partition) {
+            Some(shared) => shared,
+            None => return Err(Error::PartitionNotFound(self.logs.log_dirs()[0].join(format!("{}-{}", topic, partition)))),
+        };
+        let records: Vec<Record> = records.iter().enumerate().map(|(i, record)| {
+            let mut appended = Record::new(record.timestamp, record.key.as_deref(), record.value.as_deref());
+            match producer {
+                Some(p) => {
+                    if p.transactional { appended.attributes = TRANSACTIONAL }
+                    appended.with_producer(p.producer_id, p.epoch, next_sequence(p.base_sequence, i as i32))
+                },
+                None => appended,
+            }
+        }).collect();
+        let base_offset = shared.lock().unwrap().append_batch(&records)?;
+        // acks=all is the same as acks=1 while there are no replicas to wait for
+        if acks == 0 { Ok(None) } else { Ok(Some(base_offset)) }
+    }
+
+    fn add_partition_to_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, topic: &str, partition: u32) -> Result<()> {
+        let partitions = [TopicPartitionId::new(topic, partition)];
+        self.coordinator(transactional_id)?.add_partitions(transactional_id, producer_id, epoch, &partitions)
+    }
+
+    fn end_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, commit: bool) -> Result<()> {
+        self.coordinator(transactional_id)?.end_transaction(transactional_id, producer_id, epoch, commit)
+    }
+}
+
+
+// Sends to a broker over the native protocol, reconnecting after io errors
+pub struct BrokerTransport {
+    addr: SocketAddr,
+    conn: Option<Connection>,
+}
+
+impl BrokerTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<BrokerTransport> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(Error::InvalidConfig(String::from("broker address resolves to nothing"))),
+        };
+        Ok(BrokerTransport{ addr, conn: Some(Connection::connect(addr)?) })
+    }
+
+    fn with_conn<T, F>(&mut self, f: F) -> Result<T>
+    where F: FnOnce(&mut Connection) -> Result<T> {
+        if self.conn.is_none() {
+            self.conn = Some(Connection::connect(self.addr)?);
+        }
+        let res = f(self.conn.as_mut().unwrap());
+        if let Err(Error::Io(_)) = res {
+            // the stream may be mid-frame, start over on the next request
+            self.conn = None;
+        }
+        res
+    }
+}
+
+impl Transport for BrokerTransport {
+    fn partitions(&mut self, topic: &str) -> Result<u32> {
+        self.with_conn(|conn| Ok(conn.metadata(topic, true)?.len() as u32))
+    }
+
+    fn init_producer_id(&mut self, transactional_id: Option<&str>, timeout_ms: u32) -> Result<(i64, i16)> {
+        self.with_conn(|conn| conn.init_producer_id(transactional_id, timeout_ms))
+    }
+
+    fn send_batch(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        acks: i16,
+        producer: Option<ProducerBatch>,
+        records: &[PendingRecord],
+    ) -> Result<Option<Offset>> {
+        self.with_conn(|conn| conn.produce_batch(topic, partition, acks, producer, records))
+    }
+
+    fn add_partition_to_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, topic: &str, partition: u32) -> Result<()> {
+        self.with_conn(|conn| conn.add_partitions_to_txn(transactional_id, producer_id, epoch, &[(topic, partition)]))
+    }
+
+    fn end_txn(&mut self, transactional_id: &str, producer_id: i64, epoch: i16, commit: bool) -> Result<()> {
+        self.with_conn(|conn| conn.end_txn(transactional_id, producer_id, epoch, commit))
+    }
+}
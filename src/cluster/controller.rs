@@ -0,0 +1,1185 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{Ordering};
+use std::thread;
+use std::time::{Instant};
+
+use crate::{Error, Result};
+use crate::client::{Connection};
+use crate::cluster::Broker;
+use crate::cluster::manager::{BackgroundTasks, TopicPartitionId};
+use crate::cluster::metadata::{MetadataImage, MetadataRecord, Reassignment};
+use crate::cluster::placement::{assign_replicas};
+use crate::cluster::raft::{RaftNode};
+use crate::cluster::replica::{PartitionState};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::server::{ServerConfig};
+
+
+// What a broker answered a leader and isr request with
+#[derive(Debug)]
+pub struct LeaderAndIsrResponse {
+    // the partitions whose state the broker couldn't take on
+    pub errors: Vec<(TopicPartitionId, Error)>,
+    // AlterIsr, the partitions the broker leads with the isr it wants them
+    // to have. It goes on with the old one until the controller commits it
+    pub alter_isr: Vec<(TopicPartitionId, PartitionState)>,
+}
+
+pub fn encode_state(enc: &mut Encoder, state: &PartitionState) {
+    enc.i32(state.leader as i32);
+    enc.i32(state.leader_epoch);
+    enc.array_len(state.replicas.len());
+    for id in &state.replicas {
+        enc.i32(*id as i32);
+    }
+    enc.array_len(state.isr.len());
+    for id in &state.isr {
+        enc.i32(*id as i32);
+    }
+}
+
+pub fn decode_state(dec: &mut Decoder) -> Result<PartitionState> {
+    let broker_id = |id: i32| match id {
+        id if id >= 0 => Ok(id as u32),
+        id => Err(Error::InvalidRequest(format!("broker {}", id))),
+    };
+    let leader = broker_id(dec.i32()?)?;
+    let leader_epoch = dec.i32()?;
+    let mut replicas = vec![];
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        replicas.push(broker_id(dec.i32()?)?);
+    }
+    let mut isr = vec![];
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        isr.push(broker_id(dec.i32()?)?);
+    }
+    Ok(PartitionState{ replicas, leader, leader_epoch, isr })
+}
+
+pub fn encode_brokers(enc: &mut Encoder, brokers: &[Broker]) {
+    enc.array_len(brokers.len());
+    for broker in brokers {
+        encode_broker(enc, broker);
+    }
+}
+
+pub fn encode_broker(enc: &mut Encoder, broker: &Broker) {
+    enc.i32(broker.id() as i32);
+    enc.string(broker.host());
+    enc.i32(broker.port() as i32);
+    enc.nullable_string(broker.rack());
+}
+
+pub fn decode_brokers(dec: &mut Decoder) -> Result<Vec<Broker>> {
+    let mut brokers = vec![];
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        brokers.push(decode_broker(dec)?);
+    }
+    Ok(brokers)
+}
+
+pub fn decode_broker(dec: &mut Decoder) -> Result<Broker> {
+    let id = dec.i32()?;
+    let host = dec.string()?;
+    let port = dec.i32()?;
+    let rack = dec.nullable_string()?;
+    if id < 0 || port < 0 || port > u16::MAX as i32 {
+        return Err(Error::InvalidRequest(format!("broker {} at {}:{}", id, host, port)))
+    }
+    let broker = Broker::new(id as u32, &host, port as u16);
+    Ok(match rack {
+        Some(rack) => broker.with_rack(&rack),
+        None => broker,
+    })
+}
+
+
+struct BrokerState {
+    broker: Broker,
+    // the offset of the broker's registration in the metadata log, a new
+    // one each time it registers. Heartbeats carry it
+    epoch: i64,
+    // registered brokers only count as alive while they heartbeat, the
+    // others while they answer the controller
+    registered: bool,
+    // not alive is fenced: it leads nothing and drops out of every isr
+    alive: bool,
+    last_seen: Instant,
+    conn: Option<Connection>,
+    // partitions whose latest state the broker is still to be sent
+    pending: BTreeSet<TopicPartitionId>,
+}
+
+struct ControllerState {
+    // the quorum term the controller took over in, None while it isn't the
+    // quorum's leader. Brokers know it as the controller epoch
+    epoch: Option<i32>,
+    brokers: BTreeMap<u32, BrokerState>,
+    // as committed to the metadata quorum, and changed from there until
+    // the changes are committed too
+    partitions: BTreeMap<TopicPartitionId, PartitionState>,
+    reassignments: BTreeMap<TopicPartitionId, Reassignment>,
+    // partitions none of whose replicas is fit to lead, waiting for one to come back
+    offline: BTreeSet<TopicPartitionId>,
+    last_rebalance: Instant,
+}
+
+impl ControllerState {
+    fn alive(&self, id: u32) -> bool {
+        self.brokers.get(&id).is_some_and(|b| b.alive)
+    }
+
+    fn live_brokers(&self) -> Vec<Broker> {
+        self.brokers.values().filter(|b| b.alive).map(|b| b.broker.clone()).collect()
+    }
+
+    fn add(&mut self, broker: Broker, registered: bool, epoch: i64, unclean: bool) {
+        // a broker added again is a restart, it starts over with a new epoch
+        let id = broker.id();
+        let known = BrokerState{ broker, epoch, registered, alive: false, last_seen: Instant::now(), conn: None, pending: BTreeSet::new() };
+        self.brokers.insert(id, known);
+        self.broker_started(id, unclean);
+    }
+
+    fn load(&mut self, term: i32, image: &MetadataImage, unclean: bool) {
+        // a controller newly in charge starts from the committed image, and
+        // checks on the brokers there itself until they register with it.
+        // They all hear of every partition they have a replica of
+        self.epoch = Some(term);
+        self.brokers.clear();
+        self.offline.clear();
+        self.sync(image);
+        for broker in image.brokers() {
+            self.add(broker, false, -1, unclean);
+        }
+        self.last_rebalance = Instant::now();
+    }
+
+    fn sync(&mut self, image: &MetadataImage) {
+        let (partitions, reassignments) = committed(image);
+        self.partitions = partitions;
+        self.reassignments = reassignments;
+    }
+
+    fn changes(&self, image: &MetadataImage) -> Vec<MetadataRecord> {
+        // what the image needs to catch up with the controller
+        let (partitions, reassignments) = committed(image);
+        let mut records = vec![];
+        for (tp, state) in self.partitions.iter().filter(|(tp, s)| partitions.get(tp) != Some(s)) {
+            let topic = MetadataRecord::Topic(tp.topic.clone());
+            if image.topic(&tp.topic).is_none() && !records.contains(&topic) { records.push(topic) }
+            records.push(MetadataRecord::Partition{ topic: tp.topic.clone(), partition: tp.partition, state: state.clone() });
+        }
+        let moving: BTreeSet<&TopicPartitionId> = self.reassignments.keys().chain(reassignments.keys()).collect();
+        for tp in moving.into_iter().filter(|tp| self.reassignments.get(tp) != reassignments.get(tp)) {
+            let reassignment = self.reassignments.get(tp).cloned();
+            records.push(MetadataRecord::Reassignment{ topic: tp.topic.clone(), partition: tp.partition, reassignment });
+        }
+        records
+    }
+
+    fn send(&mut self, tp: &TopicPartitionId) {
+        // every live replica hears of the partition's new state
+        let replicas = match self.partitions.get(tp) {
+            Some(state) => state.replicas.clone(),
+            None => return,
+        };
+        for id in replicas {
+            if let Some(broker) = self.brokers.get_mut(&id).filter(|b| b.alive) {
+                broker.pending.insert(tp.clone());
+            }
+        }
+    }
+
+    fn elect(&mut self, tp: &TopicPartitionId, unclean: bool) {
+        // the first live replica in the isr, or with unclean elections the
+        // first live one at all. Without either the partition goes offline
+        // and keeps its isr, the replicas that may lead once they're back
+        let state = match self.partitions.get(tp) {
+            Some(state) => state.clone(),
+            None => return,
+        };
+        let in_sync = state.replicas.iter().find(|id| state.isr.contains(id) && self.alive(**id));
+        let leader = match (in_sync, unclean) {
+            (Some(id), _) => *id,
+            (None, true) => match state.replicas.iter().find(|id| self.alive(**id)) {
+                Some(id) => *id,
+                None => {
+                    self.offline.insert(tp.clone());
+                    return
+                },
+            },
+            (None, false) => {
+                self.offline.insert(tp.clone());
+                return
+            },
+        };
+        let mut isr: Vec<u32> = state.isr.iter().cloned().filter(|id| self.alive(*id)).collect();
+        if !isr.contains(&leader) { isr = vec![leader] }
+        let elected = PartitionState{ leader, leader_epoch: state.leader_epoch + 1, isr, ..state };
+        self.partitions.insert(tp.clone(), elected);
+        self.offline.remove(tp);
+        self.send(tp);
+    }
+
+    fn broker_failed(&mut self, id: u32, unclean: bool) {
+        // the partitions it led elect again, it drops out of the isr of the
+        // rest under a new epoch, so their leaders stop waiting on it
+        if let Some(broker) = self.brokers.get_mut(&id) {
+            broker.alive = false;
+            broker.conn = None;
+            broker.pending.clear();
+        }
+        let (mut led, mut shrunk) = (vec![], vec![]);
+        for (tp, state) in self.partitions.iter_mut().filter(|(_, s)| s.replicas.contains(&id)) {
+            if state.leader == id {
+                led.push(tp.clone());
+            } else if state.isr.contains(&id) {
+                state.isr.retain(|r| *r != id);
+                state.leader_epoch += 1;
+                shrunk.push(tp.clone());
+            }
+        }
+        for tp in shrunk {
+            self.send(&tp);
+        }
+        for tp in led {
+            self.elect(&tp, unclean);
+        }
+    }
+
+    fn alter_isr(&mut self, id: u32, tp: &TopicPartitionId, proposed: &PartitionState) {
+        // the isr a leader asked for, as long as it leads under the epoch the
+        // controller gave it. Only live replicas join. The leader hears back
+        // either way, and goes by what's committed
+        let isr: Vec<u32> = proposed.isr.iter().cloned().filter(|r| self.alive(*r)).collect();
+        let current = match self.partitions.get_mut(tp) {
+            Some(current) if current.leader == id && current.leader_epoch == proposed.leader_epoch => current,
+            _ => return,
+        };
+        let mut isr: Vec<u32> = isr.into_iter().filter(|r| current.replicas.contains(r)).collect();
+        if !isr.contains(&id) { isr.push(id) }
+        isr.sort_unstable();
+        current.isr = isr;
+        self.send(tp);
+    }
+
+    fn broker_started(&mut self, id: u32, unclean: bool) {
+        // a broker back from the dead is told about every partition it has
+        // a replica of, and may be what an offline partition was waiting for
+        let replicated: Vec<TopicPartitionId> = self.partitions.iter()
+            .filter(|(tp, s)| s.replicas.contains(&id) && !self.offline.contains(tp))
+            .map(|(tp, _)| tp.clone())
+            .collect();
+        if let Some(broker) = self.brokers.get_mut(&id) {
+            broker.alive = true;
+            broker.pending.extend(replicated);
+        }
+        let offline: Vec<TopicPartitionId> = self.offline.iter().cloned().collect();
+        for tp in offline {
+            self.elect(&tp, unclean);
+        }
+    }
+
+    fn complete_reassignments(&mut self) -> usize {
+        // once every target replica is in sync leadership moves to the new
+        // preferred leader and the original replicas are let go, they're told
+        // of the state that leaves them out and delete their copies.
+        // Returns how many partitions finished moving
+        let mut done = vec![];
+        for (tp, reassignment) in &self.reassignments {
+            let state = match self.partitions.get(tp) {
+                Some(state) if !self.offline.contains(tp) => state,
+                _ => continue,
+            };
+            if !reassignment.target.iter().all(|id| state.isr.contains(id)) { continue }
+            let leader = reassignment.target.iter()
+                .find(|id| self.alive(**id))
+                .cloned()
+                .unwrap_or(state.leader);
+            let isr = state.isr.iter().cloned().filter(|id| reassignment.target.contains(id)).collect();
+            let moved = PartitionState{
+                replicas: reassignment.target.clone(),
+                leader,
+                leader_epoch: state.leader_epoch + 1,
+                isr,
+            };
+            let removed: Vec<u32> = state.replicas.iter().cloned().filter(|id| !reassignment.target.contains(id)).collect();
+            done.push((tp.clone(), moved, removed));
+        }
+        for (tp, moved, removed) in &done {
+            self.reassignments.remove(tp);
+            self.partitions.insert(tp.clone(), moved.clone());
+            self.send(tp);
+            self.release(tp, removed);
+        }
+        done.len()
+    }
+
+    fn release(&mut self, tp: &TopicPartitionId, removed: &[u32]) {
+        // brokers no longer among the replicas hear of the state that leaves
+        // them out, and delete their copies
+        for id in removed {
+            if let Some(broker) = self.brokers.get_mut(id).filter(|b| b.alive) {
+                broker.pending.insert(tp.clone());
+            }
+        }
+    }
+
+    fn rebalance(&mut self) -> usize {
+        // leadership goes back to the preferred replica wherever it's alive
+        // and in sync. Returns how many partitions moved
+        let mut moved = vec![];
+        for (tp, state) in &self.partitions {
+            if self.offline.contains(tp) { continue }
+            match state.preferred_leader() {
+                Some(preferred) if preferred != state.leader && state.isr.contains(&preferred) && self.alive(preferred) => {
+                    moved.push((tp.clone(), preferred))
+                },
+                _ => (),
+            }
+        }
+        for (tp, preferred) in &moved {
+            if let Some(state) = self.partitions.get_mut(tp) {
+                state.leader = *preferred;
+                state.leader_epoch += 1;
+            }
+            self.send(tp);
+        }
+        moved.len()
+    }
+}
+
+
+// Decides which replica leads each partition, like kafka's controller. It
+// checks on every broker each broker_heartbeat_interval with a leader and
+// isr request carrying whatever changed for the broker's partitions. Leaders
+// answer with the isr changes they want, which the controller commits and
+// sends back with the next request. Brokers register with it and
+// heartbeat; one it hasn't heard from for broker_session_timeout is fenced,
+// and the partitions it led elect new leaders from their isrs.
+//
+// The brokers, partitions and moves are kept in a raft quorum of
+// controllers. Only its leader controls, and every change it makes is
+// committed to the quorum before brokers hear of it. Another controller
+// elected takes over from what was committed.
+pub struct Controller {
+    broker_id: u32,
+    quorum: Arc<RaftNode<MetadataImage>>,
+    config: ServerConfig,
+    state: Mutex<ControllerState>,
+}
+
+impl Controller {
+    pub fn new(broker_id: u32, quorum: Arc<RaftNode<MetadataImage>>, config: ServerConfig) -> Controller {
+        let state = ControllerState{
+            epoch: None,
+            brokers: BTreeMap::new(),
+            partitions: BTreeMap::new(),
+            reassignments: BTreeMap::new(),
+            offline: BTreeSet::new(),
+            last_rebalance: Instant::now(),
+        };
+        Controller{ broker_id, quorum, config, state: Mutex::new(state) }
+    }
+
+    pub fn broker_id(&self) -> u32 { self.broker_id }
+    pub fn quorum(&self) -> &Arc<RaftNode<MetadataImage>> { &self.quorum }
+    pub fn epoch(&self) -> Option<i32> { self.state.lock().unwrap().epoch }
+    pub fn is_active(&self) -> bool { self.active().is_ok() }
+
+    fn active(&self) -> Result<MutexGuard<'_, ControllerState>> {
+        // the state, as long as the quorum's leader is here. Newly elected
+        // it takes over from the committed image under its term
+        let mut state = self.state.lock().unwrap();
+        match self.quorum.leader_term() {
+            Some(term) if state.epoch == Some(term) => (),
+            Some(term) => self.quorum.read(|image| state.load(term, image, self.config.unclean_leader_election)),
+            None => {
+                state.epoch = None;
+                for broker in state.brokers.values_mut() {
+                    broker.conn = None;
+                }
+                let leader = self.quorum.leader().map_or_else(|| String::from("none"), |id| id.to_string());
+                return Err(Error::NotController(format!("broker {}, the controller is {}", self.broker_id, leader)))
+            },
+        }
+        Ok(state)
+    }
+
+    fn commit(&self, state: &mut ControllerState) -> Result<()> {
+        // what changed goes to the quorum, the controller goes on from what
+        // got committed. Changes that didn't make it are dropped, and with
+        // them the epoch: still leading, it takes over again from the image
+        let records = self.quorum.read(|image| state.changes(image));
+        let res = match records.split_last() {
+            Some((last, rest)) => rest.iter()
+                .try_for_each(|record| self.quorum.propose(&record.to_vec()).map(|_| ()))
+                // the last one applied has the others applied
+                .and_then(|_| self.quorum.propose_and_wait(&last.to_vec(), self.config.controller_quorum_request_timeout))
+                .map(|_| ()),
+            None => Ok(()),
+        };
+        self.quorum.read(|image| state.sync(image));
+        if res.is_err() { state.epoch = None }
+        res
+    }
+
+    fn register(&self, broker: &Broker) -> Result<i64> {
+        // the broker's epoch is where its registration is in the metadata log
+        let record = MetadataRecord::RegisterBroker(broker.clone());
+        Ok(self.quorum.propose_and_wait(&record.to_vec(), self.config.controller_quorum_request_timeout)? as i64)
+    }
+
+    pub fn add_broker(&self, broker: Broker) -> Result<()> {
+        // a broker the controller checks on itself, alive until it fails to
+        // answer for a session
+        let mut state = self.active()?;
+        let epoch = self.register(&broker)?;
+        state.add(broker, false, epoch, self.config.unclean_leader_election);
+        self.commit(&mut state)
+    }
+
+    pub fn register_broker(&self, broker: Broker) -> Result<i64> {
+        // a broker announcing itself, alive while it heartbeats under the
+        // epoch it gets back. Another live broker under the same id is refused
+        let mut state = self.active()?;
+        if let Some(known) = state.brokers.get(&broker.id()).filter(|b| b.alive && b.broker.addr() != broker.addr()) {
+            let msg = format!("broker {} is already registered at {}", broker.id(), known.broker.addr());
+            return Err(Error::InvalidConfig(msg))
+        }
+        let epoch = self.register(&broker)?;
+        state.add(broker, true, epoch, self.config.unclean_leader_election);
+        self.commit(&mut state)?;
+        Ok(epoch)
+    }
+
+    pub fn heartbeat(&self, id: u32, epoch: i64) -> Result<Vec<Broker>> {
+        // keeps a registered broker alive, unfencing it if it had lapsed.
+        // Answers with the live brokers
+        let mut state = self.active()?;
+        let revived = match state.brokers.get_mut(&id) {
+            Some(broker) if broker.epoch == epoch => {
+                broker.last_seen = Instant::now();
+                !broker.alive
+            },
+            Some(broker) => return Err(Error::StaleBrokerEpoch(format!("broker {} epoch {}, it's at {}", id, epoch, broker.epoch))),
+            None => return Err(Error::StaleBrokerEpoch(format!("broker {} isn't registered", id))),
+        };
+        if revived {
+            state.broker_started(id, self.config.unclean_leader_election);
+            self.commit(&mut state)?;
+        }
+        Ok(state.live_brokers())
+    }
+
+    pub fn brokers(&self) -> Vec<Broker> {
+        self.state.lock().unwrap().brokers.values().map(|b| b.broker.clone()).collect()
+    }
+
+    pub fn live_brokers(&self) -> Vec<Broker> { self.state.lock().unwrap().live_brokers() }
+
+    pub fn is_alive(&self, id: u32) -> bool { self.state.lock().unwrap().alive(id) }
+
+    pub fn create_partition(&self, topic: &str, partition: u32, replicas: &[u32]) -> Result<PartitionState> {
+        // the first live replica leads, the live ones start out in sync.
+        // Creating a partition again hands back what it already has
+        let tp = TopicPartitionId::new(topic, partition);
+        let mut state = self.active()?;
+        if let Some(existing) = state.partitions.get(&tp) { return Ok(existing.clone()) }
+        if let Some(unknown) = replicas.iter().find(|id| !state.brokers.contains_key(id)) {
+            return Err(Error::InvalidConfig(format!("no broker {} for {}", unknown, tp.dir_name())))
+        }
+        let isr: Vec<u32> = replicas.iter().cloned().filter(|id| state.alive(*id)).collect();
+        let leader = *isr.first().ok_or_else(|| Error::LeaderNotAvailable(tp.dir_name()))?;
+        let created = PartitionState{ replicas: replicas.to_vec(), leader, leader_epoch: 0, isr };
+        state.partitions.insert(tp.clone(), created);
+        state.send(&tp);
+        self.commit(&mut state)?;
+        state.partitions.get(&tp).cloned().ok_or_else(|| Error::LeaderNotAvailable(tp.dir_name()))
+    }
+
+    pub fn create_topic(&self, topic: &str, partitions: u32, replication_factor: u32) -> Result<Vec<PartitionState>> {
+        // the replicas placed over the live brokers and their racks, the
+        // partitions a topic already has keep theirs
+        let assignment = assign_replicas(&self.live_brokers(), partitions, replication_factor)?;
+        assignment.iter().enumerate()
+            .map(|(partition, replicas)| self.create_partition(topic, partition as u32, replicas))
+            .collect()
+    }
+
+    pub fn reassign_partition(&self, topic: &str, partition: u32, target: &[u32]) -> Result<()> {
+        // moves the partition's replicas to target, the first of which ends
+        // up leading. The new replicas join and copy the log while the
+        // original ones carry on, the move completes once they're all in
+        // sync. Reassigning again before then changes where it's headed
+        let tp = TopicPartitionId::new(topic, partition);
+        let mut state = self.active()?;
+        let current = match state.partitions.get(&tp) {
+            Some(_) if state.offline.contains(&tp) => return Err(Error::LeaderNotAvailable(tp.dir_name())),
+            Some(current) => current.clone(),
+            None => return Err(Error::PartitionNotFound(tp.dir_name().into())),
+        };
+        if target.is_empty() || target.iter().collect::<BTreeSet<_>>().len() < target.len() {
+            return Err(Error::InvalidConfig(format!("{:?} isn't a replica set for {}", target, tp.dir_name())))
+        }
+        if let Some(unknown) = target.iter().find(|id| !state.brokers.contains_key(id)) {
+            return Err(Error::InvalidConfig(format!("no broker {} for {}", unknown, tp.dir_name())))
+        }
+        let reassigning = state.reassignments.remove(&tp);
+        if reassigning.is_none() && target == &current.replicas[..] { return Ok(()) }
+        let original = reassigning.map_or_else(|| current.replicas.clone(), |r| r.original);
+        let mut replicas = target.to_vec();
+        replicas.extend(original.iter().filter(|id| !target.contains(id)));
+        // replicas added for an earlier target this one doesn't want go again
+        let dropped: Vec<u32> = current.replicas.iter().cloned().filter(|id| !replicas.contains(id)).collect();
+        let isr = current.isr.iter().cloned().filter(|id| replicas.contains(id)).collect();
+        state.partitions.insert(tp.clone(), PartitionState{ replicas, isr, leader_epoch: current.leader_epoch + 1, ..current });
+        if target != &original[..] {
+            state.reassignments.insert(tp.clone(), Reassignment{ target: target.to_vec(), original });
+        }
+        match dropped.contains(&current.leader) {
+            true => state.elect(&tp, self.config.unclean_leader_election),
+            false => state.send(&tp),
+        }
+        state.release(&tp, &dropped);
+        state.complete_reassignments();
+        self.commit(&mut state)
+    }
+
+    pub fn reassignments(&self) -> Vec<(TopicPartitionId, Vec<u32>)> {
+        // the partitions still moving, with where to
+        let (_, reassignments) = self.quorum.read(committed);
+        reassignments.into_iter().map(|(tp, r)| (tp, r.target)).collect()
+    }
+
+    pub fn state(&self, topic: &str, partition: u32) -> Option<PartitionState> {
+        // as committed, which the standby controllers have too
+        self.quorum.read(|image| image.partition(topic, partition).cloned())
+    }
+
+    pub fn leader(&self, topic: &str, partition: u32) -> Result<u32> {
+        let tp = TopicPartitionId::new(topic, partition);
+        match self.state(topic, partition) {
+            Some(_) if self.state.lock().unwrap().offline.contains(&tp) => Err(Error::LeaderNotAvailable(tp.dir_name())),
+            Some(partition) => Ok(partition.leader),
+            None => Err(Error::PartitionNotFound(tp.dir_name().into())),
+        }
+    }
+
+    pub fn rebalance_preferred_leaders(&self) -> Result<usize> {
+        let mut state = self.active()?;
+        state.last_rebalance = Instant::now();
+        let moved = state.rebalance();
+        self.commit(&mut state)?;
+        Ok(moved)
+    }
+
+    pub fn tick(&self) {
+        // one round of leader and isr requests sent to every broker at once,
+        // each given up on after broker_session_timeout. The connections are
+        // taken out so a slow broker doesn't hold up the controller's state.
+        // Only the active controller sends any
+        let (epoch, probes) = {
+            let mut state = match self.active() {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+            let epoch = state.epoch.unwrap_or_default();
+            let brokers = state.live_brokers();
+            let partitions = state.partitions.clone();
+            let probes: Vec<_> = state.brokers.iter_mut()
+                .map(|(id, broker)| {
+                    let states: Vec<_> = broker.pending.iter()
+                        .filter_map(|tp| partitions.get(tp).map(|s| (tp.clone(), s.clone())))
+                        .collect();
+                    (*id, broker.broker.addr(), broker.conn.take(), brokers.clone(), states)
+                })
+                .collect();
+            (epoch, probes)
+        };
+        let answers: Vec<_> = thread::scope(|scope| {
+            let probing: Vec<_> = probes.into_iter()
+                .map(|(id, addr, conn, brokers, states)| scope.spawn(move || {
+                    let mut conn = conn;
+                    let res = self.probe(&mut conn, &addr, epoch, &brokers, &states);
+                    (id, conn, states, res)
+                }))
+                .collect();
+            probing.into_iter().filter_map(|probe| probe.join().ok()).collect()
+        });
+        // the answers count for nothing if another controller took over since
+        let mut guard = match self.active() {
+            Ok(state) if state.epoch == Some(epoch) => state,
+            _ => return,
+        };
+        let state = &mut *guard;
+        let unclean = self.config.unclean_leader_election;
+        for (id, conn, states, res) in answers {
+            let response = match res {
+                Ok(response) => response,
+                Err(Error::NotController(msg)) => {
+                    eprintln!("latka: broker {} has heard from a newer controller: {}", id, msg);
+                    continue
+                },
+                Err(e) => {
+                    let timed_out = state.brokers.get(&id)
+                        .is_some_and(|b| b.alive && b.last_seen.elapsed() >= self.config.broker_session_timeout);
+                    if timed_out {
+                        eprintln!("latka: controller lost broker {}: {}", id, e);
+                        state.broker_failed(id, unclean);
+                    }
+                    continue
+                },
+            };
+            let revived = match state.brokers.get_mut(&id) {
+                Some(broker) => {
+                    if !broker.registered { broker.last_seen = Instant::now() }
+                    broker.conn = conn;
+                    // what's been delivered isn't sent again unless it changed since
+                    for (tp, sent) in &states {
+                        if state.partitions.get(tp) == Some(sent) { broker.pending.remove(tp); }
+                    }
+                    !broker.alive && !broker.registered
+                },
+                None => continue,
+            };
+            if revived { state.broker_started(id, unclean) }
+            for (tp, err) in &response.errors {
+                eprintln!("latka: broker {} refused the state of {}: {}", id, tp.dir_name(), err);
+            }
+            // the leader knows best who keeps up with it
+            for (tp, proposed) in &response.alter_isr {
+                state.alter_isr(id, tp, proposed);
+            }
+        }
+
+        // registered brokers are fenced once their heartbeats stop, even
+        // while they still answer
+        state.complete_reassignments();
+        let lapsed: Vec<u32> = state.brokers.iter()
+            .filter(|(_, b)| b.registered && b.alive && b.last_seen.elapsed() >= self.config.broker_session_timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in lapsed {
+            eprintln!("latka: controller fenced broker {}, it stopped heartbeating", id);
+            state.broker_failed(id, unclean);
+        }
+        if self.config.auto_leader_rebalance && state.last_rebalance.elapsed() >= self.config.leader_imbalance_check_interval {
+            state.last_rebalance = Instant::now();
+            state.rebalance();
+        }
+        if let Err(e) = self.commit(state) {
+            eprintln!("latka: controller {} can't commit to the metadata quorum: {}", self.broker_id, e);
+        }
+    }
+
+    fn probe(
+        &self,
+        conn: &mut Option<Connection>,
+        addr: &str,
+        epoch: i32,
+        brokers: &[Broker],
+        states: &[(TopicPartitionId, PartitionState)],
+    ) -> Result<LeaderAndIsrResponse> {
+        let connected = match conn {
+            Some(connected) => connected,
+            None => conn.insert(Connection::connect_timeout(addr, self.config.broker_session_timeout)?),
+        };
+        connected.leader_and_isr(self.broker_id, epoch, brokers, states)
+    }
+
+    pub fn start(controller: Arc<Controller>) -> BackgroundTasks {
+        BackgroundTasks::spawn("latka-controller", move |running| {
+            while running.load(Ordering::SeqCst) {
+                controller.tick();
+                thread::park_timeout(controller.config.broker_heartbeat_interval);
+            }
+        })
+    }
+}
+
+fn committed(image: &MetadataImage) -> (BTreeMap<TopicPartitionId, PartitionState>, BTreeMap<TopicPartitionId, Reassignment>) {
+    // the image's partitions and moves, by partition
+    let (mut partitions, mut reassignments) = (BTreeMap::new(), BTreeMap::new());
+    for name in image.topics() {
+        let topic = match image.topic(&name) {
+            Some(topic) => topic,
+            None => continue,
+        };
+        for (partition, state) in &topic.partitions {
+            partitions.insert(TopicPartitionId::new(&name, *partition), state.clone());
+        }
+        for (partition, reassignment) in &topic.reassignments {
+            reassignments.insert(TopicPartitionId::new(&name, *partition), reassignment.clone());
+        }
+    }
+    (partitions, reassignments)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::{tempdir, TempDir};
+    use super::*;
+    use crate::client::{Connection, Producer};
+    use crate::kafka::errors;
+    use crate::cluster::manager::{LogManager};
+    use std::sync::{Weak};
+    use crate::cluster::raft::{InMemoryTransport, RaftConfig, RaftPeer, METADATA_LOG_DIR};
+    use crate::cluster::replica::{ReplicaManager};
+    use crate::partition::segment::{MaxBytes};
+    use crate::server::{Server};
+
+    type ClusterBroker = (Broker, Arc<LogManager>, Arc<ReplicaManager>);
+
+    fn config(unclean_leader_election: bool) -> ServerConfig {
+        ServerConfig{
+            broker_heartbeat_interval: Duration::from_millis(10),
+            broker_session_timeout: Duration::from_millis(200),
+            replica_fetch_max_wait: Duration::from_millis(50),
+            replica_fetch_backoff: Duration::from_millis(10),
+            replica_lag_time_max: Duration::from_millis(300),
+            auto_leader_rebalance: false,
+            unclean_leader_election,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn lone_controller(dir: &Path, config: ServerConfig) -> Controller {
+        // the only voter in its quorum, it leads once ticked
+        let raft = RaftConfig{ log: MaxBytes(64 * 1024, 64 * 1024).into(), ..RaftConfig::default() };
+        let transport = Arc::new(InMemoryTransport::new());
+        let quorum = RaftNode::open(1, &[1], &dir.join(METADATA_LOG_DIR), MetadataImage::new(), transport, raft).unwrap();
+        quorum.tick();
+        Controller::new(1, Arc::new(quorum), config)
+    }
+
+    fn start(id: u32, log_dir: &Path) -> (SocketAddr, Arc<LogManager>, Arc<ReplicaManager>) {
+        let logs = Arc::new(LogManager::open(vec![log_dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+        let server = Server::bind(Broker::new(id, "127.0.0.1", 0), logs.clone(), config(false)).unwrap();
+        let (addr, replicas) = (server.local_addr().unwrap(), server.replicas().clone());
+        thread::spawn(move || server.serve());
+        (addr, logs, replicas)
+    }
+
+    fn start_cluster(dirs: &[TempDir], replication_throttled_rate: Option<u64>) -> (Arc<Controller>, Vec<ClusterBroker>) {
+        // the first broker runs the controller, the others register with it
+        let mut controller_addr = None;
+        let mut controller = None;
+        let mut brokers = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            let logs = Arc::new(LogManager::open(vec![dir.path().to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+            let config = ServerConfig{
+                controller: i == 0,
+                controller_addr: controller_addr.clone(),
+                replication_throttled_rate,
+                ..config(false)
+            };
+            let server = Server::bind(Broker::new(i as u32 + 1, "127.0.0.1", 0), logs.clone(), config).unwrap();
+            if i == 0 {
+                controller_addr = Some(server.local_addr().unwrap().to_string());
+                controller = server.controller().cloned();
+            }
+            brokers.push((server.broker().clone(), logs, server.replicas().clone()));
+            thread::spawn(move || server.serve());
+        }
+        let controller = controller.unwrap();
+        wait_for(|| controller.live_brokers().len() == dirs.len());
+        (controller, brokers)
+    }
+
+    fn unreachable_port() -> u16 {
+        // nothing listens there once the listener is dropped
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn wait_for<F: Fn() -> bool>(done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn it_elects_leaders_from_the_isr() {
+        let tmp = tempdir().unwrap();
+        let controller = lone_controller(tmp.path(), config(false));
+        for id in 1..=3 {
+            controller.add_broker(Broker::new(id, "127.0.0.1", 9092)).unwrap();
+        }
+        assert!(matches!(controller.create_partition("events", 0, &[1, 4]), Err(Error::InvalidConfig(_))));
+        let created = controller.create_partition("events", 0, &[1, 2, 3]).unwrap();
+        assert_eq!((created.leader, created.leader_epoch, created.isr), (1, 0, vec![1, 2, 3]));
+
+        let tp = TopicPartitionId::new("events", 0);
+        let mut state = controller.state.lock().unwrap();
+        state.brokers.get_mut(&1).unwrap().pending.clear();
+        state.broker_failed(3, false);
+        let shrunk = state.partitions[&tp].clone();
+        assert_eq!((shrunk.leader, shrunk.leader_epoch, shrunk.isr), (1, 1, vec![1, 2]));
+        assert!(state.brokers[&1].pending.contains(&tp), "the leader hears it's not to wait on broker 3");
+        state.broker_failed(1, false);
+        let elected = state.partitions[&tp].clone();
+        assert_eq!((elected.leader, elected.leader_epoch, elected.isr), (2, 2, vec![2]));
+
+        // broker 3 failed before any of this, it may not lead
+        state.broker_failed(2, false);
+        assert!(state.offline.contains(&tp));
+        state.broker_started(1, false);
+        assert!(state.offline.contains(&tp), "broker 1 dropped out of the isr when it failed");
+        state.broker_started(2, false);
+        assert_eq!((state.partitions[&tp].leader, state.partitions[&tp].leader_epoch), (2, 3));
+        assert!(state.brokers[&1].pending.contains(&tp), "the other replicas hear of the new leader");
+
+        assert_eq!(state.rebalance(), 0, "the preferred leader isn't back in sync");
+        // an isr change only from the leader, under the epoch it leads in
+        let proposed = PartitionState{ isr: vec![1, 2, 3], ..state.partitions[&tp].clone() };
+        state.alter_isr(1, &tp, &proposed);
+        state.alter_isr(2, &tp, &PartitionState{ leader_epoch: 2, ..proposed.clone() });
+        assert_eq!(state.partitions[&tp].isr, vec![2]);
+        state.alter_isr(2, &tp, &proposed);
+        assert_eq!(state.partitions[&tp].isr, vec![1, 2], "broker 3 is still fenced");
+        assert_eq!(state.rebalance(), 1);
+        assert_eq!((state.partitions[&tp].leader, state.partitions[&tp].leader_epoch), (1, 4));
+        drop(state);
+
+        let tmp = tempdir().unwrap();
+        let unclean = lone_controller(tmp.path(), config(true));
+        for id in 1..=2 {
+            unclean.add_broker(Broker::new(id, "127.0.0.1", 9092)).unwrap();
+        }
+        unclean.create_partition("events", 0, &[1, 2]).unwrap();
+        let mut state = unclean.state.lock().unwrap();
+        state.partitions.get_mut(&tp).unwrap().isr = vec![1];
+        state.broker_failed(1, true);
+        let elected = state.partitions[&tp].clone();
+        assert_eq!((elected.leader, elected.isr), (2, vec![2]), "out of sync, but all that's left");
+    }
+
+    #[test]
+    fn it_fails_over_and_back_to_the_preferred_leader() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let mut brokers = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            brokers.push(start(i as u32 + 1, dir.path()));
+        }
+        let tmp = tempdir().unwrap();
+        let controller = lone_controller(tmp.path(), config(false));
+        for (i, (addr, _, _)) in brokers.iter().enumerate() {
+            controller.add_broker(Broker::new(i as u32 + 1, "127.0.0.1", addr.port())).unwrap();
+        }
+        controller.create_partition("events", 0, &[1, 2, 3]).unwrap();
+        controller.tick();
+        let (first_addr, _, first) = &brokers[0];
+        assert_eq!(first.state("events", 0).map(|s| (s.leader, s.leader_epoch)), Some((1, 0)));
+        assert_eq!(brokers[1].2.state("events", 0).map(|s| s.leader), Some(1));
+
+        let mut producer = Producer::connect(first_addr).unwrap();
+        for _ in 0..3 {
+            producer.send_to("events", 0, None, b"XX").unwrap();
+        }
+        let copy = brokers[2].1.get("events", 0).unwrap();
+        wait_for(|| copy.lock().unwrap().high_watermark() == 3);
+
+        // broker 1 goes out of reach, the controller waits out its session
+        controller.add_broker(Broker::new(1, "127.0.0.1", unreachable_port())).unwrap();
+        wait_for(|| {
+            controller.tick();
+            !controller.is_alive(1)
+        });
+        assert_eq!(controller.leader("events", 0).unwrap(), 2);
+        controller.tick();
+        let (second_addr, _, second) = &brokers[1];
+        assert_eq!(second.state("events", 0).map(|s| (s.leader, s.leader_epoch)), Some((2, 1)));
+        Producer::connect(second_addr).unwrap().send_to("events", 0, None, b"YY").unwrap();
+
+        // back again it follows until the rebalance hands leadership back
+        controller.add_broker(Broker::new(1, "127.0.0.1", first_addr.port())).unwrap();
+        controller.tick();
+        assert_eq!(first.state("events", 0).map(|s| (s.leader, s.leader_epoch)), Some((2, 1)));
+        let old = brokers[0].1.get("events", 0).unwrap();
+        wait_for(|| old.lock().unwrap().log_end_offset() == 4);
+        wait_for(|| {
+            controller.tick();
+            controller.state("events", 0).unwrap().isr.contains(&1)
+        });
+        assert_eq!(controller.rebalance_preferred_leaders().unwrap(), 1);
+        controller.tick();
+        assert_eq!(first.state("events", 0).map(|s| (s.leader, s.leader_epoch)), Some((1, 2)));
+        assert!(matches!(first.check_leader("events", 0), Ok(())));
+        assert!(matches!(second.check_leader("events", 0), Err(Error::NotLeader(_))));
+        for (_, _, replicas) in &brokers {
+            replicas.shutdown();
+        }
+    }
+
+    #[test]
+    fn it_commits_isr_changes_before_the_leader_uses_them() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let mut brokers = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            brokers.push(start(i as u32 + 1, dir.path()));
+        }
+        let tmp = tempdir().unwrap();
+        let controller = lone_controller(tmp.path(), config(false));
+        for (i, (addr, _, _)) in brokers.iter().enumerate() {
+            controller.add_broker(Broker::new(i as u32 + 1, "127.0.0.1", addr.port())).unwrap();
+        }
+        controller.create_partition("events", 0, &[1, 2]).unwrap();
+        controller.tick();
+        let (leader_addr, leader_logs, leader) = &brokers[0];
+        let mut producer = Producer::connect(leader_addr).unwrap();
+        producer.send_to("events", 0, None, b"XX").unwrap();
+        let partition = leader_logs.get("events", 0).unwrap();
+        wait_for(|| partition.lock().unwrap().high_watermark() == 1);
+
+        // the follower stops copying but still answers the controller. The
+        // leader wants it out of the isr, but waits for the controller
+        brokers[1].2.shutdown();
+        producer.send_to("events", 0, None, b"YY").unwrap();
+        wait_for(|| !leader.isr_proposals().is_empty());
+        assert_eq!(leader.isr_proposals()[0].1.isr, vec![1]);
+        assert_eq!(leader.state("events", 0).unwrap().isr, vec![1, 2]);
+        assert_eq!(partition.lock().unwrap().high_watermark(), 1);
+
+        controller.tick();
+        assert_eq!(controller.state("events", 0).unwrap().isr, vec![1]);
+        controller.tick();
+        assert_eq!(leader.state("events", 0).unwrap().isr, vec![1]);
+        assert!(leader.isr_proposals().is_empty());
+        assert_eq!(partition.lock().unwrap().high_watermark(), 2);
+        leader.shutdown();
+    }
+
+    #[test]
+    fn it_fences_stale_controllers_and_probes_brokers_at_once() {
+        let tmp = tempdir().unwrap();
+        let (addr, _, replicas) = start(1, tmp.path());
+        let controller = lone_controller(&tmp.path().join("controller"), config(false));
+        controller.add_broker(Broker::new(1, "127.0.0.1", addr.port())).unwrap();
+        // brokers that take connections and never answer
+        let silent: Vec<TcpListener> = (0..3).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        for (id, listener) in silent.iter().enumerate() {
+            controller.add_broker(Broker::new(id as u32 + 2, "127.0.0.1", listener.local_addr().unwrap().port())).unwrap();
+        }
+        controller.create_partition("events", 0, &[1]).unwrap();
+        let started = Instant::now();
+        controller.tick();
+        assert!(started.elapsed() < Duration::from_millis(500), "waited out one session, not three: {:?}", started.elapsed());
+        assert_eq!(replicas.state("events", 0).map(|s| (s.leader, s.leader_epoch)), Some((1, 0)));
+
+        // the controller epoch is the quorum's term
+        let epoch = controller.epoch().unwrap();
+        assert_eq!(epoch, controller.quorum().term());
+        let mut conn = Connection::connect(addr).unwrap();
+        let res = conn.leader_and_isr(2, epoch - 1, &[], &[]);
+        assert!(matches!(res, Err(Error::NotController(_))), "{:?}", res);
+        assert!(conn.leader_and_isr(2, epoch + 1, &[], &[]).is_ok());
+        controller.tick();
+        assert!(controller.is_alive(1), "a stale controller doesn't count the broker as gone");
+    }
+
+    #[test]
+    fn it_fences_brokers_that_stop_heartbeating() {
+        let tmp = tempdir().unwrap();
+        let controller = lone_controller(tmp.path(), config(false));
+        let (port, other_port) = (unreachable_port(), unreachable_port());
+        let epoch = controller.register_broker(Broker::new(2, "127.0.0.1", port)).unwrap();
+        let other_epoch = controller.register_broker(Broker::new(3, "127.0.0.1", other_port)).unwrap();
+        assert!(matches!(controller.register_broker(Broker::new(2, "127.0.0.1", other_port)), Err(Error::InvalidConfig(_))));
+        assert!(matches!(controller.heartbeat(2, epoch + 5), Err(Error::StaleBrokerEpoch(_))));
+        assert!(matches!(controller.heartbeat(4, 0), Err(Error::StaleBrokerEpoch(_))));
+        controller.create_partition("events", 0, &[2, 3]).unwrap();
+        assert_eq!(controller.leader("events", 0).unwrap(), 2);
+
+        thread::sleep(Duration::from_millis(120));
+        controller.heartbeat(3, other_epoch).unwrap();
+        thread::sleep(Duration::from_millis(120));
+        controller.tick();
+        assert!(!controller.is_alive(2) && controller.is_alive(3));
+        assert_eq!(controller.live_brokers(), vec![Broker::new(3, "127.0.0.1", other_port)]);
+        assert_eq!(controller.leader("events", 0).unwrap(), 3);
+
+        // heartbeating again unfences it, registering again starts a new epoch
+        assert_eq!(controller.heartbeat(2, epoch).unwrap().len(), 2);
+        assert!(controller.is_alive(2));
+        let restarted = controller.register_broker(Broker::new(2, "127.0.0.1", port)).unwrap();
+        assert!(restarted > other_epoch);
+        assert!(matches!(controller.heartbeat(2, epoch), Err(Error::StaleBrokerEpoch(_))));
+    }
+
+    #[test]
+    fn it_registers_brokers_as_they_start() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let (controller, brokers) = start_cluster(&dirs, None);
+        let registered: Vec<Broker> = brokers.iter().map(|(b, _, _)| b.clone()).collect();
+        assert_eq!(controller.live_brokers(), registered);
+        for (_, _, replicas) in &brokers {
+            wait_for(|| replicas.brokers() == registered);
+        }
+
+        controller.create_partition("events", 0, &[2, 3]).unwrap();
+        wait_for(|| brokers[2].2.state("events", 0).map(|s| s.leader) == Some(2));
+        let leader = brokers[2].2.broker(2).unwrap();
+        assert_eq!(leader.addr(), registered[1].addr());
+    }
+
+    #[test]
+    fn it_reassigns_a_partition_to_other_brokers() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let (controller, brokers) = start_cluster(&dirs, Some(8 * 1024));
+        controller.create_partition("events", 0, &[1, 2]).unwrap();
+        wait_for(|| brokers[1].2.state("events", 0).map(|s| s.leader) == Some(1));
+        let mut producer = Producer::connect(brokers[0].0.addr()).unwrap();
+        for _ in 0..10 {
+            producer.send_to("events", 0, None, &[b'X'; 1024]).unwrap();
+        }
+        let original = brokers[1].1.get("events", 0).unwrap();
+        wait_for(|| original.lock().unwrap().high_watermark() == 10);
+
+        let started = Instant::now();
+        let mut admin = Connection::connect(brokers[0].0.addr()).unwrap();
+        let moves = vec![(TopicPartitionId::new("events", 0), vec![3, 4]), (TopicPartitionId::new("nope", 0), vec![3])];
+        let res = admin.reassign_partitions(&moves).unwrap();
+        assert!(res[0].1.is_ok());
+        assert!(matches!(res[1].1, Err(Error::Remote{ code: errors::UNKNOWN_TOPIC_OR_PARTITION, .. })));
+        assert_eq!(controller.state("events", 0).unwrap().replicas, vec![3, 4, 1, 2]);
+        assert!(matches!(Connection::connect(brokers[1].0.addr()).unwrap().reassign_partitions(&moves), Err(Error::NotController(_))));
+
+        wait_for(|| controller.reassignments().is_empty());
+        // two new replicas copied 20k at 8k a second, the first 8k at once
+        assert!(started.elapsed() >= Duration::from_millis(1000), "copied in {:?}", started.elapsed());
+        let moved = controller.state("events", 0).unwrap();
+        assert_eq!((moved.replicas, moved.leader), (vec![3, 4], 3));
+        for (_, logs, replicas) in &brokers[2..] {
+            wait_for(|| replicas.state("events", 0).map(|s| (s.replicas, s.leader)) == Some((vec![3, 4], 3)));
+            assert_eq!(logs.get("events", 0).unwrap().lock().unwrap().log_end_offset(), 10);
+        }
+        for (_, logs, replicas) in &brokers[..2] {
+            wait_for(|| logs.get("events", 0).is_none());
+            assert!(replicas.state("events", 0).is_none());
+            assert!(!logs.log_dirs()[0].join("events-0").exists());
+        }
+        Producer::connect(brokers[2].0.addr()).unwrap().send_to("events", 0, None, b"YY").unwrap();
+        for (_, _, replicas) in &brokers {
+            replicas.shutdown();
+        }
+    }
+
+    #[test]
+    fn it_places_new_topics_across_racks() {
+        let tmp = tempdir().unwrap();
+        let controller = lone_controller(tmp.path(), config(false));
+        for (id, rack) in [(1, "a"), (2, "a"), (3, "b"), (4, "b")] {
+            controller.add_broker(Broker::new(id, "127.0.0.1", 9092).with_rack(rack)).unwrap();
+        }
+        assert!(matches!(controller.create_topic("events", 4, 5), Err(Error::InvalidConfig(_))));
+        let created = controller.create_topic("events", 4, 2).unwrap();
+        let mut leaders: Vec<u32> = created.iter().map(|s| s.leader).collect();
+        leaders.sort_unstable();
+        assert_eq!(leaders, vec![1, 2, 3, 4]);
+        for state in &created {
+            assert_eq!(state.preferred_leader(), Some(state.leader));
+            let racks: BTreeSet<bool> = state.replicas.iter().map(|id| *id <= 2).collect();
+            assert_eq!(racks.len(), 2, "{:?} is on both racks", state.replicas);
+        }
+        assert_eq!(controller.create_topic("events", 4, 2).unwrap(), created, "the topic keeps its replicas");
+    }
+
+    #[test]
+    fn it_takes_over_from_what_the_quorum_committed() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let raft = RaftConfig{
+            election_timeout: Duration::from_millis(200),
+            heartbeat_interval: Duration::from_millis(10),
+            log: MaxBytes(64 * 1024, 64 * 1024).into(),
+            ..RaftConfig::default()
+        };
+        let config = ServerConfig{ controller_quorum_request_timeout: Duration::from_millis(300), ..config(false) };
+        let transport = Arc::new(InMemoryTransport::new());
+        let controllers: Vec<Arc<Controller>> = dirs.iter().enumerate().map(|(i, dir)| {
+            let id = i as u32 + 1;
+            let log_dir = dir.path().join(METADATA_LOG_DIR);
+            let quorum = Arc::new(RaftNode::open(id, &[1, 2, 3], &log_dir, MetadataImage::new(), transport.clone(), raft.clone()).unwrap());
+            let peer: Weak<dyn RaftPeer> = Arc::downgrade(&quorum) as Weak<RaftNode<MetadataImage>>;
+            transport.register(id, peer);
+            Arc::new(Controller::new(id, quorum, config.clone()))
+        }).collect();
+        let _tasks: Vec<_> = controllers.iter().map(|c| RaftNode::start(c.quorum().clone())).collect();
+        let active = |except: Option<u32>| controllers.iter().filter(|c| Some(c.broker_id()) != except).find(|c| c.is_active()).cloned();
+        wait_for(|| active(None).is_some());
+        let first = active(None).unwrap();
+        for id in 1..=4 {
+            first.add_broker(Broker::new(id, "127.0.0.1", 9092)).unwrap();
+        }
+        first.create_partition("events", 0, &[1, 2]).unwrap();
+        // broker 3 never catches up, the move stays under way
+        first.reassign_partition("events", 0, &[3, 2]).unwrap();
+        let (state, moving) = (first.state("events", 0).unwrap(), first.reassignments());
+        assert_eq!(state.replicas, vec![3, 2, 1]);
+        for standby in controllers.iter().filter(|c| c.broker_id() != first.broker_id()) {
+            assert!(matches!(standby.create_partition("events", 1, &[1]), Err(Error::NotController(_))));
+            wait_for(|| standby.state("events", 0) == Some(state.clone()));
+        }
+
+        // cut off, the old controller can't commit anything
+        transport.disconnect(first.broker_id());
+        wait_for(|| active(Some(first.broker_id())).is_some());
+        let second = active(Some(first.broker_id())).unwrap();
+        assert!(second.epoch() > first.epoch());
+        assert_eq!(second.state("events", 0), Some(state));
+        assert_eq!(second.reassignments(), moving);
+        assert_eq!(second.brokers().len(), 4, "the brokers are as registered");
+        second.create_partition("events", 1, &[4]).unwrap();
+        assert!(matches!(first.create_partition("events", 2, &[4]), Err(Error::Timeout(_))));
+        assert_eq!(first.state("events", 2), None);
+
+        // back, it hears of the new term and follows
+        transport.reconnect(first.broker_id());
+        wait_for(|| first.state("events", 1).is_some());
+        assert!(!first.is_active());
+        assert_eq!(second.state("events", 2), None);
+    }
+
+    #[test]
+    fn it_runs_the_controller_quorum_across_brokers() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let ports: Vec<u16> = dirs.iter().map(|_| unreachable_port()).collect();
+        let voters: Vec<(u32, String)> = ports.iter().enumerate().map(|(i, port)| (i as u32 + 1, format!("127.0.0.1:{}", port))).collect();
+        let mut servers = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            let logs = Arc::new(LogManager::open(vec![dir.path().to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+            let config = ServerConfig{
+                controller: true,
+                controller_quorum_voters: voters.clone(),
+                controller_quorum_election_timeout: Duration::from_millis(200),
+                controller_quorum_request_timeout: Duration::from_millis(500),
+                broker_session_timeout: Duration::from_secs(1),
+                ..config(false)
+            };
+            let server = Server::bind(Broker::new(i as u32 + 1, "127.0.0.1", ports[i]), logs.clone(), config).unwrap();
+            servers.push((server.controller().cloned().unwrap(), logs, server.replicas().clone()));
+            thread::spawn(move || server.serve());
+        }
+        let active = || servers.iter().map(|(c, _, _)| c).find(|c| c.is_active()).cloned();
+        // each broker finds the active controller among the voters
+        wait_for(|| active().is_some_and(|c| c.live_brokers().len() == 3));
+        let controller = active().unwrap();
+        controller.create_partition("events", 0, &[1, 2, 3]).unwrap();
+        for (standby, _, replicas) in &servers {
+            wait_for(|| standby.state("events", 0).map(|s| s.replicas) == Some(vec![1, 2, 3]));
+            wait_for(|| replicas.state("events", 0).map(|s| s.replicas) == Some(vec![1, 2, 3]));
+        }
+        assert!(!servers[0].1.log_dirs()[0].join(METADATA_LOG_DIR).join("events-0").exists());
+        assert!(servers[0].1.get("__cluster_metadata", 0).is_none(), "the metadata log isn't a partition");
+        for (_, _, replicas) in &servers {
+            replicas.shutdown();
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupState {
+    // no members
+    #[default]
+    Empty,
+    // waiting for every member to join again
+    PreparingRebalance,
+    // joined, waiting for the leader's assignment
+    CompletingRebalance,
+    Stable,
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    // empty for a member joining for the first time
+    pub member_id: String,
+    pub client_id: String,
+    pub protocol_type: String,
+    // (protocol name, metadata), most preferred first
+    pub protocols: Vec<(String, Vec<u8>)>,
+    pub session_timeout: Duration,
+    pub rebalance_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinResult {
+    pub generation: i32,
+    pub protocol: String,
+    pub leader: String,
+    pub member_id: String,
+    // every member's metadata for the chosen protocol, only the leader gets them
+    pub members: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupSummary {
+    pub state: GroupState,
+    pub generation: i32,
+    pub protocol: Option<String>,
+    pub leader: Option<String>,
+    pub members: Vec<String>,
+}
+
+
+struct Member {
+    session_timeout: Duration,
+    rebalance_timeout: Duration,
+    protocols: Vec<(String, Vec<u8>)>,
+    assignment: Vec<u8>,
+    last_seen: Instant,
+    // joined the rebalance in progress
+    joined: bool,
+}
+
+impl Member {
+    fn metadata(&self, protocol: &str) -> Vec<u8> {
+        self.protocols.iter().find(|(name, _)| name == protocol).map(|(_, m)| m.clone()).unwrap_or_default()
+    }
+
+    fn supports(&self, protocol: &str) -> bool {
+        self.protocols.iter().any(|(name, _)| name == protocol)
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    state: GroupState,
+    generation: i32,
+    protocol_type: Option<String>,
+    protocol: Option<String>,
+    leader: Option<String>,
+    members: BTreeMap<String, Member>,
+    rebalance_deadline: Option<Instant>,
+}
+
+impl Group {
+    fn start_rebalance(&mut self, now: Instant) {
+        // every member has to join again, the slowest gets its rebalance timeout
+        self.state = GroupState::PreparingRebalance;
+        let timeout = self.members.values().map(|m| m.rebalance_timeout).max().unwrap_or_default();
+        self.rebalance_deadline = Some(now + timeout);
+        for member in self.members.values_mut() {
+            member.joined = false;
+        }
+    }
+
+    fn expire(&mut self, now: Instant) -> bool {
+        // members that stopped heartbeating are dropped and the rest rebalance
+        let before = self.members.len();
+        self.members.retain(|_, m| now < m.last_seen + m.session_timeout);
+        if self.members.len() == before { return false }
+        if self.state != GroupState::PreparingRebalance { self.start_rebalance(now) }
+        self.complete_join(now);
+        true
+    }
+
+    fn complete_join(&mut self, now: Instant) -> bool {
+        // once everyone joined, or the rebalance timed out, the members that
+        // made it form the next generation
+        if self.state != GroupState::PreparingRebalance { return false }
+        let all_joined = self.members.values().all(|m| m.joined);
+        if !all_joined && self.rebalance_deadline.is_some_and(|deadline| now < deadline) { return false }
+        self.members.retain(|_, m| m.joined);
+        self.generation += 1;
+        self.rebalance_deadline = None;
+        if self.members.is_empty() {
+            self.state = GroupState::Empty;
+            self.protocol = None;
+            self.leader = None;
+            return true
+        }
+        self.state = GroupState::CompletingRebalance;
+        self.protocol = self.choose_protocol();
+        if !self.leader.as_ref().is_some_and(|leader| self.members.contains_key(leader)) {
+            self.leader = self.members.keys().next().cloned();
+        }
+        for member in self.members.values_mut() {
+            member.assignment.clear();
+        }
+        true
+    }
+
+    fn candidates(&self, except: Option<&str>) -> Option<Vec<String>> {
+        // the protocols every member (but one) supports, None without members
+        let mut members = self.members.iter().filter(|(id, _)| Some(id.as_str()) != except).map(|(_, m)| m);
+        let first = members.next()?;
+        let mut candidates: Vec<String> = first.protocols.iter().map(|(name, _)| name.clone()).collect();
+        for member in members {
+            candidates.retain(|name| member.supports(name));
+        }
+        Some(candidates)
+    }
+
+    fn choose_protocol(&self) -> Option<String> {
+        // each member votes for the first candidate it prefers, like kafka
+        let candidates = self.candidates(None)?;
+        let mut votes: Vec<(String, usize)> = candidates.iter().map(|name| (name.clone(), 0)).collect();
+        for member in self.members.values() {
+            if let Some((name, _)) = member.protocols.iter().find(|(name, _)| candidates.contains(name)) {
+                if let Some(vote) = votes.iter_mut().find(|(n, _)| n == name) { vote.1 += 1 }
+            }
+        }
+        let most = votes.iter().map(|(_, count)| *count).max()?;
+        votes.into_iter().find(|(_, count)| *count == most).map(|(name, _)| name)
+    }
+
+    fn check(&self, member_id: &str, generation: i32) -> Result<()> {
+        if !self.members.contains_key(member_id) {
+            return Err(Error::UnknownMember(String::from(member_id)))
+        }
+        if generation != self.generation {
+            return Err(Error::IllegalGeneration{ generation, current: self.generation })
+        }
+        Ok(())
+    }
+}
+
+
+// Runs consumer groups: members join, the coordinator picks a leader and a
+// protocol, the leader hands out assignments through SyncGroup and members
+// heartbeat to stay in. Joins and syncs block until the group settles, the
+// same as kafka's, so each waits on its own connection's thread.
+pub struct GroupCoordinator {
+    groups: Mutex<BTreeMap<String, Group>>,
+    changed: Condvar,
+    next_member: AtomicU64,
+    min_session_timeout: Duration,
+    max_session_timeout: Duration,
+}
+
+impl GroupCoordinator {
+    pub fn new(min_session_timeout: Duration, max_session_timeout: Duration) -> GroupCoordinator {
+        GroupCoordinator{
+            groups: Mutex::new(BTreeMap::new()),
+            changed: Condvar::new(),
+            next_member: AtomicU64::new(0),
+            min_session_timeout,
+            max_session_timeout,
+        }
+    }
+
+    pub fn group(&self, group_id: &str) -> Option<GroupSummary> {
+        let groups = self.groups.lock().unwrap();
+        groups.get(group_id).map(|group| GroupSummary{
+            state: group.state,
+            generation: group.generation,
+            protocol: group.protocol.clone(),
+            leader: group.leader.clone(),
+            members: group.members.keys().cloned().collect(),
+        })
+    }
+
+    fn wait<'a>(&self, groups: MutexGuard<'a, BTreeMap<String, Group>>, deadline: Option<Instant>) -> MutexGuard<'a, BTreeMap<String, Group>> {
+        // wakes up at least once a second to expire sessions
+        let now = Instant::now();
+        let timeout = deadline.map_or(Duration::from_secs(1), |d| d.saturating_duration_since(now)).min(Duration::from_secs(1));
+        self.changed.wait_timeout(groups, timeout).unwrap().0
+    }
+
+    pub fn join_group(&self, group_id: &str, req: JoinRequest) -> Result<JoinResult> {
+        if req.session_timeout < self.min_session_timeout || req.session_timeout > self.max_session_timeout {
+            return Err(Error::InvalidRequest(format!("session timeout {:?} is out of bounds", req.session_timeout)))
+        }
+        if req.protocols.is_empty() {
+            return Err(Error::InconsistentGroupProtocol(String::from("no protocols")))
+        }
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let group = groups.entry(String::from(group_id)).or_default();
+        if group.expire(now) { self.changed.notify_all() }
+
+        if !req.member_id.is_empty() && !group.members.contains_key(&req.member_id) {
+            return Err(Error::UnknownMember(req.member_id))
+        }
+        if group.members.keys().any(|id| *id != req.member_id) {
+            if group.protocol_type.as_deref() != Some(&req.protocol_type) {
+                return Err(Error::InconsistentGroupProtocol(format!("group speaks {:?}", group.protocol_type)))
+            }
+            let candidates = group.candidates(Some(&req.member_id)).unwrap_or_default();
+            if !req.protocols.iter().any(|(name, _)| candidates.contains(name)) {
+                return Err(Error::InconsistentGroupProtocol(format!("group supports {:?}", candidates)))
+            }
+        }
+        let member_id = match req.member_id.as_str() {
+            "" => format!("{}-{}", req.client_id, self.next_member.fetch_add(1, Ordering::SeqCst)),
+            id => String::from(id),
+        };
+        let assignment = group.members.remove(&member_id).map(|m| m.assignment).unwrap_or_default();
+        group.members.insert(member_id.clone(), Member{
+            session_timeout: req.session_timeout,
+            rebalance_timeout: req.rebalance_timeout,
+            protocols: req.protocols,
+            assignment,
+            last_seen: now,
+            joined: false,
+        });
+        group.protocol_type = Some(req.protocol_type);
+        if group.state != GroupState::PreparingRebalance { group.start_rebalance(now) }
+        group.members.get_mut(&member_id).unwrap().joined = true;
+        let target = group.generation;
+        self.changed.notify_all();
+
+        loop {
+            let now = Instant::now();
+            let group = groups.get_mut(group_id).unwrap();
+            if group.complete_join(now) { self.changed.notify_all() }
+            let member = match group.members.get_mut(&member_id) {
+                Some(member) => member,
+                None => return Err(Error::UnknownMember(member_id)),
+            };
+            // waiting to join counts as being alive
+            member.last_seen = now;
+            if group.generation > target {
+                let protocol = group.protocol.clone().unwrap_or_default();
+                let leader = group.leader.clone().unwrap_or_default();
+                let members = if leader == member_id {
+                    group.members.iter().map(|(id, m)| (id.clone(), m.metadata(&protocol))).collect()
+                } else {
+                    vec![]
+                };
+                return Ok(JoinResult{ generation: group.generation, protocol, leader, member_id, members })
+            }
+            let deadline = group.rebalance_deadline;
+            groups = self.wait(groups, deadline);
+        }
+    }
+
+    pub fn sync_group(&self, group_id: &str, generation: i32, member_id: &str, assignments: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(group_id) {
+            Some(group) => group,
+            None => return Err(Error::UnknownMember(String::from(member_id))),
+        };
+        if group.expire(Instant::now()) { self.changed.notify_all() }
+        group.check(member_id, generation)?;
+        match group.state {
+            GroupState::PreparingRebalance => return Err(Error::RebalanceInProgress),
+            GroupState::Stable => return Ok(group.members[member_id].assignment.clone()),
+            GroupState::Empty | GroupState::CompletingRebalance => (),
+        }
+        if group.leader.as_deref() == Some(member_id) {
+            // members the leader left out get nothing
+            for (id, member) in group.members.iter_mut() {
+                member.assignment = assignments.iter().find(|(m, _)| m == id).map(|(_, a)| a.clone()).unwrap_or_default();
+            }
+            group.state = GroupState::Stable;
+            self.changed.notify_all();
+            return Ok(group.members[member_id].assignment.clone())
+        }
+
+        let deadline = Instant::now() + group.members[member_id].rebalance_timeout;
+        loop {
+            let now = Instant::now();
+            let group = groups.get_mut(group_id).unwrap();
+            if group.expire(now) { self.changed.notify_all() }
+            group.check(member_id, generation)?;
+            match group.state {
+                GroupState::Stable => return Ok(group.members[member_id].assignment.clone()),
+                GroupState::CompletingRebalance if now < deadline => {
+                    group.members.get_mut(member_id).unwrap().last_seen = now;
+                },
+                _ => return Err(Error::RebalanceInProgress),
+            }
+            groups = self.wait(groups, Some(deadline));
+        }
+    }
+
+    pub fn heartbeat(&self, group_id: &str, generation: i32, member_id: &str) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(group_id) {
+            Some(group) => group,
+            None => return Err(Error::UnknownMember(String::from(member_id))),
+        };
+        let now = Instant::now();
+        if group.expire(now) { self.changed.notify_all() }
+        group.check(member_id, generation)?;
+        group.members.get_mut(member_id).unwrap().last_seen = now;
+        match group.state {
+            GroupState::PreparingRebalance => Err(Error::RebalanceInProgress),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn leave_group(&self, group_id: &str, member_id: &str) -> Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(group_id) {
+            Some(group) => group,
+            None => return Err(Error::UnknownMember(String::from(member_id))),
+        };
+        if group.members.remove(member_id).is_none() {
+            return Err(Error::UnknownMember(String::from(member_id)))
+        }
+        let now = Instant::now();
+        if group.state != GroupState::PreparingRebalance { group.start_rebalance(now) }
+        group.complete_join(now);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    pub fn validate_commit(&self, group_id: &str, generation: i32, member_id: &str) -> Result<()> {
+        // commits from outside the group (generation -1) only go to groups
+        // without members, members commit under their current generation
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(group_id) {
+            Some(group) => group,
+            None if generation < 0 => return Ok(()),
+            None => return Err(Error::UnknownMember(String::from(member_id))),
+        };
+        if group.expire(Instant::now()) { self.changed.notify_all() }
+        if generation < 0 && member_id.is_empty() {
+            if group.members.is_empty() { return Ok(()) }
+            return Err(Error::UnknownMember(String::from(member_id)))
+        }
+        group.check(member_id, generation)?;
+        match group.state {
+            // the assignment isn't out yet, nobody owns partitions to commit for
+            GroupState::CompletingRebalance => Err(Error::RebalanceInProgress),
+            _ => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    fn coordinator() -> Arc<GroupCoordinator> {
+        Arc::new(GroupCoordinator::new(Duration::from_millis(1), Duration::from_secs(60)))
+    }
+
+    fn join(member_id: &str, session_ms: u64) -> JoinRequest {
+        JoinRequest{
+            member_id: String::from(member_id),
+            client_id: String::from("worker"),
+            protocol_type: String::from("consumer"),
+            protocols: vec![(String::from("range"), vec![1]), (String::from("roundrobin"), vec![2])],
+            session_timeout: Duration::from_millis(session_ms),
+            rebalance_timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn it_rebalances_joining_members() {
+        let groups = coordinator();
+        let first = groups.join_group("workers", join("", 10_000)).unwrap();
+        assert_eq!((first.generation, first.leader.clone()), (1, first.member_id.clone()));
+        assert_eq!(first.members.len(), 1, "the leader gets the members");
+        let assignment = vec![(first.member_id.clone(), b"0,1".to_vec())];
+        assert_eq!(groups.sync_group("workers", 1, &first.member_id, &assignment).unwrap(), b"0,1".to_vec());
+        groups.heartbeat("workers", 1, &first.member_id).unwrap();
+
+        // a second member starts a rebalance the first one hears about on its heartbeat
+        let joining = {
+            let groups = groups.clone();
+            thread::spawn(move || groups.join_group("workers", join("", 10_000)).unwrap())
+        };
+        while groups.group("workers").unwrap().state != GroupState::PreparingRebalance { thread::yield_now() }
+        assert!(matches!(groups.heartbeat("workers", 1, &first.member_id), Err(Error::RebalanceInProgress)));
+        let rejoined = groups.join_group("workers", join(&first.member_id, 10_000)).unwrap();
+        let second = joining.join().unwrap();
+        assert_eq!((rejoined.generation, second.generation), (2, 2));
+        assert_eq!(rejoined.leader, first.member_id, "the leader stays");
+        assert_eq!(rejoined.protocol, "range");
+        assert!(second.members.is_empty());
+
+        let syncing = {
+            let groups = groups.clone();
+            let member_id = second.member_id.clone();
+            thread::spawn(move || groups.sync_group("workers", 2, &member_id, &[]).unwrap())
+        };
+        let assignments = vec![(first.member_id.clone(), b"0".to_vec()), (second.member_id.clone(), b"1".to_vec())];
+        assert_eq!(groups.sync_group("workers", 2, &first.member_id, &assignments).unwrap(), b"0".to_vec());
+        assert_eq!(syncing.join().unwrap(), b"1".to_vec(), "followers wait for the leader's assignment");
+        assert!(matches!(groups.heartbeat("workers", 1, &first.member_id), Err(Error::IllegalGeneration{ current: 2, .. })));
+
+        groups.leave_group("workers", &second.member_id).unwrap();
+        assert!(matches!(groups.heartbeat("workers", 2, &first.member_id), Err(Error::RebalanceInProgress)));
+        assert!(matches!(groups.heartbeat("workers", 2, &second.member_id), Err(Error::UnknownMember(_))));
+    }
+
+    #[test]
+    fn it_expires_silent_members() {
+        let groups = coordinator();
+        let first = groups.join_group("workers", join("", 20)).unwrap();
+        groups.sync_group("workers", 1, &first.member_id, &[]).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        let second = groups.join_group("workers", join("", 10_000)).unwrap();
+        assert_eq!(second.leader, second.member_id, "the silent member was dropped");
+        assert_eq!(groups.group("workers").unwrap().members, vec![second.member_id.clone()]);
+        assert!(matches!(groups.heartbeat("workers", second.generation, &first.member_id), Err(Error::UnknownMember(_))));
+    }
+
+    #[test]
+    fn it_checks_protocols_and_commits() {
+        let groups = coordinator();
+        let first = groups.join_group("workers", join("", 10_000)).unwrap();
+        let other = JoinRequest{ protocols: vec![(String::from("sticky"), vec![])], ..join("", 10_000) };
+        assert!(matches!(groups.join_group("workers", other), Err(Error::InconsistentGroupProtocol(_))));
+        assert!(matches!(groups.validate_commit("workers", 1, &first.member_id), Err(Error::RebalanceInProgress)));
+        groups.sync_group("workers", 1, &first.member_id, &[]).unwrap();
+        groups.validate_commit("workers", 1, &first.member_id).unwrap();
+        assert!(matches!(groups.validate_commit("workers", -1, ""), Err(Error::UnknownMember(_))), "the group has members");
+        groups.validate_commit("loners", -1, "").unwrap();
+        assert!(matches!(groups.join_group("workers", join("", 0)), Err(Error::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{Ordering};
+use std::thread;
+
+use crate::{Error, Result};
+use crate::client::{Connection};
+use crate::cluster::Broker;
+use crate::cluster::manager::{BackgroundTasks};
+use crate::cluster::replica::{ReplicaManager};
+use crate::server::{ServerConfig};
+
+
+#[derive(Default)]
+struct LifecycleState {
+    conn: Option<Connection>,
+    // the epoch of the current registration, None until the controller takes one
+    epoch: Option<i64>,
+    // which of the controllers to go to
+    controller: usize,
+}
+
+// Keeps a broker registered with the controller. It registers on start, then
+// heartbeats every broker_heartbeat_interval and takes the live brokers the
+// controller answers with as the ones to find partition leaders on. Told its
+// epoch is stale, the controller restarted, another took over or it was
+// fenced for good, it registers again. A controller that can't be reached
+// or isn't the active one, it tries the next
+pub struct BrokerLifecycle {
+    broker: Broker,
+    controllers: Vec<String>,
+    replicas: Arc<ReplicaManager>,
+    config: ServerConfig,
+    state: Mutex<LifecycleState>,
+}
+
+impl BrokerLifecycle {
+    pub fn new(broker: Broker, controllers: Vec<String>, replicas: Arc<ReplicaManager>, config: ServerConfig) -> BrokerLifecycle {
+        let state = Mutex::new(LifecycleState::default());
+        BrokerLifecycle{ broker, controllers, replicas, config, state }
+    }
+
+    pub fn epoch(&self) -> Option<i64> { self.state.lock().unwrap().epoch }
+
+    pub fn tick(&self) -> Result<()> {
+        // one heartbeat, after registering if need be. The connection is
+        // taken out while it's in use and dropped when it fails
+        let (conn, epoch, controller) = {
+            let mut state = self.state.lock().unwrap();
+            (state.conn.take(), state.epoch, state.controller)
+        };
+        let res = self.heartbeat(conn, epoch, &self.controllers[controller]);
+        let mut state = self.state.lock().unwrap();
+        let (conn, epoch, res) = match res {
+            Ok(heartbeat) => heartbeat,
+            Err(e) => {
+                state.controller = (controller + 1) % self.controllers.len();
+                return Err(e)
+            },
+        };
+        state.epoch = Some(epoch);
+        match res {
+            Ok(brokers) => {
+                state.conn = Some(conn);
+                self.replicas.set_brokers(brokers);
+                Ok(())
+            },
+            Err(Error::StaleBrokerEpoch(msg)) => {
+                state.conn = Some(conn);
+                state.epoch = None;
+                Err(Error::StaleBrokerEpoch(msg))
+            },
+            Err(e) => {
+                state.controller = (controller + 1) % self.controllers.len();
+                Err(e)
+            },
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn heartbeat(&self, conn: Option<Connection>, epoch: Option<i64>, addr: &str) -> Result<(Connection, i64, Result<Vec<Broker>>)> {
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => Connection::connect_timeout(addr, self.config.broker_session_timeout)?,
+        };
+        let epoch = match epoch {
+            Some(epoch) => epoch,
+            None => conn.register_broker(&self.broker)?,
+        };
+        let res = conn.broker_heartbeat(self.broker.id(), epoch);
+        Ok((conn, epoch, res))
+    }
+
+    pub fn start(lifecycle: Arc<BrokerLifecycle>) -> BackgroundTasks {
+        BackgroundTasks::spawn("latka-broker-lifecycle", move |running| {
+            while running.load(Ordering::SeqCst) {
+                if let Err(e) = lifecycle.tick() {
+                    eprintln!("latka: broker {} can't heartbeat to the controller: {}", lifecycle.broker.id(), e);
+                }
+                thread::park_timeout(lifecycle.config.broker_heartbeat_interval);
+            }
+        })
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{Error, Offset, Result};
+use crate::cluster::raft::{METADATA_LOG_DIR};
+use crate::cluster::topic::{parse_partition_dir, validate_name};
+use crate::partition::Partition;
+use crate::partition::config::{PartitionConfig};
+use crate::partition::signal::{AppendSignal};
+
+pub type SharedPartition = Arc<Mutex<Partition>>;
+
+const PRODUCER_ID_FILE: &str = "producer-id-block";
+// producer ids are handed out from blocks so the file is written once per block
+const PRODUCER_ID_BLOCK: i64 = 1000;
+// the high watermarks of a log dir's replicated partitions, in kafka's format
+const HIGH_WATERMARK_FILE: &str = "replication-offset-checkpoint";
+const CHECKPOINT_VERSION: u32 = 0;
+// how many partitions each log dir loads at once on open, kafka's
+// num.recovery.threads.per.data.dir
+pub const RECOVERY_THREADS_PER_DIR: usize = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicPartitionId {
+    pub topic: String,
+    pub partition: u32,
+}
+
+impl TopicPartitionId {
+    pub fn new(topic: &str, partition: u32) -> TopicPartitionId {
+        TopicPartitionId{ topic: String::from(topic), partition }
+    }
+    pub fn dir_name(&self) -> String { format!("{}-{}", self.topic, self.partition) }
+}
+
+
+// The LogManager owns every partition under one or more log directories (JBOD)
+// and runs retention, compaction and timed flushes for all of them
+pub struct LogManager {
+    log_dirs: Vec<PathBuf>,
+    defaults: PartitionConfig,
+    partitions: RwLock<HashMap<TopicPartitionId, SharedPartition>>,
+    appends: Arc<AppendSignal>,
+    // (next id, end of the block reserved on disk)
+    producer_ids: Mutex<(i64, i64)>,
+}
+
+impl LogManager {
+    pub fn open(log_dirs: Vec<PathBuf>, defaults: PartitionConfig) -> Result<LogManager> {
+        LogManager::open_with_recovery_threads(log_dirs, defaults, RECOVERY_THREADS_PER_DIR)
+    }
+
+    pub fn open_with_recovery_threads(log_dirs: Vec<PathBuf>, defaults: PartitionConfig, threads_per_dir: usize) -> Result<LogManager> {
+        if log_dirs.is_empty() {
+            return Err(Error::InvalidConfig(String::from("at least one log dir is needed")))
+        }
+        if threads_per_dir == 0 {
+            return Err(Error::InvalidConfig(String::from("at least one recovery thread per log dir is needed")))
+        }
+        defaults.validate()?;
+        let mut found: Vec<Vec<(TopicPartitionId, PathBuf)>> = vec![];
+        for dir in &log_dirs {
+            fs::create_dir_all(dir)?;
+            let mut in_dir = vec![];
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if !path.is_dir() { continue }
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                // the metadata log belongs to the controller's quorum
+                if name == METADATA_LOG_DIR { continue }
+                if let Some((topic, id)) = parse_partition_dir(&name) {
+                    in_dir.push((TopicPartitionId::new(topic, id), path));
+                }
+            }
+            found.push(in_dir);
+        }
+
+        // loading scans every segment's index. Each log dir has a few workers
+        // taking partitions off its queue, however many partitions there are
+        let workers: Vec<usize> = found.iter().map(|in_dir| in_dir.len().min(threads_per_dir)).collect();
+        let queues: Vec<Mutex<std::vec::IntoIter<(TopicPartitionId, PathBuf)>>> = found.into_iter()
+            .map(|in_dir| Mutex::new(in_dir.into_iter()))
+            .collect();
+        let loaded: Mutex<Vec<(TopicPartitionId, Result<Partition>)>> = Mutex::new(vec![]);
+        thread::scope(|scope| {
+            let (defaults, loaded) = (&defaults, &loaded);
+            for (queue, workers) in queues.iter().zip(workers) {
+                for _ in 0..workers {
+                    scope.spawn(move || loop {
+                        let next = queue.lock().unwrap().next();
+                        let (id, path) = match next {
+                            Some(next) => next,
+                            None => break,
+                        };
+                        let partition = Partition::load(&path, defaults.clone());
+                        loaded.lock().unwrap().push((id, partition));
+                    });
+                }
+            }
+        });
+        let results = loaded.into_inner().unwrap();
+
+        let mut high_watermarks = HashMap::new();
+        for dir in &log_dirs {
+            high_watermarks.extend(read_checkpoint(&dir.join(HIGH_WATERMARK_FILE))?);
+        }
+
+        let appends = Arc::new(AppendSignal::new());
+        let mut partitions = HashMap::new();
+        for (id, partition) in results {
+            if partitions.contains_key(&id) {
+                return Err(Error::InvalidConfig(format!("{} is in more than one log dir", id.dir_name())))
+            }
+            let mut partition = partition?;
+            partition.set_append_signal(appends.clone());
+            partition.set_high_watermark(high_watermarks.get(&id).cloned());
+            partitions.insert(id, Arc::new(Mutex::new(partition)));
+        }
+        Ok(LogManager{
+            log_dirs,
+            defaults,
+            partitions: RwLock::new(partitions),
+            appends,
+            producer_ids: Mutex::new((0, 0)),
+        })
+    }
+
+    pub fn create(&self, topic: &str, partition: u32, config: Option<PartitionConfig>) -> Result<SharedPartition> {
+        validate_name(topic)?;
+        let id = TopicPartitionId::new(topic, partition);
+        let mut partitions = self.partitions.write().unwrap();
+        if let Some(existing) = partitions.get(&id) {
+            return Ok(existing.clone())
+        }
+        let dir = self.pick_log_dir()?;
+        let config = config.unwrap_or_else(|| self.defaults.clone());
+        let mut created = Partition::create(id.dir_name(), &mut dir.clone(), config)?;
+        created.set_append_signal(self.appends.clone());
+        let shared = Arc::new(Mutex::new(created));
+        partitions.insert(id, shared.clone());
+        Ok(shared)
+    }
+
+    pub fn delete(&self, topic: &str, partition: u32) -> Result<bool> {
+        // the partition and its directory gone, false if there wasn't one
+        let removed = self.partitions.write().unwrap().remove(&TopicPartitionId::new(topic, partition));
+        match removed {
+            Some(shared) => {
+                let path = shared.lock().unwrap().path().to_path_buf();
+                fs::remove_dir_all(path)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    pub fn get(&self, topic: &str, partition: u32) -> Option<SharedPartition> {
+        self.partitions.read().unwrap().get(&TopicPartitionId::new(topic, partition)).cloned()
+    }
+
+    pub fn partition_ids(&self) -> Vec<TopicPartitionId> {
+        let mut ids: Vec<TopicPartitionId> = self.partitions.read().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn partitions_of(&self, topic: &str) -> Vec<u32> {
+        self.partition_ids().into_iter().filter(|id| id.topic == topic).map(|id| id.partition).collect()
+    }
+
+    pub fn topics(&self) -> BTreeMap<String, Vec<u32>> {
+        let mut topics: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for id in self.partition_ids() {
+            topics.entry(id.topic).or_default().push(id.partition);
+        }
+        topics
+    }
+
+    pub fn log_dirs(&self) -> &[PathBuf] { &self.log_dirs }
+    pub fn defaults(&self) -> &PartitionConfig { &self.defaults }
+
+    // bumped by an append to any of the manager's partitions
+    pub fn append_signal(&self) -> Arc<AppendSignal> { self.appends.clone() }
+
+    pub fn next_producer_id(&self) -> Result<i64> {
+        // ids are never reused, a restart skips whatever was left of the last block
+        let mut ids = self.producer_ids.lock().unwrap();
+        if ids.0 == ids.1 {
+            let path = self.log_dirs[0].join(PRODUCER_ID_FILE);
+            let start = match fs::read_to_string(&path) {
+                Ok(raw) => raw.trim().parse::<i64>()
+                    .map_err(|_| Error::InvalidConfig(format!("{} isn't a producer id", path.display())))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let end = start + PRODUCER_ID_BLOCK;
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, end.to_string())?;
+            fs::File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            *ids = (start, end);
+        }
+        let id = ids.0;
+        ids.0 += 1;
+        Ok(id)
+    }
+
+    fn pick_log_dir(&self) -> Result<PathBuf> {
+        // new partitions go to the dir with the most free space
+        let mut best: Option<(u64, &PathBuf)> = None;
+        for dir in &self.log_dirs {
+            let free = free_space(dir)?;
+            if best.is_none_or(|(most, _)| free > most) {
+                best = Some((free, dir));
+            }
+        }
+        Ok(best.map(|(_, dir)| dir.clone()).unwrap_or_else(|| self.log_dirs[0].clone()))
+    }
+
+    fn shared_partitions(&self) -> Vec<(TopicPartitionId, SharedPartition)> {
+        let partitions = self.partitions.read().unwrap();
+        partitions.iter().map(|(id, p)| (id.clone(), p.clone())).collect()
+    }
+
+    fn for_each_partition<F>(&self, mut task: F) -> (usize, Vec<(TopicPartitionId, Error)>)
+        where F: FnMut(&mut Partition) -> Result<usize> {
+        // a partition that fails is reported and skipped, the rest still run
+        let mut done = 0;
+        let mut failed = vec![];
+        for (id, partition) in self.shared_partitions() {
+            match task(&mut partition.lock().unwrap()) {
+                Ok(n) => done += n,
+                Err(e) => failed.push((id, e)),
+            }
+        }
+        (done, failed)
+    }
+
+    pub fn run_retention(&self) -> (usize, Vec<(TopicPartitionId, Error)>) {
+        self.for_each_partition(|partition| {
+            partition.roll_if_old()?;
+            partition.delete_old_segments()
+        })
+    }
+
+    pub fn run_compaction(&self) -> (usize, Vec<(TopicPartitionId, Error)>) {
+        self.for_each_partition(|partition| partition.compact())
+    }
+
+    pub fn run_flush(&self) -> (usize, Vec<(TopicPartitionId, Error)>) {
+        self.for_each_partition(|partition| Ok(partition.flush_if_due()? as usize))
+    }
+
+    pub fn flush_all(&self) -> Result<()> {
+        for (_, partition) in self.shared_partitions() {
+            partition.lock().unwrap().flush()?;
+        }
+        self.checkpoint_high_watermarks()
+    }
+
+    pub fn checkpoint_high_watermarks(&self) -> Result<()> {
+        // every log dir gets a file, an empty one once none of its partitions are replicated
+        let mut by_dir: HashMap<&Path, Vec<(TopicPartitionId, Offset)>> = HashMap::new();
+        for dir in &self.log_dirs {
+            by_dir.insert(dir.as_path(), vec![]);
+        }
+        for (id, shared) in self.shared_partitions() {
+            let partition = shared.lock().unwrap();
+            let high_watermark = match partition.replicated_high_watermark() {
+                Some(high_watermark) => high_watermark,
+                None => continue,
+            };
+            let dir = partition.path().parent().and_then(|p| self.log_dirs.iter().find(|d| d.as_path() == p));
+            if let Some(dir) = dir {
+                by_dir.entry(dir.as_path()).or_default().push((id, high_watermark));
+            }
+        }
+        for (dir, mut entries) in by_dir {
+            entries.sort();
+            write_checkpoint(&dir.join(HIGH_WATERMARK_FILE), &entries)?;
+        }
+        Ok(())
+    }
+
+    pub fn start(manager: Arc<LogManager>, interval: Duration) -> BackgroundTasks {
+        BackgroundTasks::spawn("latka-log-manager", move |running| {
+            while running.load(Ordering::SeqCst) {
+                for (task, (_, failed)) in &[
+                    ("flush", manager.run_flush()),
+                    ("retention", manager.run_retention()),
+                    ("compaction", manager.run_compaction()),
+                ] {
+                    for (id, e) in failed {
+                        eprintln!("latka: {} of {} failed: {}", task, id.dir_name(), e)
+                    }
+                }
+                thread::park_timeout(interval);
+            }
+        })
+    }
+}
+
+
+pub struct BackgroundTasks {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundTasks {
+    // runs `task` on a thread of its own, the task loops while the flag it's
+    // handed stays set. stop() clears it and unparks the thread
+    pub fn spawn<F>(name: &str, task: F) -> BackgroundTasks where F: FnOnce(Arc<AtomicBool>) + Send + 'static {
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+        let handle = thread::Builder::new().name(String::from(name)).spawn(move || task(flag))
+            .unwrap_or_else(|e| panic!("failed to spawn {}: {}", name, e));
+        BackgroundTasks{ running, handle: Some(handle) }
+    }
+
+    pub fn wake(&self) {
+        if let Some(handle) = &self.handle { handle.thread().unpark() }
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) { self.stop() }
+}
+
+fn read_checkpoint(path: &Path) -> Result<Vec<(TopicPartitionId, Offset)>> {
+    // version, then the number of entries, then "topic partition offset" a line each
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let corrupt = || Error::InvalidConfig(format!("{} isn't an offset checkpoint", path.display()));
+    let mut lines = raw.lines();
+    if lines.next().and_then(|l| l.trim().parse::<u32>().ok()) != Some(CHECKPOINT_VERSION) { return Err(corrupt()) }
+    let count = lines.next().and_then(|l| l.trim().parse::<usize>().ok()).ok_or_else(corrupt)?;
+    let mut entries = Vec::with_capacity(count);
+    for line in lines.take(count) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            [topic, partition, offset] => {
+                let partition = partition.parse::<u32>().map_err(|_| corrupt())?;
+                let offset = offset.parse::<Offset>().map_err(|_| corrupt())?;
+                entries.push((TopicPartitionId::new(topic, partition), offset));
+            },
+            _ => return Err(corrupt()),
+        }
+    }
+    if entries.len() != count { return Err(corrupt()) }
+    Ok(entries)
+}
+
+fn write_checkpoint(path: &Path, entries: &[(TopicPartitionId, Offset)]) -> Result<()> {
+    let mut raw = format!("{}\n{}\n", CHECKPOINT_VERSION, entries.len());
+    for (id, offset) in entries {
+        raw.push_str(&format!("{} {} {}\n", id.topic, id.partition, offset));
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, raw)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn free_space(dir: &Path) -> Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidConfig(format!("{} has a nul byte", dir.display())))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into())
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+    use crate::partition::segment::{MaxBytes};
+
+    #[test]
+    fn it_discovers_partitions_across_dirs() {
+        let one = tempdir().unwrap();
+        let two = tempdir().unwrap();
+        {
+            Partition::create(String::from("orders-0"), &mut one.path().to_path_buf(), MaxBytes(64, 32)).unwrap()
+                .append("YELLOW SUBMARINE".as_bytes()).unwrap();
+            Partition::create(String::from("orders-1"), &mut two.path().to_path_buf(), MaxBytes(64, 32)).unwrap();
+            Partition::create(String::from("my-events-0"), &mut two.path().to_path_buf(), MaxBytes(64, 32)).unwrap();
+            fs::create_dir_all(two.path().join("lost+found")).unwrap();
+        }
+        let dirs = vec![one.path().to_path_buf(), two.path().to_path_buf()];
+        assert!(matches!(LogManager::open_with_recovery_threads(dirs.clone(), PartitionConfig::default(), 0), Err(Error::InvalidConfig(_))));
+        let manager = LogManager::open_with_recovery_threads(dirs, PartitionConfig::default(), 2).unwrap();
+
+        assert_eq!(manager.partition_ids(), vec![
+            TopicPartitionId::new("my-events", 0),
+            TopicPartitionId::new("orders", 0),
+            TopicPartitionId::new("orders", 1),
+        ]);
+        let orders = manager.get("orders", 0).unwrap();
+        assert_eq!(orders.lock().unwrap().log_end_offset(), 1);
+        assert!(manager.get("orders", 2).is_none());
+    }
+
+    #[test]
+    fn it_refuses_duplicate_partitions() {
+        let one = tempdir().unwrap();
+        let two = tempdir().unwrap();
+        {
+            Partition::create(String::from("orders-0"), &mut one.path().to_path_buf(), MaxBytes(64, 32)).unwrap();
+            Partition::create(String::from("orders-0"), &mut two.path().to_path_buf(), MaxBytes(64, 32)).unwrap();
+        }
+        let dirs = vec![one.path().to_path_buf(), two.path().to_path_buf()];
+        assert!(matches!(LogManager::open(dirs, PartitionConfig::default()), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn it_creates_partitions() {
+        let tmp = tempdir().unwrap();
+        let manager = LogManager::open(vec![tmp.path().to_path_buf()], MaxBytes(64, 32).into()).unwrap();
+        let created = manager.create("orders", 3, None).unwrap();
+        created.lock().unwrap().append("XX".as_bytes()).unwrap();
+
+        assert!(tmp.path().join("orders-3").is_dir());
+        assert!(Arc::ptr_eq(&created, &manager.create("orders", 3, None).unwrap()), "create is idempotent");
+        assert!(manager.create("bad name", 0, None).is_err());
+        assert!(free_space(tmp.path()).unwrap() > 0);
+    }
+
+    #[test]
+    fn it_checkpoints_high_watermarks() {
+        let one = tempdir().unwrap();
+        let two = tempdir().unwrap();
+        let dirs = vec![one.path().to_path_buf(), two.path().to_path_buf()];
+        {
+            let manager = LogManager::open(dirs.clone(), MaxBytes(64 * 1024, 64 * 1024).into()).unwrap();
+            for (partition, high_watermark) in [(0, Some(2)), (1, Some(0)), (2, None)] {
+                let shared = manager.create("events", partition, None).unwrap();
+                let mut shared = shared.lock().unwrap();
+                for _ in 0..3 { shared.append(b"XX").unwrap(); }
+                shared.set_high_watermark(high_watermark);
+            }
+            manager.flush_all().unwrap();
+        }
+        let manager = LogManager::open(dirs, PartitionConfig::default()).unwrap();
+        let high_watermarks: Vec<_> = (0..3).map(|p| {
+            let shared = manager.get("events", p).unwrap();
+            let partition = shared.lock().unwrap();
+            (partition.replicated_high_watermark(), partition.high_watermark())
+        }).collect();
+        assert_eq!(high_watermarks, vec![(Some(2), 2), (Some(0), 0), (None, 3)]);
+    }
+
+    #[test]
+    fn it_hands_out_producer_ids() {
+        let tmp = tempdir().unwrap();
+        let manager = LogManager::open(vec![tmp.path().to_path_buf()], MaxBytes(64, 32).into()).unwrap();
+        assert_eq!((manager.next_producer_id().unwrap(), manager.next_producer_id().unwrap()), (0, 1));
+        drop(manager);
+
+        let manager = LogManager::open(vec![tmp.path().to_path_buf()], MaxBytes(64, 32).into()).unwrap();
+        assert_eq!(manager.next_producer_id().unwrap(), PRODUCER_ID_BLOCK, "a restart starts a new block");
+    }
+
+    #[test]
+    fn it_runs_background_tasks() {
+        let tmp = tempdir().unwrap();
+        let config = PartitionConfig {
+            retention_bytes: Some(1),
+            ..PartitionConfig::from(MaxBytes(28, 32))
+        };
+        let manager = Arc::new(LogManager::open(vec![tmp.path().to_path_buf()], config).unwrap());
+        let partition = manager.create("orders", 0, None).unwrap();
+        for _ in 0..3 {
+            partition.lock().unwrap().append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        }
+
+        let mut tasks = LogManager::start(manager.clone(), Duration::from_millis(5));
+        for _ in 0..200 {
+            if partition.lock().unwrap().segments_len() == 0 { break }
+            thread::sleep(Duration::from_millis(5));
+        }
+        tasks.stop();
+        assert_eq!(partition.lock().unwrap().log_start_offset(), 2, "retention ran in the background");
+    }
+
+    #[test]
+    fn it_carries_on_past_a_failing_partition() {
+        let tmp = tempdir().unwrap();
+        let config = PartitionConfig {
+            retention_bytes: Some(1),
+            ..PartitionConfig::from(MaxBytes(28, 32))
+        };
+        let manager = LogManager::open(vec![tmp.path().to_path_buf()], config).unwrap();
+        for partition in 0..3 {
+            let shared = manager.create("orders", partition, None).unwrap();
+            for _ in 0..3 { shared.lock().unwrap().append("YELLOW SUBMARINE".as_bytes()).unwrap(); }
+        }
+        // a log that can't be opened any more
+        let broken = tmp.path().join("orders-1").join(format!("{:0>20}.log", 0));
+        fs::remove_file(&broken).unwrap();
+        fs::create_dir(&broken).unwrap();
+
+        let (deleted, failed) = manager.run_retention();
+        assert_eq!(deleted, 4, "two segments each from the others");
+        assert_eq!(failed.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>(), vec![TopicPartitionId::new("orders", 1)]);
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap};
+
+use crate::{Error, Offset, Result};
+use crate::cluster::Broker;
+use crate::cluster::controller::{decode_state, encode_state};
+use crate::cluster::raft::{StateMachine};
+use crate::cluster::replica::{PartitionState};
+use crate::kafka::codec::{Decoder, Encoder};
+
+const REGISTER_BROKER: i8 = 1;
+const UNREGISTER_BROKER: i8 = 2;
+const TOPIC: i8 = 3;
+const REMOVE_TOPIC: i8 = 4;
+const PARTITION: i8 = 5;
+const CONFIG: i8 = 6;
+const REASSIGNMENT: i8 = 7;
+
+
+// One change to the cluster metadata, as it's written to the metadata log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataRecord {
+    RegisterBroker(Broker),
+    UnregisterBroker(u32),
+    Topic(String),
+    RemoveTopic(String),
+    Partition { topic: String, partition: u32, state: PartitionState },
+    // a topic's config when there's a topic, else a cluster wide one. No value removes it
+    Config { topic: Option<String>, key: String, value: Option<String> },
+    // where a partition is moving to, None once it's there
+    Reassignment { topic: String, partition: u32, reassignment: Option<Reassignment> },
+}
+
+// A partition on its way to other brokers. Until every target replica is in
+// sync the partition keeps its original replicas too
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reassignment {
+    pub target: Vec<u32>,
+    pub original: Vec<u32>,
+}
+
+impl MetadataRecord {
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        match self {
+            MetadataRecord::RegisterBroker(broker) => {
+                enc.i8(REGISTER_BROKER);
+                enc.i32(broker.id() as i32);
+                enc.string(broker.host());
+                enc.i32(broker.port() as i32);
+                enc.nullable_string(broker.rack());
+            },
+            MetadataRecord::UnregisterBroker(id) => {
+                enc.i8(UNREGISTER_BROKER);
+                enc.i32(*id as i32);
+            },
+            MetadataRecord::Topic(name) => {
+                enc.i8(TOPIC);
+                enc.string(name);
+            },
+            MetadataRecord::RemoveTopic(name) => {
+                enc.i8(REMOVE_TOPIC);
+                enc.string(name);
+            },
+            MetadataRecord::Partition{ topic, partition, state } => {
+                enc.i8(PARTITION);
+                enc.string(topic);
+                enc.i32(*partition as i32);
+                encode_state(&mut enc, state);
+            },
+            MetadataRecord::Config{ topic, key, value } => {
+                enc.i8(CONFIG);
+                enc.nullable_string(topic.as_deref());
+                enc.string(key);
+                enc.nullable_string(value.as_deref());
+            },
+            MetadataRecord::Reassignment{ topic, partition, reassignment } => {
+                enc.i8(REASSIGNMENT);
+                enc.string(topic);
+                enc.i32(*partition as i32);
+                match reassignment {
+                    Some(reassignment) => {
+                        encode_ids(&mut enc, &reassignment.target);
+                        encode_ids(&mut enc, &reassignment.original);
+                    },
+                    None => enc.null_array(),
+                }
+            },
+        }
+        enc.into_vec()
+    }
+
+    pub fn from_slice(raw: &[u8]) -> Result<MetadataRecord> {
+        let mut dec = Decoder::new(raw);
+        let non_negative = |what: &str, v: i32| match v {
+            v if v >= 0 => Ok(v as u32),
+            v => Err(Error::CorruptRecord(format!("metadata record with {} {}", what, v))),
+        };
+        let record = match dec.i8()? {
+            REGISTER_BROKER => {
+                let id = non_negative("broker", dec.i32()?)?;
+                let host = dec.string()?;
+                let port = non_negative("port", dec.i32()?)?;
+                let broker = Broker::new(id, &host, port as u16);
+                match dec.nullable_string()? {
+                    Some(rack) => MetadataRecord::RegisterBroker(broker.with_rack(&rack)),
+                    None => MetadataRecord::RegisterBroker(broker),
+                }
+            },
+            UNREGISTER_BROKER => MetadataRecord::UnregisterBroker(non_negative("broker", dec.i32()?)?),
+            TOPIC => MetadataRecord::Topic(dec.string()?),
+            REMOVE_TOPIC => MetadataRecord::RemoveTopic(dec.string()?),
+            PARTITION => MetadataRecord::Partition{
+                topic: dec.string()?,
+                partition: non_negative("partition", dec.i32()?)?,
+                state: decode_state(&mut dec)?,
+            },
+            CONFIG => MetadataRecord::Config{
+                topic: dec.nullable_string()?,
+                key: dec.string()?,
+                value: dec.nullable_string()?,
+            },
+            REASSIGNMENT => {
+                let topic = dec.string()?;
+                let partition = non_negative("partition", dec.i32()?)?;
+                let reassignment = match dec.array_len()? {
+                    Some(len) => {
+                        let target = decode_ids(&mut dec, len)?;
+                        let len = dec.array_len()?.unwrap_or(0);
+                        Some(Reassignment{ target, original: decode_ids(&mut dec, len)? })
+                    },
+                    None => None,
+                };
+                MetadataRecord::Reassignment{ topic, partition, reassignment }
+            },
+            kind => return Err(Error::CorruptRecord(format!("metadata record of type {}", kind))),
+        };
+        Ok(record)
+    }
+}
+
+fn encode_ids(enc: &mut Encoder, ids: &[u32]) {
+    enc.array_len(ids.len());
+    for id in ids {
+        enc.i32(*id as i32);
+    }
+}
+
+fn decode_ids(dec: &mut Decoder, len: usize) -> Result<Vec<u32>> {
+    (0..len).map(|_| match dec.i32()? {
+        id if id >= 0 => Ok(id as u32),
+        id => Err(Error::CorruptRecord(format!("metadata record with broker {}", id))),
+    }).collect()
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicImage {
+    pub partitions: BTreeMap<u32, PartitionState>,
+    pub configs: BTreeMap<String, String>,
+    pub reassignments: BTreeMap<u32, Reassignment>,
+}
+
+// The cluster metadata as of some offset in the metadata log, what every
+// member of the quorum builds by applying the log in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataImage {
+    brokers: BTreeMap<u32, Broker>,
+    topics: BTreeMap<String, TopicImage>,
+    configs: BTreeMap<String, String>,
+}
+
+impl MetadataImage {
+    pub fn new() -> MetadataImage { MetadataImage::default() }
+
+    pub fn brokers(&self) -> Vec<Broker> { self.brokers.values().cloned().collect() }
+    pub fn broker(&self, id: u32) -> Option<&Broker> { self.brokers.get(&id) }
+    pub fn topics(&self) -> Vec<String> { self.topics.keys().cloned().collect() }
+    pub fn topic(&self, name: &str) -> Option<&TopicImage> { self.topics.get(name) }
+    pub fn config(&self, key: &str) -> Option<&str> { self.configs.get(key).map(|v| v.as_str()) }
+
+    pub fn partition(&self, topic: &str, partition: u32) -> Option<&PartitionState> {
+        self.topics.get(topic).and_then(|t| t.partitions.get(&partition))
+    }
+
+    pub fn apply(&mut self, record: MetadataRecord) -> Result<()> {
+        // a partition or config of a topic that isn't there is refused, the
+        // same way by every member since they all apply the same log
+        let unknown = |topic: &str| Error::PartitionNotFound(topic.into());
+        match record {
+            MetadataRecord::RegisterBroker(broker) => { self.brokers.insert(broker.id(), broker); },
+            MetadataRecord::UnregisterBroker(id) => { self.brokers.remove(&id); },
+            MetadataRecord::Topic(name) => { self.topics.entry(name).or_default(); },
+            MetadataRecord::RemoveTopic(name) => { self.topics.remove(&name); },
+            MetadataRecord::Partition{ topic, partition, state } => {
+                let image = self.topics.get_mut(&topic).ok_or_else(|| unknown(&topic))?;
+                image.partitions.insert(partition, state);
+            },
+            MetadataRecord::Config{ topic, key, value } => {
+                let configs = match topic {
+                    Some(topic) => &mut self.topics.get_mut(&topic).ok_or_else(|| unknown(&topic))?.configs,
+                    None => &mut self.configs,
+                };
+                match value {
+                    Some(value) => configs.insert(key, value),
+                    None => configs.remove(&key),
+                };
+            },
+            MetadataRecord::Reassignment{ topic, partition, reassignment } => {
+                let image = self.topics.get_mut(&topic).ok_or_else(|| unknown(&topic))?;
+                match reassignment {
+                    Some(reassignment) => image.reassignments.insert(partition, reassignment),
+                    None => image.reassignments.remove(&partition),
+                };
+            },
+        }
+        Ok(())
+    }
+
+    pub fn records(&self) -> Vec<MetadataRecord> {
+        // what rebuilds the image from nothing, a snapshot is these in order
+        let mut records = vec![];
+        for broker in self.brokers.values() {
+            records.push(MetadataRecord::RegisterBroker(broker.clone()));
+        }
+        for (key, value) in &self.configs {
+            records.push(MetadataRecord::Config{ topic: None, key: key.clone(), value: Some(value.clone()) });
+        }
+        for (name, topic) in &self.topics {
+            records.push(MetadataRecord::Topic(name.clone()));
+            for (partition, state) in &topic.partitions {
+                records.push(MetadataRecord::Partition{ topic: name.clone(), partition: *partition, state: state.clone() });
+            }
+            for (key, value) in &topic.configs {
+                let (key, value) = (key.clone(), Some(value.clone()));
+                records.push(MetadataRecord::Config{ topic: Some(name.clone()), key, value });
+            }
+            for (partition, reassignment) in &topic.reassignments {
+                let (topic, reassignment) = (name.clone(), Some(reassignment.clone()));
+                records.push(MetadataRecord::Reassignment{ topic, partition: *partition, reassignment });
+            }
+        }
+        records
+    }
+}
+
+impl StateMachine for MetadataImage {
+    fn apply(&mut self, _offset: Offset, command: &[u8]) -> Result<()> {
+        MetadataImage::apply(self, MetadataRecord::from_slice(command)?)
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let records = self.records();
+        let mut enc = Encoder::new();
+        enc.array_len(records.len());
+        for record in records {
+            enc.bytes(Some(&record.to_vec()));
+        }
+        Ok(enc.into_vec())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        let mut dec = Decoder::new(snapshot);
+        let mut image = MetadataImage::new();
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            let raw = dec.bytes()?.ok_or_else(|| Error::CorruptRecord(String::from("null metadata record")))?;
+            image.apply(MetadataRecord::from_slice(raw)?)?;
+        }
+        *self = image;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_rebuilds_the_image_from_a_snapshot() {
+        let mut image = MetadataImage::new();
+        let state = PartitionState{ replicas: vec![1, 2], leader: 2, leader_epoch: 3, isr: vec![2] };
+        let records = vec![
+            MetadataRecord::RegisterBroker(Broker::new(1, "127.0.0.1", 9092)),
+            MetadataRecord::RegisterBroker(Broker::new(2, "127.0.0.1", 9093).with_rack("eu-west-1b")),
+            MetadataRecord::Topic(String::from("events")),
+            MetadataRecord::Partition{ topic: String::from("events"), partition: 0, state: state.clone() },
+            MetadataRecord::Config{ topic: Some(String::from("events")), key: String::from("retention.ms"), value: Some(String::from("-1")) },
+            MetadataRecord::Config{ topic: None, key: String::from("unclean.leader.election.enable"), value: Some(String::from("true")) },
+            MetadataRecord::Reassignment{ topic: String::from("events"), partition: 0, reassignment: Some(Reassignment{ target: vec![3], original: vec![1, 2] }) },
+            MetadataRecord::Reassignment{ topic: String::from("events"), partition: 1, reassignment: Some(Reassignment{ target: vec![1], original: vec![2] }) },
+            MetadataRecord::Reassignment{ topic: String::from("events"), partition: 1, reassignment: None },
+            MetadataRecord::UnregisterBroker(1),
+        ];
+        for record in records {
+            assert_eq!(MetadataRecord::from_slice(&record.to_vec()).unwrap(), record);
+            image.apply(record).unwrap();
+        }
+        let orphan = MetadataRecord::Partition{ topic: String::from("nope"), partition: 0, state: state.clone() };
+        assert!(matches!(image.apply(orphan), Err(Error::PartitionNotFound(_))));
+        assert_eq!(image.partition("events", 0), Some(&state));
+        assert_eq!(image.brokers(), vec![Broker::new(2, "127.0.0.1", 9093).with_rack("eu-west-1b")]);
+
+        let mut restored = MetadataImage::new();
+        restored.restore(&image.snapshot().unwrap()).unwrap();
+        assert_eq!(restored, image);
+        assert_eq!(restored.topic("events").unwrap().configs.get("retention.ms").map(|v| v.as_str()), Some("-1"));
+        assert_eq!(restored.topic("events").unwrap().reassignments.keys().collect::<Vec<_>>(), vec![&0]);
+    }
+}
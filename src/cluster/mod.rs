@@ -1,25 +1,88 @@
 pub type Offset = u64;
 
-use std::{io, fs};
-use std::path::PathBuf;
+pub mod controller;
+pub mod group;
+pub mod lifecycle;
+pub mod manager;
+pub mod metadata;
+pub mod offsets;
+pub mod partitioner;
+pub mod placement;
+pub mod quota;
+pub mod raft;
+pub mod replica;
+pub mod topic;
+pub mod transaction;
+
+use std::{fs};
+use std::collections::{BTreeMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 // use std::sync::{Arc, Mutex};
 use std::iter::FromIterator;
 
+use crate::{Error, Result};
 use crate::partition::Partition;
-use crate::partition::segment::{MaxBytes};
+use crate::partition::config::{PartitionConfig};
+use crate::partition::record::{Record};
+
+const REPLAY_BYTES: u64 = 1024 * 1024;
+// the partition's replicas, kept in its dir so a reopened topic still knows them
+const REPLICA_ASSIGNMENT_FILE: &str = "replica-assignment";
 
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Broker {
     id: u32,
     host: String,
     port: u16,
+    // broker.rack, replicas of a partition go to different racks where they can
+    rack: Option<String>,
 }
 
 impl Broker {
+    pub fn new(id: u32, host: &str, port: u16) -> Broker {
+        Broker{ id, host: String::from(host), port, rack: None }
+    }
+
+    pub fn with_rack(self, rack: &str) -> Broker { Broker{ rack: Some(String::from(rack)), ..self } }
+
+    pub fn id(&self) -> u32 { self.id }
+    pub fn host(&self) -> &str { &self.host }
+    pub fn port(&self) -> u16 { self.port }
+    pub fn rack(&self) -> Option<&str> { self.rack.as_deref() }
     pub fn addr(&self) -> String { format!("{}:{}", self.host, self.port) }
 }
 
 
+// The latest value of every key in one of the compacted partitions the broker
+// keeps its own state in, tombstoned keys left out
+pub fn load_compacted(partition: &Partition) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut latest = BTreeMap::new();
+    let mut offset = partition.log_start_offset();
+    while offset < partition.log_end_offset() {
+        let messages = partition.read(offset, REPLAY_BYTES)?;
+        let last = match messages.last() {
+            Some(last) => last.offset,
+            None => break,
+        };
+        for message in messages {
+            let record = Record::from_slice(&message.payload)?;
+            let key = match record.key {
+                Some(key) => key,
+                None => continue,
+            };
+            match record.value {
+                Some(value) => latest.insert(key, value),
+                None => latest.remove(&key),
+            };
+        }
+        offset = last + 1;
+    }
+    Ok(latest)
+}
+
+
 pub struct TopicPartition {
     topic: String,
     path: PathBuf,
@@ -27,6 +90,8 @@ pub struct TopicPartition {
     replica_ids: Vec<u32>,
     leader_id: u32,
     preferred_leader: u32,
+    // the replicas caught up with the leader
+    isr: Vec<u32>,
 
     pub partition: Partition,
 }
@@ -37,15 +102,21 @@ impl TopicPartition {
         log_path: String,
         partition_id: u32,
         replicas: Vec<u32>,
-        leader_id: u32,
-        preferred_leader: u32,
-    ) -> io::Result<TopicPartition> {
+        config: PartitionConfig,
+    ) -> Result<TopicPartition> {
+        // the first replica is the preferred leader, and leads to begin with
+        let preferred_leader = *replicas.first()
+            .ok_or_else(|| Error::InvalidConfig(format!("{}-{} has no replicas", topic, partition_id)))?;
+        let leader_id = preferred_leader;
         let path = PathBuf::from(format!("{}/{}-{}", log_path, &topic, partition_id));
-        let partition = Partition::create(topic.clone(), &mut path.clone(), MaxBytes(1024, 1024))?;
+        let name = format!("{}-{}", &topic, partition_id);
+        let partition = Partition::create(name, &mut PathBuf::from(log_path), config)?;
+        store_replicas(&path, &replicas)?;
         return Ok(TopicPartition{
             topic: topic,
             path: path,
             partition_id: partition_id,
+            isr: replicas.clone(),
             replica_ids: replicas,
             leader_id: leader_id,
             preferred_leader: preferred_leader,
@@ -60,17 +131,57 @@ impl TopicPartition {
         replicas: Vec<u32>,
         leader_id: u32,
         preferred_leader: u32,
-    ) -> io::Result<TopicPartition> {
+    ) -> Result<TopicPartition> {
         let path = PathBuf::from(format!("{}/{}-{}", log_path, &topic, partition_id));
-        let partition = Partition::load(&mut path.clone(), MaxBytes(1024, 1024))?;
+        let partition = Partition::load(&path, PartitionConfig::default())?;
         return Ok(TopicPartition{
             topic: topic,
             path: path,
             partition_id: partition_id,
+            isr: replicas.clone(),
             replica_ids: replicas,
             leader_id: leader_id,
             preferred_leader: preferred_leader,
             partition: partition,
         })
     }
+
+    pub fn topic(&self) -> &str { &self.topic }
+    pub fn partition_id(&self) -> u32 { self.partition_id }
+    pub fn path(&self) -> &PathBuf { &self.path }
+    pub fn replica_ids(&self) -> &[u32] { &self.replica_ids }
+    pub fn leader_id(&self) -> u32 { self.leader_id }
+    pub fn preferred_leader(&self) -> u32 { self.preferred_leader }
+    pub fn isr(&self) -> &[u32] { &self.isr }
+    pub fn set_isr(&mut self, isr: Vec<u32>) { self.isr = isr }
+}
+
+
+pub fn load_replicas(dir: &Path) -> Result<Option<Vec<u32>>> {
+    // "1 2 3", the preferred leader first. None if the partition predates it
+    let path = dir.join(REPLICA_ASSIGNMENT_FILE);
+    let raw = match fs::read_to_string(&path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let replicas = raw.split_whitespace().map(|id| id.parse::<u32>()).collect::<std::result::Result<Vec<_>, _>>();
+    match replicas {
+        Ok(replicas) if !replicas.is_empty() => Ok(Some(replicas)),
+        _ => Err(Error::CorruptRecord(format!("{} isn't a replica assignment", path.display()))),
+    }
+}
+
+fn store_replicas(dir: &Path, replicas: &[u32]) -> Result<()> {
+    // written aside and renamed, like the partition's config
+    let path = dir.join(REPLICA_ASSIGNMENT_FILE);
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        let ids: Vec<String> = replicas.iter().map(|id| id.to_string()).collect();
+        file.write_all(ids.join(" ").as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(tmp_path, path)?;
+    Ok(())
 }
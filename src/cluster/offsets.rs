@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap};
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, Offset, Result};
+use crate::cluster;
+use crate::cluster::manager::{LogManager, SharedPartition, TopicPartitionId};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::config::{CleanupPolicy, PartitionConfig};
+use crate::partition::record::{Record};
+use crate::partition::segment::{now_ms};
+
+// committed offsets live in a compacted partition keyed by group, topic and
+// partition, the same way kafka keeps them
+pub const OFFSETS_TOPIC: &str = "__consumer_offsets";
+// offset.metadata.max.bytes
+pub const MAX_METADATA_BYTES: usize = 4096;
+const KEY_VERSION: i8 = 1;
+const VALUE_VERSION: i8 = 1;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommittedOffset {
+    pub offset: Offset,
+    pub metadata: Option<String>,
+    pub commit_timestamp: u64,
+}
+
+impl CommittedOffset {
+    pub fn new(offset: Offset, metadata: Option<&str>) -> CommittedOffset {
+        CommittedOffset{ offset, metadata: metadata.map(String::from), commit_timestamp: now_ms() }
+    }
+
+    //   version i8 | offset i64 | metadata nullable string | commit_timestamp i64
+    fn to_vec(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.i8(VALUE_VERSION);
+        enc.i64(self.offset as i64);
+        enc.nullable_string(self.metadata.as_deref());
+        enc.i64(self.commit_timestamp as i64);
+        enc.into_vec()
+    }
+
+    fn from_slice(raw: &[u8]) -> Result<CommittedOffset> {
+        let mut dec = Decoder::new(raw);
+        if dec.i8()? != VALUE_VERSION {
+            return Err(Error::CorruptRecord(String::from("unknown committed offset version")))
+        }
+        Ok(CommittedOffset{
+            offset: dec.i64()? as Offset,
+            metadata: dec.nullable_string()?,
+            commit_timestamp: dec.i64()? as u64,
+        })
+    }
+}
+
+//   version i8 | group string | topic string | partition u32
+fn key_to_vec(group: &str, tp: &TopicPartitionId) -> Vec<u8> {
+    let mut enc = Encoder::new();
+    enc.i8(KEY_VERSION);
+    enc.string(group);
+    enc.string(&tp.topic);
+    enc.u32(tp.partition);
+    enc.into_vec()
+}
+
+fn key_from_slice(raw: &[u8]) -> Result<(String, TopicPartitionId)> {
+    let mut dec = Decoder::new(raw);
+    if dec.i8()? != KEY_VERSION {
+        return Err(Error::CorruptRecord(String::from("unknown committed offset key version")))
+    }
+    let group = dec.string()?;
+    let topic = dec.string()?;
+    Ok((group, TopicPartitionId::new(&topic, dec.u32()?)))
+}
+
+
+// Consumer groups' committed offsets. Every commit is appended to the offsets
+// partition before it's visible, and the partition is replayed on open
+pub struct OffsetStore {
+    log: SharedPartition,
+    offsets: Mutex<BTreeMap<String, BTreeMap<TopicPartitionId, CommittedOffset>>>,
+}
+
+impl OffsetStore {
+    pub fn open(logs: Arc<LogManager>) -> Result<OffsetStore> {
+        let config = PartitionConfig{ cleanup_policy: CleanupPolicy::Compact, ..logs.defaults().clone() };
+        let log = logs.create(OFFSETS_TOPIC, 0, Some(config))?;
+        let mut offsets: BTreeMap<String, BTreeMap<TopicPartitionId, CommittedOffset>> = BTreeMap::new();
+        for (key, value) in cluster::load_compacted(&log.lock().unwrap())? {
+            let (group, tp) = key_from_slice(&key)?;
+            offsets.entry(group).or_default().insert(tp, CommittedOffset::from_slice(&value)?);
+        }
+        Ok(OffsetStore{ log, offsets: Mutex::new(offsets) })
+    }
+
+    pub fn commit(&self, group: &str, commits: &[(TopicPartitionId, CommittedOffset)]) -> Result<()> {
+        // the group's offsets are written together, one batch in the log
+        for (_, commit) in commits {
+            if commit.metadata.as_ref().map_or(0, |m| m.len()) > MAX_METADATA_BYTES {
+                return Err(Error::InvalidRequest(format!("offset metadata is over {} bytes", MAX_METADATA_BYTES)))
+            }
+        }
+        let mut offsets = self.offsets.lock().unwrap();
+        let records: Vec<Record> = commits.iter()
+            .map(|(tp, commit)| Record::new(commit.commit_timestamp, Some(&key_to_vec(group, tp)), Some(&commit.to_vec())))
+            .collect();
+        self.append(&records)?;
+        let committed = offsets.entry(String::from(group)).or_default();
+        for (tp, commit) in commits {
+            committed.insert(tp.clone(), commit.clone());
+        }
+        Ok(())
+    }
+
+    pub fn fetch(&self, group: &str, tp: &TopicPartitionId) -> Option<CommittedOffset> {
+        self.offsets.lock().unwrap().get(group).and_then(|committed| committed.get(tp).cloned())
+    }
+
+    pub fn fetch_all(&self, group: &str) -> Vec<(TopicPartitionId, CommittedOffset)> {
+        match self.offsets.lock().unwrap().get(group) {
+            Some(committed) => committed.iter().map(|(tp, commit)| (tp.clone(), commit.clone())).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn delete_group(&self, group: &str) -> Result<usize> {
+        // tombstones every offset the group committed
+        let mut offsets = self.offsets.lock().unwrap();
+        let committed = match offsets.get(group) {
+            Some(committed) => committed,
+            None => return Ok(0),
+        };
+        let tombstones: Vec<Record> = committed.keys()
+            .map(|tp| Record::new(now_ms(), Some(&key_to_vec(group, tp)), None))
+            .collect();
+        self.append(&tombstones)?;
+        offsets.remove(group);
+        Ok(tombstones.len())
+    }
+
+    pub fn expire(&self, retention_ms: u64) -> Result<usize> {
+        // offsets.retention.ms, offsets not committed again for that long are
+        // tombstoned
+        let now = now_ms();
+        let mut offsets = self.offsets.lock().unwrap();
+        let mut tombstones = vec![];
+        for (group, committed) in offsets.iter_mut() {
+            committed.retain(|tp, commit| {
+                if commit.commit_timestamp + retention_ms > now { return true }
+                tombstones.push(Record::new(now, Some(&key_to_vec(group, tp)), None));
+                false
+            });
+        }
+        offsets.retain(|_, committed| !committed.is_empty());
+        self.append(&tombstones)?;
+        Ok(tombstones.len())
+    }
+
+    fn append(&self, records: &[Record]) -> Result<()> {
+        if records.is_empty() { return Ok(()) }
+        let mut log = self.log.lock().unwrap();
+        log.append_batch(records)?;
+        log.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+    use crate::partition::segment::{MaxBytes};
+
+    fn open(dir: &std::path::Path) -> Arc<LogManager> {
+        Arc::new(LogManager::open(vec![dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap())
+    }
+
+    #[test]
+    fn it_commits_and_fetches_offsets() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let events0 = TopicPartitionId::new("events", 0);
+        let events1 = TopicPartitionId::new("events", 1);
+        {
+            let store = OffsetStore::open(logs.clone()).unwrap();
+            assert_eq!(store.fetch("workers", &events0), None);
+            store.commit("workers", &[
+                (events0.clone(), CommittedOffset::new(3, Some("checkpoint"))),
+                (events1.clone(), CommittedOffset::new(7, None)),
+            ]).unwrap();
+            store.commit("workers", &[(events0.clone(), CommittedOffset::new(5, None))]).unwrap();
+            store.commit("other", &[(events0.clone(), CommittedOffset::new(1, None))]).unwrap();
+            assert_eq!(store.fetch("workers", &events0).unwrap().offset, 5, "the latest commit wins");
+
+            let too_large = "X".repeat(MAX_METADATA_BYTES + 1);
+            let err = store.commit("workers", &[(events0.clone(), CommittedOffset::new(9, Some(&too_large)))]);
+            assert!(matches!(err, Err(Error::InvalidRequest(_))));
+            assert_eq!(store.delete_group("other").unwrap(), 1);
+        }
+
+        let store = OffsetStore::open(logs).unwrap();
+        let committed = store.fetch_all("workers");
+        assert_eq!(committed.len(), 2, "replayed from the offsets partition");
+        assert_eq!((committed[0].1.offset, committed[0].1.metadata.as_deref()), (5, None));
+        assert_eq!((committed[1].0.clone(), committed[1].1.offset), (events1, 7));
+        assert!(store.fetch_all("other").is_empty(), "deleted groups stay deleted");
+    }
+
+    #[test]
+    fn it_expires_old_offsets() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let store = OffsetStore::open(logs.clone()).unwrap();
+        let old = CommittedOffset{ commit_timestamp: now_ms() - 10_000, ..CommittedOffset::new(3, None) };
+        store.commit("workers", &[
+            (TopicPartitionId::new("events", 0), old),
+            (TopicPartitionId::new("events", 1), CommittedOffset::new(4, None)),
+        ]).unwrap();
+        assert_eq!(store.expire(5_000).unwrap(), 1);
+        assert_eq!(store.fetch_all("workers").len(), 1);
+        assert_eq!(OffsetStore::open(logs).unwrap().fetch_all("workers").len(), 1);
+    }
+}
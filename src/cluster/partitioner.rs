@@ -0,0 +1,225 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Picks the partition a record goes to. `partitions` is always > 0.
+pub trait Partitioner: Send {
+    fn partition(&mut self, topic: &str, key: Option<&[u8]>, value: &[u8], partitions: u32) -> u32;
+
+    // a producer calls this once the batch it was filling for `partition` is
+    // sent, sticky partitioners use it to move on to another partition
+    fn on_new_batch(&mut self, _topic: &str, _partition: u32, _partitions: u32) {}
+}
+
+// custom partitioners can just be closures
+impl<F> Partitioner for F
+where F: FnMut(Option<&[u8]>, &[u8], u32) -> u32 + Send {
+    fn partition(&mut self, _topic: &str, key: Option<&[u8]>, value: &[u8], partitions: u32) -> u32 {
+        self(key, value, partitions) % partitions
+    }
+}
+
+
+// Kafka's murmur2, so keyed records land on the same partition number as they
+// would with the java client's default partitioner
+pub fn murmur2(data: &[u8]) -> i32 {
+    let seed: u32 = 0x9747_b28c;
+    let m: u32 = 0x5bd1_e995;
+    let r = 24;
+    let length = data.len();
+    let mut h: u32 = seed ^ length as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(m);
+        k ^= k >> r;
+        k = k.wrapping_mul(m);
+        h = h.wrapping_mul(m);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if tail.len() == 3 { h ^= (tail[2] as u32) << 16 }
+    if tail.len() >= 2 { h ^= (tail[1] as u32) << 8 }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(m);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(m);
+    h ^= h >> 15;
+    h as i32
+}
+
+pub fn to_positive(n: i32) -> u32 { (n & 0x7fff_ffff) as u32 }
+
+fn random_partition(partitions: u32) -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    nanos % partitions
+}
+
+
+// Keyed records are hashed with murmur2, records without a key stick to one
+// partition per batch, the same as the java client's default
+pub struct DefaultPartitioner {
+    sticky: StickyPartitioner,
+}
+
+impl DefaultPartitioner {
+    pub fn new() -> DefaultPartitioner {
+        DefaultPartitioner{ sticky: StickyPartitioner::new() }
+    }
+}
+
+impl Default for DefaultPartitioner {
+    fn default() -> DefaultPartitioner { DefaultPartitioner::new() }
+}
+
+impl Partitioner for DefaultPartitioner {
+    fn partition(&mut self, topic: &str, key: Option<&[u8]>, value: &[u8], partitions: u32) -> u32 {
+        match key {
+            Some(key) => to_positive(murmur2(key)) % partitions,
+            None => self.sticky.partition(topic, None, value, partitions),
+        }
+    }
+
+    fn on_new_batch(&mut self, topic: &str, partition: u32, partitions: u32) {
+        self.sticky.on_new_batch(topic, partition, partitions)
+    }
+}
+
+
+// Ignores keys and cycles through the partitions one record at a time
+#[derive(Default)]
+pub struct RoundRobinPartitioner {
+    next: u32,
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> RoundRobinPartitioner { RoundRobinPartitioner{ next: 0 } }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&mut self, _topic: &str, _key: Option<&[u8]>, _value: &[u8], partitions: u32) -> u32 {
+        let partition = self.next % partitions;
+        self.next = self.next.wrapping_add(1);
+        partition
+    }
+}
+
+
+// Ignores keys and fills one partition until a batch is sent (or batch_bytes
+// of values went to it), then moves to a different one
+pub struct StickyPartitioner {
+    current: Option<u32>,
+    batch_bytes: Option<u64>,
+    sent_bytes: u64,
+}
+
+impl StickyPartitioner {
+    pub fn new() -> StickyPartitioner {
+        StickyPartitioner{ current: None, batch_bytes: None, sent_bytes: 0 }
+    }
+
+    pub fn with_batch_bytes(batch_bytes: u64) -> StickyPartitioner {
+        StickyPartitioner{ current: None, batch_bytes: Some(batch_bytes), sent_bytes: 0 }
+    }
+
+    fn next_partition(&mut self, partitions: u32) -> u32 {
+        let mut next = random_partition(partitions);
+        if partitions > 1 && Some(next) == self.current {
+            next = (next + 1) % partitions;
+        }
+        self.current = Some(next);
+        self.sent_bytes = 0;
+        next
+    }
+}
+
+impl Default for StickyPartitioner {
+    fn default() -> StickyPartitioner { StickyPartitioner::new() }
+}
+
+impl Partitioner for StickyPartitioner {
+    fn partition(&mut self, _topic: &str, _key: Option<&[u8]>, value: &[u8], partitions: u32) -> u32 {
+        let full = match self.batch_bytes {
+            Some(max) => self.sent_bytes >= max,
+            None => false,
+        };
+        let partition = match self.current {
+            Some(current) if current < partitions && !full => current,
+            _ => self.next_partition(partitions),
+        };
+        self.sent_bytes += value.len() as u64;
+        partition
+    }
+
+    fn on_new_batch(&mut self, _topic: &str, partition: u32, partitions: u32) {
+        // only move when the batch that completed was the one we were filling
+        if self.current == Some(partition) {
+            self.next_partition(partitions);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_kafka_murmur2() {
+        // test vectors from kafka's UtilsTest
+        assert_eq!(murmur2("21".as_bytes()), -973932308);
+        assert_eq!(murmur2("foobar".as_bytes()), -790332482);
+        assert_eq!(murmur2("a-little-bit-long-string".as_bytes()), -985981536);
+        assert_eq!(murmur2("a-little-bit-longer-string".as_bytes()), -1486304829);
+        assert_eq!(murmur2("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8".as_bytes()), -58897971);
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn it_hashes_keys() {
+        let mut partitioner = DefaultPartitioner::new();
+        let partition = partitioner.partition("topic", Some("foobar".as_bytes()), &[], 12);
+        assert_eq!(partition, to_positive(-790332482) % 12);
+        for _ in 0..10 {
+            assert_eq!(partitioner.partition("topic", Some("foobar".as_bytes()), &[], 12), partition);
+        }
+    }
+
+    #[test]
+    fn it_round_robins() {
+        let mut partitioner = RoundRobinPartitioner::new();
+        let partitions: Vec<u32> = (0..5)
+            .map(|_| partitioner.partition("topic", Some("key".as_bytes()), &[], 3))
+            .collect();
+        assert_eq!(partitions, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn it_sticks_until_new_batch() {
+        let mut partitioner = StickyPartitioner::new();
+        let first = partitioner.partition("topic", None, &[0; 8], 4);
+        assert_eq!(partitioner.partition("topic", None, &[0; 8], 4), first);
+        partitioner.on_new_batch("topic", first, 4);
+        assert_ne!(partitioner.partition("topic", None, &[0; 8], 4), first);
+    }
+
+    #[test]
+    fn it_sticks_until_batch_bytes() {
+        let mut partitioner = StickyPartitioner::with_batch_bytes(16);
+        let first = partitioner.partition("topic", None, &[0; 8], 4);
+        assert_eq!(partitioner.partition("topic", None, &[0; 8], 4), first);
+        assert_ne!(partitioner.partition("topic", None, &[0; 8], 4), first, "16 bytes sent, moves on");
+    }
+
+    #[test]
+    fn it_uses_closures_as_custom_partitioners() {
+        let mut partitioner = |_key: Option<&[u8]>, value: &[u8], _partitions: u32| value.len() as u32;
+        assert_eq!(Partitioner::partition(&mut partitioner, "topic", None, &[0; 5], 3), 2);
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Result};
+use crate::cluster::Broker;
+
+
+// Where the replicas of a new topic's partitions go, the way kafka assigns
+// them. Each partition's first replica is its preferred leader, and those
+// walk round the brokers from a random start so leadership spreads evenly.
+// The followers come after the leader at a shift that grows every time
+// round, so the partitions one broker leads don't all follow to the same
+// brokers. When the brokers have racks the list walked alternates between
+// racks, and a partition's replicas take as many racks as there are before
+// any rack gets a second one. Brokers either all have a rack or none does
+pub fn assign_replicas(brokers: &[Broker], partitions: u32, replication_factor: u32) -> Result<Vec<Vec<u32>>> {
+    let start = random_index(brokers.len());
+    assign_replicas_from(brokers, partitions, replication_factor, start, start)
+}
+
+fn assign_replicas_from(
+    brokers: &[Broker],
+    partitions: u32,
+    replication_factor: u32,
+    start: usize,
+    shift: usize,
+) -> Result<Vec<Vec<u32>>> {
+    if partitions == 0 {
+        return Err(Error::InvalidConfig(String::from("a topic needs at least one partition")))
+    }
+    if replication_factor == 0 {
+        return Err(Error::InvalidConfig(String::from("a partition needs at least one replica")))
+    }
+    if replication_factor as usize > brokers.len() {
+        let msg = format!("replication factor {} is larger than the {} available brokers", replication_factor, brokers.len());
+        return Err(Error::InvalidConfig(msg))
+    }
+    let racks: BTreeMap<u32, &str> = brokers.iter().filter_map(|b| b.rack().map(|r| (b.id(), r))).collect();
+    if !racks.is_empty() && racks.len() < brokers.len() {
+        return Err(Error::InvalidConfig(String::from("either every broker has a rack or none does")))
+    }
+
+    let arranged = match racks.is_empty() {
+        true => {
+            let mut ids: Vec<u32> = brokers.iter().map(|b| b.id()).collect();
+            ids.sort_unstable();
+            ids
+        },
+        false => rack_alternated(&racks),
+    };
+    let num_racks = racks.values().collect::<BTreeSet<_>>().len().max(1);
+    let n = arranged.len();
+    let (start, mut shift) = (start % n, shift % n);
+    let mut assignment = Vec::with_capacity(partitions as usize);
+    for partition in 0..partitions as usize {
+        if partition > 0 && partition % n == 0 { shift += 1 }
+        let first = (partition + start) % n;
+        let mut replicas = vec![arranged[first]];
+        let mut covered: BTreeSet<&str> = racks.get(&arranged[first]).into_iter().cloned().collect();
+        let mut k = 0;
+        while replicas.len() < replication_factor as usize {
+            // the next broker along, passed over while its rack has a
+            // replica and another rack doesn't yet
+            let broker = arranged[follower_index(first, shift * num_racks, k, n)];
+            k += 1;
+            let rack = racks.get(&broker).cloned();
+            if replicas.contains(&broker) { continue }
+            if let Some(rack) = rack {
+                if covered.contains(rack) && covered.len() < num_racks { continue }
+                covered.insert(rack);
+            }
+            replicas.push(broker);
+        }
+        assignment.push(replicas);
+    }
+    Ok(assignment)
+}
+
+fn follower_index(first: usize, shift: usize, follower: usize, brokers: usize) -> usize {
+    // every broker but the leader's, one per follower from shift on
+    (first + 1 + (shift + follower) % (brokers - 1)) % brokers
+}
+
+fn rack_alternated(racks: &BTreeMap<u32, &str>) -> Vec<u32> {
+    // the first broker of each rack, then the second of each, and so on
+    let mut by_rack: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+    for (id, rack) in racks {
+        by_rack.entry(rack).or_default().push(*id);
+    }
+    let deepest = by_rack.values().map(|ids| ids.len()).max().unwrap_or(0);
+    (0..deepest).flat_map(|i| by_rack.values().filter_map(move |ids| ids.get(i).cloned())).collect()
+}
+
+fn random_index(len: usize) -> usize {
+    // no rand dependency, the clock's nanos spread topics over the brokers well enough
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as usize).unwrap_or(0);
+    nanos % len.max(1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brokers(racks: &[&str]) -> Vec<Broker> {
+        racks.iter().enumerate().map(|(i, rack)| match *rack {
+            "" => Broker::new(i as u32, "127.0.0.1", 9092),
+            rack => Broker::new(i as u32, "127.0.0.1", 9092).with_rack(rack),
+        }).collect()
+    }
+
+    #[test]
+    fn it_spreads_leaders_and_replicas_evenly() {
+        let assignment = assign_replicas_from(&brokers(&["", "", "", "", ""]), 10, 3, 0, 0).unwrap();
+        assert_eq!(assignment[..6].to_vec(), vec![
+            vec![0, 1, 2], vec![1, 2, 3], vec![2, 3, 4], vec![3, 4, 0], vec![4, 0, 1],
+            // second time round the followers shift by one
+            vec![0, 2, 3],
+        ]);
+        for id in 0..5 {
+            assert_eq!(assignment.iter().filter(|r| r[0] == id).count(), 2, "broker {} leads two", id);
+            assert_eq!(assignment.iter().filter(|r| r.contains(&id)).count(), 6, "broker {} has six replicas", id);
+        }
+        for replicas in &assignment {
+            assert_eq!(replicas.iter().collect::<BTreeSet<_>>().len(), 3);
+        }
+
+        let random = assign_replicas(&brokers(&["", "", ""]), 3, 3).unwrap();
+        assert_eq!(random.iter().map(|r| r[0]).collect::<BTreeSet<_>>().len(), 3);
+    }
+
+    #[test]
+    fn it_places_replicas_on_distinct_racks() {
+        // racks a, a, b, b, c: alternated 0 (a) 2 (b) 4 (c) 1 (a) 3 (b)
+        let racked = brokers(&["a", "a", "b", "b", "c"]);
+        let rack_of = |id: u32| racked[id as usize].rack().unwrap();
+        let assignment = assign_replicas_from(&racked, 20, 3, 0, 0).unwrap();
+        assert_eq!(assignment[0], vec![0, 2, 4]);
+        for replicas in &assignment {
+            let racks: BTreeSet<&str> = replicas.iter().map(|id| rack_of(*id)).collect();
+            assert_eq!(racks.len(), 3, "{:?} spans every rack", replicas);
+        }
+        for id in 0..5 {
+            assert_eq!(assignment.iter().filter(|r| r[0] == id).count(), 4);
+        }
+
+        // more replicas than racks, every rack first then a second on one
+        let assignment = assign_replicas_from(&brokers(&["a", "a", "b", "b"]), 4, 3, 0, 0).unwrap();
+        for replicas in &assignment {
+            assert_eq!(replicas.iter().map(|id| id / 2).collect::<BTreeSet<_>>().len(), 2);
+            assert_eq!(replicas.iter().collect::<BTreeSet<_>>().len(), 3);
+        }
+    }
+
+    #[test]
+    fn it_refuses_impossible_assignments() {
+        assert!(matches!(assign_replicas(&brokers(&["", ""]), 1, 3), Err(Error::InvalidConfig(_))));
+        assert!(matches!(assign_replicas(&brokers(&["", ""]), 0, 1), Err(Error::InvalidConfig(_))));
+        assert!(matches!(assign_replicas(&brokers(&["", ""]), 1, 0), Err(Error::InvalidConfig(_))));
+        assert!(matches!(assign_replicas(&brokers(&["a", ""]), 1, 1), Err(Error::InvalidConfig(_))));
+        assert_eq!(assign_replicas(&brokers(&["a"]), 2, 1).unwrap(), vec![vec![0], vec![0]]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::{Mutex};
+use std::time::{Instant};
+
+// how long a quiet spell the quota saves up for, bytes at the rate for this long can go at once
+const BURST_SECS: f64 = 1.0;
+
+
+struct Bucket {
+    rate: Option<u64>,
+    // bytes that may go now, below zero when a fetch overshot and is paid off first
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + earned).min(rate as f64 * BURST_SECS);
+        self.refilled = now;
+    }
+}
+
+// The bytes a second a broker may copy to replicas that are catching up,
+// like kafka's leader.replication.throttled.rate, so moving a partition
+// doesn't starve producers and consumers of disk and network. A fetch takes
+// what's there to take, the first message always goes so a fetch progresses
+pub struct ReplicationQuota {
+    bucket: Mutex<Bucket>,
+}
+
+impl ReplicationQuota {
+    pub fn new(rate: Option<u64>) -> ReplicationQuota {
+        let tokens = rate.map_or(0.0, |r| r as f64 * BURST_SECS);
+        ReplicationQuota{ bucket: Mutex::new(Bucket{ rate, tokens, refilled: Instant::now() }) }
+    }
+
+    pub fn rate(&self) -> Option<u64> { self.bucket.lock().unwrap().rate }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        *bucket = Bucket{ rate, tokens: rate.map_or(0.0, |r| r as f64 * BURST_SECS), refilled: Instant::now() };
+    }
+
+    pub fn allowance(&self) -> Option<u64> {
+        // None when unthrottled, otherwise the bytes a fetch may take now
+        let mut bucket = self.bucket.lock().unwrap();
+        let rate = bucket.rate?;
+        bucket.refill(rate);
+        Some(bucket.tokens.max(0.0) as u64)
+    }
+
+    pub fn record(&self, bytes: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(rate) = bucket.rate {
+            bucket.refill(rate);
+            bucket.tokens -= bytes as f64;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn it_limits_the_replication_rate() {
+        let unthrottled = ReplicationQuota::new(None);
+        unthrottled.record(1 << 30);
+        assert_eq!(unthrottled.allowance(), None);
+
+        let quota = ReplicationQuota::new(Some(1000));
+        assert_eq!(quota.allowance(), Some(1000));
+        // overshooting is paid off before anything else goes
+        quota.record(1500);
+        assert_eq!(quota.allowance(), Some(0));
+        thread::sleep(Duration::from_millis(600));
+        let allowance = quota.allowance().unwrap();
+        assert!(allowance > 0 && allowance < 200, "{} bytes after 0.6s", allowance);
+
+        quota.set_rate(Some(10));
+        assert_eq!(quota.allowance(), Some(10));
+        quota.set_rate(None);
+        assert_eq!(quota.allowance(), None);
+    }
+}
@@ -0,0 +1,1104 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::sync::atomic::{Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Offset, Result};
+use crate::client::{Connection};
+use crate::cluster::manager::{BackgroundTasks};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::Partition;
+use crate::partition::config::{PartitionConfig};
+use crate::partition::epoch::{UNDEFINED_EPOCH};
+use crate::partition::message::{Message};
+use crate::partition::record::{Record};
+use crate::partition::segment::{now_ms};
+
+pub const QUORUM_STATE_FILE: &str = "quorum-state";
+// the controllers' metadata log, in the first log dir next to the partitions
+pub const METADATA_LOG_DIR: &str = "__cluster_metadata-0";
+const SNAPSHOT_SUFFIX: &str = ".checkpoint";
+
+
+// What the quorum agrees on, fed the log's commands in order
+pub trait StateMachine: Send {
+    fn apply(&mut self, offset: Offset, command: &[u8]) -> Result<()>;
+    fn snapshot(&self) -> Result<Vec<u8>>;
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoteRequest {
+    pub term: i32,
+    pub candidate: u32,
+    // how far the candidate's log goes, voters only back one at least as up to date
+    pub last_term: i32,
+    pub log_end_offset: Offset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoteResponse {
+    pub term: i32,
+    pub granted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: i32,
+    pub offset: Offset,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendRequest {
+    pub term: i32,
+    pub leader: u32,
+    // the entries follow on from prev_log_end, whose last entry the
+    // follower must have under prev_term
+    pub prev_log_end: Offset,
+    pub prev_term: i32,
+    pub entries: Vec<Entry>,
+    pub commit_offset: Offset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppendResponse {
+    pub term: i32,
+    pub success: bool,
+    // where the follower's log ends, the leader backs up to it on a mismatch
+    pub log_end_offset: Offset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotRequest {
+    pub term: i32,
+    pub leader: u32,
+    pub snapshot: SnapshotId,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotId {
+    // the snapshot covers every entry before end_offset, the last of them written under term
+    pub end_offset: Offset,
+    pub term: i32,
+}
+
+impl SnapshotId {
+    fn file_name(&self) -> String { format!("{:0>20}-{:0>10}{}", self.end_offset, self.term, SNAPSHOT_SUFFIX) }
+
+    fn parse(file_name: &str) -> Option<SnapshotId> {
+        let stem = file_name.strip_suffix(SNAPSHOT_SUFFIX)?;
+        let (end_offset, term) = stem.split_once('-')?;
+        Some(SnapshotId{ end_offset: end_offset.parse().ok()?, term: term.parse().ok()? })
+    }
+}
+
+// How one quorum member reaches another
+pub trait RaftTransport: Send + Sync {
+    fn vote(&self, to: u32, req: &VoteRequest) -> Result<VoteResponse>;
+    fn append(&self, to: u32, req: &AppendRequest) -> Result<AppendResponse>;
+    fn install_snapshot(&self, to: u32, req: &SnapshotRequest) -> Result<i32>;
+}
+
+// The receiving end of a transport
+pub trait RaftPeer: Send + Sync {
+    fn handle_vote(&self, req: &VoteRequest) -> Result<VoteResponse>;
+    fn handle_append(&self, req: &AppendRequest) -> Result<AppendResponse>;
+    fn handle_snapshot(&self, req: &SnapshotRequest) -> Result<i32>;
+}
+
+
+// Hands requests straight to the other nodes of the same process. Nodes
+// can be cut off to play out failures
+#[derive(Default)]
+pub struct InMemoryTransport {
+    nodes: RwLock<BTreeMap<u32, Weak<dyn RaftPeer>>>,
+    disconnected: RwLock<BTreeSet<u32>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> InMemoryTransport { InMemoryTransport::default() }
+
+    pub fn register(&self, id: u32, node: Weak<dyn RaftPeer>) {
+        self.nodes.write().unwrap().insert(id, node);
+    }
+
+    pub fn disconnect(&self, id: u32) { self.disconnected.write().unwrap().insert(id); }
+    pub fn reconnect(&self, id: u32) { self.disconnected.write().unwrap().remove(&id); }
+
+    fn peer(&self, from: u32, to: u32) -> Result<Arc<dyn RaftPeer>> {
+        let disconnected = self.disconnected.read().unwrap();
+        let node = match disconnected.contains(&from) || disconnected.contains(&to) {
+            true => None,
+            false => self.nodes.read().unwrap().get(&to).and_then(|n| n.upgrade()),
+        };
+        node.ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionRefused, format!("node {} is unreachable", to)).into())
+    }
+}
+
+impl RaftTransport for InMemoryTransport {
+    fn vote(&self, to: u32, req: &VoteRequest) -> Result<VoteResponse> {
+        self.peer(req.candidate, to)?.handle_vote(req)
+    }
+
+    fn append(&self, to: u32, req: &AppendRequest) -> Result<AppendResponse> {
+        self.peer(req.leader, to)?.handle_append(req)
+    }
+
+    fn install_snapshot(&self, to: u32, req: &SnapshotRequest) -> Result<i32> {
+        self.peer(req.leader, to)?.handle_snapshot(req)
+    }
+}
+
+
+// Reaches the other members over the native protocol, through a connection
+// to each kept open between requests. A member that doesn't answer within
+// timeout is given up on until the next request
+pub struct TcpTransport {
+    peers: BTreeMap<u32, (String, Mutex<Option<Connection>>)>,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn new(peers: &[(u32, String)], timeout: Duration) -> TcpTransport {
+        let peers = peers.iter().map(|(id, addr)| (*id, (addr.clone(), Mutex::new(None)))).collect();
+        TcpTransport{ peers, timeout }
+    }
+
+    fn call<T, F>(&self, to: u32, f: F) -> Result<T> where F: FnOnce(&mut Connection) -> Result<T> {
+        let (addr, conn) = self.peers.get(&to)
+            .ok_or_else(|| Error::InvalidConfig(format!("node {} isn't a voter", to)))?;
+        let mut conn = conn.lock().unwrap();
+        let connected = match conn.as_mut() {
+            Some(connected) => connected,
+            None => conn.insert(Connection::connect_timeout(addr, self.timeout)?),
+        };
+        let res = f(connected);
+        if res.is_err() { *conn = None }
+        res
+    }
+}
+
+impl RaftTransport for TcpTransport {
+    fn vote(&self, to: u32, req: &VoteRequest) -> Result<VoteResponse> {
+        self.call(to, |conn| conn.raft_vote(req))
+    }
+
+    fn append(&self, to: u32, req: &AppendRequest) -> Result<AppendResponse> {
+        self.call(to, |conn| conn.raft_append(req))
+    }
+
+    fn install_snapshot(&self, to: u32, req: &SnapshotRequest) -> Result<i32> {
+        self.call(to, |conn| conn.raft_install_snapshot(req))
+    }
+}
+
+pub fn encode_vote_request(enc: &mut Encoder, req: &VoteRequest) {
+    enc.i32(req.term);
+    enc.i32(req.candidate as i32);
+    enc.i32(req.last_term);
+    enc.i64(req.log_end_offset as i64);
+}
+
+pub fn decode_vote_request(dec: &mut Decoder) -> Result<VoteRequest> {
+    Ok(VoteRequest{ term: dec.i32()?, candidate: node_id(dec)?, last_term: dec.i32()?, log_end_offset: offset(dec)? })
+}
+
+pub fn encode_vote_response(enc: &mut Encoder, res: &VoteResponse) {
+    enc.i32(res.term);
+    enc.boolean(res.granted);
+}
+
+pub fn decode_vote_response(dec: &mut Decoder) -> Result<VoteResponse> {
+    Ok(VoteResponse{ term: dec.i32()?, granted: dec.boolean()? })
+}
+
+pub fn encode_append_request(enc: &mut Encoder, req: &AppendRequest) {
+    enc.i32(req.term);
+    enc.i32(req.leader as i32);
+    enc.i64(req.prev_log_end as i64);
+    enc.i32(req.prev_term);
+    enc.array_len(req.entries.len());
+    for entry in &req.entries {
+        enc.i32(entry.term);
+        enc.i64(entry.offset as i64);
+        enc.bytes(Some(&entry.payload));
+    }
+    enc.i64(req.commit_offset as i64);
+}
+
+pub fn decode_append_request(dec: &mut Decoder) -> Result<AppendRequest> {
+    let (term, leader, prev_log_end, prev_term) = (dec.i32()?, node_id(dec)?, offset(dec)?, dec.i32()?);
+    let mut entries = vec![];
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        let (term, offset) = (dec.i32()?, offset(dec)?);
+        let payload = dec.bytes()?.unwrap_or_default().to_vec();
+        entries.push(Entry{ term, offset, payload });
+    }
+    Ok(AppendRequest{ term, leader, prev_log_end, prev_term, entries, commit_offset: offset(dec)? })
+}
+
+pub fn encode_append_response(enc: &mut Encoder, res: &AppendResponse) {
+    enc.i32(res.term);
+    enc.boolean(res.success);
+    enc.i64(res.log_end_offset as i64);
+}
+
+pub fn decode_append_response(dec: &mut Decoder) -> Result<AppendResponse> {
+    Ok(AppendResponse{ term: dec.i32()?, success: dec.boolean()?, log_end_offset: offset(dec)? })
+}
+
+pub fn encode_snapshot_request(enc: &mut Encoder, req: &SnapshotRequest) {
+    enc.i32(req.term);
+    enc.i32(req.leader as i32);
+    enc.i64(req.snapshot.end_offset as i64);
+    enc.i32(req.snapshot.term);
+    enc.bytes(Some(&req.data));
+}
+
+pub fn decode_snapshot_request(dec: &mut Decoder) -> Result<SnapshotRequest> {
+    let (term, leader) = (dec.i32()?, node_id(dec)?);
+    let snapshot = SnapshotId{ end_offset: offset(dec)?, term: dec.i32()? };
+    let data = dec.bytes()?.unwrap_or_default().to_vec();
+    Ok(SnapshotRequest{ term, leader, snapshot, data })
+}
+
+fn node_id(dec: &mut Decoder) -> Result<u32> {
+    match dec.i32()? {
+        id if id >= 0 => Ok(id as u32),
+        id => Err(Error::InvalidRequest(format!("raft node {}", id))),
+    }
+}
+
+fn offset(dec: &mut Decoder) -> Result<Offset> {
+    match dec.i64()? {
+        offset if offset >= 0 => Ok(offset as Offset),
+        offset => Err(Error::InvalidRequest(format!("raft offset {}", offset))),
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    // controller.quorum.election.timeout.ms, a follower that hears nothing
+    // from a leader for between one and two of these stands for election
+    pub election_timeout: Duration,
+    // how often the leader sends appends, empty ones keep followers from electing
+    pub heartbeat_interval: Duration,
+    pub max_append_bytes: u64,
+    // how many applied entries past the last snapshot it takes to write another
+    pub snapshot_entries: u64,
+    // the log's segments, a snapshot deletes those it covers whole
+    pub log: PartitionConfig,
+}
+
+impl Default for RaftConfig {
+    fn default() -> RaftConfig {
+        RaftConfig{
+            election_timeout: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_millis(100),
+            max_append_bytes: 1024 * 1024,
+            snapshot_entries: 20_000,
+            log: PartitionConfig::default(),
+        }
+    }
+}
+
+struct Progress {
+    // where the next append to the follower starts
+    next: Offset,
+    // how much of the leader's log the follower is known to have
+    matched: Offset,
+}
+
+struct RaftState<M> {
+    role: Role,
+    term: i32,
+    voted_for: Option<u32>,
+    leader: Option<u32>,
+    votes: BTreeSet<u32>,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    // the entries' terms are the log's leader epochs, the commit offset its high watermark
+    log: Partition,
+    snapshot: Option<SnapshotId>,
+    applied: Offset,
+    machine: M,
+    progress: BTreeMap<u32, Progress>,
+}
+
+impl<M: StateMachine> RaftState<M> {
+    fn log_end_offset(&self) -> Offset { self.log.log_end_offset() }
+    fn commit_offset(&self) -> Offset { self.log.high_watermark() }
+
+    fn term_at(&self, offset: Offset) -> Option<i32> {
+        if let Some(snapshot) = self.snapshot {
+            if offset + 1 == snapshot.end_offset { return Some(snapshot.term) }
+        }
+        if offset < self.log.log_start_offset() || offset >= self.log_end_offset() { return None }
+        let epochs = self.log.leader_epochs().entries();
+        epochs.iter().rev().find(|e| e.start_offset <= offset).map(|e| e.epoch)
+    }
+
+    fn last_term(&self) -> i32 {
+        match self.log_end_offset() {
+            0 => UNDEFINED_EPOCH,
+            end => self.term_at(end - 1).unwrap_or(UNDEFINED_EPOCH),
+        }
+    }
+
+    fn snapshot_end(&self) -> Offset { self.snapshot.map_or(0, |s| s.end_offset) }
+}
+
+
+// A member of a Raft quorum whose log is a latka partition. The leader
+// takes commands, replicates them to the followers and commits them once a
+// majority has them; every member applies committed commands to its state
+// machine in order. Now and then the state machine is written to a
+// snapshot and the log before it deleted, followers too far behind are sent
+// the snapshot instead.
+pub struct RaftNode<M> {
+    id: u32,
+    voters: Vec<u32>,
+    dir: PathBuf,
+    config: RaftConfig,
+    transport: Arc<dyn RaftTransport>,
+    state: Mutex<RaftState<M>>,
+    // woken whenever the commit offset moves
+    committed: Condvar,
+}
+
+impl<M: StateMachine> RaftNode<M> {
+    pub fn open(
+        id: u32,
+        voters: &[u32],
+        dir: &Path,
+        mut machine: M,
+        transport: Arc<dyn RaftTransport>,
+        config: RaftConfig,
+    ) -> Result<RaftNode<M>> {
+        if !voters.contains(&id) {
+            return Err(Error::InvalidConfig(format!("node {} isn't one of the voters {:?}", id, voters)))
+        }
+        let mut log = match dir.is_dir() {
+            true => Partition::load(dir, config.log.clone())?,
+            false => {
+                let name = dir.file_name().map(|n| n.to_string_lossy().to_string())
+                    .ok_or_else(|| Error::InvalidConfig(format!("{} can't hold a log", dir.display())))?;
+                let mut parent = dir.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+                Partition::create(name, &mut parent, config.log.clone())?
+            },
+        };
+        let snapshot = latest_snapshot(dir)?;
+        if let Some(snapshot) = snapshot {
+            machine.restore(&fs::read(dir.join(snapshot.file_name()))?)?;
+            if log.log_end_offset() < snapshot.end_offset { log.truncate_fully_and_start_at(snapshot.end_offset)? }
+        }
+        // nothing past the snapshot counts as committed until a leader says so
+        let applied = snapshot.map_or(0, |s| s.end_offset);
+        log.set_high_watermark(Some(applied));
+        let (term, voted_for) = read_quorum_state(dir)?;
+        let now = Instant::now();
+        // a lone voter has no leader to wait to hear from
+        let election_deadline = match voters.len() {
+            1 => now,
+            _ => now + election_timeout(&config),
+        };
+        let state = RaftState{
+            role: Role::Follower,
+            term,
+            voted_for,
+            leader: None,
+            votes: BTreeSet::new(),
+            election_deadline,
+            last_heartbeat: now,
+            log,
+            snapshot,
+            applied,
+            machine,
+            progress: BTreeMap::new(),
+        };
+        Ok(RaftNode{
+            id,
+            voters: voters.to_vec(),
+            dir: dir.to_path_buf(),
+            config,
+            transport,
+            state: Mutex::new(state),
+            committed: Condvar::new(),
+        })
+    }
+
+    pub fn id(&self) -> u32 { self.id }
+    pub fn role(&self) -> Role { self.state.lock().unwrap().role }
+    pub fn term(&self) -> i32 { self.state.lock().unwrap().term }
+    pub fn leader(&self) -> Option<u32> { self.state.lock().unwrap().leader }
+    pub fn log_end_offset(&self) -> Offset { self.state.lock().unwrap().log_end_offset() }
+    pub fn commit_offset(&self) -> Offset { self.state.lock().unwrap().commit_offset() }
+    pub fn applied_offset(&self) -> Offset { self.state.lock().unwrap().applied }
+    pub fn log_start_offset(&self) -> Offset { self.state.lock().unwrap().log.log_start_offset() }
+    pub fn snapshot_id(&self) -> Option<SnapshotId> { self.state.lock().unwrap().snapshot }
+
+    pub fn leader_term(&self) -> Option<i32> {
+        // the term this node leads in, once the entry it started the term
+        // with is applied. Then the state machine has all the earlier
+        // leaders committed
+        let state = self.state.lock().unwrap();
+        match state.role {
+            Role::Leader if state.applied > 0 && state.term_at(state.applied - 1) == Some(state.term) => Some(state.term),
+            _ => None,
+        }
+    }
+
+    pub fn read<T, F: FnOnce(&M) -> T>(&self, f: F) -> T {
+        // the state machine as of the applied offset
+        f(&self.state.lock().unwrap().machine)
+    }
+
+    pub fn propose(&self, command: &[u8]) -> Result<Offset> {
+        // appends a command to the leader's log and returns its offset, it
+        // isn't committed until a majority has it
+        let offset = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(Error::NotLeader(format!("metadata quorum, node {} follows {:?}", self.id, state.leader)))
+            }
+            let offset = state.log.append(&Record::new(now_ms(), None, Some(command)).to_vec()?)? - 1;
+            self.advance_commit(&mut state);
+            offset
+        };
+        self.replicate();
+        Ok(offset)
+    }
+
+    pub fn propose_and_wait(&self, command: &[u8], timeout: Duration) -> Result<Offset> {
+        // until the command is applied here. It may have been lost to a new
+        // leader in the meantime, then it's NotLeader and up to the caller
+        let term = self.term();
+        let offset = self.propose(command)?;
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.applied <= offset {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(format!("metadata offset {} isn't committed", offset)))
+            }
+            state = self.committed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        match state.term_at(offset) {
+            Some(t) if t != term => Err(Error::NotLeader(format!("metadata offset {} was overwritten", offset))),
+            _ => Ok(offset),
+        }
+    }
+
+    pub fn tick(&self) {
+        // the leader's heartbeats, everybody else's election timeout
+        let (role, due) = {
+            let state = self.state.lock().unwrap();
+            let now = Instant::now();
+            match state.role {
+                Role::Leader => (Role::Leader, now >= state.last_heartbeat + self.config.heartbeat_interval),
+                role => (role, now >= state.election_deadline),
+            }
+        };
+        match (role, due) {
+            (Role::Leader, true) => self.replicate(),
+            (_, true) => self.start_election(),
+            _ => (),
+        }
+    }
+
+    pub fn start(node: Arc<RaftNode<M>>) -> BackgroundTasks where M: 'static {
+        BackgroundTasks::spawn(&format!("latka-raft-{}", node.id), move |running| {
+            let interval = node.config.heartbeat_interval / 2;
+            while running.load(Ordering::SeqCst) {
+                node.tick();
+                thread::park_timeout(interval);
+            }
+        })
+    }
+
+    fn start_election(&self) {
+        let req = {
+            let mut state = self.state.lock().unwrap();
+            state.term += 1;
+            state.role = Role::Candidate;
+            state.voted_for = Some(self.id);
+            state.leader = None;
+            state.votes = std::iter::once(self.id).collect();
+            state.election_deadline = Instant::now() + election_timeout(&self.config);
+            if let Err(e) = write_quorum_state(&self.dir, state.term, state.voted_for) {
+                eprintln!("latka: raft node {} can't store its vote: {}", self.id, e);
+                return
+            }
+            if self.voters.len() == 1 {
+                self.become_leader(&mut state);
+                None
+            } else {
+                Some(VoteRequest{ term: state.term, candidate: self.id, last_term: state.last_term(), log_end_offset: state.log_end_offset() })
+            }
+        };
+        let req = match req {
+            Some(req) => req,
+            None => return,
+        };
+        for peer in self.voters.iter().filter(|id| **id != self.id) {
+            let res = match self.transport.vote(*peer, &req) {
+                Ok(res) => res,
+                Err(_) => continue,
+            };
+            let mut state = self.state.lock().unwrap();
+            if res.term > state.term {
+                self.step_down(&mut state, res.term);
+                return
+            }
+            if state.role != Role::Candidate || state.term != req.term || !res.granted { continue }
+            state.votes.insert(*peer);
+            if state.votes.len() > self.voters.len() / 2 {
+                self.become_leader(&mut state);
+                break
+            }
+        }
+        if self.role() == Role::Leader { self.replicate() }
+    }
+
+    fn become_leader(&self, state: &mut RaftState<M>) {
+        // a new term starts with an entry of its own, the entries of earlier
+        // terms are only committed along with it
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        let log_end = state.log_end_offset();
+        state.progress = self.voters.iter()
+            .filter(|id| **id != self.id)
+            .map(|id| (*id, Progress{ next: log_end, matched: 0 }))
+            .collect();
+        let term = state.term;
+        let res = state.log.assign_leader_epoch(term, log_end)
+            .and_then(|_| Record::new(now_ms(), None, None).to_vec())
+            .and_then(|noop| state.log.append(&noop));
+        if let Err(e) = res {
+            eprintln!("latka: raft node {} can't start its term: {}", self.id, e);
+        }
+        self.advance_commit(state);
+    }
+
+    fn step_down(&self, state: &mut RaftState<M>, term: i32) {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+            if let Err(e) = write_quorum_state(&self.dir, term, None) {
+                eprintln!("latka: raft node {} can't store its term: {}", self.id, e);
+            }
+        }
+        if state.role != Role::Follower { state.election_deadline = Instant::now() + election_timeout(&self.config) }
+        state.role = Role::Follower;
+        state.progress.clear();
+    }
+
+    fn replicate(&self) {
+        // one round of appends, or snapshots for followers that need
+        // entries the log no longer has. The lock isn't held while sending
+        let requests: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader { return }
+            state.last_heartbeat = Instant::now();
+            let peers: Vec<(u32, Offset)> = state.progress.iter().map(|(id, p)| (*id, p.next)).collect();
+            peers.into_iter().map(|(id, next)| (id, self.request_for(&state, next))).collect()
+        };
+        for (peer, req) in requests {
+            match req {
+                Ok(Ok(req)) => {
+                    let res = self.transport.append(peer, &req);
+                    let mut state = self.state.lock().unwrap();
+                    match res {
+                        Ok(res) => self.appended(&mut state, peer, &req, res),
+                        Err(_) => continue,
+                    }
+                },
+                Ok(Err(req)) => {
+                    let res = self.transport.install_snapshot(peer, &req);
+                    let mut state = self.state.lock().unwrap();
+                    match res {
+                        Ok(term) if term > state.term => self.step_down(&mut state, term),
+                        Ok(_) if state.role == Role::Leader && state.term == req.term => {
+                            if let Some(progress) = state.progress.get_mut(&peer) {
+                                progress.matched = progress.matched.max(req.snapshot.end_offset);
+                                progress.next = progress.matched;
+                            }
+                        },
+                        _ => continue,
+                    }
+                },
+                Err(e) => eprintln!("latka: raft node {} can't replicate to {}: {}", self.id, peer, e),
+            }
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn request_for(&self, state: &RaftState<M>, next: Offset) -> Result<std::result::Result<AppendRequest, SnapshotRequest>> {
+        let log_start = state.log.log_start_offset();
+        if next < log_start {
+            if let Some(snapshot) = state.snapshot {
+                let data = fs::read(self.dir.join(snapshot.file_name()))?;
+                return Ok(Err(SnapshotRequest{ term: state.term, leader: self.id, snapshot, data }))
+            }
+        }
+        let next = next.max(log_start);
+        let prev_term = match next {
+            0 => UNDEFINED_EPOCH,
+            next => state.term_at(next - 1).unwrap_or(UNDEFINED_EPOCH),
+        };
+        let mut entries = vec![];
+        if next < state.log_end_offset() {
+            for message in state.log.read(next, self.config.max_append_bytes)? {
+                let term = state.term_at(message.offset).unwrap_or(UNDEFINED_EPOCH);
+                entries.push(Entry{ term, offset: message.offset, payload: message.payload });
+            }
+        }
+        Ok(Ok(AppendRequest{
+            term: state.term,
+            leader: self.id,
+            prev_log_end: next,
+            prev_term,
+            entries,
+            commit_offset: state.commit_offset(),
+        }))
+    }
+
+    fn appended(&self, state: &mut RaftState<M>, peer: u32, req: &AppendRequest, res: AppendResponse) {
+        if res.term > state.term {
+            self.step_down(state, res.term);
+            return
+        }
+        if state.role != Role::Leader || state.term != req.term { return }
+        let progress = match state.progress.get_mut(&peer) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if res.success {
+            let end = req.entries.last().map_or(req.prev_log_end, |e| e.offset + 1);
+            progress.matched = progress.matched.max(end);
+            progress.next = progress.matched;
+            self.advance_commit(state);
+        } else {
+            // back up one at a time, or straight to where a short log ends
+            progress.next = req.prev_log_end.saturating_sub(1).min(res.log_end_offset);
+        }
+    }
+
+    fn advance_commit(&self, state: &mut RaftState<M>) {
+        // the offset a majority has, if the entry before it is from this term
+        if state.role != Role::Leader { return }
+        let mut matched: Vec<Offset> = state.progress.values().map(|p| p.matched).collect();
+        matched.push(state.log_end_offset());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = matched[self.voters.len() / 2];
+        if majority > state.commit_offset() && state.term_at(majority - 1) == Some(state.term) {
+            state.log.set_high_watermark(Some(majority));
+            self.apply(state);
+        }
+    }
+
+    fn apply(&self, state: &mut RaftState<M>) {
+        // commands the state machine refuses are refused the same way by
+        // every member, the log carries on past them
+        while state.applied < state.commit_offset() {
+            let messages = match state.log.read(state.applied, self.config.max_append_bytes) {
+                Ok(messages) if !messages.is_empty() => messages,
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("latka: raft node {} can't read offset {}: {}", self.id, state.applied, e);
+                    break
+                },
+            };
+            let commit = state.commit_offset();
+            for message in messages.into_iter().take_while(|m| m.offset < commit) {
+                match Record::from_slice(&message.payload) {
+                    Ok(Record{ value: Some(command), .. }) => {
+                        if let Err(e) = state.machine.apply(message.offset, &command) {
+                            eprintln!("latka: raft node {} skipped offset {}: {}", self.id, message.offset, e);
+                        }
+                    },
+                    Ok(_) => (),
+                    Err(e) => eprintln!("latka: raft node {} skipped offset {}: {}", self.id, message.offset, e),
+                }
+                state.applied = message.offset + 1;
+            }
+        }
+        self.committed.notify_all();
+        if state.applied >= state.snapshot_end() + self.config.snapshot_entries {
+            if let Err(e) = self.take_snapshot(state) {
+                eprintln!("latka: raft node {} can't take a snapshot: {}", self.id, e);
+            }
+        }
+    }
+
+    fn take_snapshot(&self, state: &mut RaftState<M>) -> Result<()> {
+        let end_offset = state.applied;
+        let term = match end_offset {
+            0 => return Ok(()),
+            end => state.term_at(end - 1).unwrap_or(UNDEFINED_EPOCH),
+        };
+        let snapshot = SnapshotId{ end_offset, term };
+        write_snapshot(&self.dir, snapshot, &state.machine.snapshot()?)?;
+        state.snapshot = Some(snapshot);
+        state.log.delete_segments_before(end_offset)?;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Result<Option<SnapshotId>> {
+        // a snapshot of everything applied so far, whatever the entry count
+        let mut state = self.state.lock().unwrap();
+        self.take_snapshot(&mut state)?;
+        Ok(state.snapshot)
+    }
+
+    fn follow(&self, state: &mut RaftState<M>, term: i32, leader: u32) {
+        if term > state.term || state.role != Role::Follower { self.step_down(state, term) }
+        state.leader = Some(leader);
+        state.election_deadline = Instant::now() + election_timeout(&self.config);
+    }
+}
+
+impl<M: StateMachine> RaftPeer for RaftNode<M> {
+    fn handle_vote(&self, req: &VoteRequest) -> Result<VoteResponse> {
+        let mut state = self.state.lock().unwrap();
+        if req.term > state.term {
+            self.step_down(&mut state, req.term);
+            state.leader = None;
+        }
+        let up_to_date = (req.last_term, req.log_end_offset) >= (state.last_term(), state.log_end_offset());
+        let free = state.voted_for.is_none() || state.voted_for == Some(req.candidate);
+        let granted = req.term == state.term && free && up_to_date;
+        if granted {
+            state.voted_for = Some(req.candidate);
+            write_quorum_state(&self.dir, state.term, state.voted_for)?;
+            state.election_deadline = Instant::now() + election_timeout(&self.config);
+        }
+        Ok(VoteResponse{ term: state.term, granted })
+    }
+
+    fn handle_append(&self, req: &AppendRequest) -> Result<AppendResponse> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let reject = |state: &RaftState<M>, log_end_offset| AppendResponse{ term: state.term, success: false, log_end_offset };
+        if req.term < state.term { return Ok(reject(state, state.log_end_offset())) }
+        self.follow(state, req.term, req.leader);
+
+        // what the snapshot covers is committed, it agrees with any leader
+        let log_end = state.log_end_offset();
+        if req.prev_log_end > log_end { return Ok(reject(state, log_end)) }
+        if req.prev_log_end > state.snapshot_end() && state.term_at(req.prev_log_end - 1) != Some(req.prev_term) {
+            return Ok(reject(state, req.prev_log_end - 1))
+        }
+        let snapshot_end = state.snapshot_end();
+        for entry in req.entries.iter().filter(|e| e.offset >= snapshot_end) {
+            if entry.offset < state.log_end_offset() {
+                if state.term_at(entry.offset) == Some(entry.term) { continue }
+                // a leader that never committed this wrote it, the new one's wins
+                state.log.truncate_to(entry.offset)?;
+            }
+            state.log.assign_leader_epoch(entry.term, entry.offset)?;
+            state.log.append_replica(&[Message::new(entry.offset, 0, &entry.payload)])?;
+        }
+        // only what this request showed to match the leader's log may be
+        // committed, a tail past it can still be a deposed leader's
+        let log_end = state.log_end_offset();
+        let commit = req.commit_offset.min(req.prev_log_end + req.entries.len() as Offset).min(log_end);
+        if commit > state.commit_offset() {
+            state.log.set_high_watermark(Some(commit));
+            self.apply(state);
+        }
+        Ok(AppendResponse{ term: state.term, success: true, log_end_offset: log_end })
+    }
+
+    fn handle_snapshot(&self, req: &SnapshotRequest) -> Result<i32> {
+        // the log is kept from the snapshot on if it has the snapshot's last
+        // entry, otherwise it starts over where the snapshot ends
+        let mut state = self.state.lock().unwrap();
+        if req.term < state.term { return Ok(state.term) }
+        self.follow(&mut state, req.term, req.leader);
+        if req.snapshot.end_offset <= state.snapshot_end() { return Ok(state.term) }
+        write_snapshot(&self.dir, req.snapshot, &req.data)?;
+        state.machine.restore(&req.data)?;
+        let end = req.snapshot.end_offset;
+        match state.term_at(end - 1) == Some(req.snapshot.term) {
+            true => { state.log.delete_segments_before(end)?; },
+            false => state.log.truncate_fully_and_start_at(end)?,
+        }
+        state.snapshot = Some(req.snapshot);
+        state.applied = end;
+        if state.commit_offset() < end { state.log.set_high_watermark(Some(end)) }
+        self.committed.notify_all();
+        Ok(state.term)
+    }
+}
+
+
+fn election_timeout(config: &RaftConfig) -> Duration {
+    // somewhere between one and two timeouts so candidates don't keep
+    // splitting the vote. No rand dependency, the clock's nanos will do
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let spread = config.election_timeout.as_micros().max(1) as u64;
+    config.election_timeout + Duration::from_micros(nanos as u64 % spread)
+}
+
+fn read_quorum_state(dir: &Path) -> Result<(i32, Option<u32>)> {
+    // "term voted_for", -1 for no vote
+    let raw = match fs::read_to_string(dir.join(QUORUM_STATE_FILE)) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, None)),
+        Err(e) => return Err(e.into()),
+    };
+    let corrupt = || Error::CorruptRecord(format!("{} isn't a quorum state", dir.join(QUORUM_STATE_FILE).display()));
+    let mut fields = raw.split_whitespace();
+    let term = fields.next().and_then(|f| f.parse::<i32>().ok()).ok_or_else(corrupt)?;
+    let voted_for = fields.next().and_then(|f| f.parse::<i64>().ok()).ok_or_else(corrupt)?;
+    Ok((term, if voted_for < 0 { None } else { Some(voted_for as u32) }))
+}
+
+fn write_quorum_state(dir: &Path, term: i32, voted_for: Option<u32>) -> Result<()> {
+    // a vote is on disk before it's given, or a restart could vote twice
+    let raw = format!("{} {}\n", term, voted_for.map_or(-1, |id| id as i64));
+    write_atomically(&dir.join(QUORUM_STATE_FILE), raw.as_bytes())
+}
+
+fn latest_snapshot(dir: &Path) -> Result<Option<SnapshotId>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        snapshots.extend(SnapshotId::parse(&entry?.file_name().to_string_lossy()));
+    }
+    Ok(snapshots.into_iter().max_by_key(|s| s.end_offset))
+}
+
+fn write_snapshot(dir: &Path, snapshot: SnapshotId, data: &[u8]) -> Result<()> {
+    // older snapshots go once the new one is safely down
+    write_atomically(&dir.join(snapshot.file_name()), data)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(id) = SnapshotId::parse(&entry.file_name().to_string_lossy()) {
+            if id.end_offset < snapshot.end_offset { fs::remove_file(entry.path())? }
+        }
+    }
+    Ok(())
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempdir, TempDir};
+    use super::*;
+    use crate::cluster::Broker;
+    use crate::cluster::metadata::{MetadataImage, MetadataRecord};
+    use crate::partition::segment::{MaxBytes};
+
+    struct Quorum {
+        dirs: Vec<TempDir>,
+        transport: Arc<InMemoryTransport>,
+        nodes: Vec<Arc<RaftNode<MetadataImage>>>,
+        tasks: Vec<BackgroundTasks>,
+    }
+
+    impl Quorum {
+        fn start(dirs: Vec<TempDir>) -> Quorum {
+            let config = RaftConfig{
+                election_timeout: Duration::from_millis(100),
+                heartbeat_interval: Duration::from_millis(10),
+                snapshot_entries: 10,
+                log: MaxBytes(1024, 1024).into(),
+                ..RaftConfig::default()
+            };
+            let transport = Arc::new(InMemoryTransport::new());
+            let voters: Vec<u32> = (1..=dirs.len() as u32).collect();
+            let nodes: Vec<_> = dirs.iter().zip(&voters).map(|(dir, id)| {
+                let transport = transport.clone();
+                let log_dir = dir.path().join(METADATA_LOG_DIR);
+                Arc::new(RaftNode::open(*id, &voters, &log_dir, MetadataImage::new(), transport, config.clone()).unwrap())
+            }).collect();
+            for node in &nodes {
+                let peer: Weak<dyn RaftPeer> = Arc::downgrade(node) as Weak<RaftNode<MetadataImage>>;
+                transport.register(node.id(), peer);
+            }
+            let tasks = nodes.iter().map(|n| RaftNode::start(n.clone())).collect();
+            Quorum{ dirs, transport, nodes, tasks }
+        }
+
+        fn stop(mut self) -> Vec<TempDir> {
+            self.tasks.clear();
+            self.nodes.clear();
+            self.dirs
+        }
+
+        fn node(&self, id: u32) -> &RaftNode<MetadataImage> { &self.nodes[id as usize - 1] }
+
+        fn leader(&self) -> u32 {
+            // the one leader of the highest term, once there is one
+            let mut leader = None;
+            wait_for(|| {
+                let connected: Vec<_> = self.nodes.iter().filter(|n| !self.transport.disconnected.read().unwrap().contains(&n.id())).collect();
+                let term = connected.iter().map(|n| n.term()).max().unwrap();
+                let leaders: Vec<_> = connected.iter().filter(|n| n.role() == Role::Leader && n.term() == term).collect();
+                leader = leaders.first().map(|n| n.id());
+                leaders.len() == 1
+            });
+            leader.unwrap()
+        }
+
+        fn propose(&self, record: MetadataRecord) {
+            // a leader can lose its term before it commits, then it's the next one's
+            wait_for(|| self.node(self.leader()).propose_and_wait(&record.to_vec(), Duration::from_secs(1)).is_ok());
+        }
+
+        fn wait_until_applied(&self, ids: &[u32]) {
+            // a new leader commits its log along with its first entry, until
+            // then the others are level with it but not done
+            let leader = self.node(self.leader());
+            wait_for(|| leader.applied_offset() == leader.log_end_offset() && ids.iter().all(|id| {
+                let node = self.node(*id);
+                node.applied_offset() == leader.applied_offset() && node.read(|m| m.clone()) == leader.read(|m| m.clone())
+            }));
+        }
+    }
+
+    fn wait_for<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn register(id: u32) -> MetadataRecord { MetadataRecord::RegisterBroker(Broker::new(id, "127.0.0.1", 9092 + id as u16)) }
+
+    #[test]
+    fn it_elects_a_leader_and_replicates_records() {
+        let quorum = Quorum::start(vec![tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()]);
+        wait_for(|| (1..=3).all(|id| quorum.node(id).leader() == Some(quorum.leader())));
+        let leader = quorum.leader();
+        let follower = if leader == 1 { 2 } else { 1 };
+        assert!(matches!(quorum.node(follower).propose(b"nope"), Err(Error::NotLeader(_))));
+
+        quorum.propose(MetadataRecord::Topic(String::from("events")));
+        quorum.propose(register(7));
+        quorum.wait_until_applied(&[1, 2, 3]);
+        for id in 1..=3 {
+            let image = quorum.node(id).read(|m| m.clone());
+            assert_eq!(image.topics(), vec![String::from("events")]);
+            assert_eq!(image.broker(7), Some(&Broker::new(7, "127.0.0.1", 9099)));
+        }
+    }
+
+    #[test]
+    fn it_commits_only_what_matches_the_leader() {
+        let dir = tempdir().unwrap();
+        let transport = Arc::new(InMemoryTransport::new());
+        let config = RaftConfig{ log: MaxBytes(1024, 1024).into(), ..RaftConfig::default() };
+        let node = RaftNode::open(2, &[1, 2, 3], &dir.path().join(METADATA_LOG_DIR), MetadataImage::new(), transport, config).unwrap();
+        let entry = |term, offset, record: MetadataRecord| {
+            Entry{ term, offset, payload: Record::new(0, None, Some(&record.to_vec())).to_vec().unwrap() }
+        };
+        let append = |term, prev_log_end, prev_term, entries, commit_offset| {
+            node.handle_append(&AppendRequest{ term, leader: 1, prev_log_end, prev_term, entries, commit_offset }).unwrap()
+        };
+        let tail = vec![entry(1, 0, register(1)), entry(1, 1, register(2)), entry(1, 2, register(3))];
+        assert!(append(1, 0, 0, tail, 1).success);
+        assert_eq!((node.log_end_offset(), node.commit_offset()), (3, 1));
+
+        // a new leader that never had offsets 1 and 2 committed its own there,
+        // its heartbeat only vouches for offset 0
+        assert!(append(2, 1, 1, vec![], 3).success);
+        assert_eq!((node.commit_offset(), node.applied_offset()), (1, 1));
+        assert_eq!(node.read(|m| m.brokers().len()), 1);
+
+        let entries = vec![entry(2, 1, MetadataRecord::Topic(String::from("events"))), entry(2, 2, register(4))];
+        assert!(append(2, 1, 1, entries, 3).success);
+        assert_eq!(node.commit_offset(), 3);
+        assert_eq!(node.read(|m| m.brokers().iter().map(|b| b.id()).collect::<Vec<_>>()), vec![1, 4]);
+        assert_eq!(node.read(|m| m.topics()), vec![String::from("events")]);
+    }
+
+    #[test]
+    fn it_fails_over_to_a_new_leader() {
+        let quorum = Quorum::start(vec![tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()]);
+        quorum.propose(register(1));
+        let (old, term) = (quorum.leader(), quorum.node(quorum.leader()).term());
+
+        quorum.transport.disconnect(old);
+        let new = quorum.leader();
+        assert_ne!(new, old);
+        assert!(quorum.node(new).term() > term);
+        quorum.propose(register(2));
+        // cut off, the old leader can't commit anything
+        assert!(quorum.node(old).propose_and_wait(&register(3).to_vec(), Duration::from_millis(200)).is_err());
+
+        quorum.transport.reconnect(old);
+        quorum.wait_until_applied(&[1, 2, 3]);
+        let image = quorum.node(old).read(|m| m.clone());
+        assert_eq!(image.brokers().iter().map(|b| b.id()).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn it_catches_a_lagging_node_up_from_a_snapshot() {
+        let quorum = Quorum::start(vec![tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()]);
+        quorum.propose(register(1));
+        let leader = quorum.leader();
+        let lagging = if leader == 3 { 2 } else { 3 };
+        quorum.wait_until_applied(&[lagging]);
+
+        quorum.transport.disconnect(lagging);
+        for id in 0..60 {
+            quorum.propose(MetadataRecord::Topic(format!("topic-{}", id)));
+        }
+        let leader = quorum.node(quorum.leader());
+        assert!(leader.snapshot_id().is_some());
+        assert!(leader.log_start_offset() > quorum.node(lagging).log_end_offset());
+
+        quorum.transport.reconnect(lagging);
+        quorum.wait_until_applied(&[1, 2, 3]);
+        let node = quorum.node(lagging);
+        assert_eq!(node.read(|m| m.topics().len()), 60);
+        assert!(node.snapshot_id().is_some());
+        assert!(node.log_start_offset() > 0);
+    }
+
+    #[test]
+    fn it_recovers_its_state_from_disk() {
+        let quorum = Quorum::start(vec![tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()]);
+        for id in 0..25 {
+            quorum.propose(MetadataRecord::Topic(format!("topic-{}", id)));
+        }
+        quorum.wait_until_applied(&[1, 2, 3]);
+        let (term, image) = (quorum.node(1).term(), quorum.node(1).read(|m| m.clone()));
+        let dirs = quorum.stop();
+
+        let quorum = Quorum::start(dirs);
+        for id in 1..=3 {
+            // what the snapshot holds is there before any leader is
+            assert!(quorum.node(id).read(|m| m.topics().len()) >= 10);
+            assert!(quorum.node(id).term() >= term);
+        }
+        quorum.wait_until_applied(&[1, 2, 3]);
+        assert_eq!(quorum.node(1).read(|m| m.clone()), image);
+        assert!(quorum.node(quorum.leader()).term() > term);
+    }
+}
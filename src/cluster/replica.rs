@@ -0,0 +1,789 @@
+use std::collections::{BTreeMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Offset, Result};
+use crate::client::{Connection};
+use crate::cluster::Broker;
+use crate::cluster::manager::{BackgroundTasks, LogManager, SharedPartition, TopicPartitionId};
+use crate::cluster::quota::{ReplicationQuota};
+use crate::partition::Partition;
+use crate::partition::epoch::{EpochEndOffset, EpochEntry, UNDEFINED_EPOCH};
+use crate::partition::message::{Message};
+use crate::partition::slice::{FileSlice};
+use crate::server::{ServerConfig};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionState {
+    // every broker with a copy of the partition, the first is the preferred leader
+    pub replicas: Vec<u32>,
+    pub leader: u32,
+    // bumped every time leadership moves, requests from an older one are fenced
+    pub leader_epoch: i32,
+    // the replicas caught up with the leader, the leader always among them
+    pub isr: Vec<u32>,
+}
+
+impl PartitionState {
+    pub fn preferred_leader(&self) -> Option<u32> { self.replicas.first().cloned() }
+}
+
+// What the leader knows of one follower, from the follower's fetches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FollowerState {
+    // a fetch from an offset means the follower has everything before it
+    pub log_end_offset: Offset,
+    pub last_fetch: Instant,
+    // the last fetch from the leader's log end, a follower that hasn't
+    // caught up for replica_lag_time_max drops out of the isr
+    pub last_caught_up: Instant,
+}
+
+// What the leader sends a follower for one partition, the messages straight
+// from the log file
+pub struct FollowerRead {
+    pub log_end_offset: Offset,
+    pub high_watermark: Offset,
+    pub epochs: Vec<EpochEntry>,
+    pub records: Option<FileSlice>,
+}
+
+// What a follower got back from the leader for one partition
+pub struct ReplicaFetch {
+    pub log_end_offset: Offset,
+    pub high_watermark: Offset,
+    // the leader's epochs from the one the fetch started in
+    pub epochs: Vec<EpochEntry>,
+    pub messages: Vec<Message>,
+}
+
+struct Replica {
+    state: PartitionState,
+    // by broker id, only kept while this broker leads
+    followers: BTreeMap<u32, FollowerState>,
+    // a new follower first cuts its log back to where it agrees with the leader's
+    truncating: bool,
+    // the state came from a controller, which has the last word on the isr
+    controlled: bool,
+    // the isr the leader asked the controller for, the high watermark goes
+    // by state.isr until the controller sends it back committed
+    proposed_isr: Option<Vec<u32>>,
+}
+
+impl Replica {
+    fn wanted_isr(&self) -> &Vec<u32> { self.proposed_isr.as_ref().unwrap_or(&self.state.isr) }
+
+    fn propose_isr(&mut self, isr: Vec<u32>) {
+        // without a controller the leader decides alone
+        if !self.controlled {
+            self.state.isr = isr;
+            return
+        }
+        self.proposed_isr = if isr != self.state.isr { Some(isr) } else { None };
+    }
+
+    fn high_watermark(&self, leader: u32, log_end_offset: Offset) -> Offset {
+        // the least any in-sync replica has, followers yet to fetch have nothing
+        self.state.isr.iter()
+            .map(|id| match *id == leader {
+                true => log_end_offset,
+                false => self.followers.get(id).map_or(0, |f| f.log_end_offset),
+            })
+            .min()
+            .unwrap_or(log_end_offset)
+    }
+}
+
+
+// Keeps this broker's partitions in step with their replicas elsewhere. A
+// partition the broker leads takes writes and serves its followers' fetches,
+// one it follows is written only by a fetcher thread copying the leader's
+// log, one per leader broker like kafka's replica fetchers. Partitions
+// nobody assigned replicas to are this broker's alone, and it leads them.
+pub struct ReplicaManager {
+    broker_id: u32,
+    logs: Arc<LogManager>,
+    config: ServerConfig,
+    brokers: RwLock<BTreeMap<u32, Broker>>,
+    replicas: Mutex<BTreeMap<TopicPartitionId, Replica>>,
+    fetchers: Mutex<BTreeMap<u32, BackgroundTasks>>,
+    // what followers outside the isr may copy from this broker
+    quota: ReplicationQuota,
+    // the newest controller heard from and its epoch, older ones are fenced
+    controller: Mutex<Option<(u32, i32)>>,
+}
+
+impl ReplicaManager {
+    pub fn new(broker: &Broker, logs: Arc<LogManager>, config: ServerConfig) -> ReplicaManager {
+        let mut brokers = BTreeMap::new();
+        brokers.insert(broker.id(), broker.clone());
+        ReplicaManager{
+            broker_id: broker.id(),
+            logs,
+            brokers: RwLock::new(brokers),
+            replicas: Mutex::new(BTreeMap::new()),
+            fetchers: Mutex::new(BTreeMap::new()),
+            quota: ReplicationQuota::new(config.replication_throttled_rate),
+            controller: Mutex::new(None),
+            config,
+        }
+    }
+
+    pub fn broker_id(&self) -> u32 { self.broker_id }
+
+    pub fn replication_throttle(&self) -> Option<u64> { self.quota.rate() }
+    pub fn set_replication_throttle(&self, rate: Option<u64>) { self.quota.set_rate(rate) }
+
+    pub fn add_broker(&self, broker: Broker) {
+        self.brokers.write().unwrap().insert(broker.id(), broker);
+    }
+
+    pub fn set_brokers(&self, live: Vec<Broker>) {
+        // the cluster's live brokers as the controller sees them, this
+        // broker always among them
+        let mut brokers = self.brokers.write().unwrap();
+        brokers.retain(|id, _| *id == self.broker_id);
+        for broker in live.into_iter().filter(|b| b.id() != self.broker_id) {
+            brokers.insert(broker.id(), broker);
+        }
+    }
+
+    pub fn broker(&self, id: u32) -> Option<Broker> { self.brokers.read().unwrap().get(&id).cloned() }
+    pub fn brokers(&self) -> Vec<Broker> { self.brokers.read().unwrap().values().cloned().collect() }
+
+    pub fn state(&self, topic: &str, partition: u32) -> Option<PartitionState> {
+        self.replicas.lock().unwrap().get(&TopicPartitionId::new(topic, partition)).map(|r| r.state.clone())
+    }
+
+    pub fn follower(&self, topic: &str, partition: u32, replica: u32) -> Option<FollowerState> {
+        let replicas = self.replicas.lock().unwrap();
+        replicas.get(&TopicPartitionId::new(topic, partition)).and_then(|r| r.followers.get(&replica).cloned())
+    }
+
+    pub fn become_leader(&self, topic: &str, partition: u32, replicas: &[u32], leader_epoch: i32) -> Result<SharedPartition> {
+        // every replica starts out in sync and has replica_lag_time_max to show it is
+        self.lead(topic, partition, replicas, replicas, leader_epoch, false)
+    }
+
+    fn lead(
+        &self,
+        topic: &str,
+        partition: u32,
+        replicas: &[u32],
+        isr: &[u32],
+        leader_epoch: i32,
+        controlled: bool,
+    ) -> Result<SharedPartition> {
+        // the high watermark carries on from the checkpoint, or from the log
+        // end when nobody else had a copy before. The new epoch starts at the
+        // log end. Leading on under a new epoch keeps what's known of the followers
+        let tp = TopicPartitionId::new(topic, partition);
+        let mut all = self.replicas.lock().unwrap();
+        check_epoch(all.get(&tp), leader_epoch)?;
+        let shared = self.logs.create(topic, partition, None)?;
+        {
+            let mut partition = shared.lock().unwrap();
+            let log_end = partition.log_end_offset();
+            partition.assign_leader_epoch(leader_epoch, log_end)?;
+            start_high_watermark(&mut partition);
+        }
+        let mut isr = isr.to_vec();
+        if !isr.contains(&self.broker_id) { isr.push(self.broker_id) }
+        isr.sort_unstable();
+        let now = Instant::now();
+        let known = all.remove(&tp).filter(|r| r.state.leader == self.broker_id).map(|r| r.followers).unwrap_or_default();
+        let followers = replicas.iter()
+            .filter(|id| **id != self.broker_id)
+            .map(|id| {
+                let follower = known.get(id).cloned();
+                (*id, follower.unwrap_or(FollowerState{ log_end_offset: 0, last_fetch: now, last_caught_up: now }))
+            })
+            .collect();
+        let state = PartitionState{ replicas: replicas.to_vec(), leader: self.broker_id, leader_epoch, isr };
+        all.insert(tp, Replica{ state, followers, truncating: false, controlled, proposed_isr: None });
+        Ok(shared)
+    }
+
+    pub fn become_follower(
+        self: &Arc<Self>,
+        topic: &str,
+        partition: u32,
+        replicas: &[u32],
+        leader: u32,
+        leader_epoch: i32,
+    ) -> Result<SharedPartition> {
+        if leader == self.broker_id {
+            return Err(Error::InvalidRequest(format!("broker {} can't follow itself", leader)))
+        }
+        let tp = TopicPartitionId::new(topic, partition);
+        let mut all = self.replicas.lock().unwrap();
+        check_epoch(all.get(&tp), leader_epoch)?;
+        let shared = self.logs.create(topic, partition, None)?;
+        start_high_watermark(&mut shared.lock().unwrap());
+        let state = PartitionState{ replicas: replicas.to_vec(), leader, leader_epoch, isr: replicas.to_vec() };
+        all.insert(tp, Replica{ state, followers: BTreeMap::new(), truncating: true, controlled: false, proposed_isr: None });
+        drop(all);
+
+        let mut fetchers = self.fetchers.lock().unwrap();
+        match fetchers.get(&leader) {
+            Some(fetcher) => fetcher.wake(),
+            None => {
+                let manager = self.clone();
+                let name = format!("latka-replica-fetcher-{}", leader);
+                fetchers.insert(leader, BackgroundTasks::spawn(&name, move |running| manager.run_fetcher(leader, running)));
+            },
+        }
+        Ok(shared)
+    }
+
+    pub fn check_controller(&self, controller_id: u32, epoch: i32) -> Result<()> {
+        // a controller from before the latest one elected is out of date
+        let mut controller = self.controller.lock().unwrap();
+        if let Some((current_id, current_epoch)) = *controller {
+            if epoch < current_epoch {
+                let msg = format!("controller {} epoch {}, controller {} is at {}", controller_id, epoch, current_id, current_epoch);
+                return Err(Error::NotController(msg))
+            }
+        }
+        *controller = Some((controller_id, epoch));
+        Ok(())
+    }
+
+    pub fn apply(self: &Arc<Self>, topic: &str, partition: u32, state: &PartitionState) -> Result<()> {
+        // a leader and isr from the controller. Sent again with an epoch this
+        // broker already has only the isr can have changed, committed by the
+        // controller. Only the isr the controller elected from starts out in
+        // sync with a new leader
+        if let Some(current) = self.state(topic, partition) {
+            if (current.leader, current.leader_epoch) == (state.leader, state.leader_epoch) {
+                self.commit_isr(&TopicPartitionId::new(topic, partition), &state.isr);
+                return Ok(())
+            }
+        }
+        if !state.replicas.contains(&self.broker_id) { return self.remove(topic, partition) }
+        match state.leader == self.broker_id {
+            true => self.lead(topic, partition, &state.replicas, &state.isr, state.leader_epoch, true)?,
+            false => self.become_follower(topic, partition, &state.replicas, state.leader, state.leader_epoch)?,
+        };
+        Ok(())
+    }
+
+    fn remove(&self, topic: &str, partition: u32) -> Result<()> {
+        // the partition was moved off this broker, its fetcher lets go of
+        // it and the log is deleted
+        let tp = TopicPartitionId::new(topic, partition);
+        let mut all = self.replicas.lock().unwrap();
+        if all.remove(&tp).is_none() { return Ok(()) }
+        self.logs.delete(topic, partition)?;
+        Ok(())
+    }
+
+    fn commit_isr(&self, tp: &TopicPartitionId, isr: &[u32]) {
+        // the isr the controller settled on, whatever the leader asked for.
+        // From here on it's what the high watermark goes by
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.get_mut(tp) {
+            replica.state.isr = isr.to_vec();
+            replica.proposed_isr = None;
+            self.advance_high_watermark(tp, replica);
+        }
+    }
+
+    pub fn isr_proposals(&self) -> Vec<(TopicPartitionId, PartitionState)> {
+        // AlterIsr: the isr changes this broker wants for partitions it
+        // leads, each under the epoch it leads in, for the controller to commit
+        let replicas = self.replicas.lock().unwrap();
+        replicas.iter()
+            .filter(|(_, replica)| replica.state.leader == self.broker_id)
+            .filter_map(|(tp, replica)| {
+                let isr = replica.proposed_isr.clone()?;
+                Some((tp.clone(), PartitionState{ isr, ..replica.state.clone() }))
+            })
+            .collect()
+    }
+
+    pub fn check_leader(&self, topic: &str, partition: u32) -> Result<()> {
+        match self.replicas.lock().unwrap().get(&TopicPartitionId::new(topic, partition)) {
+            Some(replica) if replica.state.leader != self.broker_id => {
+                Err(Error::NotLeader(TopicPartitionId::new(topic, partition).dir_name()))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_leader_epoch(&self, topic: &str, partition: u32, current_leader_epoch: i32) -> Result<()> {
+        // a client's idea of the leader epoch, from its metadata. -1 skips the
+        // check, partitions that aren't replicated stay at epoch 0
+        if current_leader_epoch < 0 { return Ok(()) }
+        let tp = TopicPartitionId::new(topic, partition);
+        let current = self.replicas.lock().unwrap().get(&tp).map(|r| r.state.leader_epoch).unwrap_or(0);
+        if current_leader_epoch != current {
+            return Err(Error::FencedLeaderEpoch{ epoch: current_leader_epoch, current })
+        }
+        Ok(())
+    }
+
+    pub fn appended(&self, topic: &str, partition: u32) {
+        // with nobody else in sync an append is committed as soon as it's written
+        let tp = TopicPartitionId::new(topic, partition);
+        let replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.get(&tp) {
+            self.advance_high_watermark(&tp, replica);
+        }
+    }
+
+    pub fn wait_for_high_watermark(&self, topic: &str, partition: u32, offset: Offset, timeout: Duration) -> Result<()> {
+        // acks=all, until every in-sync replica has everything before offset
+        let appends = self.logs.append_signal();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let seen = appends.appends();
+            self.check_leader(topic, partition)?;
+            let shared = self.logs.get(topic, partition)
+                .ok_or_else(|| Error::PartitionNotFound(self.logs.log_dirs()[0].join(format!("{}-{}", topic, partition))))?;
+            if shared.lock().unwrap().high_watermark() >= offset { return Ok(()) }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(format!("{}-{} isn't replicated up to {}", topic, partition, offset)))
+            }
+            appends.wait(seen, deadline - now);
+        }
+    }
+
+    pub fn read_for_follower(
+        &self,
+        replica_id: u32,
+        tp: &TopicPartitionId,
+        leader_epoch: i32,
+        offset: Offset,
+        max_bytes: u64,
+    ) -> Result<FollowerRead> {
+        // the leader's side of a follower's fetch. Fetching from an offset
+        // means the follower has what's before it, which may move the high
+        // watermark or put the follower back in sync
+        let mut replicas = self.replicas.lock().unwrap();
+        let replica = match replicas.get_mut(tp) {
+            Some(replica) if replica.state.leader == self.broker_id => replica,
+            _ => return Err(Error::NotLeader(tp.dir_name())),
+        };
+        if leader_epoch != replica.state.leader_epoch {
+            return Err(Error::FencedLeaderEpoch{ epoch: leader_epoch, current: replica.state.leader_epoch })
+        }
+        if !replica.state.replicas.contains(&replica_id) || replica_id == self.broker_id {
+            return Err(Error::InvalidRequest(format!("broker {} isn't a follower of {}", replica_id, tp.dir_name())))
+        }
+        let shared = self.logs.get(&tp.topic, tp.partition)
+            .ok_or_else(|| Error::PartitionNotFound(self.logs.log_dirs()[0].join(tp.dir_name())))?;
+        let partition = shared.lock().unwrap();
+        let log_end_offset = partition.log_end_offset();
+        if offset > log_end_offset { return Err(Error::OffsetOutOfRange(offset)) }
+
+        let now = Instant::now();
+        let follower = replica.followers.entry(replica_id)
+            .or_insert(FollowerState{ log_end_offset: offset, last_fetch: now, last_caught_up: now });
+        follower.log_end_offset = offset;
+        follower.last_fetch = now;
+        if offset == log_end_offset { follower.last_caught_up = now }
+        if !replica.wanted_isr().contains(&replica_id) && offset >= partition.high_watermark() {
+            let mut isr = replica.wanted_isr().clone();
+            isr.push(replica_id);
+            isr.sort_unstable();
+            replica.propose_isr(isr);
+        }
+        drop(partition);
+        self.advance_high_watermark(tp, replica);
+
+        // a follower outside the isr copies at the throttled rate
+        let allowance = match replica.state.isr.contains(&replica_id) {
+            true => None,
+            false => self.quota.allowance(),
+        };
+        let partition = shared.lock().unwrap();
+        let records = match allowance {
+            Some(0) => None,
+            Some(allowance) => partition.fetch(offset, max_bytes.min(allowance))?,
+            None => partition.fetch(offset, max_bytes)?,
+        };
+        if allowance.is_some() {
+            self.quota.record(records.as_ref().map_or(0, |r| r.length));
+        }
+        let epochs = partition.leader_epochs().entries_from(offset);
+        Ok(FollowerRead{ log_end_offset, high_watermark: partition.high_watermark(), epochs, records })
+    }
+
+    pub fn end_offset_for_epoch(&self, tp: &TopicPartitionId, current_leader_epoch: i32, epoch: i32) -> Result<EpochEndOffset> {
+        // OffsetsForLeaderEpoch, answered by the leader. A current epoch of
+        // -1 skips the check, the way kafka's consumers ask
+        let replicas = self.replicas.lock().unwrap();
+        let replica = replicas.get(tp);
+        if let Some(replica) = replica {
+            if replica.state.leader != self.broker_id { return Err(Error::NotLeader(tp.dir_name())) }
+            if current_leader_epoch >= 0 && current_leader_epoch != replica.state.leader_epoch {
+                return Err(Error::FencedLeaderEpoch{ epoch: current_leader_epoch, current: replica.state.leader_epoch })
+            }
+        }
+        let shared = self.logs.get(&tp.topic, tp.partition)
+            .ok_or_else(|| Error::PartitionNotFound(self.logs.log_dirs()[0].join(tp.dir_name())))?;
+        let partition = shared.lock().unwrap();
+        Ok(partition.end_offset_for_epoch(epoch))
+    }
+
+    pub fn shrink_isr(&self) -> usize {
+        // drops followers that haven't caught up for replica_lag_time_max,
+        // the high watermark moves on without them once the controller
+        // commits the smaller isr. Returns how many were dropped
+        let mut dropped = 0;
+        let mut replicas = self.replicas.lock().unwrap();
+        for (tp, replica) in replicas.iter_mut().filter(|(_, r)| r.state.leader == self.broker_id) {
+            let (broker_id, lag_max) = (self.broker_id, self.config.replica_lag_time_max);
+            let followers = &replica.followers;
+            let isr: Vec<u32> = replica.wanted_isr().iter().cloned()
+                .filter(|id| *id == broker_id || followers.get(id).is_some_and(|f| f.last_caught_up.elapsed() <= lag_max))
+                .collect();
+            if isr.len() < replica.wanted_isr().len() {
+                dropped += replica.wanted_isr().len() - isr.len();
+                replica.propose_isr(isr);
+            }
+            self.advance_high_watermark(tp, replica);
+        }
+        dropped
+    }
+
+    fn advance_high_watermark(&self, tp: &TopicPartitionId, replica: &Replica) {
+        // only the leader moves it, and only forwards
+        if replica.state.leader != self.broker_id { return }
+        let shared = match self.logs.get(&tp.topic, tp.partition) {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut partition = shared.lock().unwrap();
+        let high_watermark = replica.high_watermark(self.broker_id, partition.log_end_offset());
+        if high_watermark > partition.high_watermark() {
+            partition.set_high_watermark(Some(high_watermark));
+            // consumers waiting on a fetch can read further now
+            self.logs.append_signal().notify();
+        }
+    }
+
+    pub fn start(manager: Arc<ReplicaManager>) -> BackgroundTasks {
+        // isr expiry every half the lag time like kafka, high watermark
+        // checkpoints as often as configured
+        BackgroundTasks::spawn("latka-replica-manager", move |running| {
+            let config = manager.config.clone();
+            let interval = (config.replica_lag_time_max / 2).min(config.replica_high_watermark_checkpoint_interval);
+            let mut last_checkpoint = Instant::now();
+            while running.load(Ordering::SeqCst) {
+                manager.shrink_isr();
+                if last_checkpoint.elapsed() >= config.replica_high_watermark_checkpoint_interval {
+                    if let Err(e) = manager.logs.checkpoint_high_watermarks() {
+                        eprintln!("latka: high watermark checkpoint failed: {}", e);
+                    }
+                    last_checkpoint = Instant::now();
+                }
+                thread::park_timeout(interval);
+            }
+        })
+    }
+
+    fn following(&self, leader: u32, truncating: bool) -> Vec<(TopicPartitionId, i32, Offset)> {
+        // the partitions followed from the leader, those still to truncate or
+        // those to fetch, with the leader epoch and where each log ends
+        let replicas = self.replicas.lock().unwrap();
+        replicas.iter()
+            .filter(|(_, replica)| replica.state.leader == leader && replica.truncating == truncating)
+            .filter_map(|(tp, replica)| {
+                let shared = self.logs.get(&tp.topic, tp.partition)?;
+                let log_end = shared.lock().unwrap().log_end_offset();
+                Some((tp.clone(), replica.state.leader_epoch, log_end))
+            })
+            .collect()
+    }
+
+    fn truncate(&self, leader: u32, tp: &TopicPartitionId, leader_epoch: i32, end: Option<EpochEndOffset>) -> Result<()> {
+        // cuts the log back to where it stops agreeing with the leader's: the
+        // end of the last epoch both have, or the high watermark when the
+        // leader has none of this log's epochs or it has no epochs at all
+        let mut replicas = self.replicas.lock().unwrap();
+        let replica = match replicas.get_mut(tp) {
+            Some(replica) if replica.state.leader == leader && replica.state.leader_epoch == leader_epoch => replica,
+            _ => return Ok(()),
+        };
+        let shared = match self.logs.get(&tp.topic, tp.partition) {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let mut partition = shared.lock().unwrap();
+        let truncate_to = match end {
+            Some((epoch, leader_end)) if epoch != UNDEFINED_EPOCH => {
+                let (_, own_end) = partition.end_offset_for_epoch(epoch);
+                own_end.min(leader_end)
+            },
+            _ => partition.high_watermark(),
+        };
+        partition.truncate_to(truncate_to)?;
+        replica.truncating = false;
+        Ok(())
+    }
+
+    fn append_fetched(&self, leader: u32, tp: &TopicPartitionId, leader_epoch: i32, fetched: ReplicaFetch) -> Result<()> {
+        // leadership may have moved while the fetch was out
+        let state = self.state(&tp.topic, tp.partition);
+        if state.map(|s| (s.leader, s.leader_epoch)) != Some((leader, leader_epoch)) { return Ok(()) }
+        let shared = match self.logs.get(&tp.topic, tp.partition) {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let mut partition = shared.lock().unwrap();
+        let log_end = partition.log_end_offset();
+        let messages: Vec<Message> = fetched.messages.into_iter().filter(|m| m.offset >= log_end).collect();
+        partition.append_replica(&messages)?;
+        // the follower's epochs follow the leader's as far as what it copied
+        let log_end = partition.log_end_offset();
+        for entry in fetched.epochs.iter().filter(|e| e.start_offset < log_end) {
+            partition.assign_leader_epoch(entry.epoch, entry.start_offset)?;
+        }
+        // a follower's high watermark is the leader's, as far as its own log goes
+        partition.set_high_watermark(Some(fetched.high_watermark));
+        Ok(())
+    }
+
+    fn fetch_once(&self, leader: u32, conn: &mut Option<Connection>) -> Result<bool> {
+        // false when nothing is followed from the leader
+        let truncating = self.following(leader, true);
+        let positions = self.following(leader, false);
+        if truncating.is_empty() && positions.is_empty() { return Ok(false) }
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let broker = self.broker(leader)
+                    .ok_or_else(|| Error::InvalidConfig(format!("no address for broker {}", leader)))?;
+                conn.insert(Connection::connect(broker.addr())?)
+            },
+        };
+        let mut failed = None;
+
+        // a log without epochs has nothing to ask the leader about
+        let mut requests = vec![];
+        for (tp, leader_epoch, _) in truncating {
+            let shared = match self.logs.get(&tp.topic, tp.partition) {
+                Some(shared) => shared,
+                None => continue,
+            };
+            let latest = shared.lock().unwrap().leader_epochs().latest_epoch();
+            match latest {
+                Some(epoch) => requests.push((tp, leader_epoch, epoch)),
+                None => if let Err(e) = self.truncate(leader, &tp, leader_epoch, None) { failed.get_or_insert(e); },
+            }
+        }
+        if !requests.is_empty() {
+            let answers = conn.offsets_for_leader_epoch(self.broker_id, &requests)?;
+            for ((tp, res), (_, leader_epoch, _)) in answers.into_iter().zip(&requests) {
+                match res.and_then(|end| self.truncate(leader, &tp, *leader_epoch, Some(end))) {
+                    Ok(()) => (),
+                    Err(e) => { failed.get_or_insert(e); },
+                }
+            }
+        }
+
+        if !positions.is_empty() {
+            let max_wait_ms = self.config.replica_fetch_max_wait.as_millis().min(i32::MAX as u128) as u32;
+            let fetched = conn.replica_fetch(self.broker_id, &positions, max_wait_ms, self.config.replica_fetch_max_bytes)?;
+            for ((tp, res), (_, leader_epoch, _)) in fetched.into_iter().zip(&positions) {
+                match res.and_then(|fetched| self.append_fetched(leader, &tp, *leader_epoch, fetched)) {
+                    Ok(()) => (),
+                    Err(e) => { failed.get_or_insert(e); },
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(true),
+        }
+    }
+
+    fn run_fetcher(&self, leader: u32, running: Arc<AtomicBool>) {
+        let mut conn = None;
+        while running.load(Ordering::SeqCst) {
+            match self.fetch_once(leader, &mut conn) {
+                Ok(true) => (),
+                Ok(false) => thread::park_timeout(self.config.replica_fetch_backoff),
+                Err(e) => {
+                    eprintln!("latka: replica fetch from broker {} failed: {}", leader, e);
+                    if matches!(e, Error::Io(_)) { conn = None }
+                    thread::park_timeout(self.config.replica_fetch_backoff);
+                },
+            }
+        }
+    }
+
+    pub fn shutdown(&self) {
+        let fetchers = std::mem::take(&mut *self.fetchers.lock().unwrap());
+        for (_, mut fetcher) in fetchers {
+            fetcher.stop();
+        }
+    }
+}
+
+
+fn check_epoch(replica: Option<&Replica>, leader_epoch: i32) -> Result<()> {
+    // leadership only moves forwards, an older epoch is a stale request
+    match replica {
+        Some(replica) if leader_epoch < replica.state.leader_epoch => {
+            Err(Error::FencedLeaderEpoch{ epoch: leader_epoch, current: replica.state.leader_epoch })
+        },
+        _ => Ok(()),
+    }
+}
+
+fn start_high_watermark(partition: &mut Partition) {
+    let high_watermark = partition.high_watermark();
+    partition.set_high_watermark(Some(high_watermark));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use super::*;
+    use crate::client::{Acks, PendingRecord, Producer};
+    use crate::partition::segment::{MaxBytes};
+    use crate::server::{Server};
+
+    fn start(id: u32, log_dir: &Path) -> (SocketAddr, Arc<LogManager>, Arc<ReplicaManager>) {
+        let logs = Arc::new(LogManager::open(vec![log_dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+        let config = ServerConfig{
+            replica_fetch_max_wait: Duration::from_millis(50),
+            replica_fetch_backoff: Duration::from_millis(10),
+            replica_lag_time_max: Duration::from_millis(200),
+            ..ServerConfig::default()
+        };
+        let server = Server::bind(Broker::new(id, "127.0.0.1", 0), logs.clone(), config).unwrap();
+        let (addr, replicas) = (server.local_addr().unwrap(), server.replicas().clone());
+        thread::spawn(move || server.serve());
+        (addr, logs, replicas)
+    }
+
+    fn wait_for<F: Fn() -> bool>(done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn it_replicates_from_the_leader() {
+        let (one, two) = (tempdir().unwrap(), tempdir().unwrap());
+        let (leader_addr, leader_logs, leader) = start(1, one.path());
+        let (follower_addr, follower_logs, follower) = start(2, two.path());
+        leader.add_broker(Broker::new(2, "127.0.0.1", follower_addr.port()));
+        follower.add_broker(Broker::new(1, "127.0.0.1", leader_addr.port()));
+        leader.become_leader("events", 0, &[1, 2], 0).unwrap();
+        follower.become_follower("events", 0, &[1, 2], 1, 0).unwrap();
+
+        let mut producer = Producer::connect(leader_addr).unwrap();
+        for value in &[&b"one"[..], b"two", b"three"] {
+            producer.send_to("events", 0, Some(b"k"), value).unwrap();
+        }
+        let res = Producer::connect(follower_addr).unwrap().send_to("events", 0, None, b"nope");
+        assert!(matches!(res, Err(Error::NotLeader(_))), "followers don't take writes");
+
+        let replica = follower_logs.get("events", 0).unwrap();
+        wait_for(|| replica.lock().unwrap().log_end_offset() == 3);
+        let copied = replica.lock().unwrap().read(0, 1024).unwrap();
+        let original = leader_logs.get("events", 0).unwrap().lock().unwrap().read(0, 1024).unwrap();
+        assert_eq!(copied.iter().map(|m| (m.offset, &m.payload)).collect::<Vec<_>>(), original.iter().map(|m| (m.offset, &m.payload)).collect::<Vec<_>>());
+
+        // the follower's next fetch tells the leader how far it got
+        wait_for(|| leader.follower("events", 0, 2).map(|f| f.log_end_offset) == Some(3));
+        assert!(matches!(leader.check_leader("events", 0), Ok(())));
+        assert!(matches!(follower.check_leader("events", 0), Err(Error::NotLeader(_))));
+        follower.shutdown();
+    }
+
+    #[test]
+    fn it_tracks_the_isr_and_high_watermark() {
+        let (one, two) = (tempdir().unwrap(), tempdir().unwrap());
+        let (leader_addr, leader_logs, leader) = start(1, one.path());
+        let (follower_addr, _, follower) = start(2, two.path());
+        leader.add_broker(Broker::new(2, "127.0.0.1", follower_addr.port()));
+        follower.add_broker(Broker::new(1, "127.0.0.1", leader_addr.port()));
+        leader.become_leader("events", 0, &[1, 2], 0).unwrap();
+        follower.become_follower("events", 0, &[1, 2], 1, 0).unwrap();
+
+        let mut producer = Producer::connect(leader_addr).unwrap();
+        for _ in 0..3 {
+            producer.send_to("events", 0, None, b"XX").unwrap();
+        }
+        let partition = leader_logs.get("events", 0).unwrap();
+        wait_for(|| partition.lock().unwrap().high_watermark() == 3);
+        assert_eq!(leader.state("events", 0).unwrap().isr, vec![1, 2]);
+
+        // with the follower gone what's appended isn't committed, consumers
+        // don't see it until the follower drops out of the isr
+        follower.shutdown();
+        producer.send_to("events", 0, None, b"YY").unwrap();
+        let mut conn = Connection::connect(leader_addr).unwrap();
+        let fetched = conn.fetch("events", 0, 0, 1024).unwrap();
+        assert_eq!((fetched.high_watermark, fetched.records.len()), (3, 3));
+
+        let record = PendingRecord{ timestamp: 0, key: None, value: Some(b"ZZ".to_vec()) };
+        assert_eq!(conn.produce_batch("events", 0, -1, None, &[record]).unwrap(), Some(4), "acks=all waits out the follower");
+        assert_eq!(leader.state("events", 0).unwrap().isr, vec![1]);
+        assert_eq!(partition.lock().unwrap().high_watermark(), 5);
+        assert_eq!(conn.fetch("events", 0, 3, 1024).unwrap().records.len(), 2);
+
+        // a single produce with acks=all is back once it's committed
+        producer.set_acks(Acks::All).unwrap();
+        assert_eq!(producer.send_to("events", 0, None, b"AA").unwrap(), 5);
+        assert_eq!(partition.lock().unwrap().high_watermark(), 6);
+        assert!(matches!(producer.set_acks(Acks::None), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn it_truncates_what_the_new_leader_never_had() {
+        let (one, two) = (tempdir().unwrap(), tempdir().unwrap());
+        let (first_addr, first_logs, first) = start(1, one.path());
+        let (second_addr, second_logs, second) = start(2, two.path());
+        first.add_broker(Broker::new(2, "127.0.0.1", second_addr.port()));
+        second.add_broker(Broker::new(1, "127.0.0.1", first_addr.port()));
+        first.become_leader("events", 0, &[1, 2], 0).unwrap();
+        second.become_follower("events", 0, &[1, 2], 1, 0).unwrap();
+
+        let mut producer = Producer::connect(first_addr).unwrap();
+        for _ in 0..3 {
+            producer.send_to("events", 0, None, b"XX").unwrap();
+        }
+        let copy = second_logs.get("events", 0).unwrap();
+        wait_for(|| copy.lock().unwrap().log_end_offset() == 3);
+
+        // the first broker takes two more the second never gets before it fails over
+        second.shutdown();
+        for _ in 0..2 {
+            producer.send_to("events", 0, None, b"LOST").unwrap();
+        }
+        second.become_leader("events", 0, &[1, 2], 1).unwrap();
+        Producer::connect(second_addr).unwrap().send_to("events", 0, None, b"NEW").unwrap();
+        let res = second.become_follower("events", 0, &[1, 2], 1, 0);
+        assert!(matches!(res, Err(Error::FencedLeaderEpoch{ epoch: 0, current: 1 })));
+
+        first.become_follower("events", 0, &[1, 2], 2, 1).unwrap();
+        let old = first_logs.get("events", 0).unwrap();
+        wait_for(|| {
+            let old = old.lock().unwrap();
+            old.log_end_offset() == 4 && old.leader_epochs().latest_epoch() == Some(1)
+        });
+        let payloads: Vec<_> = old.lock().unwrap().read(0, 1024).unwrap().into_iter().map(|m| m.payload).collect();
+        let expected: Vec<_> = copy.lock().unwrap().read(0, 1024).unwrap().into_iter().map(|m| m.payload).collect();
+        assert_eq!(payloads, expected, "the records only the old leader had are gone");
+        let epochs: Vec<_> = old.lock().unwrap().leader_epochs().entries().iter().map(|e| (e.epoch, e.start_offset)).collect();
+        assert_eq!(epochs, vec![(0, 0), (1, 3)]);
+        first.shutdown();
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Offset, Result};
+use crate::cluster::{self, Broker, TopicPartition};
+use crate::cluster::partitioner::{Partitioner, DefaultPartitioner};
+use crate::cluster::placement::{assign_replicas};
+use crate::partition::config::{TopicConfig};
+use crate::partition::record::{Record};
+use crate::partition::segment::{now_ms};
+
+const MAX_TOPIC_NAME_LEN: usize = 249;
+const LOCAL_BROKER_ID: u32 = 0;
+
+
+// A Topic owns its partitions, laid out as `<log_dir>/<topic>-<n>`
+pub struct Topic {
+    name: String,
+    log_dir: PathBuf,
+    partitions: Vec<TopicPartition>,
+    partitioner: Box<dyn Partitioner>,
+}
+
+impl Topic {
+    pub fn create(name: String, log_dir: &Path, partitions: u32, config: TopicConfig) -> Result<Topic> {
+        // every partition this broker's alone
+        Topic::create_with_assignment(name, log_dir, vec![vec![LOCAL_BROKER_ID]; partitions as usize], config)
+    }
+
+    pub fn create_replicated(
+        name: String,
+        log_dir: &Path,
+        partitions: u32,
+        replication_factor: u32,
+        brokers: &[Broker],
+        config: TopicConfig,
+    ) -> Result<Topic> {
+        // the replicas spread over the brokers and their racks, see assign_replicas
+        validate_name(&name)?;
+        let assignment = assign_replicas(brokers, partitions, replication_factor)?;
+        Topic::create_with_assignment(name, log_dir, assignment, config)
+    }
+
+    fn create_with_assignment(name: String, log_dir: &Path, assignment: Vec<Vec<u32>>, config: TopicConfig) -> Result<Topic> {
+        validate_name(&name)?;
+        if assignment.is_empty() {
+            return Err(Error::InvalidConfig(String::from("a topic needs at least one partition")))
+        }
+        config.validate()?;
+        fs::create_dir_all(log_dir)?;
+        let log_path = log_dir.to_string_lossy().to_string();
+        let mut topic_partitions = Vec::with_capacity(assignment.len());
+        for (id, replicas) in assignment.into_iter().enumerate() {
+            topic_partitions.push(TopicPartition::new(
+                name.clone(),
+                log_path.clone(),
+                id as u32,
+                replicas,
+                config.clone(),
+            )?);
+        }
+        Ok(Topic{
+            name,
+            log_dir: log_dir.to_path_buf(),
+            partitions: topic_partitions,
+            partitioner: Box::new(DefaultPartitioner::new()),
+        })
+    }
+
+    pub fn open(name: String, log_dir: &Path) -> Result<Topic> {
+        validate_name(&name)?;
+        let ids = Topic::scan(&name, log_dir)?;
+        if ids.is_empty() {
+            return Err(Error::PartitionNotFound(log_dir.join(format!("{}-0", name))))
+        }
+        // partition ids are dense, a gap means a partition directory went missing
+        if let Some(missing) = (0..ids.len() as u32).find(|id| !ids.contains(id)) {
+            return Err(Error::PartitionNotFound(log_dir.join(format!("{}-{}", name, missing))))
+        }
+
+        let log_path = log_dir.to_string_lossy().to_string();
+        let mut partitions = Vec::with_capacity(ids.len());
+        for id in 0..ids.len() as u32 {
+            // partitions from before assignments were kept are this broker's alone
+            let dir = log_dir.join(format!("{}-{}", name, id));
+            let replicas = cluster::load_replicas(&dir)?.unwrap_or_else(|| vec![LOCAL_BROKER_ID]);
+            let leader = replicas[0];
+            partitions.push(TopicPartition::open(
+                name.clone(),
+                log_path.clone(),
+                id,
+                replicas,
+                leader,
+                leader,
+            )?);
+        }
+        Ok(Topic{
+            name,
+            log_dir: log_dir.to_path_buf(),
+            partitions,
+            partitioner: Box::new(DefaultPartitioner::new()),
+        })
+    }
+
+    pub fn scan(name: &str, log_dir: &Path) -> Result<Vec<u32>> {
+        // returns the partition ids of `name` found under log_dir, sorted
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(log_dir)? {
+            let path = entry?.path();
+            if !path.is_dir() { continue }
+            let dir_name = match path.file_name() {
+                Some(dir_name) => dir_name.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if let Some((topic, id)) = parse_partition_dir(&dir_name) {
+                if topic == name { ids.push(id) }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    pub fn set_partitioner<P: Partitioner + 'static>(&mut self, partitioner: P) {
+        self.partitioner = Box::new(partitioner);
+    }
+
+    pub fn send(&mut self, key: Option<&[u8]>, value: &[u8]) -> Result<(u32, Offset)> {
+        // written as a record so the key is kept for compaction and readers
+        let count = self.partitions.len() as u32;
+        let id = self.partitioner.partition(&self.name, key, value, count);
+        let record = Record::new(now_ms(), key, Some(value)).to_vec()?;
+        let offset = self.append(id, &record)?;
+        // every send is its own batch, the same as client::Producer
+        self.partitioner.on_new_batch(&self.name, id, count);
+        Ok((id, offset))
+    }
+
+    pub fn append(&mut self, partition_id: u32, value: &[u8]) -> Result<Offset> {
+        match self.partitions.get_mut(partition_id as usize) {
+            Some(tp) => tp.partition.append(value),
+            None => Err(Error::PartitionNotFound(self.log_dir.join(format!("{}-{}", self.name, partition_id)))),
+        }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn log_dir(&self) -> &Path { &self.log_dir }
+    pub fn partitions_len(&self) -> u32 { self.partitions.len() as u32 }
+    pub fn partitions(&self) -> &[TopicPartition] { &self.partitions }
+    pub fn partition(&self, id: u32) -> Option<&TopicPartition> { self.partitions.get(id as usize) }
+    pub fn partition_mut(&mut self, id: u32) -> Option<&mut TopicPartition> {
+        self.partitions.get_mut(id as usize)
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<()> {
+    // same rules as kafka, names end up in directory names
+    let legal = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::InvalidConfig(format!("{:?} is not a valid topic name", name)))
+    }
+    if name.len() > MAX_TOPIC_NAME_LEN {
+        return Err(Error::InvalidConfig(format!("topic names are at most {} characters", MAX_TOPIC_NAME_LEN)))
+    }
+    if !name.chars().all(legal) {
+        return Err(Error::InvalidConfig(format!("{:?} may only contain [a-zA-Z0-9._-]", name)))
+    }
+    Ok(())
+}
+
+pub fn parse_partition_dir(dir_name: &str) -> Option<(&str, u32)> {
+    // `<topic>-<n>`, topic names may have dashes of their own
+    let split = dir_name.rfind('-')?;
+    let (topic, id) = (&dir_name[..split], &dir_name[split + 1..]);
+    if topic.is_empty() { return None }
+    match id.parse::<u32>() {
+        Ok(id) => Some((topic, id)),
+        Err(_) => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet};
+    use tempfile::tempdir;
+    use super::*;
+    use crate::partition::segment::{MaxBytes};
+    use crate::cluster::partitioner::{RoundRobinPartitioner, to_positive, murmur2};
+
+    #[test]
+    fn it_creates_partition_dirs() {
+        let tmp = tempdir().unwrap();
+        let topic = Topic::create(String::from("my-topic"), tmp.path(), 3, MaxBytes(64, 32).into()).unwrap();
+
+        assert_eq!(topic.partitions_len(), 3);
+        for id in 0..3 {
+            assert!(tmp.path().join(format!("my-topic-{}", id)).is_dir(), "partition dir exists");
+            assert_eq!(topic.partition(id).unwrap().partition_id(), id);
+        }
+    }
+
+    #[test]
+    fn it_assigns_replicas_on_creation() {
+        let tmp = tempdir().unwrap();
+        // racks a, b, c, a, b, c
+        let brokers: Vec<Broker> = (0..6)
+            .map(|id| Broker::new(id, "127.0.0.1", 9092).with_rack(["a", "b", "c"][id as usize % 3]))
+            .collect();
+        let topic = Topic::create_replicated(String::from("my-topic"), tmp.path(), 6, 3, &brokers, MaxBytes(64, 32).into())
+            .unwrap();
+        let mut leaders = vec![];
+        for partition in topic.partitions() {
+            let replicas = partition.replica_ids();
+            assert_eq!(partition.preferred_leader(), replicas[0]);
+            assert_eq!(partition.leader_id(), replicas[0]);
+            assert_eq!(replicas.iter().map(|id| id % 3).collect::<BTreeSet<_>>().len(), 3);
+            leaders.push(replicas[0]);
+        }
+        leaders.sort_unstable();
+        assert_eq!(leaders, vec![0, 1, 2, 3, 4, 5]);
+
+        // the assignment survives reopening
+        let assigned: Vec<Vec<u32>> = topic.partitions().iter().map(|p| p.replica_ids().to_vec()).collect();
+        drop(topic);
+        let reopened = Topic::open(String::from("my-topic"), tmp.path()).unwrap();
+        assert_eq!(reopened.partitions().iter().map(|p| p.replica_ids().to_vec()).collect::<Vec<_>>(), assigned);
+        for partition in reopened.partitions() {
+            assert_eq!(partition.leader_id(), partition.replica_ids()[0]);
+            assert_eq!(partition.preferred_leader(), partition.replica_ids()[0]);
+        }
+
+        let local = Topic::create(String::from("local"), tmp.path(), 2, MaxBytes(64, 32).into()).unwrap();
+        assert_eq!(local.partition(1).unwrap().replica_ids(), &[LOCAL_BROKER_ID]);
+    }
+
+    #[test]
+    fn it_reopens_topic() {
+        let tmp = tempdir().unwrap();
+        {
+            let mut topic = Topic::create(String::from("my-topic"), tmp.path(), 2, MaxBytes(64, 32).into()).unwrap();
+            topic.append(1, "YELLOW SUBMARINE".as_bytes()).unwrap();
+            Topic::create(String::from("my"), tmp.path(), 1, MaxBytes(64, 32).into()).unwrap();
+        }
+        let mut topic = Topic::open(String::from("my-topic"), tmp.path()).unwrap();
+        assert_eq!(topic.partitions_len(), 2, "doesn't pick up the `my` topic");
+        assert_eq!(topic.append(1, "XX".as_bytes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn it_errors_on_missing_partitions() {
+        let tmp = tempdir().unwrap();
+        {
+            Topic::create(String::from("topic"), tmp.path(), 3, MaxBytes(64, 32).into()).unwrap();
+        }
+        fs::remove_dir_all(tmp.path().join("topic-1")).unwrap();
+        let res = Topic::open(String::from("topic"), tmp.path());
+        assert!(matches!(res, Err(Error::PartitionNotFound(_))));
+        let res = Topic::open(String::from("nope"), tmp.path());
+        assert!(matches!(res, Err(Error::PartitionNotFound(_))));
+    }
+
+    #[test]
+    fn it_rejects_bad_names() {
+        let tmp = tempdir().unwrap();
+        for name in &["", "..", "a/b", "spaced out"] {
+            let res = Topic::create(String::from(*name), tmp.path(), 1, MaxBytes(64, 32).into());
+            assert!(matches!(res, Err(Error::InvalidConfig(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn it_sends_with_partitioner() {
+        let tmp = tempdir().unwrap();
+        let mut topic = Topic::create(String::from("topic"), tmp.path(), 4, MaxBytes(1024, 1024).into()).unwrap();
+
+        let (id, _) = topic.send(Some("foobar".as_bytes()), "XX".as_bytes()).unwrap();
+        assert_eq!(id, to_positive(murmur2("foobar".as_bytes())) % 4, "keyed records hash");
+        let message = &topic.partition(id).unwrap().partition.read(0, 1024).unwrap()[0];
+        let record = Record::from_slice(&message.payload).unwrap();
+        assert_eq!((record.key, record.value), (Some(b"foobar".to_vec()), Some(b"XX".to_vec())));
+
+        // keyless records stick to a partition per send, then move on
+        let sent: Vec<u32> = (0..20).map(|_| topic.send(None, "XX".as_bytes()).unwrap().0).collect();
+        assert!(sent.windows(2).all(|w| w[0] != w[1]), "{:?}", sent);
+        assert!(sent.iter().collect::<BTreeSet<_>>().len() > 1);
+
+        topic.set_partitioner(RoundRobinPartitioner::new());
+        let sent: Vec<(u32, Offset)> = (0..5).map(|_| topic.send(None, "XX".as_bytes()).unwrap()).collect();
+        assert_eq!(sent.iter().map(|s| s.0).collect::<Vec<u32>>(), vec![0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn it_parses_partition_dirs() {
+        assert_eq!(parse_partition_dir("my-topic-12"), Some(("my-topic", 12)));
+        assert_eq!(parse_partition_dir("topic"), None);
+        assert_eq!(parse_partition_dir("-1"), None);
+        assert_eq!(parse_partition_dir("topic-x"), None);
+    }
+}
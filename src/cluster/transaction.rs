@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, Result};
+use crate::cluster;
+use crate::cluster::manager::{LogManager, SharedPartition, TopicPartitionId};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::config::{CleanupPolicy, PartitionConfig};
+use crate::partition::record::{Record};
+use crate::partition::segment::{now_ms};
+use crate::partition::transaction::{Marker};
+
+// transaction state is kept in a compacted partition keyed by transactional id,
+// the same way kafka keeps it
+pub const TRANSACTION_TOPIC: &str = "__transaction_state";
+const STATE_VERSION: i8 = 1;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnState {
+    Empty,
+    Ongoing,
+    PrepareCommit,
+    PrepareAbort,
+    CompleteCommit,
+    CompleteAbort,
+}
+
+impl TxnState {
+    fn as_i8(self) -> i8 {
+        match self {
+            TxnState::Empty => 0,
+            TxnState::Ongoing => 1,
+            TxnState::PrepareCommit => 2,
+            TxnState::PrepareAbort => 3,
+            TxnState::CompleteCommit => 4,
+            TxnState::CompleteAbort => 5,
+        }
+    }
+
+    fn from_i8(state: i8) -> Result<TxnState> {
+        Ok(match state {
+            0 => TxnState::Empty,
+            1 => TxnState::Ongoing,
+            2 => TxnState::PrepareCommit,
+            3 => TxnState::PrepareAbort,
+            4 => TxnState::CompleteCommit,
+            5 => TxnState::CompleteAbort,
+            state => return Err(Error::CorruptRecord(format!("unknown transaction state {}", state))),
+        })
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionMeta {
+    pub producer_id: i64,
+    pub epoch: i16,
+    pub timeout_ms: u32,
+    pub state: TxnState,
+    pub partitions: BTreeSet<TopicPartitionId>,
+    pub started_ms: u64,
+}
+
+impl TransactionMeta {
+    //   version i8 | producer_id i64 | epoch i16 | timeout_ms u32 | state i8 |
+    //   started_ms i64 | [topic string | partition u32]
+    fn to_vec(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.i8(STATE_VERSION);
+        enc.i64(self.producer_id);
+        enc.i16(self.epoch);
+        enc.u32(self.timeout_ms);
+        enc.i8(self.state.as_i8());
+        enc.i64(self.started_ms as i64);
+        enc.array_len(self.partitions.len());
+        for tp in &self.partitions {
+            enc.string(&tp.topic);
+            enc.u32(tp.partition);
+        }
+        enc.into_vec()
+    }
+
+    fn from_slice(raw: &[u8]) -> Result<TransactionMeta> {
+        let mut dec = Decoder::new(raw);
+        if dec.i8()? != STATE_VERSION {
+            return Err(Error::CorruptRecord(String::from("unknown transaction state version")))
+        }
+        let mut meta = TransactionMeta{
+            producer_id: dec.i64()?,
+            epoch: dec.i16()?,
+            timeout_ms: dec.u32()?,
+            state: TxnState::from_i8(dec.i8()?)?,
+            started_ms: dec.i64()? as u64,
+            partitions: BTreeSet::new(),
+        };
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            let topic = dec.string()?;
+            meta.partitions.insert(TopicPartitionId::new(&topic, dec.u32()?));
+        }
+        Ok(meta)
+    }
+
+    fn check(&self, producer_id: i64, epoch: i16) -> Result<()> {
+        if producer_id != self.producer_id {
+            return Err(Error::InvalidTxnState(format!("producer {} doesn't own the transactional id", producer_id)))
+        }
+        if epoch < self.epoch {
+            return Err(Error::ProducerFenced{ producer_id, epoch })
+        }
+        Ok(())
+    }
+}
+
+
+// Runs the transactions of every transactional id. A transaction's partitions
+// are registered before the producer writes to them; ending it writes a
+// COMMIT or ABORT marker into each. The PREPARE state is persisted before the
+// markers, so a restart finishes whatever was half done.
+pub struct TransactionCoordinator {
+    logs: Arc<LogManager>,
+    log: SharedPartition,
+    transactions: Mutex<BTreeMap<String, TransactionMeta>>,
+}
+
+impl TransactionCoordinator {
+    pub fn open(logs: Arc<LogManager>) -> Result<TransactionCoordinator> {
+        let config = PartitionConfig{ cleanup_policy: CleanupPolicy::Compact, ..logs.defaults().clone() };
+        let log = logs.create(TRANSACTION_TOPIC, 0, Some(config))?;
+        let mut transactions = BTreeMap::new();
+        for (key, value) in cluster::load_compacted(&log.lock().unwrap())? {
+            transactions.insert(String::from_utf8_lossy(&key).to_string(), TransactionMeta::from_slice(&value)?);
+        }
+        let coordinator = TransactionCoordinator{ logs, log, transactions: Mutex::new(transactions) };
+        coordinator.recover()?;
+        Ok(coordinator)
+    }
+
+    fn recover(&self) -> Result<()> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let prepared: Vec<String> = transactions.iter()
+            .filter(|(_, meta)| matches!(meta.state, TxnState::PrepareCommit | TxnState::PrepareAbort))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in prepared {
+            let meta = transactions.get_mut(&id).unwrap();
+            self.complete(&id, meta)?;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, id: &str, meta: &TransactionMeta) -> Result<()> {
+        let record = Record::new(now_ms(), Some(id.as_bytes()), Some(&meta.to_vec()));
+        let mut log = self.log.lock().unwrap();
+        log.append(&record.to_vec()?)?;
+        log.flush()
+    }
+
+    pub fn transaction(&self, transactional_id: &str) -> Option<TransactionMeta> {
+        self.transactions.lock().unwrap().get(transactional_id).cloned()
+    }
+
+    pub fn init_producer_id(&self, transactional_id: &str, timeout_ms: u32) -> Result<(i64, i16)> {
+        // a new producer for the id fences the old one: the epoch goes up and
+        // whatever it left open is aborted
+        self.abort_timed_out()?;
+        let mut transactions = self.transactions.lock().unwrap();
+        let meta = match transactions.get(transactional_id).cloned() {
+            Some(mut meta) => {
+                if meta.epoch < i16::MAX { meta.epoch += 1 }
+                if meta.state == TxnState::Ongoing {
+                    // the abort markers carry the new epoch, fencing the old
+                    // producer in every partition it wrote to
+                    meta.state = TxnState::PrepareAbort;
+                    self.persist(transactional_id, &meta)?;
+                    self.complete(transactional_id, &mut meta)?;
+                }
+                if meta.epoch == i16::MAX {
+                    meta.producer_id = self.logs.next_producer_id()?;
+                    meta.epoch = 0;
+                }
+                meta.timeout_ms = timeout_ms;
+                meta.state = TxnState::Empty;
+                meta.partitions.clear();
+                meta
+            },
+            None => TransactionMeta{
+                producer_id: self.logs.next_producer_id()?,
+                epoch: 0,
+                timeout_ms,
+                state: TxnState::Empty,
+                partitions: BTreeSet::new(),
+                started_ms: 0,
+            },
+        };
+        self.persist(transactional_id, &meta)?;
+        let ids = (meta.producer_id, meta.epoch);
+        transactions.insert(String::from(transactional_id), meta);
+        Ok(ids)
+    }
+
+    pub fn add_partitions(&self, transactional_id: &str, producer_id: i64, epoch: i16, partitions: &[TopicPartitionId]) -> Result<()> {
+        self.abort_timed_out()?;
+        let mut transactions = self.transactions.lock().unwrap();
+        let meta = match transactions.get_mut(transactional_id) {
+            Some(meta) => meta,
+            None => return Err(Error::InvalidTxnState(format!("unknown transactional id {}", transactional_id))),
+        };
+        meta.check(producer_id, epoch)?;
+        match meta.state {
+            TxnState::Ongoing => {
+                if partitions.iter().all(|tp| meta.partitions.contains(tp)) { return Ok(()) }
+            },
+            TxnState::Empty | TxnState::CompleteCommit | TxnState::CompleteAbort => {
+                meta.state = TxnState::Ongoing;
+                meta.started_ms = now_ms();
+                meta.partitions.clear();
+            },
+            state => return Err(Error::InvalidTxnState(format!("transaction is in {:?}", state))),
+        }
+        meta.partitions.extend(partitions.iter().cloned());
+        self.persist(transactional_id, meta)
+    }
+
+    pub fn end_transaction(&self, transactional_id: &str, producer_id: i64, epoch: i16, commit: bool) -> Result<()> {
+        self.abort_timed_out()?;
+        let mut transactions = self.transactions.lock().unwrap();
+        let meta = match transactions.get_mut(transactional_id) {
+            Some(meta) => meta,
+            None => return Err(Error::InvalidTxnState(format!("unknown transactional id {}", transactional_id))),
+        };
+        meta.check(producer_id, epoch)?;
+        match (meta.state, commit) {
+            (TxnState::Ongoing, _) => {},
+            // a retried end call, the markers are written
+            (TxnState::CompleteCommit, true) | (TxnState::CompleteAbort, false) => return Ok(()),
+            // nothing was written, there is nothing to abort
+            (TxnState::Empty, false) => return Ok(()),
+            (state, _) => return Err(Error::InvalidTxnState(format!("can't end a transaction in {:?}", state))),
+        }
+        meta.state = if commit { TxnState::PrepareCommit } else { TxnState::PrepareAbort };
+        self.persist(transactional_id, meta)?;
+        self.complete(transactional_id, meta)
+    }
+
+    fn complete(&self, transactional_id: &str, meta: &mut TransactionMeta) -> Result<()> {
+        // writes the markers of a prepared transaction and moves it to complete
+        let (marker, state) = match meta.state {
+            TxnState::PrepareCommit => (Marker::Commit, TxnState::CompleteCommit),
+            TxnState::PrepareAbort => (Marker::Abort, TxnState::CompleteAbort),
+            state => return Err(Error::InvalidTxnState(format!("{:?} has no markers to write", state))),
+        };
+        for tp in &meta.partitions {
+            // a partition deleted since needs no marker
+            if let Some(partition) = self.logs.get(&tp.topic, tp.partition) {
+                partition.lock().unwrap().write_marker(meta.producer_id, meta.epoch, marker)?;
+            }
+        }
+        meta.state = state;
+        self.persist(transactional_id, meta)
+    }
+
+    pub fn abort_timed_out(&self) -> Result<usize> {
+        // a transaction open longer than its timeout is aborted under a bumped
+        // epoch, so the producer that left it can't write to it any more
+        let now = now_ms();
+        let mut transactions = self.transactions.lock().unwrap();
+        let mut aborted = 0;
+        for (id, meta) in transactions.iter_mut() {
+            if meta.state != TxnState::Ongoing || now < meta.started_ms + meta.timeout_ms as u64 { continue }
+            if meta.epoch < i16::MAX { meta.epoch += 1 }
+            meta.state = TxnState::PrepareAbort;
+            self.persist(id, meta)?;
+            self.complete(id, meta)?;
+            aborted += 1;
+        }
+        Ok(aborted)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+    use crate::partition::segment::{MaxBytes};
+    use crate::partition::transaction::{TRANSACTIONAL};
+
+    fn open(dir: &std::path::Path) -> Arc<LogManager> {
+        let logs = LogManager::open(vec![dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap();
+        logs.create("events", 0, None).unwrap();
+        logs.create("events", 1, None).unwrap();
+        Arc::new(logs)
+    }
+
+    fn write(logs: &LogManager, partition: u32, producer_id: i64, epoch: i16, sequence: i32) -> Result<u64> {
+        let mut record = Record::new(0, None, Some(b"XX")).with_producer(producer_id, epoch, sequence);
+        record.attributes = TRANSACTIONAL;
+        logs.get("events", partition).unwrap().lock().unwrap().append_batch(&[record])
+    }
+
+    #[test]
+    fn it_commits_and_aborts() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let coordinator = TransactionCoordinator::open(logs.clone()).unwrap();
+        let (pid, epoch) = coordinator.init_producer_id("tx", 60_000).unwrap();
+        let both = [TopicPartitionId::new("events", 0), TopicPartitionId::new("events", 1)];
+
+        coordinator.add_partitions("tx", pid, epoch, &both).unwrap();
+        write(&logs, 0, pid, epoch, 0).unwrap();
+        write(&logs, 1, pid, epoch, 0).unwrap();
+        let events0 = logs.get("events", 0).unwrap();
+        assert_eq!(events0.lock().unwrap().last_stable_offset(), 0, "open transaction");
+        coordinator.end_transaction("tx", pid, epoch, true).unwrap();
+        assert_eq!(events0.lock().unwrap().last_stable_offset(), 2, "record and marker are stable");
+        assert_eq!(events0.lock().unwrap().read_committed(0, 1024).unwrap().0.len(), 1);
+        coordinator.end_transaction("tx", pid, epoch, true).unwrap();
+
+        coordinator.add_partitions("tx", pid, epoch, &both[..1]).unwrap();
+        write(&logs, 0, pid, epoch, 1).unwrap();
+        coordinator.end_transaction("tx", pid, epoch, false).unwrap();
+        let partition = events0.lock().unwrap();
+        assert_eq!(partition.aborted_transactions(0, 4).unwrap().len(), 1);
+        assert_eq!(partition.read_committed(0, 1024).unwrap().0.iter().map(|m| m.offset).collect::<Vec<u64>>(), vec![0]);
+        assert_eq!(coordinator.transaction("tx").unwrap().state, TxnState::CompleteAbort);
+    }
+
+    #[test]
+    fn it_fences_old_producers() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let coordinator = TransactionCoordinator::open(logs.clone()).unwrap();
+        let (pid, epoch) = coordinator.init_producer_id("tx", 60_000).unwrap();
+        coordinator.add_partitions("tx", pid, epoch, &[TopicPartitionId::new("events", 0)]).unwrap();
+        write(&logs, 0, pid, epoch, 0).unwrap();
+
+        let (new_pid, new_epoch) = coordinator.init_producer_id("tx", 60_000).unwrap();
+        assert_eq!((new_pid, new_epoch), (pid, epoch + 1));
+        let partition = logs.get("events", 0).unwrap();
+        assert_eq!(partition.lock().unwrap().last_stable_offset(), 2, "the open transaction was aborted");
+        assert!(matches!(coordinator.end_transaction("tx", pid, epoch, true), Err(Error::ProducerFenced{ .. })));
+        assert!(matches!(write(&logs, 0, pid, epoch, 1), Err(Error::ProducerFenced{ .. })));
+    }
+
+    #[test]
+    fn it_aborts_timed_out_transactions() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let coordinator = TransactionCoordinator::open(logs.clone()).unwrap();
+        let (pid, epoch) = coordinator.init_producer_id("tx", 0).unwrap();
+        coordinator.add_partitions("tx", pid, epoch, &[TopicPartitionId::new("events", 0)]).unwrap();
+        write(&logs, 0, pid, epoch, 0).unwrap();
+        assert_eq!(coordinator.abort_timed_out().unwrap(), 1);
+        assert_eq!(coordinator.transaction("tx").unwrap().epoch, epoch + 1);
+        assert!(matches!(coordinator.end_transaction("tx", pid, epoch, true), Err(Error::ProducerFenced{ .. })));
+    }
+
+    #[test]
+    fn it_recovers_prepared_transactions() {
+        let tmp = tempdir().unwrap();
+        let logs = open(tmp.path());
+        let (pid, epoch) = {
+            let coordinator = TransactionCoordinator::open(logs.clone()).unwrap();
+            let (pid, epoch) = coordinator.init_producer_id("tx", 60_000).unwrap();
+            coordinator.add_partitions("tx", pid, epoch, &[TopicPartitionId::new("events", 0)]).unwrap();
+            write(&logs, 0, pid, epoch, 0).unwrap();
+            // crashed between persisting the prepare and writing the markers
+            let mut meta = coordinator.transaction("tx").unwrap();
+            meta.state = TxnState::PrepareCommit;
+            coordinator.persist("tx", &meta).unwrap();
+            (pid, epoch)
+        };
+
+        let coordinator = TransactionCoordinator::open(logs.clone()).unwrap();
+        assert_eq!(coordinator.transaction("tx").unwrap().state, TxnState::CompleteCommit);
+        assert_eq!(logs.get("events", 0).unwrap().lock().unwrap().last_stable_offset(), 2);
+        assert_eq!(coordinator.init_producer_id("tx", 60_000).unwrap(), (pid, epoch + 1));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{fmt, io};
+use std::path::PathBuf;
+
+use crate::Offset;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+
+#[derive(Debug)]
+pub enum Error {
+    // the offset isn't in the log (below the oldest segment or past the log end)
+    OffsetOutOfRange(Offset),
+    // bytes on disk don't make sense (index pointing past the log end, short header)
+    CorruptRecord(String),
+    // a segment can't take another message (positions are u32 in the index)
+    SegmentFull,
+    // an index has no room left for another entry
+    IndexFull,
+    // a message (header included) is bigger than max.message.bytes
+    MessageTooLarge { size: u64, max: u64 },
+    InvalidConfig(String),
+    // there is no partition directory at the path
+    PartitionNotFound(PathBuf),
+    // another writer holds the partition directory lock
+    Locked(PathBuf),
+    // the partition was opened read-only
+    ReadOnly,
+    // bytes off the wire that aren't a request we understand
+    InvalidRequest(String),
+    // gave up waiting (for buffer memory, a response, replicas)
+    Timeout(String),
+    // a producer with a newer epoch took over the producer id
+    ProducerFenced { producer_id: i64, epoch: i16 },
+    // an idempotent producer skipped or reordered sequences
+    OutOfOrderSequence { producer_id: i64, expected: i32, got: i32 },
+    // a transaction call that doesn't fit the transaction's state
+    InvalidTxnState(String),
+    // the group doesn't know the member (it left or its session timed out)
+    UnknownMember(String),
+    // a group member is behind the group's current generation
+    IllegalGeneration { generation: i32, current: i32 },
+    // the group is rebalancing, the member has to join again
+    RebalanceInProgress,
+    // a member's protocol doesn't match what the rest of the group speaks
+    InconsistentGroupProtocol(String),
+    // this broker doesn't lead the partition, it follows or leadership moved
+    NotLeader(String),
+    // a request made under another leader epoch than the partition's
+    FencedLeaderEpoch { epoch: i32, current: i32 },
+    // the partition has no leader, none of its replicas is fit to lead
+    LeaderNotAvailable(String),
+    // a broker heartbeat under a registration the controller no longer has,
+    // the broker registers again
+    StaleBrokerEpoch(String),
+    // a request only the controller answers, sent to another broker
+    NotController(String),
+    // a broker answered a request with an error code
+    Remote { code: i16, message: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OffsetOutOfRange(offset) => write!(f, "offset {} is out of range", offset),
+            Error::CorruptRecord(msg) => write!(f, "corrupt record: {}", msg),
+            Error::SegmentFull => write!(f, "segment is full"),
+            Error::IndexFull => write!(f, "index is full"),
+            Error::MessageTooLarge { size, max } => {
+                write!(f, "message of {} bytes is larger than max.message.bytes {}", size, max)
+            },
+            Error::InvalidConfig(msg) => write!(f, "invalid config: {}", msg),
+            Error::PartitionNotFound(path) => write!(f, "no partition at {}", path.display()),
+            Error::Locked(path) => write!(f, "{} is locked by another writer", path.display()),
+            Error::ReadOnly => write!(f, "partition is opened read-only"),
+            Error::InvalidRequest(msg) => write!(f, "invalid request: {}", msg),
+            Error::Timeout(msg) => write!(f, "timed out: {}", msg),
+            Error::ProducerFenced { producer_id, epoch } => {
+                write!(f, "producer {} epoch {} was fenced by a newer epoch", producer_id, epoch)
+            },
+            Error::OutOfOrderSequence { producer_id, expected, got } => {
+                write!(f, "producer {} sent sequence {}, expected {}", producer_id, got, expected)
+            },
+            Error::InvalidTxnState(msg) => write!(f, "invalid transaction state: {}", msg),
+            Error::UnknownMember(id) => write!(f, "unknown group member {}", id),
+            Error::IllegalGeneration { generation, current } => {
+                write!(f, "generation {} is not the group's current generation {}", generation, current)
+            },
+            Error::RebalanceInProgress => write!(f, "group is rebalancing"),
+            Error::InconsistentGroupProtocol(msg) => write!(f, "inconsistent group protocol: {}", msg),
+            Error::NotLeader(partition) => write!(f, "not the leader of {}", partition),
+            Error::FencedLeaderEpoch { epoch, current } => {
+                write!(f, "leader epoch {} is not the partition's current epoch {}", epoch, current)
+            },
+            Error::LeaderNotAvailable(partition) => write!(f, "{} has no leader", partition),
+            Error::StaleBrokerEpoch(msg) => write!(f, "stale broker epoch: {}", msg),
+            Error::NotController(msg) => write!(f, "not the controller: {}", msg),
+            Error::Remote { code, message } => write!(f, "broker error {}: {}", code, message),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// io::Error isn't Clone, a copy keeps its kind and message. Producers need
+// this to fail every record of a batch with the same error
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            Error::OffsetOutOfRange(offset) => Error::OffsetOutOfRange(*offset),
+            Error::CorruptRecord(msg) => Error::CorruptRecord(msg.clone()),
+            Error::SegmentFull => Error::SegmentFull,
+            Error::IndexFull => Error::IndexFull,
+            Error::MessageTooLarge { size, max } => Error::MessageTooLarge { size: *size, max: *max },
+            Error::InvalidConfig(msg) => Error::InvalidConfig(msg.clone()),
+            Error::PartitionNotFound(path) => Error::PartitionNotFound(path.clone()),
+            Error::Locked(path) => Error::Locked(path.clone()),
+            Error::ReadOnly => Error::ReadOnly,
+            Error::InvalidRequest(msg) => Error::InvalidRequest(msg.clone()),
+            Error::Timeout(msg) => Error::Timeout(msg.clone()),
+            Error::ProducerFenced { producer_id, epoch } => {
+                Error::ProducerFenced { producer_id: *producer_id, epoch: *epoch }
+            },
+            Error::OutOfOrderSequence { producer_id, expected, got } => {
+                Error::OutOfOrderSequence { producer_id: *producer_id, expected: *expected, got: *got }
+            },
+            Error::InvalidTxnState(msg) => Error::InvalidTxnState(msg.clone()),
+            Error::UnknownMember(id) => Error::UnknownMember(id.clone()),
+            Error::IllegalGeneration { generation, current } => {
+                Error::IllegalGeneration { generation: *generation, current: *current }
+            },
+            Error::RebalanceInProgress => Error::RebalanceInProgress,
+            Error::InconsistentGroupProtocol(msg) => Error::InconsistentGroupProtocol(msg.clone()),
+            Error::NotLeader(partition) => Error::NotLeader(partition.clone()),
+            Error::FencedLeaderEpoch { epoch, current } => Error::FencedLeaderEpoch { epoch: *epoch, current: *current },
+            Error::LeaderNotAvailable(partition) => Error::LeaderNotAvailable(partition.clone()),
+            Error::StaleBrokerEpoch(msg) => Error::StaleBrokerEpoch(msg.clone()),
+            Error::NotController(msg) => Error::NotController(msg.clone()),
+            Error::Remote { code, message } => Error::Remote { code: *code, message: message.clone() },
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), err.to_string())),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error { Error::Io(err) }
+}
+
+// Read/Write/Seek impls still have to speak io::Error
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::ReadOnly => io::Error::new(io::ErrorKind::PermissionDenied, err.to_string()),
+            Error::Locked(_) => io::Error::new(io::ErrorKind::WouldBlock, err.to_string()),
+            Error::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, err.to_string()),
+            Error::PartitionNotFound(_) => io::Error::new(io::ErrorKind::NotFound, err.to_string()),
+            Error::OffsetOutOfRange(_) | Error::InvalidConfig(_) | Error::MessageTooLarge { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+            },
+            _ => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_wraps_io_errors() {
+        let err: Error = io::Error::new(io::ErrorKind::NotFound, "gone").into();
+        assert!(matches!(err, Error::Io(_)));
+        assert_eq!(err.to_string(), "gone");
+    }
+
+    #[test]
+    fn it_converts_back_to_io_errors() {
+        let err: io::Error = Error::ReadOnly.into();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err: io::Error = Error::CorruptRecord(String::from("short header")).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "corrupt record: short header");
+    }
+
+    #[test]
+    fn it_clones_io_errors() {
+        let err: Error = io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+        match err.clone() {
+            Error::Io(copy) => assert_eq!((copy.kind(), copy.to_string()), (io::ErrorKind::ConnectionReset, String::from("reset"))),
+            other => panic!("{:?}", other),
+        }
+    }
+}
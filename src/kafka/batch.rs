@@ -0,0 +1,338 @@
+use crate::{Error, Result};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::message::{Message};
+use crate::partition::record::{Record};
+use crate::partition::transaction::{CONTROL, TRANSACTIONAL};
+
+pub const MAGIC_V2: i8 = 2;
+const COMPRESSION_MASK: i16 = 0x07;
+// base offset and batch length come before what the batch length counts
+const BATCH_PREFIX_LEN: usize = 8 + 4;
+const NO_TIMESTAMP: i64 = -1;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchRecord {
+    pub offset_delta: i32,
+    pub timestamp: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub headers: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl BatchRecord {
+    pub fn new(offset_delta: i32, timestamp: i64, key: Option<&[u8]>, value: Option<&[u8]>) -> BatchRecord {
+        BatchRecord{
+            offset_delta,
+            timestamp,
+            key: key.map(|k| k.to_vec()),
+            value: value.map(|v| v.to_vec()),
+            headers: vec![],
+        }
+    }
+}
+
+
+// Kafka's v2 record batch (magic 2), what clients produce and expect back from
+// a fetch. Compressed batches aren't supported yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordBatch {
+    pub base_offset: i64,
+    pub partition_leader_epoch: i32,
+    pub attributes: i16,
+    pub last_offset_delta: i32,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub base_sequence: i32,
+    pub records: Vec<BatchRecord>,
+}
+
+impl RecordBatch {
+    pub fn new(base_offset: i64, records: Vec<BatchRecord>) -> RecordBatch {
+        let last_offset_delta = records.last().map_or(0, |r| r.offset_delta);
+        RecordBatch{
+            base_offset,
+            partition_leader_epoch: 0,
+            attributes: 0,
+            last_offset_delta,
+            producer_id: -1,
+            producer_epoch: -1,
+            base_sequence: -1,
+            records,
+        }
+    }
+
+    pub fn from_messages(messages: &[Message]) -> Vec<RecordBatch> {
+        // a new batch starts wherever the producer or the transactional and
+        // control bits change, so read_committed clients can tell what to skip.
+        // Payloads that aren't records (raw appends) go out as unkeyed values
+        let mut batches: Vec<RecordBatch> = vec![];
+        for message in messages {
+            let record = Record::from_slice(&message.payload).ok();
+            let (producer_id, producer_epoch, sequence, attributes) = match &record {
+                Some(r) => (r.producer_id, r.producer_epoch, r.sequence, (r.attributes & (TRANSACTIONAL | CONTROL)) as i16),
+                None => (-1, -1, -1, 0),
+            };
+            let fits = batches.last().is_some_and(|b| {
+                b.producer_id == producer_id && b.producer_epoch == producer_epoch
+                    && b.attributes == attributes && attributes & CONTROL as i16 == 0
+            });
+            if !fits {
+                let mut batch = RecordBatch::new(message.offset as i64, vec![]);
+                batch.producer_id = producer_id;
+                batch.producer_epoch = producer_epoch;
+                batch.base_sequence = sequence;
+                batch.attributes = attributes;
+                batches.push(batch);
+            }
+            let batch = batches.last_mut().unwrap();
+            let delta = (message.offset as i64 - batch.base_offset) as i32;
+            batch.last_offset_delta = delta;
+            batch.records.push(match record {
+                Some(record) => BatchRecord{
+                    offset_delta: delta,
+                    timestamp: record.timestamp as i64,
+                    key: record.key,
+                    value: record.value,
+                    headers: record.headers,
+                },
+                None => BatchRecord::new(delta, NO_TIMESTAMP, None, Some(&message.payload)),
+            });
+        }
+        batches
+    }
+
+    pub fn decode_all(raw: &[u8]) -> Result<Vec<RecordBatch>> {
+        let mut dec = Decoder::new(raw);
+        let mut batches = vec![];
+        while !dec.is_empty() {
+            batches.push(RecordBatch::decode(&mut dec)?);
+        }
+        Ok(batches)
+    }
+
+    pub fn decode(dec: &mut Decoder) -> Result<RecordBatch> {
+        let base_offset = dec.i64()?;
+        let len = dec.i32()?;
+        if len < 0 { return Err(Error::InvalidRequest(format!("batch length {}", len))) }
+        let mut dec = Decoder::new(dec.take(len as usize)?);
+        let partition_leader_epoch = dec.i32()?;
+        let magic = dec.i8()?;
+        if magic != MAGIC_V2 {
+            return Err(Error::InvalidRequest(format!("message format v{} isn't supported", magic)))
+        }
+        let crc = dec.u32()?;
+        let rest = dec.take(dec.remaining())?;
+        if crc32c(rest) != crc {
+            return Err(Error::CorruptRecord(String::from("record batch crc mismatch")))
+        }
+
+        let mut dec = Decoder::new(rest);
+        let attributes = dec.i16()?;
+        if attributes & COMPRESSION_MASK != 0 {
+            return Err(Error::InvalidRequest(String::from("compressed batches aren't supported")))
+        }
+        let last_offset_delta = dec.i32()?;
+        let base_timestamp = dec.i64()?;
+        let _max_timestamp = dec.i64()?;
+        let producer_id = dec.i64()?;
+        let producer_epoch = dec.i16()?;
+        let base_sequence = dec.i32()?;
+        let count = dec.i32()?;
+        if count < 0 || count as usize > dec.remaining() {
+            return Err(Error::InvalidRequest(format!("batch claims {} records", count)))
+        }
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = dec.varint()?;
+            if len < 0 { return Err(Error::InvalidRequest(format!("record length {}", len))) }
+            let mut rec = Decoder::new(dec.take(len as usize)?);
+            let _attributes = rec.i8()?;
+            let timestamp = base_timestamp.checked_add(rec.varlong()?)
+                .ok_or_else(|| Error::InvalidRequest(String::from("record timestamp overflows")))?;
+            let offset_delta = rec.varint()?;
+            let key = rec.varbytes()?.map(|k| k.to_vec());
+            let value = rec.varbytes()?.map(|v| v.to_vec());
+            let header_count = rec.varint()?;
+            let mut headers = vec![];
+            for _ in 0..header_count.max(0) {
+                let name = match rec.varbytes()? {
+                    Some(name) => String::from_utf8_lossy(name).to_string(),
+                    None => return Err(Error::InvalidRequest(String::from("null header key"))),
+                };
+                headers.push((name, rec.varbytes()?.map(|v| v.to_vec())));
+            }
+            records.push(BatchRecord{ offset_delta, timestamp, key, value, headers });
+        }
+        Ok(RecordBatch{
+            base_offset,
+            partition_leader_epoch,
+            attributes,
+            last_offset_delta,
+            producer_id,
+            producer_epoch,
+            base_sequence,
+            records,
+        })
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) {
+        let base_timestamp = self.records.first().map_or(NO_TIMESTAMP, |r| r.timestamp);
+        let max_timestamp = self.records.iter().map(|r| r.timestamp).max().unwrap_or(NO_TIMESTAMP);
+
+        // everything after the crc is what the crc covers
+        let mut body = Encoder::new();
+        body.i16(self.attributes);
+        body.i32(self.last_offset_delta);
+        body.i64(base_timestamp);
+        body.i64(max_timestamp);
+        body.i64(self.producer_id);
+        body.i16(self.producer_epoch);
+        body.i32(self.base_sequence);
+        body.i32(self.records.len() as i32);
+        for record in &self.records {
+            let mut rec = Encoder::new();
+            rec.i8(0);
+            rec.varlong(record.timestamp - base_timestamp);
+            rec.varint(record.offset_delta);
+            rec.varbytes(record.key.as_deref());
+            rec.varbytes(record.value.as_deref());
+            rec.varint(record.headers.len() as i32);
+            for (name, value) in &record.headers {
+                rec.varbytes(Some(name.as_bytes()));
+                rec.varbytes(value.as_deref());
+            }
+            body.varint(rec.len() as i32);
+            body.raw(rec.as_slice());
+        }
+
+        enc.i64(self.base_offset);
+        enc.i32((4 + 1 + 4 + body.len()) as i32);
+        enc.i32(self.partition_leader_epoch);
+        enc.i8(MAGIC_V2);
+        enc.u32(crc32c(body.as_slice()));
+        enc.raw(body.as_slice());
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        self.encode(&mut enc);
+        enc.into_vec()
+    }
+
+    pub fn is_complete(raw: &[u8]) -> bool {
+        // fetch responses may cut the last batch short, clients drop the tail
+        if raw.len() < BATCH_PREFIX_LEN { return false }
+        let len = i32::from_be_bytes([raw[8], raw[9], raw[10], raw[11]]);
+        len >= 0 && raw.len() >= BATCH_PREFIX_LEN + len as usize
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::transaction::{Marker};
+
+    #[test]
+    fn it_computes_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[]), 0);
+    }
+
+    #[test]
+    fn it_round_trips_batches() {
+        let mut record = BatchRecord::new(2, 1_500, Some(b"key"), None);
+        record.headers.push((String::from("trace"), Some(b"abc".to_vec())));
+        let batch = RecordBatch::new(40, vec![
+            BatchRecord::new(0, 1_000, None, Some(b"YELLOW SUBMARINE")),
+            record,
+        ]);
+        let raw = batch.to_vec();
+        assert!(RecordBatch::is_complete(&raw));
+        assert!(!RecordBatch::is_complete(&raw[..raw.len() - 1]));
+
+        let decoded = RecordBatch::decode_all(&raw).unwrap();
+        assert_eq!(decoded, vec![batch]);
+        assert_eq!(decoded[0].last_offset_delta, 2);
+    }
+
+    #[test]
+    fn it_rejects_bad_batches() {
+        let mut raw = RecordBatch::new(0, vec![BatchRecord::new(0, 0, None, Some(b"XX"))]).to_vec();
+        let last = raw.len() - 1;
+        raw[last] ^= 0xff;
+        assert!(matches!(RecordBatch::decode_all(&raw), Err(Error::CorruptRecord(_))), "crc");
+        raw[16] = 1;
+        assert!(matches!(RecordBatch::decode_all(&raw), Err(Error::InvalidRequest(_))), "magic 1");
+
+        let mut raw = RecordBatch::new(0, vec![
+            BatchRecord::new(0, i64::MAX, None, Some(b"XX")),
+            BatchRecord::new(1, i64::MAX, None, Some(b"XX")),
+        ]).to_vec();
+        // the last record's timestamp delta, 0, made 1 past the base of i64::MAX
+        let delta = raw.len() - 7;
+        assert_eq!(raw[delta..], [0, 2, 1, 4, 88, 88, 0]);
+        raw[delta] = 2;
+        let crc = crc32c(&raw[21..]);
+        raw[17..21].copy_from_slice(&crc.to_be_bytes());
+        assert!(matches!(RecordBatch::decode_all(&raw), Err(Error::InvalidRequest(_))), "timestamp overflow");
+    }
+
+    #[test]
+    fn it_batches_messages() {
+        let mut record = Record::new(7, Some(b"k"), Some(b"v"));
+        record.headers.push((String::from("trace"), None));
+        let messages = vec![Message::new(3, 0, &record.to_vec().unwrap()), Message::new(5, 20, b"raw")];
+        let batch = &RecordBatch::from_messages(&messages)[0];
+        assert_eq!((batch.base_offset, batch.last_offset_delta), (3, 2), "compacted gaps are fine");
+        let mut expected = BatchRecord::new(0, 7, Some(b"k"), Some(b"v"));
+        expected.headers.push((String::from("trace"), None));
+        assert_eq!(batch.records[0], expected);
+        assert_eq!(batch.records[1].value, Some(b"raw".to_vec()));
+        assert!(RecordBatch::from_messages(&[]).is_empty());
+    }
+
+    #[test]
+    fn it_splits_transactional_batches() {
+        let mut txn = Record::new(7, None, Some(b"v")).with_producer(9, 1, 0);
+        txn.attributes = TRANSACTIONAL;
+        let messages = vec![
+            Message::new(0, 0, &Record::new(7, None, Some(b"v")).to_vec().unwrap()),
+            Message::new(1, 0, &txn.to_vec().unwrap()),
+            Message::new(2, 0, &txn.clone().with_producer(9, 1, 1).to_vec().unwrap()),
+            Message::new(3, 0, &Marker::Commit.record(8, 9, 1).to_vec().unwrap()),
+        ];
+        let batches = RecordBatch::from_messages(&messages);
+        let summary: Vec<(i64, i64, i16, i32, usize)> = batches.iter()
+            .map(|b| (b.base_offset, b.producer_id, b.attributes, b.base_sequence, b.records.len()))
+            .collect();
+        assert_eq!(summary, vec![(0, -1, 0, -1, 1), (1, 9, 0x10, 0, 2), (3, 9, 0x30, -1, 1)]);
+    }
+}
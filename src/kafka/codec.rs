@@ -0,0 +1,207 @@
+use byteorder::{ByteOrder, BigEndian};
+
+use crate::{Error, Result};
+
+
+// Reads the big-endian primitives of the kafka protocol off a request body
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> { Decoder{ buf, pos: 0 } }
+
+    pub fn remaining(&self) -> usize { self.buf.len() - self.pos }
+    pub fn is_empty(&self) -> bool { self.remaining() == 0 }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.remaining() {
+            return Err(Error::InvalidRequest(format!("wanted {} bytes, {} left", n, self.remaining())))
+        }
+        let taken = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    pub fn i8(&mut self) -> Result<i8> { Ok(self.take(1)?[0] as i8) }
+    pub fn boolean(&mut self) -> Result<bool> { Ok(self.i8()? != 0) }
+    pub fn i16(&mut self) -> Result<i16> { Ok(BigEndian::read_i16(self.take(2)?)) }
+    pub fn i32(&mut self) -> Result<i32> { Ok(BigEndian::read_i32(self.take(4)?)) }
+    pub fn u32(&mut self) -> Result<u32> { Ok(BigEndian::read_u32(self.take(4)?)) }
+    pub fn i64(&mut self) -> Result<i64> { Ok(BigEndian::read_i64(self.take(8)?)) }
+
+    pub fn nullable_string(&mut self) -> Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 { return Ok(None) }
+        let raw = self.take(len as usize)?;
+        match std::str::from_utf8(raw) {
+            Ok(s) => Ok(Some(String::from(s))),
+            Err(_) => Err(Error::InvalidRequest(String::from("string isn't utf-8"))),
+        }
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        match self.nullable_string()? {
+            Some(s) => Ok(s),
+            None => Err(Error::InvalidRequest(String::from("unexpected null string"))),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        let len = self.i32()?;
+        if len < 0 { return Ok(None) }
+        Ok(Some(self.take(len as usize)?))
+    }
+
+    pub fn array_len(&mut self) -> Result<Option<usize>> {
+        // None is a null array, which some requests use to mean "everything"
+        let len = self.i32()?;
+        if len < 0 { return Ok(None) }
+        if len as usize > self.remaining() {
+            return Err(Error::InvalidRequest(format!("array of {} can't fit in {} bytes", len, self.remaining())))
+        }
+        Ok(Some(len as usize))
+    }
+
+    pub fn varlong(&mut self) -> Result<i64> {
+        // zigzag encoded, as in the v2 record format
+        let mut raw: u64 = 0;
+        for shift in (0..70).step_by(7) {
+            let byte = self.take(1)?[0];
+            raw |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((raw >> 1) as i64 ^ -((raw & 1) as i64))
+            }
+        }
+        Err(Error::InvalidRequest(String::from("varint is too long")))
+    }
+
+    pub fn varint(&mut self) -> Result<i32> { Ok(self.varlong()? as i32) }
+
+    pub fn varbytes(&mut self) -> Result<Option<&'a [u8]>> {
+        let len = self.varint()?;
+        if len < 0 { return Ok(None) }
+        Ok(Some(self.take(len as usize)?))
+    }
+}
+
+
+// Builds a response (or request) body
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder { Encoder{ buf: vec![] } }
+
+    pub fn len(&self) -> usize { self.buf.len() }
+    pub fn is_empty(&self) -> bool { self.buf.is_empty() }
+    pub fn into_vec(self) -> Vec<u8> { self.buf }
+    pub fn as_slice(&self) -> &[u8] { &self.buf }
+
+    pub fn raw(&mut self, raw: &[u8]) { self.buf.extend_from_slice(raw) }
+    pub fn i8(&mut self, v: i8) { self.buf.push(v as u8) }
+    pub fn boolean(&mut self, v: bool) { self.i8(v as i8) }
+    pub fn i16(&mut self, v: i16) { self.raw(&v.to_be_bytes()) }
+    pub fn i32(&mut self, v: i32) { self.raw(&v.to_be_bytes()) }
+    pub fn u32(&mut self, v: u32) { self.raw(&v.to_be_bytes()) }
+    pub fn i64(&mut self, v: i64) { self.raw(&v.to_be_bytes()) }
+
+    pub fn string(&mut self, s: &str) {
+        self.i16(s.len() as i16);
+        self.raw(s.as_bytes());
+    }
+
+    pub fn nullable_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => self.string(s),
+            None => self.i16(-1),
+        }
+    }
+
+    pub fn bytes(&mut self, b: Option<&[u8]>) {
+        match b {
+            Some(b) => {
+                self.i32(b.len() as i32);
+                self.raw(b);
+            },
+            None => self.i32(-1),
+        }
+    }
+
+    pub fn array_len(&mut self, len: usize) { self.i32(len as i32) }
+    pub fn null_array(&mut self) { self.i32(-1) }
+
+    pub fn varlong(&mut self, v: i64) {
+        let mut raw = ((v << 1) ^ (v >> 63)) as u64;
+        while raw >= 0x80 {
+            self.buf.push((raw as u8 & 0x7f) | 0x80);
+            raw >>= 7;
+        }
+        self.buf.push(raw as u8);
+    }
+
+    pub fn varint(&mut self, v: i32) { self.varlong(v as i64) }
+
+    pub fn varbytes(&mut self, b: Option<&[u8]>) {
+        match b {
+            Some(b) => {
+                self.varint(b.len() as i32);
+                self.raw(b);
+            },
+            None => self.varint(-1),
+        }
+    }
+
+    pub fn put_i32_at(&mut self, at: usize, v: i32) {
+        // back-fills a length once what follows it is written
+        self.buf[at..at + 4].copy_from_slice(&v.to_be_bytes());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_varints() {
+        let mut enc = Encoder::new();
+        for v in &[0i64, -1, 1, 63, -64, 64, 300, i32::MAX as i64, i64::MIN] {
+            enc.varlong(*v);
+        }
+        assert_eq!(&enc.as_slice()[..5], &[0, 1, 2, 126, 127], "zigzag");
+        let raw = enc.into_vec();
+        let mut dec = Decoder::new(&raw);
+        for v in &[0i64, -1, 1, 63, -64, 64, 300, i32::MAX as i64, i64::MIN] {
+            assert_eq!(dec.varlong().unwrap(), *v);
+        }
+        assert!(dec.is_empty());
+    }
+
+    #[test]
+    fn it_round_trips_strings_and_bytes() {
+        let mut enc = Encoder::new();
+        enc.string("latka");
+        enc.nullable_string(None);
+        enc.bytes(Some(&[1, 2]));
+        enc.bytes(None);
+        let raw = enc.into_vec();
+        assert_eq!(&raw[..7], &[0, 5, b'l', b'a', b't', b'k', b'a']);
+
+        let mut dec = Decoder::new(&raw);
+        assert_eq!(dec.string().unwrap(), "latka");
+        assert_eq!(dec.nullable_string().unwrap(), None);
+        assert_eq!(dec.bytes().unwrap(), Some(&[1u8, 2][..]));
+        assert_eq!(dec.bytes().unwrap(), None);
+        assert!(matches!(dec.i8(), Err(Error::InvalidRequest(_))), "nothing left");
+    }
+
+    #[test]
+    fn it_rejects_impossible_arrays() {
+        let raw = [0, 0, 0, 9, 1];
+        assert!(matches!(Decoder::new(&raw).array_len(), Err(Error::InvalidRequest(_))));
+    }
+}
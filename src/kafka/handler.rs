@@ -0,0 +1,774 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result};
+use crate::cluster::Broker;
+use crate::cluster::group::{GroupCoordinator, JoinRequest};
+use crate::cluster::manager::{LogManager, SharedPartition, TopicPartitionId};
+use crate::cluster::offsets::{CommittedOffset, OffsetStore, MAX_METADATA_BYTES};
+use crate::cluster::replica::{PartitionState, ReplicaManager};
+use crate::cluster::transaction::{TransactionCoordinator};
+use crate::cluster::topic::{validate_name};
+use crate::kafka::{self, errors, RequestHeader, SUPPORTED_APIS};
+use crate::kafka::batch::{RecordBatch};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::producer::{next_sequence};
+use crate::partition::record::{Record};
+use crate::partition::transaction::{AbortedTxn, Isolation, TRANSACTIONAL};
+use crate::partition::segment::{now_ms};
+use crate::server::{ServerConfig};
+
+const LATEST_TIMESTAMP: i64 = -1;
+const EARLIEST_TIMESTAMP: i64 = -2;
+
+
+// What a fetch read from one partition
+struct PartitionRead {
+    high_watermark: i64,
+    last_stable_offset: i64,
+    log_start: i64,
+    // only for read_committed
+    aborted: Option<Vec<AbortedTxn>>,
+    records: Vec<u8>,
+}
+
+impl PartitionRead {
+    fn failed() -> PartitionRead {
+        PartitionRead{ high_watermark: -1, last_stable_offset: -1, log_start: -1, aborted: None, records: vec![] }
+    }
+}
+
+
+// Answers kafka requests from the partitions of one broker's LogManager
+pub struct Handler {
+    broker: Broker,
+    logs: Arc<LogManager>,
+    transactions: Arc<TransactionCoordinator>,
+    offsets: Arc<OffsetStore>,
+    groups: Arc<GroupCoordinator>,
+    replicas: Arc<ReplicaManager>,
+    config: ServerConfig,
+}
+
+impl Handler {
+    pub fn new(
+        broker: Broker,
+        logs: Arc<LogManager>,
+        transactions: Arc<TransactionCoordinator>,
+        offsets: Arc<OffsetStore>,
+        groups: Arc<GroupCoordinator>,
+        replicas: Arc<ReplicaManager>,
+        config: ServerConfig,
+    ) -> Handler {
+        Handler{ broker, logs, transactions, offsets, groups, replicas, config }
+    }
+
+    pub fn broker(&self) -> &Broker { &self.broker }
+
+    // returns the response frame (without its size), None when the client asked
+    // for no response (acks=0 produce)
+    pub fn handle(&self, frame: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut dec = Decoder::new(frame);
+        let header = RequestHeader::decode(&mut dec)?;
+        let mut enc = Encoder::new();
+        enc.i32(header.correlation_id);
+        let version = header.api_version;
+
+        if header.api_key == kafka::API_VERSIONS {
+            self.api_versions(version, &mut enc);
+            return Ok(Some(enc.into_vec()))
+        }
+        if !kafka::supports(header.api_key, version) {
+            return Err(Error::InvalidRequest(format!(
+                "api {} v{} isn't supported", header.api_key, version)))
+        }
+        let respond = match header.api_key {
+            kafka::PRODUCE => self.produce(version, &mut dec, &mut enc)?,
+            kafka::FETCH => { self.fetch(version, &mut dec, &mut enc)?; true },
+            kafka::LIST_OFFSETS => { self.list_offsets(version, &mut dec, &mut enc)?; true },
+            kafka::METADATA => { self.metadata(version, &mut dec, &mut enc)?; true },
+            kafka::OFFSET_COMMIT => { self.offset_commit(version, &mut dec, &mut enc)?; true },
+            kafka::OFFSET_FETCH => { self.offset_fetch(version, &mut dec, &mut enc)?; true },
+            kafka::JOIN_GROUP => { self.join_group(version, header.client_id.as_deref(), &mut dec, &mut enc)?; true },
+            kafka::SYNC_GROUP => { self.sync_group(version, &mut dec, &mut enc)?; true },
+            kafka::HEARTBEAT => { self.heartbeat(version, &mut dec, &mut enc)?; true },
+            kafka::LEAVE_GROUP => { self.leave_group(version, &mut dec, &mut enc)?; true },
+            kafka::INIT_PRODUCER_ID => { self.init_producer_id(&mut dec, &mut enc)?; true },
+            kafka::FIND_COORDINATOR => { self.find_coordinator(version, &mut dec, &mut enc)?; true },
+            kafka::ADD_PARTITIONS_TO_TXN => { self.add_partitions_to_txn(&mut dec, &mut enc)?; true },
+            kafka::END_TXN => { self.end_txn(&mut dec, &mut enc)?; true },
+            _ => unreachable!("checked by supports"),
+        };
+        if respond { Ok(Some(enc.into_vec())) } else { Ok(None) }
+    }
+
+    fn api_versions(&self, version: i16, enc: &mut Encoder) {
+        // newer clients open with a version we don't speak, answering in the v0
+        // layout with UNSUPPORTED_VERSION makes them retry with one we do
+        let supported = kafka::supports(kafka::API_VERSIONS, version);
+        enc.i16(if supported { errors::NONE } else { errors::UNSUPPORTED_VERSION });
+        enc.array_len(SUPPORTED_APIS.len());
+        for (key, min, max) in SUPPORTED_APIS {
+            enc.i16(*key);
+            enc.i16(*min);
+            enc.i16(*max);
+        }
+        if supported && version >= 1 { enc.i32(0) }
+    }
+
+    fn metadata(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let requested = match dec.array_len()? {
+            Some(0) if version == 0 => None,
+            Some(len) => {
+                let mut topics = Vec::with_capacity(len);
+                for _ in 0..len { topics.push(dec.string()?) }
+                Some(topics)
+            },
+            None => None,
+        };
+        let allow_auto_create = if version >= 4 { dec.boolean()? } else { true };
+
+        let mut topics: Vec<(String, i16, Vec<u32>)> = vec![];
+        match requested {
+            None => {
+                for (name, partitions) in self.logs.topics() {
+                    topics.push((name, errors::NONE, partitions));
+                }
+            },
+            Some(names) => for name in names {
+                let partitions = self.logs.partitions_of(&name);
+                if !partitions.is_empty() {
+                    topics.push((name, errors::NONE, partitions));
+                } else if validate_name(&name).is_err() {
+                    topics.push((name, errors::INVALID_TOPIC_EXCEPTION, vec![]));
+                } else if self.config.auto_create_topics && allow_auto_create {
+                    let res = self.create_topic(&name);
+                    topics.push(match res {
+                        Ok(partitions) => (name, errors::NONE, partitions),
+                        Err(e) => (name, kafka::error_code(&e), vec![]),
+                    });
+                } else {
+                    topics.push((name, errors::UNKNOWN_TOPIC_OR_PARTITION, vec![]));
+                }
+            },
+        }
+
+        let id = self.broker.id() as i32;
+        let brokers = self.replicas.brokers();
+        if version >= 3 { enc.i32(0) }
+        enc.array_len(brokers.len());
+        for broker in &brokers {
+            enc.i32(broker.id() as i32);
+            enc.string(broker.host());
+            enc.i32(broker.port() as i32);
+            if version >= 1 { enc.nullable_string(broker.rack()) }
+        }
+        if version >= 2 { enc.nullable_string(Some(&self.config.cluster_id)) }
+        if version >= 1 { enc.i32(id) }
+        enc.array_len(topics.len());
+        for (name, error, partitions) in topics {
+            enc.i16(error);
+            enc.string(&name);
+            if version >= 1 { enc.boolean(false) }
+            enc.array_len(partitions.len());
+            for partition in partitions {
+                // partitions without replicas elsewhere are this broker's alone
+                let state = self.replicas.state(&name, partition).unwrap_or_else(|| {
+                    PartitionState{ replicas: vec![id as u32], leader: id as u32, leader_epoch: 0, isr: vec![id as u32] }
+                });
+                enc.i16(errors::NONE);
+                enc.i32(partition as i32);
+                enc.i32(state.leader as i32);
+                if version >= 7 { enc.i32(state.leader_epoch) }
+                for brokers in &[&state.replicas, &state.isr] {
+                    enc.array_len(brokers.len());
+                    for broker in brokers.iter() { enc.i32(*broker as i32) }
+                }
+                if version >= 5 { enc.array_len(0) }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_topic(&self, name: &str) -> Result<Vec<u32>> {
+        for id in 0..self.config.num_partitions {
+            self.logs.create(name, id, None)?;
+        }
+        Ok(self.logs.partitions_of(name))
+    }
+
+    fn produce(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<bool> {
+        let _transactional_id = dec.nullable_string()?;
+        let acks = dec.i16()?;
+        let timeout = Duration::from_millis(dec.i32()?.max(0) as u64);
+        let topic_count = dec.array_len()?.unwrap_or(0);
+        let mut responses = Vec::with_capacity(topic_count);
+        for _ in 0..topic_count {
+            let name = dec.string()?;
+            let partition_count = dec.array_len()?.unwrap_or(0);
+            let mut partitions = Vec::with_capacity(partition_count);
+            for _ in 0..partition_count {
+                let index = dec.i32()?;
+                let records = dec.bytes()?.unwrap_or(&[]);
+                let (error, base_offset, log_start, log_end) = match self.append(&name, index, records) {
+                    Ok((base_offset, log_start, log_end)) => (errors::NONE, base_offset as i64, log_start as i64, log_end),
+                    Err(e) => (kafka::error_code(&e), -1, -1, 0),
+                };
+                partitions.push((index, error, base_offset, log_start, log_end));
+            }
+            responses.push((name, partitions));
+        }
+        if acks == 0 { return Ok(false) }
+        // acks=all answers once the isr has everything appended, the wait
+        // shared by every partition in the request
+        if acks == -1 {
+            let deadline = Instant::now() + timeout;
+            for (name, partitions) in &mut responses {
+                for (index, error, _, _, log_end) in partitions.iter_mut().filter(|p| p.1 == errors::NONE) {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if let Err(e) = self.replicas.wait_for_high_watermark(name, *index as u32, *log_end, left) {
+                        *error = kafka::error_code(&e);
+                    }
+                }
+            }
+        }
+
+        enc.array_len(responses.len());
+        for (name, partitions) in responses {
+            enc.string(&name);
+            enc.array_len(partitions.len());
+            for (index, error, base_offset, log_start, _) in partitions {
+                enc.i32(index);
+                enc.i16(error);
+                enc.i64(base_offset);
+                enc.i64(-1); // log append time, records keep their create time
+                if version >= 5 { enc.i64(log_start) }
+            }
+        }
+        enc.i32(0);
+        Ok(true)
+    }
+
+    fn offset_commit(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let (generation, member_id) = if version >= 1 { (dec.i32()?, dec.string()?) } else { (-1, String::new()) };
+        if version >= 2 { let _retention_time_ms = dec.i64()?; }
+        let mut requested = vec![];
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            let name = dec.string()?;
+            let mut partitions = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                let index = dec.i32()?;
+                let offset = dec.i64()?;
+                // v1 lets the client pick the commit timestamp, -1 is now
+                let timestamp = if version == 1 { dec.i64()? } else { -1 };
+                let metadata = dec.nullable_string()?;
+                partitions.push((index, offset, timestamp, metadata));
+            }
+            requested.push((name, partitions));
+        }
+
+        let mut commits = vec![];
+        let mut errors = vec![];
+        for (name, partitions) in &requested {
+            for (index, offset, timestamp, metadata) in partitions {
+                let error = if self.partition(name, *index).is_err() {
+                    errors::UNKNOWN_TOPIC_OR_PARTITION
+                } else if metadata.as_ref().map_or(0, |m| m.len()) > MAX_METADATA_BYTES {
+                    errors::OFFSET_METADATA_TOO_LARGE
+                } else if *offset < 0 {
+                    errors::INVALID_REQUEST
+                } else {
+                    let mut commit = CommittedOffset::new(*offset as u64, metadata.as_deref());
+                    if *timestamp >= 0 { commit.commit_timestamp = *timestamp as u64 }
+                    commits.push((TopicPartitionId::new(name, *index as u32), commit));
+                    errors::NONE
+                };
+                errors.push(error);
+            }
+        }
+        // the valid commits go in together, a failed write fails all of them,
+        // and only the group's current members may commit
+        let failed = match self.groups.validate_commit(&group, generation, &member_id) {
+            Ok(()) => self.offsets.commit(&group, &commits).err(),
+            Err(e) => Some(e),
+        }.map(|e| kafka::error_code(&e));
+
+        if version >= 3 { enc.i32(0) }
+        let mut errors = errors.into_iter();
+        enc.array_len(requested.len());
+        for (name, partitions) in requested {
+            enc.string(&name);
+            enc.array_len(partitions.len());
+            for (index, ..) in partitions {
+                enc.i32(index);
+                let error = errors.next().unwrap();
+                enc.i16(if error == errors::NONE { failed.unwrap_or(error) } else { error });
+            }
+        }
+        Ok(())
+    }
+
+    fn offset_fetch(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // a null topic list (v2+) asks for everything the group committed
+        let group = dec.string()?;
+        let requested: Vec<(String, Vec<i32>)> = match dec.array_len()? {
+            Some(len) => {
+                let mut topics = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let name = dec.string()?;
+                    let mut indexes = vec![];
+                    for _ in 0..dec.array_len()?.unwrap_or(0) { indexes.push(dec.i32()?) }
+                    topics.push((name, indexes));
+                }
+                topics
+            },
+            None => {
+                let mut topics: Vec<(String, Vec<i32>)> = vec![];
+                for (tp, _) in self.offsets.fetch_all(&group) {
+                    match topics.last_mut() {
+                        Some((name, indexes)) if *name == tp.topic => indexes.push(tp.partition as i32),
+                        _ => topics.push((tp.topic, vec![tp.partition as i32])),
+                    }
+                }
+                topics
+            },
+        };
+
+        if version >= 3 { enc.i32(0) }
+        enc.array_len(requested.len());
+        for (name, indexes) in requested {
+            enc.string(&name);
+            enc.array_len(indexes.len());
+            for index in indexes {
+                enc.i32(index);
+                // nothing committed is offset -1, not an error
+                let committed = if index < 0 { None } else { self.offsets.fetch(&group, &TopicPartitionId::new(&name, index as u32)) };
+                match committed {
+                    Some(committed) => {
+                        enc.i64(committed.offset as i64);
+                        enc.nullable_string(committed.metadata.as_deref());
+                    },
+                    None => {
+                        enc.i64(-1);
+                        enc.nullable_string(Some(""));
+                    },
+                }
+                enc.i16(errors::NONE);
+            }
+        }
+        if version >= 2 { enc.i16(errors::NONE) }
+        Ok(())
+    }
+
+    fn join_group(&self, version: i16, client_id: Option<&str>, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let session_timeout = Duration::from_millis(dec.i32()?.max(0) as u64);
+        // v0 rebalances within the session timeout
+        let rebalance_timeout = if version >= 1 { Duration::from_millis(dec.i32()?.max(0) as u64) } else { session_timeout };
+        let member_id = dec.string()?;
+        let protocol_type = dec.string()?;
+        let mut protocols = vec![];
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            protocols.push((dec.string()?, dec.bytes()?.unwrap_or_default().to_vec()));
+        }
+        let req = JoinRequest{
+            member_id: member_id.clone(),
+            // new members' ids start with their client id, like kafka's
+            client_id: String::from(client_id.unwrap_or("consumer")),
+            protocol_type,
+            protocols,
+            session_timeout,
+            rebalance_timeout,
+        };
+        if version >= 2 { enc.i32(0) }
+        match self.groups.join_group(&group, req) {
+            Ok(joined) => {
+                enc.i16(errors::NONE);
+                enc.i32(joined.generation);
+                enc.string(&joined.protocol);
+                enc.string(&joined.leader);
+                enc.string(&joined.member_id);
+                enc.array_len(joined.members.len());
+                for (id, metadata) in joined.members {
+                    enc.string(&id);
+                    enc.bytes(Some(&metadata));
+                }
+            },
+            Err(e) => {
+                let code = match e {
+                    Error::InvalidRequest(_) => errors::INVALID_SESSION_TIMEOUT,
+                    e => kafka::error_code(&e),
+                };
+                enc.i16(code);
+                enc.i32(-1);
+                enc.string("");
+                enc.string("");
+                enc.string(&member_id);
+                enc.array_len(0);
+            },
+        }
+        Ok(())
+    }
+
+    fn sync_group(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let generation = dec.i32()?;
+        let member_id = dec.string()?;
+        let mut assignments = vec![];
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            assignments.push((dec.string()?, dec.bytes()?.unwrap_or_default().to_vec()));
+        }
+        if version >= 1 { enc.i32(0) }
+        match self.groups.sync_group(&group, generation, &member_id, &assignments) {
+            Ok(assignment) => {
+                enc.i16(errors::NONE);
+                enc.bytes(Some(&assignment));
+            },
+            Err(e) => {
+                enc.i16(kafka::error_code(&e));
+                enc.bytes(Some(&[]));
+            },
+        }
+        Ok(())
+    }
+
+    fn heartbeat(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let generation = dec.i32()?;
+        let member_id = dec.string()?;
+        if version >= 1 { enc.i32(0) }
+        enc.i16(match self.groups.heartbeat(&group, generation, &member_id) {
+            Ok(()) => errors::NONE,
+            Err(e) => kafka::error_code(&e),
+        });
+        Ok(())
+    }
+
+    fn leave_group(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let member_id = dec.string()?;
+        if version >= 1 { enc.i32(0) }
+        enc.i16(match self.groups.leave_group(&group, &member_id) {
+            Ok(()) => errors::NONE,
+            Err(e) => kafka::error_code(&e),
+        });
+        Ok(())
+    }
+
+    fn init_producer_id(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // v0 and v1 only differ in throttling semantics
+        let transactional_id = dec.nullable_string()?;
+        let transaction_timeout_ms = dec.i32()?.max(0) as u32;
+        enc.i32(0);
+        let ids = match transactional_id {
+            Some(id) => self.transactions.init_producer_id(&id, transaction_timeout_ms),
+            None => self.logs.next_producer_id().map(|id| (id, 0)),
+        };
+        match ids {
+            Ok((id, epoch)) => {
+                enc.i16(errors::NONE);
+                enc.i64(id);
+                enc.i16(epoch);
+            },
+            Err(e) => {
+                enc.i16(kafka::error_code(&e));
+                enc.i64(-1);
+                enc.i16(-1);
+            },
+        }
+        Ok(())
+    }
+
+    fn find_coordinator(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // this broker coordinates every group and transactional id
+        let _key = dec.string()?;
+        if version >= 1 { let _key_type = dec.i8()?; }
+        if version >= 1 { enc.i32(0) }
+        enc.i16(errors::NONE);
+        if version >= 1 { enc.nullable_string(None) }
+        enc.i32(self.broker.id() as i32);
+        enc.string(self.broker.host());
+        enc.i32(self.broker.port() as i32);
+        Ok(())
+    }
+
+    fn add_partitions_to_txn(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let transactional_id = dec.string()?;
+        let producer_id = dec.i64()?;
+        let producer_epoch = dec.i16()?;
+        let mut requested = vec![];
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            let name = dec.string()?;
+            let mut indexes = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) { indexes.push(dec.i32()?) }
+            requested.push((name, indexes));
+        }
+        // unknown partitions fail the whole request, like kafka
+        let mut partitions = vec![];
+        let mut unknown = false;
+        for (name, indexes) in &requested {
+            for index in indexes {
+                match self.partition(name, *index) {
+                    Ok(_) => partitions.push(TopicPartitionId::new(name, *index as u32)),
+                    Err(_) => unknown = true,
+                }
+            }
+        }
+        let error = if unknown {
+            errors::UNKNOWN_TOPIC_OR_PARTITION
+        } else {
+            match self.transactions.add_partitions(&transactional_id, producer_id, producer_epoch, &partitions) {
+                Ok(()) => errors::NONE,
+                Err(e) => kafka::error_code(&e),
+            }
+        };
+        enc.i32(0);
+        enc.array_len(requested.len());
+        for (name, indexes) in requested {
+            enc.string(&name);
+            enc.array_len(indexes.len());
+            for index in indexes {
+                enc.i32(index);
+                enc.i16(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_txn(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let transactional_id = dec.string()?;
+        let producer_id = dec.i64()?;
+        let producer_epoch = dec.i16()?;
+        let commit = dec.boolean()?;
+        enc.i32(0);
+        enc.i16(match self.transactions.end_transaction(&transactional_id, producer_id, producer_epoch, commit) {
+            Ok(()) => errors::NONE,
+            Err(e) => kafka::error_code(&e),
+        });
+        Ok(())
+    }
+
+    fn partition(&self, topic: &str, index: i32) -> Result<SharedPartition> {
+        if index >= 0 {
+            if let Some(partition) = self.logs.get(topic, index as u32) { return Ok(partition) }
+        }
+        let dir = format!("{}-{}", topic, index);
+        Err(Error::PartitionNotFound(self.logs.log_dirs()[0].join(dir)))
+    }
+
+    fn leader(&self, topic: &str, index: i32) -> Result<SharedPartition> {
+        // a partition clients read and write, only on the broker leading it
+        let shared = self.partition(topic, index)?;
+        self.replicas.check_leader(topic, index as u32)?;
+        Ok(shared)
+    }
+
+    fn append(&self, topic: &str, index: i32, records: &[u8]) -> Result<(u64, u64, u64)> {
+        // returns the offset of the first record appended, the log start and the log end
+        let batches = RecordBatch::decode_all(records)
+            .map_err(|e| Error::CorruptRecord(e.to_string()))?;
+        let shared = self.leader(topic, index)?;
+        let mut partition = shared.lock().unwrap();
+        let mut base_offset = None;
+        for batch in batches {
+            let records: Vec<Record> = batch.records.iter().map(|record| {
+                let timestamp = if record.timestamp < 0 { now_ms() } else { record.timestamp as u64 };
+                let mut appended = Record::new(timestamp, record.key.as_deref(), record.value.as_deref());
+                appended.headers = record.headers.clone();
+                if batch.producer_id < 0 { return appended }
+                appended.attributes = batch.attributes as u8 & TRANSACTIONAL;
+                let sequence = next_sequence(batch.base_sequence, record.offset_delta);
+                appended.with_producer(batch.producer_id, batch.producer_epoch, sequence)
+            }).collect();
+            let offset = partition.append_batch(&records)?;
+            base_offset.get_or_insert(offset);
+        }
+        let base_offset = base_offset.unwrap_or_else(|| partition.log_end_offset());
+        let appended = (base_offset, partition.log_start_offset(), partition.log_end_offset());
+        drop(partition);
+        self.replicas.appended(topic, index as u32);
+        Ok(appended)
+    }
+
+    fn fetch(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let _replica_id = dec.i32()?;
+        let max_wait_ms = dec.i32()?.max(0) as u64;
+        let min_bytes = dec.i32()?.max(0) as u64;
+        let max_bytes = dec.i32()?.max(0) as u64;
+        let isolation = Isolation::from_i8(dec.i8()?);
+        if version >= 7 {
+            let _session_id = dec.i32()?;
+            let _session_epoch = dec.i32()?;
+        }
+        let topic_count = dec.array_len()?.unwrap_or(0);
+        let mut requested = Vec::with_capacity(topic_count);
+        for _ in 0..topic_count {
+            let name = dec.string()?;
+            let partition_count = dec.array_len()?.unwrap_or(0);
+            let mut partitions = Vec::with_capacity(partition_count);
+            for _ in 0..partition_count {
+                let index = dec.i32()?;
+                let current_leader_epoch = if version >= 9 { dec.i32()? } else { -1 };
+                let offset = dec.i64()?;
+                if version >= 5 { let _log_start_offset = dec.i64()?; }
+                let partition_max_bytes = dec.i32()?.max(0) as u64;
+                partitions.push((index, current_leader_epoch, offset, partition_max_bytes));
+            }
+            requested.push((name, partitions));
+        }
+        // forgotten topics and rack id only matter to fetch sessions and
+        // follower fetching, neither of which exists here yet
+
+        // long poll: read, and if there's less than min_bytes sleep until an
+        // append lands somewhere or max_wait_ms is up, then read again
+        let appends = self.logs.append_signal();
+        let deadline = Instant::now() + Duration::from_millis(max_wait_ms);
+        let responses = loop {
+            let seen = appends.appends();
+            let mut remaining = max_bytes;
+            let mut failed = false;
+            let mut responses = Vec::with_capacity(requested.len());
+            for (name, partitions) in &requested {
+                let mut read = Vec::with_capacity(partitions.len());
+                for (index, current_leader_epoch, offset, partition_max_bytes) in partitions {
+                    let res = self.replicas.check_leader_epoch(name, *index as u32, *current_leader_epoch)
+                        .and_then(|_| self.read(name, *index, *offset, (*partition_max_bytes).min(remaining), isolation));
+                    match &res {
+                        Ok(read) => remaining = remaining.saturating_sub(read.records.len() as u64),
+                        Err(_) => failed = true,
+                    }
+                    read.push((*index, res));
+                }
+                responses.push((name, read));
+            }
+            let now = Instant::now();
+            if max_bytes - remaining >= min_bytes || failed || now >= deadline { break responses }
+            appends.wait(seen, deadline - now);
+        };
+
+        enc.i32(0);
+        if version >= 7 {
+            enc.i16(errors::NONE);
+            enc.i32(0);
+        }
+        enc.array_len(responses.len());
+        for (name, partitions) in responses {
+            enc.string(name);
+            enc.array_len(partitions.len());
+            for (index, res) in partitions {
+                let (error, read) = match res {
+                    Ok(read) => (errors::NONE, read),
+                    Err(e) => (kafka::error_code(&e), PartitionRead::failed()),
+                };
+                enc.i32(index);
+                enc.i16(error);
+                enc.i64(read.high_watermark);
+                enc.i64(read.last_stable_offset);
+                if version >= 5 { enc.i64(read.log_start) }
+                match read.aborted {
+                    Some(aborted) => {
+                        enc.array_len(aborted.len());
+                        for txn in aborted {
+                            enc.i64(txn.producer_id);
+                            enc.i64(txn.first_offset as i64);
+                        }
+                    },
+                    None => enc.null_array(),
+                }
+                if version >= 11 { enc.i32(-1) }
+                enc.bytes(Some(&read.records));
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, topic: &str, index: i32, offset: i64, max_bytes: u64, isolation: Isolation) -> Result<PartitionRead> {
+        let shared = self.leader(topic, index)?;
+        let partition = shared.lock().unwrap();
+        let mut read = PartitionRead{
+            high_watermark: partition.high_watermark() as i64,
+            last_stable_offset: partition.last_stable_offset() as i64,
+            log_start: partition.log_start_offset() as i64,
+            aborted: None,
+            records: vec![],
+        };
+        if offset < 0 { return Err(Error::OffsetOutOfRange(0)) }
+        // read_committed stops at the last stable offset, and gets the aborted
+        // transactions it reads into so the client can drop their records
+        let end = match isolation {
+            Isolation::ReadCommitted => read.last_stable_offset,
+            Isolation::ReadUncommitted => read.high_watermark,
+        };
+        if offset >= end || max_bytes == 0 {
+            if offset as u64 > partition.log_end_offset() { return Err(Error::OffsetOutOfRange(offset as u64)) }
+            return Ok(read)
+        }
+        let mut messages = partition.read(offset as u64, max_bytes)?;
+        messages.retain(|m| (m.offset as i64) < end);
+        if isolation == Isolation::ReadCommitted {
+            let last = messages.last().map_or(offset as u64, |m| m.offset);
+            read.aborted = Some(partition.aborted_transactions(offset as u64, last + 1)?);
+        }
+        for batch in RecordBatch::from_messages(&messages) {
+            read.records.extend_from_slice(&batch.to_vec());
+        }
+        Ok(read)
+    }
+
+    fn list_offsets(&self, version: i16, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let _replica_id = dec.i32()?;
+        if version >= 2 { let _isolation_level = dec.i8()?; }
+        let topic_count = dec.array_len()?.unwrap_or(0);
+        let mut responses = Vec::with_capacity(topic_count);
+        for _ in 0..topic_count {
+            let name = dec.string()?;
+            let partition_count = dec.array_len()?.unwrap_or(0);
+            let mut partitions = Vec::with_capacity(partition_count);
+            for _ in 0..partition_count {
+                let index = dec.i32()?;
+                let current_leader_epoch = if version >= 4 { dec.i32()? } else { -1 };
+                let timestamp = dec.i64()?;
+                let res = self.replicas.check_leader_epoch(&name, index as u32, current_leader_epoch)
+                    .and_then(|_| self.offset_for(&name, index, timestamp));
+                partitions.push((index, res));
+            }
+            responses.push((name, partitions));
+        }
+
+        if version >= 2 { enc.i32(0) }
+        enc.array_len(responses.len());
+        for (name, partitions) in responses {
+            enc.string(&name);
+            enc.array_len(partitions.len());
+            for (index, res) in partitions {
+                let (error, (timestamp, offset)) = match res {
+                    Ok(found) => (errors::NONE, found),
+                    Err(e) => (kafka::error_code(&e), (-1, -1)),
+                };
+                enc.i32(index);
+                enc.i16(error);
+                enc.i64(timestamp);
+                enc.i64(offset);
+                if version >= 4 {
+                    enc.i32(self.replicas.state(&name, index as u32).map(|s| s.leader_epoch).unwrap_or(0))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn offset_for(&self, topic: &str, index: i32, timestamp: i64) -> Result<(i64, i64)> {
+        let shared = self.leader(topic, index)?;
+        let partition = shared.lock().unwrap();
+        match timestamp {
+            LATEST_TIMESTAMP => Ok((-1, partition.high_watermark() as i64)),
+            EARLIEST_TIMESTAMP => Ok((-1, partition.log_start_offset() as i64)),
+            ts if ts < 0 => Err(Error::InvalidRequest(format!("timestamp {}", ts))),
+            ts => match partition.offset_for_timestamp(ts as u64)? {
+                Some((offset, found)) => Ok((found as i64, offset as i64)),
+                None => Ok((-1, -1)),
+            },
+        }
+    }
+}
@@ -0,0 +1,130 @@
+pub mod batch;
+pub mod codec;
+pub mod handler;
+
+use crate::{Error, Result};
+use crate::kafka::codec::{Decoder, Encoder};
+
+pub const PRODUCE: i16 = 0;
+pub const FETCH: i16 = 1;
+pub const LIST_OFFSETS: i16 = 2;
+pub const METADATA: i16 = 3;
+pub const OFFSET_COMMIT: i16 = 8;
+pub const OFFSET_FETCH: i16 = 9;
+pub const FIND_COORDINATOR: i16 = 10;
+pub const JOIN_GROUP: i16 = 11;
+pub const HEARTBEAT: i16 = 12;
+pub const LEAVE_GROUP: i16 = 13;
+pub const SYNC_GROUP: i16 = 14;
+pub const API_VERSIONS: i16 = 18;
+pub const INIT_PRODUCER_ID: i16 = 22;
+pub const ADD_PARTITIONS_TO_TXN: i16 = 24;
+pub const END_TXN: i16 = 26;
+
+// (api key, min version, max version). Only versions before each api moved to
+// flexible (tagged field) encoding, so every header is v1 and response header v0
+pub const SUPPORTED_APIS: &[(i16, i16, i16)] = &[
+    (PRODUCE, 3, 7),
+    (FETCH, 4, 11),
+    (LIST_OFFSETS, 1, 5),
+    (METADATA, 0, 7),
+    (OFFSET_COMMIT, 0, 3),
+    (OFFSET_FETCH, 1, 3),
+    (FIND_COORDINATOR, 0, 2),
+    (JOIN_GROUP, 0, 3),
+    (HEARTBEAT, 0, 2),
+    (LEAVE_GROUP, 0, 2),
+    (SYNC_GROUP, 0, 2),
+    (API_VERSIONS, 0, 2),
+    (INIT_PRODUCER_ID, 0, 1),
+    (ADD_PARTITIONS_TO_TXN, 0, 1),
+    (END_TXN, 0, 1),
+];
+
+pub fn supports(api_key: i16, api_version: i16) -> bool {
+    SUPPORTED_APIS.iter().any(|(key, min, max)| *key == api_key && api_version >= *min && api_version <= *max)
+}
+
+pub mod errors {
+    pub const UNKNOWN_SERVER_ERROR: i16 = -1;
+    pub const NONE: i16 = 0;
+    pub const OFFSET_OUT_OF_RANGE: i16 = 1;
+    pub const CORRUPT_MESSAGE: i16 = 2;
+    pub const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+    pub const LEADER_NOT_AVAILABLE: i16 = 5;
+    pub const NOT_LEADER_FOR_PARTITION: i16 = 6;
+    pub const REQUEST_TIMED_OUT: i16 = 7;
+    pub const MESSAGE_TOO_LARGE: i16 = 10;
+    pub const OFFSET_METADATA_TOO_LARGE: i16 = 12;
+    pub const INVALID_TOPIC_EXCEPTION: i16 = 17;
+    pub const NOT_ENOUGH_REPLICAS: i16 = 19;
+    pub const NOT_ENOUGH_REPLICAS_AFTER_APPEND: i16 = 20;
+    pub const ILLEGAL_GENERATION: i16 = 22;
+    pub const INCONSISTENT_GROUP_PROTOCOL: i16 = 23;
+    pub const UNKNOWN_MEMBER_ID: i16 = 25;
+    pub const INVALID_SESSION_TIMEOUT: i16 = 26;
+    pub const REBALANCE_IN_PROGRESS: i16 = 27;
+    pub const UNSUPPORTED_VERSION: i16 = 35;
+    pub const NOT_CONTROLLER: i16 = 41;
+    pub const INVALID_REQUEST: i16 = 42;
+    pub const OUT_OF_ORDER_SEQUENCE_NUMBER: i16 = 45;
+    pub const INVALID_PRODUCER_EPOCH: i16 = 47;
+    pub const INVALID_TXN_STATE: i16 = 48;
+    pub const FENCED_LEADER_EPOCH: i16 = 74;
+    pub const UNKNOWN_LEADER_EPOCH: i16 = 75;
+    pub const STALE_BROKER_EPOCH: i16 = 77;
+}
+
+pub fn error_code(err: &Error) -> i16 {
+    match err {
+        Error::OffsetOutOfRange(_) => errors::OFFSET_OUT_OF_RANGE,
+        Error::CorruptRecord(_) => errors::CORRUPT_MESSAGE,
+        Error::PartitionNotFound(_) => errors::UNKNOWN_TOPIC_OR_PARTITION,
+        Error::MessageTooLarge { .. } => errors::MESSAGE_TOO_LARGE,
+        Error::InvalidRequest(_) => errors::INVALID_REQUEST,
+        Error::Timeout(_) => errors::REQUEST_TIMED_OUT,
+        Error::OutOfOrderSequence { .. } => errors::OUT_OF_ORDER_SEQUENCE_NUMBER,
+        Error::ProducerFenced { .. } => errors::INVALID_PRODUCER_EPOCH,
+        Error::InvalidTxnState(_) => errors::INVALID_TXN_STATE,
+        Error::UnknownMember(_) => errors::UNKNOWN_MEMBER_ID,
+        Error::IllegalGeneration { .. } => errors::ILLEGAL_GENERATION,
+        Error::RebalanceInProgress => errors::REBALANCE_IN_PROGRESS,
+        Error::InconsistentGroupProtocol(_) => errors::INCONSISTENT_GROUP_PROTOCOL,
+        Error::NotLeader(_) => errors::NOT_LEADER_FOR_PARTITION,
+        Error::LeaderNotAvailable(_) => errors::LEADER_NOT_AVAILABLE,
+        Error::StaleBrokerEpoch(_) => errors::STALE_BROKER_EPOCH,
+        Error::NotController(_) => errors::NOT_CONTROLLER,
+        // a newer epoch than the broker knows means it hasn't heard of it yet
+        Error::FencedLeaderEpoch { epoch, current } if epoch > current => errors::UNKNOWN_LEADER_EPOCH,
+        Error::FencedLeaderEpoch { .. } => errors::FENCED_LEADER_EPOCH,
+        Error::Remote{ code, .. } => *code,
+        _ => errors::UNKNOWN_SERVER_ERROR,
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHeader {
+    pub api_key: i16,
+    pub api_version: i16,
+    pub correlation_id: i32,
+    pub client_id: Option<String>,
+}
+
+impl RequestHeader {
+    pub fn decode(dec: &mut Decoder) -> Result<RequestHeader> {
+        Ok(RequestHeader{
+            api_key: dec.i16()?,
+            api_version: dec.i16()?,
+            correlation_id: dec.i32()?,
+            client_id: dec.nullable_string()?,
+        })
+    }
+
+    pub fn encode(&self, enc: &mut Encoder) {
+        enc.i16(self.api_key);
+        enc.i16(self.api_version);
+        enc.i32(self.correlation_id);
+        enc.nullable_string(self.client_id.as_deref());
+    }
+}
@@ -1,5 +1,13 @@
 // #![allow(dead_code)]
 // #![allow(unused_imports)]
 // #![allow(unused_variables)]
+pub mod client;
 pub mod cluster;
+pub mod error;
+pub mod kafka;
+pub mod native;
 pub mod partition;
+pub mod server;
+pub type Offset = u64;
+
+pub use crate::error::{Error, Result};
@@ -0,0 +1,657 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Offset, Result};
+use crate::cluster::controller::{self, Controller};
+use crate::cluster::group::{GroupCoordinator, JoinRequest};
+use crate::cluster::manager::{LogManager, SharedPartition, TopicPartitionId};
+use crate::cluster::offsets::{CommittedOffset, OffsetStore};
+use crate::cluster::raft::{self, RaftPeer};
+use crate::cluster::replica::{ReplicaManager};
+use crate::cluster::transaction::{TransactionCoordinator};
+use crate::cluster::topic::{validate_name};
+use crate::kafka::{self, errors};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::native::{self, Response};
+use crate::partition::message::{Message};
+use crate::partition::producer::{next_sequence};
+use crate::partition::record::{Record, NO_PRODUCER_ID};
+use crate::partition::segment::{now_ms};
+use crate::partition::slice::{FileSlice};
+use crate::partition::transaction::{Isolation, TRANSACTIONAL};
+use crate::server::{ServerConfig};
+
+
+// What a consumer fetch found in one partition. read_uncommitted goes
+// straight from the log file, read_committed is copied to leave aborted
+// records out
+enum Records {
+    Slice(Option<FileSlice>),
+    Copied(Vec<Message>),
+}
+
+struct Read {
+    high_watermark: Offset,
+    next_offset: Offset,
+    records: Records,
+}
+
+impl Read {
+    fn bytes(&self) -> u64 {
+        match &self.records {
+            Records::Slice(slice) => slice.as_ref().map_or(0, |s| s.length),
+            Records::Copied(messages) => messages.iter().map(|m| m.payload.len() as u64).sum(),
+        }
+    }
+}
+
+
+// Answers native requests from the partitions of one broker's LogManager
+pub struct Handler {
+    logs: Arc<LogManager>,
+    transactions: Arc<TransactionCoordinator>,
+    offsets: Arc<OffsetStore>,
+    groups: Arc<GroupCoordinator>,
+    replicas: Arc<ReplicaManager>,
+    // on the broker running the controller, which brokers register with
+    controller: Option<Arc<Controller>>,
+    config: ServerConfig,
+}
+
+impl Handler {
+    pub fn new(
+        logs: Arc<LogManager>,
+        transactions: Arc<TransactionCoordinator>,
+        offsets: Arc<OffsetStore>,
+        groups: Arc<GroupCoordinator>,
+        replicas: Arc<ReplicaManager>,
+        controller: Option<Arc<Controller>>,
+        config: ServerConfig,
+    ) -> Handler {
+        Handler{ logs, transactions, offsets, groups, replicas, controller, config }
+    }
+
+    // errors become the response status, only garbage framing closes the
+    // connection. None when the client asked for no response (acks=0)
+    pub fn handle(&self, frame: &[u8]) -> Result<Option<Response>> {
+        let mut dec = Decoder::new(frame);
+        let op = dec.i8()?;
+        let mut body = Encoder::new();
+        // log byte ranges the body leaves room for, sent from the files
+        let mut slices = vec![];
+        let mut respond = true;
+        let res = match op {
+            native::PRODUCE => self.produce(&mut dec, &mut body, &mut respond),
+            native::PRODUCE_BATCH => self.produce_batch(&mut dec, &mut body, &mut respond),
+            native::FETCH => self.fetch(&mut dec, &mut body, &mut slices),
+            native::METADATA => self.metadata(&mut dec, &mut body),
+            native::LIST_OFFSETS => self.list_offsets(&mut dec, &mut body),
+            native::INIT_PRODUCER_ID => self.init_producer_id(&mut dec, &mut body),
+            native::ADD_PARTITIONS_TO_TXN => self.add_partitions_to_txn(&mut dec),
+            native::END_TXN => self.end_txn(&mut dec),
+            native::OFFSET_COMMIT => self.offset_commit(&mut dec),
+            native::OFFSET_FETCH => self.offset_fetch(&mut dec, &mut body),
+            native::JOIN_GROUP => self.join_group(&mut dec, &mut body),
+            native::SYNC_GROUP => self.sync_group(&mut dec, &mut body),
+            native::HEARTBEAT => self.heartbeat(&mut dec),
+            native::LEAVE_GROUP => self.leave_group(&mut dec),
+            native::REPLICA_FETCH => self.replica_fetch(&mut dec, &mut body, &mut slices),
+            native::OFFSETS_FOR_LEADER_EPOCH => self.offsets_for_leader_epoch(&mut dec, &mut body),
+            native::LEADER_AND_ISR => self.leader_and_isr(&mut dec, &mut body),
+            native::BROKER_REGISTRATION => self.register_broker(&mut dec, &mut body),
+            native::BROKER_HEARTBEAT => self.broker_heartbeat(&mut dec, &mut body),
+            native::REASSIGN_PARTITIONS => self.reassign_partitions(&mut dec, &mut body),
+            native::RAFT_VOTE => self.raft_vote(&mut dec, &mut body),
+            native::RAFT_APPEND => self.raft_append(&mut dec, &mut body),
+            native::RAFT_SNAPSHOT => self.raft_snapshot(&mut dec, &mut body),
+            op => Err(Error::InvalidRequest(format!("unknown op {}", op))),
+        };
+        if !respond { return Ok(None) }
+        let mut enc = Encoder::new();
+        match res {
+            Ok(()) => {
+                enc.i16(errors::NONE);
+                enc.nullable_string(None);
+                let header = enc.len();
+                enc.raw(body.as_slice());
+                let slices = slices.into_iter().map(|(at, slice)| (header + at, slice)).collect();
+                Ok(Some(Response::new(enc.into_vec(), slices)))
+            },
+            Err(e) => {
+                enc.i16(kafka::error_code(&e));
+                enc.nullable_string(Some(&e.to_string()));
+                Ok(Some(Response::new(enc.into_vec(), vec![])))
+            },
+        }
+    }
+
+    fn partition(&self, topic: &str, partition: i32) -> Result<SharedPartition> {
+        if partition >= 0 {
+            if let Some(shared) = self.logs.get(topic, partition as u32) { return Ok(shared) }
+        }
+        let dir = format!("{}-{}", topic, partition);
+        Err(Error::PartitionNotFound(self.logs.log_dirs()[0].join(dir)))
+    }
+
+    fn leader(&self, topic: &str, partition: i32) -> Result<SharedPartition> {
+        // a partition clients read and write, only on the broker leading it
+        let shared = self.partition(topic, partition)?;
+        self.replicas.check_leader(topic, partition as u32)?;
+        Ok(shared)
+    }
+
+    fn produce(&self, dec: &mut Decoder, enc: &mut Encoder, respond: &mut bool) -> Result<()> {
+        // topic | partition i32 | acks i16 | key bytes | value bytes, acks
+        // the same as a batch's
+        let topic = dec.string()?;
+        let partition = dec.i32()?;
+        let acks = dec.i16()?;
+        *respond = acks != 0;
+        let key = dec.bytes()?;
+        let value = dec.bytes()?;
+        let record = Record::new(now_ms(), key, value).to_vec()?;
+        let shared = self.leader(&topic, partition)?;
+        let offset = {
+            let mut shared = shared.lock().unwrap();
+            let offset = shared.log_end_offset();
+            shared.append(&record)?;
+            offset
+        };
+        self.replicas.appended(&topic, partition as u32);
+        if acks == -1 {
+            self.replicas.wait_for_high_watermark(&topic, partition as u32, offset + 1, self.config.produce_timeout)?;
+        }
+        enc.i64(offset as i64);
+        Ok(())
+    }
+
+    fn produce_batch(&self, dec: &mut Decoder, enc: &mut Encoder, respond: &mut bool) -> Result<()> {
+        // appends the records back to back, the response is the first one's offset.
+        // A producer id of -1 means the producer isn't idempotent
+        let topic = dec.string()?;
+        let partition = dec.i32()?;
+        let acks = dec.i16()?;
+        *respond = acks != 0;
+        let producer_id = dec.i64()?;
+        let producer_epoch = dec.i16()?;
+        let base_sequence = dec.i32()?;
+        let transactional = dec.boolean()?;
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut records = Vec::with_capacity(count);
+        for i in 0..count {
+            let timestamp = dec.i64()?;
+            let timestamp = if timestamp < 0 { now_ms() } else { timestamp as u64 };
+            let mut record = Record::new(timestamp, dec.bytes()?, dec.bytes()?);
+            if transactional { record.attributes = TRANSACTIONAL }
+            records.push(match producer_id {
+                NO_PRODUCER_ID => record,
+                _ => record.with_producer(producer_id, producer_epoch, next_sequence(base_sequence, i as i32)),
+            });
+        }
+        let shared = self.leader(&topic, partition)?;
+        let base_offset = shared.lock().unwrap().append_batch(&records)?;
+        self.replicas.appended(&topic, partition as u32);
+        if acks == -1 {
+            let end = base_offset + records.len() as Offset;
+            self.replicas.wait_for_high_watermark(&topic, partition as u32, end, self.config.produce_timeout)?;
+        }
+        enc.i64(base_offset as i64);
+        Ok(())
+    }
+
+    fn init_producer_id(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // a transactional id goes through the coordinator, which fences the
+        // previous producer with that id
+        let transactional_id = dec.nullable_string()?;
+        let timeout_ms = dec.i32()?.max(0) as u32;
+        let (producer_id, epoch) = match transactional_id {
+            Some(id) => self.transactions.init_producer_id(&id, timeout_ms)?,
+            None => (self.logs.next_producer_id()?, 0),
+        };
+        enc.i64(producer_id);
+        enc.i16(epoch);
+        Ok(())
+    }
+
+    fn add_partitions_to_txn(&self, dec: &mut Decoder) -> Result<()> {
+        let transactional_id = dec.string()?;
+        let producer_id = dec.i64()?;
+        let epoch = dec.i16()?;
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut partitions = Vec::with_capacity(count);
+        for _ in 0..count {
+            let topic = dec.string()?;
+            let partition = dec.i32()?;
+            self.partition(&topic, partition)?;
+            partitions.push(TopicPartitionId::new(&topic, partition as u32));
+        }
+        self.transactions.add_partitions(&transactional_id, producer_id, epoch, &partitions)
+    }
+
+    fn end_txn(&self, dec: &mut Decoder) -> Result<()> {
+        let transactional_id = dec.string()?;
+        let producer_id = dec.i64()?;
+        let epoch = dec.i16()?;
+        let commit = dec.boolean()?;
+        self.transactions.end_transaction(&transactional_id, producer_id, epoch, commit)
+    }
+
+    fn offset_commit(&self, dec: &mut Decoder) -> Result<()> {
+        // group | generation i32 | member string | topic |
+        // [partition i32 | offset i64 | metadata nullable string],
+        // all of them or none are committed
+        let group = dec.string()?;
+        let generation = dec.i32()?;
+        let member_id = dec.string()?;
+        let topic = dec.string()?;
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut commits = Vec::with_capacity(count);
+        for _ in 0..count {
+            let partition = dec.i32()?;
+            let offset = dec.i64()?;
+            let metadata = dec.nullable_string()?;
+            self.partition(&topic, partition)?;
+            if offset < 0 { return Err(Error::InvalidRequest(format!("offset {}", offset))) }
+            commits.push((TopicPartitionId::new(&topic, partition as u32), CommittedOffset::new(offset as u64, metadata.as_deref())));
+        }
+        self.groups.validate_commit(&group, generation, &member_id)?;
+        self.offsets.commit(&group, &commits)
+    }
+
+    fn join_group(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // group | member | client | protocol type | session_timeout_ms i32 |
+        // rebalance_timeout_ms i32 | [protocol | metadata bytes]
+        let group = dec.string()?;
+        let mut req = JoinRequest{
+            member_id: dec.string()?,
+            client_id: dec.string()?,
+            protocol_type: dec.string()?,
+            session_timeout: Duration::from_millis(dec.i32()?.max(0) as u64),
+            rebalance_timeout: Duration::from_millis(dec.i32()?.max(0) as u64),
+            protocols: vec![],
+        };
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            req.protocols.push((dec.string()?, dec.bytes()?.unwrap_or_default().to_vec()));
+        }
+        let joined = self.groups.join_group(&group, req)?;
+        enc.i32(joined.generation);
+        enc.string(&joined.protocol);
+        enc.string(&joined.leader);
+        enc.string(&joined.member_id);
+        enc.array_len(joined.members.len());
+        for (id, metadata) in joined.members {
+            enc.string(&id);
+            enc.bytes(Some(&metadata));
+        }
+        Ok(())
+    }
+
+    fn sync_group(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let group = dec.string()?;
+        let generation = dec.i32()?;
+        let member_id = dec.string()?;
+        let mut assignments = vec![];
+        for _ in 0..dec.array_len()?.unwrap_or(0) {
+            assignments.push((dec.string()?, dec.bytes()?.unwrap_or_default().to_vec()));
+        }
+        enc.bytes(Some(&self.groups.sync_group(&group, generation, &member_id, &assignments)?));
+        Ok(())
+    }
+
+    fn heartbeat(&self, dec: &mut Decoder) -> Result<()> {
+        let group = dec.string()?;
+        let generation = dec.i32()?;
+        self.groups.heartbeat(&group, generation, &dec.string()?)
+    }
+
+    fn leave_group(&self, dec: &mut Decoder) -> Result<()> {
+        let group = dec.string()?;
+        self.groups.leave_group(&group, &dec.string()?)
+    }
+
+    fn offset_fetch(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // what the group committed for the topic, partitions without a commit left out
+        let group = dec.string()?;
+        let topic = dec.string()?;
+        let committed: Vec<_> = self.offsets.fetch_all(&group).into_iter().filter(|(tp, _)| tp.topic == topic).collect();
+        enc.array_len(committed.len());
+        for (tp, commit) in committed {
+            enc.i32(tp.partition as i32);
+            enc.i64(commit.offset as i64);
+            enc.nullable_string(commit.metadata.as_deref());
+            enc.i64(commit.commit_timestamp as i64);
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, dec: &mut Decoder, enc: &mut Encoder, slices: &mut Vec<(usize, FileSlice)>) -> Result<()> {
+        let topic = dec.string()?;
+        let max_wait_ms = dec.i32()?.max(0) as u64;
+        let min_bytes = dec.i32()?.max(0) as u64;
+        let isolation = Isolation::from_i8(dec.i8()?);
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut requested = Vec::with_capacity(count);
+        for _ in 0..count {
+            requested.push((dec.i32()?, dec.i64()?, dec.i32()?.max(0) as u64));
+        }
+
+        // same long poll as a kafka fetch
+        let appends = self.logs.append_signal();
+        let deadline = Instant::now() + Duration::from_millis(max_wait_ms);
+        let results = loop {
+            let seen = appends.appends();
+            let results: Vec<_> = requested.iter()
+                .map(|(partition, offset, max_bytes)| (*partition, self.read(&topic, *partition, *offset, *max_bytes, isolation)))
+                .collect();
+            let mut bytes = 0;
+            let mut failed = false;
+            for (_, res) in &results {
+                match res {
+                    Ok(read) => bytes += read.bytes(),
+                    Err(_) => failed = true,
+                }
+            }
+            let now = Instant::now();
+            if bytes >= min_bytes || failed || now >= deadline { break results }
+            appends.wait(seen, deadline - now);
+        };
+
+        enc.array_len(results.len());
+        for (partition, res) in results {
+            enc.i32(partition);
+            let read = match res {
+                Ok(read) => {
+                    enc.i16(errors::NONE);
+                    enc.nullable_string(None);
+                    read
+                },
+                Err(e) => {
+                    enc.i16(kafka::error_code(&e));
+                    enc.nullable_string(Some(&e.to_string()));
+                    continue
+                },
+            };
+            enc.i64(read.high_watermark as i64);
+            enc.i64(read.next_offset as i64);
+            match read.records {
+                Records::Slice(slice) => native::encode_messages(enc, slices, slice),
+                Records::Copied(messages) => native::encode_copied_messages(enc, &messages)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, topic: &str, partition: i32, offset: i64, max_bytes: u64, isolation: Isolation) -> Result<Read> {
+        // the high watermark, where the next read starts and whatever is past
+        // offset. read_committed leaves out what the client isn't to see, so the
+        // next offset can be past the last message returned
+        if offset < 0 { return Err(Error::InvalidRequest(format!("offset {}", offset))) }
+        let offset = offset as u64;
+        let shared = self.leader(topic, partition)?;
+        let partition = shared.lock().unwrap();
+        // past the high watermark is there but not every in-sync replica has it yet
+        let high_watermark = partition.high_watermark();
+        if offset > partition.log_end_offset() { return Err(Error::OffsetOutOfRange(offset)) }
+        if isolation == Isolation::ReadCommitted {
+            let (messages, next_offset) = partition.read_committed(offset, max_bytes)?;
+            return Ok(Read{ high_watermark, next_offset, records: Records::Copied(messages) })
+        }
+        let slice = partition.fetch_upto(offset, high_watermark, max_bytes)?;
+        let next_offset = slice.as_ref().map_or(offset, |s| s.next_offset);
+        Ok(Read{ high_watermark, next_offset, records: Records::Slice(slice) })
+    }
+
+    fn metadata(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // the partitions of a topic, created first when asked to and allowed
+        let topic = dec.string()?;
+        let create = dec.boolean()?;
+        validate_name(&topic)?;
+        let mut partitions = self.logs.partitions_of(&topic);
+        if partitions.is_empty() && create && self.config.auto_create_topics {
+            for id in 0..self.config.num_partitions {
+                self.logs.create(&topic, id, None)?;
+            }
+            partitions = self.logs.partitions_of(&topic);
+        }
+        enc.array_len(partitions.len());
+        for id in partitions {
+            enc.i32(id as i32);
+        }
+        Ok(())
+    }
+
+    fn list_offsets(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        let topic = dec.string()?;
+        let partition = dec.i32()?;
+        let shared = self.leader(&topic, partition)?;
+        let partition = shared.lock().unwrap();
+        enc.i64(partition.log_start_offset() as i64);
+        enc.i64(partition.high_watermark() as i64);
+        Ok(())
+    }
+
+    fn replica_fetch(&self, dec: &mut Decoder, enc: &mut Encoder, slices: &mut Vec<(usize, FileSlice)>) -> Result<()> {
+        // replica_id i32 | max_wait_ms i32 | max_bytes i32 |
+        // [topic | partition i32 | leader_epoch i32 | offset i64], from a
+        // follower copying the partitions this broker leads
+        let replica_id = dec.i32()?;
+        let max_wait_ms = dec.i32()?.max(0) as u64;
+        let max_bytes = dec.i32()?.max(0) as u64;
+        if replica_id < 0 { return Err(Error::InvalidRequest(format!("replica {}", replica_id))) }
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut requested = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tp = TopicPartitionId::new(&dec.string()?, dec.i32()?.max(0) as u32);
+            let leader_epoch = dec.i32()?;
+            let offset = dec.i64()?;
+            if offset < 0 { return Err(Error::InvalidRequest(format!("offset {}", offset))) }
+            requested.push((tp, leader_epoch, offset as u64));
+        }
+
+        // waits for something to copy, fetching again on every append
+        let appends = self.logs.append_signal();
+        let deadline = Instant::now() + Duration::from_millis(max_wait_ms);
+        let results = loop {
+            let seen = appends.appends();
+            let results: Vec<_> = requested.iter()
+                .map(|(tp, leader_epoch, offset)| {
+                    (tp, self.replicas.read_for_follower(replica_id as u32, tp, *leader_epoch, *offset, max_bytes))
+                })
+                .collect();
+            let mut bytes = 0;
+            let mut failed = false;
+            for (_, res) in &results {
+                match res {
+                    Ok(fetched) => bytes += fetched.records.as_ref().map_or(0, |r| r.length),
+                    Err(_) => failed = true,
+                }
+            }
+            let now = Instant::now();
+            if bytes > 0 || failed || now >= deadline { break results }
+            appends.wait(seen, deadline - now);
+        };
+
+        enc.array_len(results.len());
+        for (tp, res) in results {
+            enc.string(&tp.topic);
+            enc.i32(tp.partition as i32);
+            let fetched = match res {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    enc.i16(kafka::error_code(&e));
+                    enc.nullable_string(Some(&e.to_string()));
+                    continue
+                },
+            };
+            enc.i16(errors::NONE);
+            enc.nullable_string(None);
+            enc.i64(fetched.log_end_offset as i64);
+            enc.i64(fetched.high_watermark as i64);
+            enc.array_len(fetched.epochs.len());
+            for entry in &fetched.epochs {
+                enc.i32(entry.epoch);
+                enc.i64(entry.start_offset as i64);
+            }
+            native::encode_messages(enc, slices, fetched.records);
+        }
+        Ok(())
+    }
+
+    fn offsets_for_leader_epoch(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // replica_id i32 | [topic | partition i32 | current_leader_epoch i32 | epoch i32],
+        // answered per partition with the leader's epoch at or below it and its end offset
+        let _replica_id = dec.i32()?;
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut answers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tp = TopicPartitionId::new(&dec.string()?, dec.i32()?.max(0) as u32);
+            let current_leader_epoch = dec.i32()?;
+            let epoch = dec.i32()?;
+            let res = self.replicas.end_offset_for_epoch(&tp, current_leader_epoch, epoch);
+            answers.push((tp, res));
+        }
+        enc.array_len(answers.len());
+        for (tp, res) in answers {
+            enc.string(&tp.topic);
+            enc.i32(tp.partition as i32);
+            match res {
+                Ok((epoch, end_offset)) => {
+                    enc.i16(errors::NONE);
+                    enc.nullable_string(None);
+                    enc.i32(epoch);
+                    enc.i64(end_offset as i64);
+                },
+                Err(e) => {
+                    enc.i16(kafka::error_code(&e));
+                    enc.nullable_string(Some(&e.to_string()));
+                },
+            }
+        }
+        Ok(())
+    }
+    fn leader_and_isr(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // controller_id i32 | controller_epoch i32 | [broker_id i32 | host | port i32] |
+        // [topic | partition i32 | leader i32 | leader_epoch i32 | [replica i32] | [isr i32]],
+        // answered with a status per partition, then the partitions this
+        // broker leads whose isr it wants changed, with the isr it wants
+        let controller_id = dec.i32()?;
+        let controller_epoch = dec.i32()?;
+        if controller_id < 0 { return Err(Error::InvalidRequest(format!("controller {}", controller_id))) }
+        self.replicas.check_controller(controller_id as u32, controller_epoch)?;
+        // the live brokers, fenced ones drop out
+        self.replicas.set_brokers(controller::decode_brokers(dec)?);
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut applied = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tp = TopicPartitionId::new(&dec.string()?, dec.i32()?.max(0) as u32);
+            let state = controller::decode_state(dec)?;
+            let res = self.replicas.apply(&tp.topic, tp.partition, &state);
+            applied.push((tp, res));
+        }
+        enc.array_len(applied.len());
+        for (tp, res) in applied {
+            enc.string(&tp.topic);
+            enc.i32(tp.partition as i32);
+            match res {
+                Ok(()) => {
+                    enc.i16(errors::NONE);
+                    enc.nullable_string(None);
+                },
+                Err(e) => {
+                    enc.i16(kafka::error_code(&e));
+                    enc.nullable_string(Some(&e.to_string()));
+                },
+            }
+        }
+        let proposals = self.replicas.isr_proposals();
+        enc.array_len(proposals.len());
+        for (tp, state) in proposals {
+            enc.string(&tp.topic);
+            enc.i32(tp.partition as i32);
+            controller::encode_state(enc, &state);
+        }
+        Ok(())
+    }
+
+    fn controller(&self) -> Result<&Arc<Controller>> {
+        self.controller.as_ref().ok_or_else(|| Error::NotController(format!("broker {}", self.replicas.broker_id())))
+    }
+
+    fn register_broker(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // broker_id i32 | host | port i32, answered with the broker's epoch i64
+        let broker = controller::decode_broker(dec)?;
+        enc.i64(self.controller()?.register_broker(broker)?);
+        Ok(())
+    }
+
+    fn broker_heartbeat(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // broker_id i32 | epoch i64, answered with [broker_id i32 | host | port i32]
+        // of the live brokers
+        let id = dec.i32()?;
+        let epoch = dec.i64()?;
+        if id < 0 { return Err(Error::InvalidRequest(format!("broker {}", id))) }
+        let brokers = self.controller()?.heartbeat(id as u32, epoch)?;
+        controller::encode_brokers(enc, &brokers);
+        Ok(())
+    }
+
+    fn reassign_partitions(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // [topic | partition i32 | [replica i32]], answered with a status per
+        // partition once its move has started
+        let controller = self.controller()?;
+        let count = dec.array_len()?.unwrap_or(0);
+        let mut started = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tp = TopicPartitionId::new(&dec.string()?, dec.i32()?.max(0) as u32);
+            let mut target = vec![];
+            for _ in 0..dec.array_len()?.unwrap_or(0) {
+                let id = dec.i32()?;
+                if id < 0 { return Err(Error::InvalidRequest(format!("broker {}", id))) }
+                target.push(id as u32);
+            }
+            let res = controller.reassign_partition(&tp.topic, tp.partition, &target);
+            started.push((tp, res));
+        }
+        enc.array_len(started.len());
+        for (tp, res) in started {
+            enc.string(&tp.topic);
+            enc.i32(tp.partition as i32);
+            match res {
+                Ok(()) => {
+                    enc.i16(errors::NONE);
+                    enc.nullable_string(None);
+                },
+                Err(e) => {
+                    enc.i16(kafka::error_code(&e));
+                    enc.nullable_string(Some(&e.to_string()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn raft_vote(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // term i32 | candidate i32 | last_term i32 | log_end_offset i64,
+        // answered with term i32 | granted bool
+        let req = raft::decode_vote_request(dec)?;
+        raft::encode_vote_response(enc, &self.controller()?.quorum().handle_vote(&req)?);
+        Ok(())
+    }
+
+    fn raft_append(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // term i32 | leader i32 | prev_log_end i64 | prev_term i32 |
+        // [term i32 | offset i64 | payload bytes] | commit_offset i64,
+        // answered with term i32 | success bool | log_end_offset i64
+        let req = raft::decode_append_request(dec)?;
+        raft::encode_append_response(enc, &self.controller()?.quorum().handle_append(&req)?);
+        Ok(())
+    }
+
+    fn raft_snapshot(&self, dec: &mut Decoder, enc: &mut Encoder) -> Result<()> {
+        // term i32 | leader i32 | end_offset i64 | snapshot_term i32 | data bytes,
+        // answered with term i32
+        let req = raft::decode_snapshot_request(dec)?;
+        enc.i32(self.controller()?.quorum().handle_snapshot(&req)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+pub mod handler;
+
+use std::io::{Write};
+use std::net::{TcpStream};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::{Error, Result};
+use crate::kafka::{errors};
+use crate::kafka::codec::{Decoder, Encoder};
+use crate::partition::message::{Message};
+use crate::partition::slice::{self, FileSlice};
+
+// A native connection opens with these 4 bytes. Read as a kafka request size
+// they're over a gigabyte, so one port can serve both protocols.
+pub const MAGIC: [u8; 4] = *b"LTK1";
+
+// Requests are `op i8 | body`, responses `error i16 | message nullable string | body`,
+// both framed by a u32 size. Error codes are kafka's. A fetch carries its own
+// status per partition, so one bad partition doesn't fail the others.
+pub const PRODUCE: i8 = 1;
+pub const FETCH: i8 = 2;
+pub const METADATA: i8 = 3;
+pub const LIST_OFFSETS: i8 = 4;
+pub const PRODUCE_BATCH: i8 = 5;
+pub const INIT_PRODUCER_ID: i8 = 6;
+pub const ADD_PARTITIONS_TO_TXN: i8 = 7;
+pub const END_TXN: i8 = 8;
+pub const OFFSET_COMMIT: i8 = 9;
+pub const OFFSET_FETCH: i8 = 10;
+pub const JOIN_GROUP: i8 = 11;
+pub const SYNC_GROUP: i8 = 12;
+pub const HEARTBEAT: i8 = 13;
+pub const LEAVE_GROUP: i8 = 14;
+pub const REPLICA_FETCH: i8 = 15;
+pub const OFFSETS_FOR_LEADER_EPOCH: i8 = 16;
+pub const LEADER_AND_ISR: i8 = 17;
+pub const BROKER_REGISTRATION: i8 = 18;
+pub const BROKER_HEARTBEAT: i8 = 19;
+pub const REASSIGN_PARTITIONS: i8 = 20;
+// between the members of the metadata quorum
+pub const RAFT_VOTE: i8 = 21;
+pub const RAFT_APPEND: i8 = 22;
+pub const RAFT_SNAPSHOT: i8 = 23;
+
+// A response framed and ready to go, with the log byte ranges that go into
+// it at their positions sent straight from the segment files
+pub struct Response {
+    frame: Vec<u8>,
+    slices: Vec<(usize, FileSlice)>,
+}
+
+impl Response {
+    pub fn new(frame: Vec<u8>, slices: Vec<(usize, FileSlice)>) -> Response { Response{ frame, slices } }
+
+    pub fn len(&self) -> usize {
+        self.frame.len() + self.slices.iter().map(|(_, slice)| slice.length as usize).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn write_to(&self, stream: &mut TcpStream) -> Result<()> {
+        stream.write_u32::<BigEndian>(self.len() as u32)?;
+        let mut written = 0;
+        for (at, slice) in &self.slices {
+            stream.write_all(&self.frame[written..*at])?;
+            slice.transfer_to(stream)?;
+            written = *at;
+        }
+        stream.write_all(&self.frame[written..])?;
+        Ok(())
+    }
+}
+
+pub fn encode_messages(enc: &mut Encoder, slices: &mut Vec<(usize, FileSlice)>, slice: Option<FileSlice>) {
+    // [size i32] | bytes, the messages of a slice as they're laid out in the
+    // log. The bytes follow from the file, the encoder only has their length
+    let slice = match slice {
+        Some(slice) => slice,
+        None => {
+            enc.array_len(0);
+            enc.i32(0);
+            return
+        },
+    };
+    enc.array_len(slice.sizes.len());
+    for size in &slice.sizes {
+        enc.i32(*size as i32);
+    }
+    enc.i32(slice.length as i32);
+    slices.push((enc.len(), slice));
+}
+
+pub fn encode_copied_messages(enc: &mut Encoder, messages: &[Message]) -> Result<()> {
+    // the same, for messages already read out of the log
+    let raw: Vec<Vec<u8>> = messages.iter().map(|m| m.to_vec()).collect::<Result<_>>()?;
+    enc.array_len(raw.len());
+    for message in &raw {
+        enc.i32(message.len() as i32);
+    }
+    enc.i32(raw.iter().map(|m| m.len()).sum::<usize>() as i32);
+    for message in &raw {
+        enc.raw(message);
+    }
+    Ok(())
+}
+
+pub fn decode_messages(dec: &mut Decoder) -> Result<Vec<Message>> {
+    let mut sizes = vec![];
+    for _ in 0..dec.array_len()?.unwrap_or(0) {
+        let size = dec.i32()?;
+        if size < 0 { return Err(Error::CorruptRecord(format!("message of {} bytes", size))) }
+        sizes.push(size as u32);
+    }
+    let raw = dec.bytes()?.unwrap_or_default();
+    slice::split_messages(raw, &sizes)
+}
+
+pub fn remote_error(code: i16, message: Option<String>) -> Error {
+    let message = message.unwrap_or_default();
+    match code {
+        errors::INVALID_REQUEST => Error::InvalidRequest(message),
+        errors::INVALID_TXN_STATE => Error::InvalidTxnState(message),
+        errors::UNKNOWN_MEMBER_ID => Error::UnknownMember(message),
+        // the generations are in the message, what matters is the member is behind
+        errors::ILLEGAL_GENERATION => Error::IllegalGeneration{ generation: -1, current: -1 },
+        errors::REBALANCE_IN_PROGRESS => Error::RebalanceInProgress,
+        errors::INCONSISTENT_GROUP_PROTOCOL => Error::InconsistentGroupProtocol(message),
+        errors::NOT_LEADER_FOR_PARTITION => Error::NotLeader(message),
+        errors::LEADER_NOT_AVAILABLE => Error::LeaderNotAvailable(message),
+        errors::STALE_BROKER_EPOCH => Error::StaleBrokerEpoch(message),
+        errors::NOT_CONTROLLER => Error::NotController(message),
+        // the epochs are in the message, what matters is which side is behind
+        errors::FENCED_LEADER_EPOCH => Error::FencedLeaderEpoch{ epoch: -1, current: 0 },
+        errors::UNKNOWN_LEADER_EPOCH => Error::FencedLeaderEpoch{ epoch: 0, current: -1 },
+        code => Error::Remote{ code, message },
+    }
+}
+
+pub fn decode_status(dec: &mut Decoder) -> Result<()> {
+    let code = dec.i16()?;
+    let message = dec.nullable_string()?;
+    if code == errors::NONE { Ok(()) } else { Err(remote_error(code, message)) }
+}
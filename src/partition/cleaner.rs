@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use crate::{Result};
+use crate::partition::{Partition, Offset};
+use crate::partition::config::{CleanupPolicy};
+use crate::partition::message::{Message};
+use crate::partition::record::{Record};
+use crate::partition::segment::{now_ms, Access};
+use crate::partition::transaction::{CONTROL};
+
+
+// Retention, compaction and timed flushes. The log manager calls these from its
+// background thread; the active segment is never deleted or compacted.
+impl Partition {
+    pub fn log_start_offset(&self) -> Offset {
+        match self.segments.first() {
+            Some(segment) => segment.base_offset,
+            None => self.active_segment.base_offset,
+        }
+    }
+
+    pub fn log_end_offset(&self) -> Offset { self.active_segment.newest_offset() }
+
+    pub fn size(&self) -> Result<u64> {
+        let mut size = self.active_segment.size()?;
+        for segment in &self.segments {
+            size += segment.size()?;
+        }
+        Ok(size)
+    }
+
+    pub fn roll_if_old(&mut self) -> Result<bool> {
+        // an idle partition's active segment ages past segment.ms with no
+        // append to roll it, retention rolls it so it can be deleted in time
+        if self.access == Access::ReadOnly || !self.is_due_to_roll() { return Ok(false) }
+        self.split()?;
+        Ok(true)
+    }
+
+    pub fn delete_old_segments(&mut self) -> Result<usize> {
+        // drops the oldest inactive segments past retention.ms or retention.bytes
+        if self.config.cleanup_policy != CleanupPolicy::Delete { return Ok(0) }
+        let now = now_ms();
+        let mut size = self.size()?;
+        let mut deleted = 0;
+        while !self.segments.is_empty() {
+            let oldest = &self.segments[0];
+            let segment_size = oldest.size()?;
+            let expired = match self.config.retention_ms {
+                Some(ms) => now.saturating_sub(oldest.last_write_ms()?) >= ms,
+                None => false,
+            };
+            let oversized = match self.config.retention_bytes {
+                Some(max) => size - segment_size >= max,
+                None => false,
+            };
+            if !expired && !oversized { break }
+            let oldest = self.segments.remove(0);
+            oldest.delete()?;
+            size -= segment_size;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    pub fn delete_segments_before(&mut self, offset: Offset) -> Result<usize> {
+        // drops the inactive segments with nothing at or past offset, what a
+        // snapshot up to offset made redundant
+        let mut deleted = 0;
+        while !self.segments.is_empty() {
+            let next_base = self.segments.get(1).map_or(self.active_segment.base_offset, |s| s.base_offset);
+            if next_base > offset { break }
+            self.segments.remove(0).delete()?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    pub fn compact(&mut self) -> Result<usize> {
+        // keeps only the latest record per key in the inactive segments. Payloads
+        // that aren't keyed records are left alone, so are transaction markers.
+        // Keys are only read from what was written since the last pass, a
+        // partition that hasn't rolled a segment since then is skipped
+        if self.config.cleanup_policy != CleanupPolicy::Compact { return Ok(0) }
+        let dirty_end = self.active_segment.base_offset;
+        if self.segments.is_empty() || dirty_end <= self.clean_offset { return Ok(0) }
+
+        let clean_offset = self.clean_offset;
+        let mut latest: HashMap<Vec<u8>, Offset> = HashMap::new();
+        for segment in self.segments.iter().chain(std::iter::once(&self.active_segment)) {
+            if segment.newest_offset() <= clean_offset { continue }
+            for message in segment.read_messages()? {
+                if message.offset < clean_offset { continue }
+                if let Ok(Record{ key: Some(key), attributes, .. }) = Record::from_slice(&message.payload) {
+                    if attributes & CONTROL == 0 { latest.insert(key, message.offset); }
+                }
+            }
+        }
+        let superseded = |message: &Message| match Record::from_slice(&message.payload) {
+            Ok(Record{ key: Some(key), attributes, .. }) if attributes & CONTROL == 0 => {
+                latest.get(&key).is_some_and(|&offset| offset > message.offset)
+            },
+            _ => false,
+        };
+
+        let mut removed = 0;
+        for i in 0..self.segments.len() {
+            // a segment keeps its last message, and is left as it is when
+            // that's all it would lose
+            let last = self.segments[i].newest_offset().saturating_sub(1);
+            let droppable = self.segments[i].read_messages()?.iter()
+                .any(|message| message.offset != last && superseded(message));
+            if !droppable { continue }
+            let mut dropped = 0;
+            let rewritten = self.segments[i].rewrite(|message| {
+                let keep = !superseded(message);
+                if !keep { dropped += 1 }
+                keep
+            })?;
+            self.segments[i] = rewritten;
+            removed += dropped;
+        }
+        self.clean_offset = dirty_end;
+        Ok(removed)
+    }
+
+    pub fn flush_if_due(&mut self) -> Result<bool> {
+        if self.unflushed == 0 || !self.check_flush() { return Ok(false) }
+        self.flush()?;
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+    use super::*;
+    use crate::partition::config::{PartitionConfig};
+    use crate::partition::segment::{MaxBytes};
+
+    fn keyed(partition: &mut Partition, key: &str, value: Option<&str>) -> Offset {
+        let record = Record::new(0, Some(key.as_bytes()), value.map(|v| v.as_bytes()));
+        partition.append(&record.to_vec().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn it_deletes_segments_past_retention_bytes() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            retention_bytes: Some(40),
+            ..PartitionConfig::from(MaxBytes(28, 32))
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+        for _ in 0..4 {
+            partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        }
+        assert_eq!(partition.segments_len(), 3);
+
+        assert_eq!(partition.delete_old_segments().unwrap(), 2, "keeps 56 bytes >= 40");
+        assert_eq!(partition.segments_len(), 1);
+        assert_eq!(partition.log_start_offset(), 2);
+        assert!(partition.fetch(0, 1024).is_err(), "deleted offsets are out of range");
+        assert_eq!(partition.fetch(2, 1024).unwrap().unwrap().length, 28);
+    }
+
+    #[test]
+    fn it_deletes_segments_past_retention_ms() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            retention_ms: Some(60_000),
+            ..PartitionConfig::from(MaxBytes(28, 32))
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+        for _ in 0..3 {
+            partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        }
+        assert_eq!(partition.delete_old_segments().unwrap(), 0, "nothing old yet");
+
+        // a rolled segment ages by the last write kept beside it, not the
+        // log file's mtime
+        let mut path = partition.path.clone();
+        drop(partition);
+        path.push("00000000000000000000.log");
+        let log = OpenOptions::new().write(true).open(&path).unwrap();
+        log.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        path.pop();
+        let mut partition = Partition::load(&path, MaxBytes(28, 32)).unwrap();
+        assert_eq!(partition.delete_old_segments().unwrap(), 0, "mtime doesn't count");
+
+        drop(partition);
+        let hour_ago = now_ms() - 3_600_000;
+        fs::write(path.join("00000000000000000000.timestamp"), format!("{} {}", hour_ago, hour_ago)).unwrap();
+        let mut partition = Partition::load(&path, MaxBytes(28, 32)).unwrap();
+        assert_eq!(partition.delete_old_segments().unwrap(), 1);
+        assert!(!path.join("00000000000000000000.log").exists(), "segment files removed");
+        assert_eq!(partition.log_start_offset(), 1);
+    }
+
+    #[test]
+    fn it_rolls_idle_segments_for_retention() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            segment_ms: Some(60_000),
+            retention_ms: Some(60_000),
+            ..PartitionConfig::from(MaxBytes(1024, 1024))
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+        partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        partition.append("XX".as_bytes()).unwrap();
+        assert!(!partition.roll_if_old().unwrap(), "young segment isn't rolled");
+
+        // nothing appended since an hour ago
+        let path = partition.path.clone();
+        drop(partition);
+        let hour_ago = now_ms() - 3_600_000;
+        fs::write(path.join("00000000000000000000.timestamp"), format!("{} {}", hour_ago, hour_ago)).unwrap();
+        let mut partition = Partition::load(&path, MaxBytes(1024, 1024)).unwrap();
+        assert!(partition.roll_if_old().unwrap());
+        assert_eq!((partition.segments_len(), partition.active_segment.base_offset), (1, 2));
+        assert!(!partition.roll_if_old().unwrap(), "the new segment is empty");
+        assert_eq!(partition.delete_old_segments().unwrap(), 1);
+        assert_eq!((partition.log_start_offset(), partition.log_end_offset()), (2, 2));
+    }
+
+    #[test]
+    fn it_compacts_keyed_records() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            cleanup_policy: CleanupPolicy::Compact,
+            ..PartitionConfig::from(MaxBytes(96, 64))
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+        keyed(&mut partition, "a", Some("1"));
+        keyed(&mut partition, "b", Some("1"));
+        keyed(&mut partition, "a", Some("2"));
+        keyed(&mut partition, "c", Some("1"));
+        keyed(&mut partition, "b", None);
+        keyed(&mut partition, "a", Some("3"));
+        keyed(&mut partition, "c", Some("2"));
+        assert_eq!(partition.segments_len(), 2, "two full segments of 3 records");
+
+        assert_eq!(partition.compact().unwrap(), 3, "a=1, b=1 and c=1 are superseded");
+        let kept: Vec<Offset> = partition.segments.iter()
+            .flat_map(|s| s.read_messages().unwrap())
+            .map(|m| m.offset)
+            .collect();
+        assert_eq!(kept, vec![2, 4, 5], "a=2 is the last of its segment, b's tombstone is latest");
+        assert_eq!(partition.fetch(0, 1024).unwrap().unwrap().position, 0, "fetch skips ahead");
+        assert_eq!(partition.log_end_offset(), 7);
+        assert_eq!(partition.compact().unwrap(), 0, "nothing left to do");
+    }
+
+    #[test]
+    fn it_only_compacts_what_changed() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            cleanup_policy: CleanupPolicy::Compact,
+            ..PartitionConfig::from(MaxBytes(96, 64))
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+        for key in &["a", "b", "c", "d", "e", "f", "g"] {
+            keyed(&mut partition, key, Some("1"));
+        }
+        assert_eq!(partition.compact().unwrap(), 0, "every key is distinct");
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for segment in &partition.segments {
+            let log = OpenOptions::new().write(true).open(partition.path.join(format!("{:0>20}.log", segment.base_offset))).unwrap();
+            log.set_modified(hour_ago).unwrap();
+        }
+        let modified: Vec<u64> = partition.segments.iter().map(|s| s.last_modified_ms().unwrap()).collect();
+        assert_eq!(partition.compact().unwrap(), 0, "nothing rolled since the last pass");
+
+        keyed(&mut partition, "a", Some("2"));
+        keyed(&mut partition, "h", Some("1"));
+        keyed(&mut partition, "i", Some("1"));
+        assert_eq!(partition.segments_len(), 3);
+        assert_eq!(partition.compact().unwrap(), 1, "a=1 is superseded");
+        assert!(partition.segments[0].last_modified_ms().unwrap() > modified[0], "rewritten");
+        assert_eq!(partition.segments[1].last_modified_ms().unwrap(), modified[1], "nothing to drop, left alone");
+        assert_eq!(partition.read(0, 1024).unwrap()[0].offset, 1);
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{Error, Result};
+use crate::partition::entry::{ENTRY_WIDTH};
+use crate::partition::segment::{MaxBytes};
+
+pub const CONFIG_FILE: &str = "partition.properties";
+
+const TEN_MB: u64 = 1024 * 1024 * 10;
+const ONE_MB: u64 = 1024 * 1024;
+const SEVEN_DAYS_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+const DEFAULT_SEGMENT_MAX_BYTES: u64 = TEN_MB;
+const DEFAULT_INDEX_MAX_BYTES: u64 = TEN_MB;
+const DEFAULT_MAX_MESSAGE_BYTES: u64 = ONE_MB;
+
+// Topic level settings apply to every partition of the topic
+pub type TopicConfig = PartitionConfig;
+
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CleanupPolicy {
+    Delete,
+    Compact,
+}
+
+// Recorded so producers and brokers agree on the codec, the log stores
+// message payloads as they're given
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PartitionConfig {
+    pub segment_bytes: u64,
+    pub index_bytes: u64,
+    pub segment_ms: Option<u64>,
+    pub segment_jitter_ms: u64,
+    pub retention_ms: Option<u64>,
+    pub retention_bytes: Option<u64>,
+    pub cleanup_policy: CleanupPolicy,
+    pub compression: Compression,
+    pub flush_messages: Option<u64>,
+    pub flush_ms: Option<u64>,
+    pub max_message_bytes: u64,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> PartitionConfig {
+        PartitionConfig {
+            segment_bytes: DEFAULT_SEGMENT_MAX_BYTES,
+            index_bytes: DEFAULT_INDEX_MAX_BYTES,
+            segment_ms: Some(SEVEN_DAYS_MS),
+            segment_jitter_ms: 0,
+            retention_ms: Some(SEVEN_DAYS_MS),
+            retention_bytes: None,
+            cleanup_policy: CleanupPolicy::Delete,
+            compression: Compression::None,
+            flush_messages: None, // leave it to the OS
+            flush_ms: None,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+impl From<MaxBytes> for PartitionConfig {
+    fn from(max_bytes: MaxBytes) -> PartitionConfig {
+        PartitionConfig {
+            segment_bytes: max_bytes.0,
+            index_bytes: max_bytes.1,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES.min(max_bytes.0),
+            ..PartitionConfig::default()
+        }
+    }
+}
+
+impl PartitionConfig {
+    pub fn max_bytes(&self) -> MaxBytes { MaxBytes(self.segment_bytes, self.index_bytes) }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.segment_bytes == 0 {
+            return Err(Error::InvalidConfig(String::from("segment.bytes must be greater than 0")))
+        }
+        if !self.index_bytes.is_multiple_of(ENTRY_WIDTH as u64) || self.index_bytes < 2 * ENTRY_WIDTH as u64 {
+            return Err(Error::InvalidConfig(String::from(
+                "segment.index.bytes must be a multiple of 8 and at least 16")))
+        }
+        if self.max_message_bytes == 0 {
+            return Err(Error::InvalidConfig(String::from("max.message.bytes must be greater than 0")))
+        }
+        if self.max_message_bytes > self.segment_bytes {
+            // every message has to fit in an empty segment
+            return Err(Error::InvalidConfig(String::from("max.message.bytes must not exceed segment.bytes")))
+        }
+        if self.segment_ms == Some(0) {
+            return Err(Error::InvalidConfig(String::from("segment.ms must be greater than 0")))
+        }
+        if let Some(ms) = self.segment_ms {
+            if self.segment_jitter_ms >= ms {
+                return Err(Error::InvalidConfig(String::from("segment.jitter.ms must be less than segment.ms")))
+            }
+        }
+        if self.flush_messages == Some(0) {
+            return Err(Error::InvalidConfig(String::from("flush.messages must be greater than 0")))
+        }
+        Ok(())
+    }
+
+    pub fn path(dir: &Path) -> PathBuf { dir.join(CONFIG_FILE) }
+
+    // reads `<dir>/partition.properties`, None if the partition predates it
+    pub fn load(dir: &Path) -> Result<Option<PartitionConfig>> {
+        let path = PartitionConfig::path(dir);
+        if !path.exists() { return Ok(None) }
+        let config = PartitionConfig::parse(&fs::read_to_string(path)?)?;
+        Ok(Some(config))
+    }
+
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        // write aside and rename so a crash never leaves half a config behind
+        let path = PartitionConfig::path(dir);
+        let tmp_path = path.with_extension("properties.tmp");
+        {
+            let mut file = OpenOptions::new().create(true).truncate(true).write(true).open(&tmp_path)?;
+            file.write_all(self.to_string().as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn parse(raw: &str) -> Result<PartitionConfig> {
+        // java style properties, keys we don't write keep their defaults
+        let mut config = PartitionConfig::default();
+        for line in raw.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(Error::InvalidConfig(format!("expected key=value, got {:?}", line))),
+            };
+            match key {
+                "segment.bytes" => config.segment_bytes = parse_value(key, value)?,
+                "segment.index.bytes" => config.index_bytes = parse_value(key, value)?,
+                "segment.ms" => config.segment_ms = parse_optional(key, value)?,
+                "segment.jitter.ms" => config.segment_jitter_ms = parse_value(key, value)?,
+                "retention.ms" => config.retention_ms = parse_optional(key, value)?,
+                "retention.bytes" => config.retention_bytes = parse_optional(key, value)?,
+                "cleanup.policy" => config.cleanup_policy = match value {
+                    "delete" => CleanupPolicy::Delete,
+                    "compact" => CleanupPolicy::Compact,
+                    _ => return Err(invalid_value(key, value)),
+                },
+                "compression.type" => config.compression = match value {
+                    "none" | "uncompressed" => Compression::None,
+                    "gzip" => Compression::Gzip,
+                    "snappy" => Compression::Snappy,
+                    "lz4" => Compression::Lz4,
+                    "zstd" => Compression::Zstd,
+                    _ => return Err(invalid_value(key, value)),
+                },
+                "flush.messages" => config.flush_messages = parse_optional(key, value)?,
+                "flush.ms" => config.flush_ms = parse_optional(key, value)?,
+                "max.message.bytes" => config.max_message_bytes = parse_value(key, value)?,
+                _ => return Err(Error::InvalidConfig(format!("unknown config {}", key))),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl std::fmt::Display for PartitionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let optional = |value: Option<u64>| match value {
+            Some(v) => v.to_string(),
+            None => String::from("-1"),
+        };
+        let cleanup_policy = match self.cleanup_policy {
+            CleanupPolicy::Delete => "delete",
+            CleanupPolicy::Compact => "compact",
+        };
+        let compression = match self.compression {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        };
+        writeln!(f, "segment.bytes={}", self.segment_bytes)?;
+        writeln!(f, "segment.index.bytes={}", self.index_bytes)?;
+        writeln!(f, "segment.ms={}", optional(self.segment_ms))?;
+        writeln!(f, "segment.jitter.ms={}", self.segment_jitter_ms)?;
+        writeln!(f, "retention.ms={}", optional(self.retention_ms))?;
+        writeln!(f, "retention.bytes={}", optional(self.retention_bytes))?;
+        writeln!(f, "cleanup.policy={}", cleanup_policy)?;
+        writeln!(f, "compression.type={}", compression)?;
+        writeln!(f, "flush.messages={}", optional(self.flush_messages))?;
+        writeln!(f, "flush.ms={}", optional(self.flush_ms))?;
+        writeln!(f, "max.message.bytes={}", self.max_message_bytes)
+    }
+}
+
+fn invalid_value(key: &str, value: &str) -> Error {
+    Error::InvalidConfig(format!("{}={} is not a valid value", key, value))
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse::<T>().map_err(|_| invalid_value(key, value))
+}
+
+fn parse_optional(key: &str, value: &str) -> Result<Option<u64>> {
+    // kafka uses -1 for "no limit"
+    if value == "-1" { return Ok(None) }
+    Ok(Some(parse_value(key, value)?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn it_defaults_config() {
+        let config = PartitionConfig::default();
+        assert_eq!(config.max_bytes(), MaxBytes(TEN_MB, TEN_MB));
+        assert_eq!(config.cleanup_policy, CleanupPolicy::Delete);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn it_converts_max_bytes() {
+        let config = PartitionConfig::from(MaxBytes(64, 32));
+        assert_eq!(config.segment_bytes, 64);
+        assert_eq!(config.index_bytes, 32);
+        assert_eq!(config.max_message_bytes, 64, "capped at the segment size");
+        let config = PartitionConfig::from(MaxBytes(TEN_MB, 32));
+        assert_eq!(config.max_message_bytes, DEFAULT_MAX_MESSAGE_BYTES);
+    }
+
+    #[test]
+    fn it_rejects_invalid_config() {
+        let config = PartitionConfig::from(MaxBytes(64, 20));
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig(_))), "index bytes not a multiple of 8");
+        let config = PartitionConfig::from(MaxBytes(0, 32));
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig(_))), "empty segments");
+        assert!(PartitionConfig::parse("segment.bytes=lots").is_err(), "not a number");
+        assert!(PartitionConfig::parse("segment.bytes=64\nmax.message.bytes=65").is_err(), "message can't fit a segment");
+        assert!(PartitionConfig::parse("segment.bytez=64").is_err(), "unknown key");
+        assert!(PartitionConfig::parse("cleanup.policy=shred").is_err(), "unknown policy");
+        assert!(PartitionConfig::parse("segment.ms=10\nsegment.jitter.ms=10").is_err(), "jitter swallows segment.ms");
+    }
+
+    #[test]
+    fn it_parses_properties() {
+        let config = PartitionConfig::parse("\
+            # written by hand\n\
+            segment.bytes = 64\n\
+            max.message.bytes = 64\n\
+            retention.ms=-1\n\
+            cleanup.policy=compact\n\
+            compression.type=lz4\n").unwrap();
+        assert_eq!(config.segment_bytes, 64);
+        assert_eq!(config.index_bytes, DEFAULT_INDEX_MAX_BYTES, "missing keys default");
+        assert_eq!(config.retention_ms, None);
+        assert_eq!(config.cleanup_policy, CleanupPolicy::Compact);
+        assert_eq!(config.compression, Compression::Lz4);
+    }
+
+    #[test]
+    fn it_stores_and_loads_config() {
+        let tmp = tempdir().unwrap();
+        assert!(PartitionConfig::load(tmp.path()).unwrap().is_none(), "nothing stored yet");
+
+        let config = PartitionConfig {
+            retention_bytes: Some(4096),
+            flush_messages: Some(10),
+            compression: Compression::Zstd,
+            ..PartitionConfig::from(MaxBytes(64, 32))
+        };
+        config.store(tmp.path()).unwrap();
+        assert_eq!(PartitionConfig::load(tmp.path()).unwrap(), Some(config));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Offset, Result};
+
+pub const EPOCH_FILE: &str = "leader-epoch-checkpoint";
+const EPOCH_VERSION: u32 = 0;
+// what an OffsetsForLeaderEpoch answers for an epoch older than any it knows
+pub const UNDEFINED_EPOCH: i32 = -1;
+
+// an epoch and the offset just past its last message
+pub type EpochEndOffset = (i32, Offset);
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpochEntry {
+    pub epoch: i32,
+    // the first offset written under the epoch
+    pub start_offset: Offset,
+}
+
+
+// The leader epochs a partition's log was written under and where each one
+// starts, kept in the partition dir in kafka's checkpoint format. A follower
+// compares them with the leader's to find where their logs part ways.
+#[derive(Debug, Clone)]
+pub struct LeaderEpochCache {
+    path: PathBuf,
+    entries: Vec<EpochEntry>,
+}
+
+impl LeaderEpochCache {
+    pub fn empty(dir: &Path) -> LeaderEpochCache {
+        LeaderEpochCache{ path: dir.join(EPOCH_FILE), entries: vec![] }
+    }
+
+    pub fn load(dir: &Path) -> Result<LeaderEpochCache> {
+        // version, then the number of entries, then "epoch start_offset" a line each
+        let mut cache = LeaderEpochCache::empty(dir);
+        let raw = match fs::read_to_string(&cache.path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e.into()),
+        };
+        let path = cache.path.clone();
+        let corrupt = || Error::CorruptRecord(format!("{} isn't a leader epoch checkpoint", path.display()));
+        let mut lines = raw.lines();
+        if lines.next().and_then(|l| l.trim().parse::<u32>().ok()) != Some(EPOCH_VERSION) { return Err(corrupt()) }
+        let count = lines.next().and_then(|l| l.trim().parse::<usize>().ok()).ok_or_else(corrupt)?;
+        for line in lines.take(count) {
+            let mut fields = line.split(' ');
+            let epoch = fields.next().and_then(|f| f.parse::<i32>().ok()).ok_or_else(corrupt)?;
+            let start_offset = fields.next().and_then(|f| f.parse::<Offset>().ok()).ok_or_else(corrupt)?;
+            cache.entries.push(EpochEntry{ epoch, start_offset });
+        }
+        if cache.entries.len() != count { return Err(corrupt()) }
+        Ok(cache)
+    }
+
+    pub fn entries(&self) -> &[EpochEntry] { &self.entries }
+    pub fn latest_epoch(&self) -> Option<i32> { self.entries.last().map(|e| e.epoch) }
+
+    pub fn assign(&mut self, epoch: i32, start_offset: Offset) -> Result<()> {
+        // only a newer epoch is recorded, again or older ones are already covered
+        if self.latest_epoch().is_some_and(|latest| epoch <= latest) { return Ok(()) }
+        // an epoch that wrote nothing gives way to the next one at the same offset
+        self.entries.retain(|e| e.start_offset < start_offset);
+        self.entries.push(EpochEntry{ epoch, start_offset });
+        self.store()
+    }
+
+    pub fn end_offset_for(&self, epoch: i32, log_end_offset: Offset) -> EpochEndOffset {
+        // the largest epoch at or below `epoch` and where it ends: the start
+        // of the epoch after it, or the log end for the latest. UNDEFINED_EPOCH
+        // when the log has nothing that old
+        match self.entries.iter().rposition(|e| e.epoch <= epoch) {
+            None => (UNDEFINED_EPOCH, log_end_offset),
+            Some(i) => match self.entries.get(i + 1) {
+                Some(next) => (self.entries[i].epoch, next.start_offset),
+                None => (self.entries[i].epoch, log_end_offset),
+            },
+        }
+    }
+
+    pub fn entries_from(&self, offset: Offset) -> Vec<EpochEntry> {
+        // the epoch in effect at offset and every one after
+        let first = self.entries.iter().rposition(|e| e.start_offset <= offset).unwrap_or(0);
+        self.entries[first..].to_vec()
+    }
+
+    pub fn truncate_from_end(&mut self, offset: Offset) -> Result<()> {
+        // after the log was cut back to offset, epochs starting there or later are gone
+        let before = self.entries.len();
+        self.entries.retain(|e| e.start_offset < offset);
+        if self.entries.len() == before { return Ok(()) }
+        self.store()
+    }
+
+    fn store(&self) -> Result<()> {
+        let mut raw = format!("{}\n{}\n", EPOCH_VERSION, self.entries.len());
+        for entry in &self.entries {
+            raw.push_str(&format!("{} {}\n", entry.epoch, entry.start_offset));
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, raw)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn it_finds_where_epochs_end() {
+        let tmp = tempdir().unwrap();
+        let mut cache = LeaderEpochCache::empty(tmp.path());
+        cache.assign(1, 0).unwrap();
+        cache.assign(1, 4).unwrap();
+        cache.assign(3, 5).unwrap();
+        cache.assign(2, 7).unwrap();
+        cache.assign(4, 8).unwrap();
+
+        let cache = LeaderEpochCache::load(tmp.path()).unwrap();
+        assert_eq!(cache.entries().iter().map(|e| (e.epoch, e.start_offset)).collect::<Vec<_>>(), vec![(1, 0), (3, 5), (4, 8)]);
+        assert_eq!(cache.end_offset_for(0, 10), (UNDEFINED_EPOCH, 10));
+        assert_eq!(cache.end_offset_for(1, 10), (1, 5));
+        assert_eq!(cache.end_offset_for(2, 10), (1, 5), "the follower's epoch 2 never wrote here");
+        assert_eq!(cache.end_offset_for(4, 10), (4, 10));
+        assert_eq!(cache.entries_from(6).len(), 2);
+
+        let mut cache = cache;
+        cache.truncate_from_end(5).unwrap();
+        assert_eq!(LeaderEpochCache::load(tmp.path()).unwrap().latest_epoch(), Some(1));
+    }
+}
@@ -1,11 +1,10 @@
-use std::{io};
 use std::fs::{OpenOptions, File};
-use std::io::{Read, Write};
 use std::path::PathBuf;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
-use memmap::{MmapMut, MmapOptions};
+use memmap::{Mmap, MmapMut, MmapOptions};
 
+use crate::{Error, Result};
 use crate::partition::{Offset};
 use crate::partition::entry::{Entry, RelativeEntry, ENTRY_WIDTH};
 
@@ -14,6 +13,29 @@ fn idx_name(base_offset: Offset) -> PathBuf {
 }
 
 
+// Writers map the index read-write, read-only partitions map it read-only
+#[derive(Debug)]
+enum IndexMap {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl IndexMap {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            IndexMap::ReadWrite(mmap) => &mmap[..],
+            IndexMap::ReadOnly(mmap) => &mmap[..],
+        }
+    }
+    fn as_mut_slice(&mut self) -> Result<&mut [u8]> {
+        match self {
+            IndexMap::ReadWrite(mmap) => Ok(&mut mmap[..]),
+            IndexMap::ReadOnly(_) => Err(Error::ReadOnly),
+        }
+    }
+}
+
+
 // The Index is a memory mapped `.index` file
 #[derive(Debug)]
 pub struct Index {
@@ -25,12 +47,12 @@ pub struct Index {
     file: File,
     position: Offset, // position mutex
     // TODO: mmap mutex
-    mmap: MmapMut,
+    mmap: IndexMap,
     // TODO: readwrite mutex
 }
 
 impl Index {
-    pub fn open(path: PathBuf, base_offset: Offset, max_bytes: u64) -> io::Result<Index> {
+    pub fn open(path: PathBuf, base_offset: Offset, max_bytes: u64) -> Result<Index> {
         let file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
         let size = file.metadata()?.len();
         if size == 0 {
@@ -44,18 +66,40 @@ impl Index {
             base_offset: base_offset,
             file: file,
             position: 0,
-            mmap: mmap,
+            mmap: IndexMap::ReadWrite(mmap),
         };
         let entry = index.find_latest_entry()?;
         index.position = entry.position;
         Ok(index)
     }
 
-    pub fn new(mut path: PathBuf, base_offset: Offset, max_bytes: u64) -> io::Result<Index> {
+    pub fn open_read_only(path: PathBuf, base_offset: Offset, max_bytes: u64) -> Result<Index> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let mmap = if size == 0 {
+            // an index the writer hasn't sized yet reads as empty
+            MmapMut::map_anon(max_bytes as usize)?.make_read_only()?
+        } else {
+            unsafe { MmapOptions::new().map(&file)? }
+        };
+        let mut index = Index {
+            max_bytes,
+            path,
+            base_offset,
+            file,
+            position: 0,
+            mmap: IndexMap::ReadOnly(mmap),
+        };
+        let entry = index.find_latest_entry()?;
+        index.position = entry.position;
+        Ok(index)
+    }
+
+    pub fn new(mut path: PathBuf, base_offset: Offset, max_bytes: u64) -> Result<Index> {
         if max_bytes % ENTRY_WIDTH as u64 != 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "max_bytes must be divisible by 8"))
+            return Err(Error::InvalidConfig(String::from("max_bytes must be divisible by 8")))
         } else if max_bytes < 16 {
-            return Err(io::Error::new(io::ErrorKind::Other, "max_bytes must 16 or greater"))
+            return Err(Error::InvalidConfig(String::from("max_bytes must 16 or greater")))
         }
 
 
@@ -73,30 +117,41 @@ impl Index {
             base_offset: base_offset,
             file: file,
             position: 0,
-            mmap: mmap,
+            mmap: IndexMap::ReadWrite(mmap),
         })
     }
 
+    pub fn flush(&self) -> Result<()> {
+        if let IndexMap::ReadWrite(mmap) = &self.mmap {
+            mmap.flush()?;
+        }
+        Ok(())
+    }
+
     pub fn path_buf(&self) -> PathBuf {
         self.path.clone()
     }
-    pub fn len(&self) -> io::Result<u64> {
+    pub fn len(&self) -> Result<u64> {
         let meta = self.file.metadata()?;
         Ok(meta.len())
     }
     pub fn is_empty(&self) -> bool {
         // if the first two entries are zeroes, then the index is "empty"
-        self.mmap[0..16] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        let mmap = self.mmap.as_slice();
+        mmap.len() < 16 || mmap[0..16] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
     }
-    pub fn write_at(&mut self, relative_entry: RelativeEntry, offset: Offset) -> io::Result<()> {
+    pub fn write_at(&mut self, relative_entry: RelativeEntry, offset: Offset) -> Result<()> {
         let mut buf = vec![];
         buf.write_u32::<BigEndian>(relative_entry.offset)?;
         buf.write_u32::<BigEndian>(relative_entry.position)?;
-        self.mmap[offset as usize..offset as usize + ENTRY_WIDTH as usize].copy_from_slice(&buf);
+        let mmap = self.mmap.as_mut_slice()?;
+        let (off, end) = (offset as usize, offset as usize + ENTRY_WIDTH as usize);
+        if end > mmap.len() { return Err(Error::IndexFull) }
+        mmap[off..end].copy_from_slice(&buf);
         Ok(())
     }
 
-    pub fn write_entry(&mut self, entry: Entry) -> io::Result<()> {
+    pub fn write_entry(&mut self, entry: Entry) -> Result<()> {
         let relative_entry = RelativeEntry::new(entry, self.base_offset);
         // write_at
         self.write_at(relative_entry, relative_entry.offset as Offset * ENTRY_WIDTH as Offset)?;
@@ -105,18 +160,21 @@ impl Index {
         Ok(())
     }
 
-    pub fn read_at(&mut self, buf: &mut [u8], offset: Offset) -> io::Result<usize> {
+    pub fn read_at(&mut self, buf: &mut [u8], offset: Offset) -> Result<usize> {
         if buf.len() != ENTRY_WIDTH as usize {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "EOF"))
+            return Err(Error::CorruptRecord(String::from("index entries are 8 bytes")))
         }
 
         let off = offset as usize;
         let end = off + ENTRY_WIDTH as usize;
-        buf.copy_from_slice(&self.mmap[off..end]);
+        if end > self.mmap.as_slice().len() {
+            return Err(Error::OffsetOutOfRange(self.base_offset + offset / ENTRY_WIDTH as Offset))
+        }
+        buf.copy_from_slice(&self.mmap.as_slice()[off..end]);
         Ok(ENTRY_WIDTH as usize)
     }
 
-    pub fn find_latest_entry(&mut self) -> io::Result<Entry> {
+    pub fn find_latest_entry(&mut self) -> Result<Entry> {
         // super naive and dumb "search" for latest entry
         let end = self.len()?;
         let index_count = end / 8;
@@ -132,11 +190,11 @@ impl Index {
         Ok(latest_entry)
     }
 
-    pub fn read_log_entry(&mut self, offset: Offset) -> io::Result<Entry> {
+    pub fn read_log_entry(&mut self, offset: Offset) -> Result<Entry> {
         self.read_entry(offset * ENTRY_WIDTH as u64)
     }
 
-    pub fn read_entry(&mut self, offset: Offset) -> io::Result<Entry> {
+    pub fn read_entry(&mut self, offset: Offset) -> Result<Entry> {
         let mut buffer = [0; 8];
         let _ = self.read_at(&mut buffer, offset)?;
 
@@ -154,15 +212,27 @@ impl Index {
         Ok(result)
     }
 
-    pub fn read_entry_at_log_offset(&mut self, offset: Offset) -> io::Result<Entry> {
+    pub fn read_entry_at_log_offset(&mut self, offset: Offset) -> Result<Entry> {
         self.read_entry(offset * ENTRY_WIDTH as Offset)
     }
 
+    pub fn clear_from(&mut self, relative_offset: Offset) -> Result<()> {
+        // zeroes the entries from relative_offset on, the way they were before being written
+        let start = (relative_offset * ENTRY_WIDTH as Offset) as usize;
+        let mmap = self.mmap.as_mut_slice()?;
+        if start < mmap.len() {
+            for byte in &mut mmap[start..] { *byte = 0 }
+        }
+        self.position = self.position.min(start as Offset);
+        self.flush()
+    }
+
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::io::{Read, Write};
     use tempfile::tempdir;
     use super::*;
 
@@ -273,4 +343,30 @@ mod tests {
         let entry = index.read_entry(ENTRY_WIDTH as u64).unwrap();
         assert_eq!(entry, expected, "second offset");
     }
+
+    #[test]
+    fn it_returns_index_full() {
+        let tmp = tempdir().unwrap();
+        let mut index = Index::new(tmp.path().to_path_buf(), 0, 16).unwrap();
+        index.write_entry(Entry{offset: 1, position: 16}).unwrap();
+        let err = index.write_entry(Entry{offset: 2, position: 54}).unwrap_err();
+        assert!(matches!(err, Error::IndexFull), "no slot for offset 2");
+        assert!(matches!(index.read_log_entry(2).unwrap_err(), Error::OffsetOutOfRange(2)));
+    }
+
+    #[test]
+    fn it_refuses_writes_when_read_only() {
+        let tmp = tempdir().unwrap();
+        {
+            let mut index = Index::new(tmp.path().to_path_buf(), 0, 32).unwrap();
+            index.write_entry(Entry{offset: 1, position: 16}).unwrap();
+        }
+        let mut path = tmp.path().to_path_buf();
+        path.push("00000000000000000000.index");
+        let mut index = Index::open_read_only(path, 0, 32).unwrap();
+
+        assert_eq!(index.find_latest_entry().unwrap(), Entry{offset: 1, position: 16});
+        let err = index.write_entry(Entry{offset: 2, position: 32}).unwrap_err();
+        assert!(matches!(err, Error::ReadOnly));
+    }
 }
@@ -0,0 +1,64 @@
+use std::{io};
+use std::fs::{OpenOptions, File};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+
+const LOCK_FILE: &str = ".lock";
+
+
+// An advisory flock(2) on `<dir>/.lock`, held for as long as the writer lives.
+// The kernel drops it when the file is closed, so a crashed process never
+// leaves a stale lock behind.
+#[derive(Debug)]
+pub struct DirLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl DirLock {
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(Error::Locked(dir.to_path_buf()))
+            }
+            return Err(err.into())
+        }
+        Ok(DirLock{ path, file })
+    }
+
+    pub fn path_buf(&self) -> PathBuf { self.path.clone() }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn it_locks_dir_once() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        fs::create_dir_all(&tmp).unwrap();
+
+        let lock = DirLock::acquire(&tmp).unwrap();
+        assert!(lock.path_buf().exists(), "lock file exists");
+        let err = DirLock::acquire(&tmp).unwrap_err();
+        assert!(matches!(err, Error::Locked(_)), "second writer is refused");
+
+        drop(lock);
+        assert!(DirLock::acquire(&tmp).is_ok(), "lock released on drop");
+    }
+}
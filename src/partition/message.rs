@@ -1,12 +1,12 @@
-use std::{io};
 use std::fs::{OpenOptions, File};
 use std::io::prelude::*;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
+use crate::{Error, Result};
 use crate::partition::Offset;
 
-// const MSG_HEADER_LEN: u64 = 12;
+pub const MSG_HEADER_LEN: usize = 12;
 
 pub struct Message {
     pub offset: Offset,
@@ -27,24 +27,27 @@ impl Message {
         self.payload.len() + self.offset as usize + self.position as usize
     }
 
-    pub fn from_vec(raw: &mut Vec<u8>) -> Message {
+    pub fn from_vec(raw: &mut Vec<u8>) -> Result<Message> {
+        if raw.len() < MSG_HEADER_LEN {
+            return Err(Error::CorruptRecord(format!("{} bytes is shorter than a message header", raw.len())))
+        }
         let off = BigEndian::read_u64(&raw[0..8]);
         let pos = BigEndian::read_u32(&raw[8..12]);
-        raw.drain(0..12);
-        Message {
+        raw.drain(0..MSG_HEADER_LEN);
+        Ok(Message {
             offset: off,
             position: pos,
             payload: raw.to_vec(),
-        }
+        })
     }
 
-    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
         let mut buf = vec![];
 
         buf.write_u64::<BigEndian>(self.offset)?;
         buf.write_u32::<BigEndian>(self.position)?;
-        if buf.len() != 12 {
-            return Err(io::Error::new(io::ErrorKind::Other, "Header wrong size"))
+        if buf.len() != MSG_HEADER_LEN {
+            return Err(Error::CorruptRecord(String::from("Header wrong size")))
         }
         buf.append(&mut self.payload.to_vec());
 
@@ -69,12 +72,18 @@ mod tests {
     #[test]
     fn message_from_vec() {
         let mut raw = vec![0u8, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 3, 0, 1, 2, 3];
-        let message = Message::from_vec(&mut raw);
+        let message = Message::from_vec(&mut raw).unwrap();
         assert_eq!(message.offset, 1);
         assert_eq!(message.position, 3);
         assert_eq!(message.payload, vec![0, 1, 2, 3]);
     }
 
+    #[test]
+    fn message_from_short_vec() {
+        let mut raw = vec![0u8, 0, 0, 0, 0, 0, 0, 1, 0, 0];
+        assert!(matches!(Message::from_vec(&mut raw), Err(Error::CorruptRecord(_))));
+    }
+
     #[test]
     fn message_to_vec() {
         let message = Message::new(1, 3, &[0, 1, 2, 3]);
@@ -6,41 +6,75 @@ pub mod index;
 pub mod message;
 pub mod entry;
 pub mod reader;
+pub mod slice;
+pub mod lock;
+pub mod config;
+pub mod record;
+pub mod cleaner;
+pub mod epoch;
+pub mod signal;
+pub mod producer;
+pub mod transaction;
 
 pub type Offset = u64;
 
-// const TEN_MB: u64 = 1024 * 1024 * 1;
-// const DEFAULT_SEGMENT_MAX_BYTES: u64 = TEN_MB;
-// pconst DEFAULT_INDEX_MAX_BYTES: u64 = TEN_MB;
-
-use std::{io, fs};
+use std::{fs};
 use std::fs::{OpenOptions};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 // use std::sync::{Arc, Mutex};
 use std::iter::FromIterator;
 
-use crate::partition::message::{Message};
-use crate::partition::entry::{Entry};
-use crate::partition::segment::{SegmentMeta, MaxBytes};
+use crate::{Error, Result};
+use crate::partition::message::{Message, MSG_HEADER_LEN};
+use crate::partition::segment::{SegmentMeta, MaxBytes, Access, now_ms};
+use crate::partition::slice::{FileSlice};
+use crate::partition::lock::{DirLock};
+use crate::partition::config::{PartitionConfig};
+use crate::partition::record::{Record};
+use crate::partition::epoch::{EpochEndOffset, LeaderEpochCache};
+use crate::partition::signal::{AppendSignal};
+use crate::partition::producer::{ProducerBatch, ProducerState, next_sequence};
+use crate::partition::transaction::{self as txn, AbortedTxn, Marker, TRANSACTIONAL};
 
 
 pub struct Partition {
     // options
     path: PathBuf,
     max_bytes: MaxBytes,
+    config: PartitionConfig,
     // attributes
     name: String,
     segments: Vec<SegmentMeta>,
     active_segment: SegmentMeta, // TODO: use arc to hold segments and mutexes
+    access: Access,
+    _lock: Option<DirLock>, // held by writers only
+    unflushed: u64,
+    last_flush: Instant,
+    roll_jitter_ms: u64, // drawn per active segment so partitions don't all roll at once
+    appends: Arc<AppendSignal>,
+    producers: ProducerState,
+    // None while no other broker has a copy, everything appended is committed
+    high_watermark: Option<Offset>,
+    epochs: LeaderEpochCache,
+    // compaction has read the keys of everything before here
+    clean_offset: Offset,
 }
 
 
 
 impl Partition {
-    pub fn create(name: String, path: &mut PathBuf, max_bytes: MaxBytes) -> io::Result<Partition> {
+    pub fn create<C: Into<PartitionConfig>>(name: String, path: &mut PathBuf, config: C) -> Result<Partition> {
+        let config = config.into();
+        config.validate()?;
         path.push(name.clone());
         fs::create_dir_all(path.clone())?;
+        let lock = DirLock::acquire(path)?;
+        config.store(path)?;
+        let max_bytes = config.max_bytes();
+        let roll_jitter_ms = roll_jitter(&config);
         let active = SegmentMeta::new(path.clone(), 0, max_bytes);
         let segments: Vec<SegmentMeta> = Vec::new();
         Ok(
@@ -48,37 +82,101 @@ impl Partition {
                 name: name,
                 path: path.to_path_buf(),
                 max_bytes: max_bytes,
+                config,
                 segments: segments,
                 active_segment: active,
+                access: Access::ReadWrite,
+                _lock: Some(lock),
+                unflushed: 0,
+                last_flush: Instant::now(),
+                roll_jitter_ms,
+                appends: Arc::new(AppendSignal::new()),
+                producers: ProducerState::new(),
+                high_watermark: None,
+                epochs: LeaderEpochCache::empty(path),
+                clean_offset: 0,
             }
         )
     }
 
-    pub fn load(path: &mut PathBuf, max_bytes: MaxBytes) -> io::Result<Partition> {
-        let mut segments = Partition::scan(path.clone(), max_bytes)?;
+    // the config persisted in the directory wins, `defaults` only fills in for
+    // partitions written before configs were stored alongside the log
+    pub fn load<C: Into<PartitionConfig>>(path: &Path, defaults: C) -> Result<Partition> {
+        if !path.is_dir() { return Err(Error::PartitionNotFound(path.to_path_buf())) }
+        let lock = DirLock::acquire(path)?;
+        let config = match PartitionConfig::load(path)? {
+            Some(config) => config,
+            None => {
+                let config = defaults.into();
+                config.validate()?;
+                config.store(path)?;
+                config
+            },
+        };
+        let mut partition = Partition::load_as(path, config, Access::ReadWrite)?;
+        partition._lock = Some(lock);
+        partition.epochs = LeaderEpochCache::load(path)?;
+        partition.load_producer_state()?;
+        Ok(partition)
+    }
+
+    pub fn open_read_only<C: Into<PartitionConfig>>(path: &Path, defaults: C) -> Result<Partition> {
+        // readers don't take the lock, they can follow along behind a live writer
+        if !path.is_dir() { return Err(Error::PartitionNotFound(path.to_path_buf())) }
+        let config = match PartitionConfig::load(path)? {
+            Some(config) => config,
+            None => defaults.into(),
+        };
+        config.validate()?;
+        Partition::load_as(path, config, Access::ReadOnly)
+    }
+
+    fn load_as(path: &Path, config: PartitionConfig, access: Access) -> Result<Partition> {
+        let max_bytes = config.max_bytes();
+        let roll_jitter_ms = roll_jitter(&config);
+        let mut segments = Partition::scan_as(path.to_path_buf(), max_bytes, access)?;
         let latest_segment = match segments.pop() {
             Some(seg) => seg,
-            None => SegmentMeta::new(path.clone(), 0, max_bytes),
+            None if access == Access::ReadOnly => SegmentMeta::new(path.to_path_buf(), 0, max_bytes).read_only(),
+            None => SegmentMeta::new(path.to_path_buf(), 0, max_bytes),
         };
 
-        let name = path.file_stem().unwrap();
+        let name = match path.file_stem() {
+            Some(name) => name,
+            None => return Err(Error::InvalidConfig(format!("{} has no partition name", path.display()))),
+        };
         Ok(
             Partition {
                 path: path.to_path_buf(),
                 max_bytes: max_bytes,
+                config,
                 name: String::from(name.to_string_lossy()),
                 segments: segments,
                 active_segment: latest_segment,
+                access,
+                _lock: None,
+                unflushed: 0,
+                last_flush: Instant::now(),
+                roll_jitter_ms,
+                appends: Arc::new(AppendSignal::new()),
+                producers: ProducerState::new(),
+                high_watermark: None,
+                epochs: LeaderEpochCache::empty(path),
+                clean_offset: 0,
             }
         )
     }
 
-    pub fn scan(path: PathBuf, max_bytes: MaxBytes) -> io::Result<Vec<SegmentMeta>> {
+    pub fn scan(path: PathBuf, max_bytes: MaxBytes) -> Result<Vec<SegmentMeta>> {
+        Partition::scan_as(path, max_bytes, Access::ReadWrite)
+    }
+
+    pub fn scan_as(path: PathBuf, max_bytes: MaxBytes, access: Access) -> Result<Vec<SegmentMeta>> {
         // returns a Vec of SegmentMeta segments, sorted low to high
         let mut segments: Vec<SegmentMeta> = Vec::new();
         for entry in fs::read_dir(path.clone())? {
             let log_path = entry?.path();
-            let segment_meta = match SegmentMeta::load(log_path, max_bytes) {
+            let segment_meta = match SegmentMeta::load_as(log_path, max_bytes, access)? {
                 Some(meta) => meta,
                 None => continue,
             };
@@ -88,37 +186,323 @@ impl Partition {
         Ok(segments)
     }
 
-    fn check_split(&mut self) -> bool {
-        self.active_segment.is_full()
+    fn check_split(&mut self, offset: Offset, size: u64) -> bool {
+        // roll before a message that would overshoot the segment, not after
+        if self.active_segment.is_full() || !self.active_segment.has_room_for(size) { return true }
+        if !self.active_segment.can_index(offset) { return true }
+        self.is_due_to_roll()
+    }
+
+    fn is_due_to_roll(&self) -> bool {
+        // segment.ms, less the jitter drawn for the active segment
+        match self.config.segment_ms {
+            Some(ms) => self.active_segment.is_older_than(ms.saturating_sub(self.roll_jitter_ms), now_ms()),
+            None => false,
+        }
+    }
+
+    fn split(&mut self) -> Result<()> {
+        let next_offset = self.active_segment.newest_offset();
+        self.roll_at(next_offset)
     }
 
-    fn split(&mut self) -> io::Result<()> {
-        self.segments.push(self.active_segment.clone());
-        self.active_segment = SegmentMeta::new(
-            self.path.clone(),
-            self.active_segment.newest_offset(),
-            self.max_bytes,
-        );
+    fn roll_at(&mut self, base_offset: Offset) -> Result<()> {
+        // starts the next segment at base_offset, past the log end leaves a gap
+        // the way compaction does. An empty active segment is just replaced
+        if self.active_segment.current_position() == 0 && self.active_segment.base_offset <= base_offset {
+            self.active_segment.delete()?;
+        } else {
+            self.active_segment.sync()?;
+            self.active_segment.seal()?;
+            self.segments.push(self.active_segment.clone());
+        }
+        // so a restart only replays the segments after this one for producer state
+        self.producers.snapshot(&self.path, base_offset)?;
+        self.active_segment = SegmentMeta::new(self.path.clone(), base_offset, self.max_bytes);
+        self.roll_jitter_ms = roll_jitter(&self.config);
         Ok(())
     }
 
-    pub fn append(&mut self, message: &[u8])-> io::Result<Offset> {
-        if self.check_split() {
-            self.split()?
+    fn check_message_size(&self, message: &[u8]) -> Result<u64> {
+        let size = (MSG_HEADER_LEN + message.len()) as u64;
+        if size > self.config.max_message_bytes {
+            return Err(Error::MessageTooLarge { size, max: self.config.max_message_bytes })
         }
+        Ok(size)
+    }
 
-        let next_offset = self.active_segment.newest_offset();
-        let position = self.active_segment.current_position();
-        let message = Message::new(next_offset, position as u32, message);
-        let payload = message.to_vec()?;
-        let _ = self.active_segment.write(&payload)?;
+    pub fn append(&mut self, message: &[u8])-> Result<Offset> {
+        let offset = self.log_end_offset();
+        self.append_at(offset, message)
+    }
 
-        let entry = Entry::new(next_offset, position);
-        let _ = self.active_segment.write_index_entry(entry)?;
+    fn append_at(&mut self, offset: Offset, message: &[u8]) -> Result<Offset> {
+        // offset is at or past the log end, a gap stays inside the active
+        // segment unless it's empty, then the segment just starts at offset
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
+        let size = self.check_message_size(message)?;
+        let empty = self.active_segment.current_position() == 0;
+        if self.check_split(offset, size) || (empty && offset > self.active_segment.base_offset) {
+            self.roll_at(offset)?
+        }
+        self.active_segment.append_at(offset, message)?;
+
+        self.unflushed += 1;
+        if self.check_flush() {
+            self.flush()?;
+        }
+        self.appends.notify();
 
         Ok(self.active_segment.newest_offset())
     }
 
+    pub fn append_batch(&mut self, records: &[Record]) -> Result<Offset> {
+        // appends the records back to back and returns the first one's offset.
+        // An idempotent producer's batch is checked against its sequences first,
+        // a retry of a batch already in the log gets back where it went
+        let base_offset = self.log_end_offset();
+        let first = match records.first() {
+            Some(first) => first,
+            None => return Ok(base_offset),
+        };
+        let transactional = first.attributes & TRANSACTIONAL != 0;
+        if transactional && !first.has_producer() {
+            return Err(Error::InvalidRequest(String::from("transactional records need a producer id")))
+        }
+        let producer = if first.has_producer() {
+            let batch = ProducerBatch{
+                producer_id: first.producer_id,
+                epoch: first.producer_epoch,
+                base_sequence: first.sequence,
+                count: records.len() as i32,
+                transactional,
+            };
+            let consistent = records.iter().enumerate().all(|(i, r)| {
+                r.producer_id == batch.producer_id && r.producer_epoch == batch.epoch
+                    && r.sequence == next_sequence(batch.base_sequence, i as i32)
+                    && (r.attributes & TRANSACTIONAL != 0) == transactional
+            });
+            if !consistent {
+                return Err(Error::InvalidRequest(String::from("a batch needs one producer and consecutive sequences")))
+            }
+            if let Some(offset) = self.producers.check(&batch)? { return Ok(offset) }
+            Some(batch)
+        } else {
+            None
+        };
+        // every record is checked before the first is written, and a batch
+        // that fails partway is taken back out so a retry writes all of it
+        let payloads = records.iter().map(|record| record.to_vec()).collect::<Result<Vec<Vec<u8>>>>()?;
+        for payload in &payloads {
+            self.check_message_size(payload)?;
+        }
+        for payload in &payloads {
+            if let Err(e) = self.append(payload) {
+                self.truncate_to(base_offset)?;
+                return Err(e)
+            }
+        }
+        if let Some(batch) = producer {
+            self.producers.update(&batch, base_offset);
+        }
+        Ok(base_offset)
+    }
+
+    pub fn append_replica(&mut self, messages: &[Message]) -> Result<Offset> {
+        // a follower appends what it fetched from the leader at the leader's
+        // offsets, skipping over offsets the leader compacted away. Producer
+        // state and aborted transactions are replayed from the records the same
+        // way a restart rebuilds them. Returns the new log end
+        for message in messages {
+            let log_end = self.log_end_offset();
+            if message.offset < log_end {
+                return Err(Error::InvalidRequest(format!(
+                    "replicated offset {} is below the log end {}", message.offset, log_end)))
+            }
+            self.append_at(message.offset, &message.payload)?;
+            if let Ok(record) = Record::from_slice(&message.payload) {
+                if let Some(aborted) = self.producers.replay(message.offset, &record) {
+                    txn::append_aborted(&self.active_segment.txn_index_path(), &aborted)?;
+                }
+            }
+        }
+        Ok(self.log_end_offset())
+    }
+
+    pub fn producer_state(&self) -> &ProducerState { &self.producers }
+
+    pub fn write_marker(&mut self, producer_id: i64, epoch: i16, marker: Marker) -> Result<Offset> {
+        // ends the producer's open transaction here, an abort goes into the
+        // aborted-transaction index of the segment the marker lands in
+        if let Some(current) = self.producers.epoch(producer_id) {
+            if epoch < current { return Err(Error::ProducerFenced{ producer_id, epoch }) }
+        }
+        let offset = self.log_end_offset();
+        self.append(&marker.record(now_ms(), producer_id, epoch).to_vec()?)?;
+        let first_offset = self.producers.end_transaction(producer_id, epoch)?;
+        if let (Marker::Abort, Some(first_offset)) = (marker, first_offset) {
+            let aborted = AbortedTxn{ producer_id, first_offset, last_offset: offset };
+            txn::append_aborted(&self.active_segment.txn_index_path(), &aborted)?;
+        }
+        Ok(offset)
+    }
+
+    // read_committed consumers read up to here, the first offset of the
+    // oldest transaction without a marker yet
+    pub fn last_stable_offset(&self) -> Offset {
+        self.producers.first_unstable_offset().unwrap_or_else(|| self.log_end_offset()).min(self.high_watermark())
+    }
+
+    // consumers only see what's below here, the offsets every in-sync replica has
+    pub fn high_watermark(&self) -> Offset {
+        let log_end = self.log_end_offset();
+        self.high_watermark.map_or(log_end, |hw| hw.min(log_end))
+    }
+
+    pub fn replicated_high_watermark(&self) -> Option<Offset> { self.high_watermark }
+    pub fn set_high_watermark(&mut self, high_watermark: Option<Offset>) { self.high_watermark = high_watermark }
+
+    pub fn leader_epochs(&self) -> &LeaderEpochCache { &self.epochs }
+
+    pub fn assign_leader_epoch(&mut self, epoch: i32, start_offset: Offset) -> Result<()> {
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
+        self.epochs.assign(epoch, start_offset)
+    }
+
+    // the largest epoch at or below `epoch` this log has and where it ends
+    pub fn end_offset_for_epoch(&self, epoch: i32) -> EpochEndOffset {
+        self.epochs.end_offset_for(epoch, self.log_end_offset())
+    }
+
+    pub fn truncate_to(&mut self, offset: Offset) -> Result<()> {
+        // drops every message from offset on, what a follower wrote that the
+        // new leader never had. Producer state, aborted transactions and
+        // leader epochs are brought back to what's left
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
+        if offset >= self.log_end_offset() { return Ok(()) }
+        if offset < self.log_start_offset() { return Err(Error::OffsetOutOfRange(offset)) }
+        while self.active_segment.base_offset > offset {
+            self.active_segment.delete()?;
+            self.active_segment = match self.segments.pop() {
+                Some(segment) => segment,
+                None => return Err(Error::OffsetOutOfRange(offset)),
+            };
+        }
+        self.active_segment.truncate_to(offset)?;
+        let txn_index = self.active_segment.txn_index_path();
+        let aborted = txn::read_aborted(&txn_index)?;
+        if aborted.iter().any(|txn| txn.last_offset >= offset) {
+            let _ = fs::remove_file(&txn_index);
+            for txn in aborted.iter().filter(|txn| txn.last_offset < offset) {
+                txn::append_aborted(&txn_index, txn)?;
+            }
+        }
+        ProducerState::delete_snapshots_after(&self.path, offset)?;
+        self.load_producer_state()?;
+        self.epochs.truncate_from_end(offset)?;
+        self.high_watermark = self.high_watermark.map(|hw| hw.min(offset));
+        self.clean_offset = self.clean_offset.min(offset);
+        Ok(())
+    }
+
+    pub fn truncate_fully_and_start_at(&mut self, offset: Offset) -> Result<()> {
+        // drops the whole log, the next append goes to offset. How a replica
+        // too far behind to catch up from the log starts over from a snapshot
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
+        for segment in self.segments.drain(..) {
+            segment.delete()?;
+        }
+        self.active_segment.delete()?;
+        self.active_segment = SegmentMeta::new(self.path.clone(), offset, self.max_bytes);
+        // the empty segment's files mark where the log starts for a reload
+        self.active_segment.sync()?;
+        ProducerState::delete_snapshots_after(&self.path, 0)?;
+        self.producers = ProducerState::new();
+        self.epochs.truncate_from_end(0)?;
+        self.high_watermark = self.high_watermark.map(|_| offset);
+        self.clean_offset = offset;
+        Ok(())
+    }
+
+    pub fn aborted_transactions(&self, from: Offset, to: Offset) -> Result<Vec<AbortedTxn>> {
+        // the aborted transactions overlapping [from, to)
+        let mut aborted = vec![];
+        for segment in self.segments.iter().chain(std::iter::once(&self.active_segment)) {
+            for txn in txn::read_aborted(&segment.txn_index_path())? {
+                if txn.last_offset >= from && txn.first_offset < to { aborted.push(txn) }
+            }
+        }
+        Ok(aborted)
+    }
+
+    pub fn read_committed(&self, offset: Offset, max_bytes: u64) -> Result<(Vec<Message>, Offset)> {
+        // like read, but stops at the last stable offset and leaves out markers
+        // and aborted records. Also returns where the next read starts, a read
+        // of nothing but aborted records still moves the reader past them
+        let stable = self.last_stable_offset();
+        if offset >= stable { return Ok((vec![], offset)) }
+        let mut messages = self.read(offset, max_bytes)?;
+        messages.retain(|m| m.offset < stable);
+        let next = messages.last().map_or(offset, |m| m.offset + 1);
+        let aborted = self.aborted_transactions(offset, next)?;
+        Ok((txn::filter_committed(messages, &aborted), next))
+    }
+
+    fn load_producer_state(&mut self) -> Result<()> {
+        // start from the newest snapshot and replay the records written after it
+        let log_end = self.log_end_offset();
+        let (from, state) = ProducerState::load_latest(&self.path, log_end)?.unwrap_or_default();
+        self.producers = state;
+        let segments: Vec<SegmentMeta> = self.segments.iter()
+            .chain(std::iter::once(&self.active_segment))
+            .filter(|s| s.newest_offset() > from)
+            .cloned()
+            .collect();
+        for segment in segments {
+            let indexed = txn::read_aborted(&segment.txn_index_path())?;
+            for message in segment.read_messages()? {
+                if message.offset < from { continue }
+                let record = match Record::from_slice(&message.payload) {
+                    Ok(record) => record,
+                    Err(_) => continue,
+                };
+                // an abort marker whose index entry didn't make it to disk
+                if let Some(aborted) = self.producers.replay(message.offset, &record) {
+                    if !indexed.contains(&aborted) {
+                        txn::append_aborted(&segment.txn_index_path(), &aborted)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_flush(&self) -> bool {
+        let by_count = match self.config.flush_messages {
+            Some(n) => self.unflushed >= n,
+            None => false,
+        };
+        let by_time = match self.config.flush_ms {
+            Some(ms) => self.last_flush.elapsed().as_millis() >= ms as u128,
+            None => false,
+        };
+        by_count || by_time
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        // fsync the active segment, inactive ones were synced when they rolled
+        self.active_segment.sync()?;
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    pub fn config(&self) -> &PartitionConfig { &self.config }
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn append_signal(&self) -> Arc<AppendSignal> { self.appends.clone() }
+    pub fn set_append_signal(&mut self, appends: Arc<AppendSignal>) { self.appends = appends }
+
     pub fn find_segment(&self, offset: Offset) -> Option<SegmentMeta> {
         // Find the segment a given offset is in (between two base_segments)
         // the segments are sorted smallest -> largest
@@ -138,6 +522,61 @@ impl Partition {
         None
     }
     pub fn segments_len(&self) -> usize { self.segments.len() }
+
+    // fetch and read hand out the raw log, past the high watermark and
+    // aborted transactions included, for replication and the broker's own
+    // replays. Whatever serves consumers bounds them, with fetch_upto or
+    // read_committed
+    pub fn fetch(&self, offset: Offset, max_bytes: u64) -> Result<Option<FileSlice>> {
+        self.fetch_upto(offset, self.log_end_offset(), max_bytes)
+    }
+
+    pub fn fetch_upto(&self, offset: Offset, upto: Offset, max_bytes: u64) -> Result<Option<FileSlice>> {
+        // a fetch never spans segments, the consumer comes back for the next one.
+        // None means the consumer is caught up at the log end or upto
+        if offset >= self.active_segment.base_offset {
+            return self.active_segment.slice(offset, upto, max_bytes)
+        }
+        match self.find_segment(offset) {
+            Some(segment) => segment.slice(offset, upto, max_bytes),
+            None => Err(Error::OffsetOutOfRange(offset)),
+        }
+    }
+
+    pub fn read(&self, offset: Offset, max_bytes: u64) -> Result<Vec<Message>> {
+        // same bounds as fetch, for callers that need the messages rather than bytes
+        if offset >= self.active_segment.base_offset {
+            return self.active_segment.read_from(offset, max_bytes)
+        }
+        match self.find_segment(offset) {
+            Some(segment) => segment.read_from(offset, max_bytes),
+            None => Err(Error::OffsetOutOfRange(offset)),
+        }
+    }
+
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> Result<Option<(Offset, u64)>> {
+        // the first keyed record written at or after timestamp, with its timestamp
+        for segment in self.segments.iter().chain(std::iter::once(&self.active_segment)) {
+            for message in segment.read_messages()? {
+                if let Ok(record) = Record::from_slice(&message.payload) {
+                    if record.timestamp >= timestamp {
+                        return Ok(Some((message.offset, record.timestamp)))
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn roll_jitter(config: &PartitionConfig) -> u64 {
+    // no rand dependency, the clock's nanos are noisy enough to spread rolls
+    if config.segment_jitter_ms == 0 { return 0 }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    nanos % config.segment_jitter_ms
 }
 
 pub struct Reader {
@@ -148,8 +587,8 @@ pub struct Reader {
 }
 
 impl Reader {
-    pub fn new_reader(offset: Offset, path: PathBuf, max_bytes: MaxBytes) -> Option<Reader> {
-        let mut segments = Partition::scan(path, max_bytes).ok()?;
+    pub fn new_reader(offset: Offset, path: PathBuf, max_bytes: MaxBytes) -> Result<Reader> {
+        let mut segments = Partition::scan(path, max_bytes)?;
         segments.reverse();  // largest -> smallest
         let mut cursor: Option<SegmentMeta> = None;
         loop {
@@ -162,14 +601,14 @@ impl Reader {
         }
 
         if let Some(active) = cursor {
-            return Some(Reader{
+            return Ok(Reader{
                 segments: segments,
                 active_segment: active,
                 max_bytes: max_bytes,
                 offset: offset,
             });
         }
-        None
+        Err(Error::OffsetOutOfRange(offset))
     }
 }
 
@@ -179,6 +618,7 @@ impl Reader {
 mod tests {
     use tempfile::tempdir;
     use super::*;
+    use crate::partition::config::{CleanupPolicy};
 
     #[test]
     fn it_creates_new_partition() {
@@ -239,7 +679,7 @@ mod tests {
             path.push("00000000000000000088.log");
             let _ = OpenOptions::new().create(true).write(true).open(&path).unwrap();
         }
-        let partition = Partition::load(&mut tmp, MaxBytes(64, 64)).unwrap();
+        let partition = Partition::load(&tmp, MaxBytes(64, 64)).unwrap();
 
         assert_eq!(partition.active_segment.newest_offset(), 88, "next offset is 88");
         assert_eq!(partition.segments.len(), 1, "One 'docketed' existing segment meta");
@@ -264,7 +704,7 @@ mod tests {
             path.push("00000000000000000201.log");
             let _ = OpenOptions::new().create(true).write(true).open(&path).unwrap();
         }
-        let partition = Partition::load(&mut tmp, MaxBytes(64, 64)).unwrap();
+        let partition = Partition::load(&tmp, MaxBytes(64, 64)).unwrap();
 
         assert!(partition.find_segment(10).is_none());
         assert!(partition.find_segment(20).is_some());
@@ -295,7 +735,7 @@ mod tests {
             let mut idx = OpenOptions::new().create(true).write(true).open(&path).unwrap();
             let _ = idx.write(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14]).unwrap();
         }
-        let partition = Partition::load(&mut tmp, MaxBytes(64, 64)).unwrap();
+        let partition = Partition::load(&tmp, MaxBytes(64, 64)).unwrap();
 
         assert_eq!(partition.active_segment.newest_offset(), 90, "next offset is 90!");
         assert_eq!(partition.segments.len(), 1, "One 'docketed' existing segment meta");
@@ -337,13 +777,13 @@ mod tests {
     fn it_splits_when_full() {
         let tmp = tempdir().unwrap().path().to_path_buf();
         let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(28, 16)).unwrap();
-        assert_eq!(partition.active_segment.size(), 0);
+        assert_eq!(partition.active_segment.size().unwrap(), 0);
         let first_offset = partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
-        assert_eq!(partition.active_segment.size(), 28);
+        assert_eq!(partition.active_segment.size().unwrap(), 28);
         let second_offset = partition.append("XX".as_bytes()).unwrap();
-        assert_eq!(partition.active_segment.size(), 14);
+        assert_eq!(partition.active_segment.size().unwrap(), 14);
         let third_offset = partition.append("XX".as_bytes()).unwrap();
-        assert_eq!(partition.active_segment.size(), 28);
+        assert_eq!(partition.active_segment.size().unwrap(), 28);
         let first_segment = {
             let mut path = partition.path.clone();
             path.push("00000000000000000000.log");
@@ -385,10 +825,347 @@ mod tests {
         assert_eq!(second_offset, 2, "second (next) offset is 2!");
         assert_eq!(third_offset, 3, "third (next) offset is 3!");
         assert_eq!(partition.active_segment.newest_offset(), 3);
-        assert_eq!(partition.active_segment.size(), 28);
+        assert_eq!(partition.active_segment.size().unwrap(), 28);
         assert_eq!(first_segment.as_bytes(), expected_first_segment.as_bytes(), "first segment write");
         assert_eq!(first_index.as_bytes(), expected_first_index, "first index write");
         assert_eq!(second_segment.as_bytes(), expected_second_segment, "second segment write");
         assert_eq!(second_index.as_bytes(), expected_second_index, "second index write");
     }
+
+    #[test]
+    fn it_persists_config() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            flush_messages: Some(1),
+            ..PartitionConfig::from(MaxBytes(64, 32))
+        };
+        let path = {
+            let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config.clone()).unwrap();
+            partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+            assert_eq!(partition.unflushed, 0, "flushed after every message");
+            partition.path.clone()
+        };
+
+        let partition = Partition::load(&path, MaxBytes(1024, 1024)).unwrap();
+        assert_eq!(partition.config(), &config, "stored config wins over the defaults");
+        assert_eq!(partition.max_bytes, MaxBytes(64, 32));
+    }
+
+    #[test]
+    fn it_splits_before_overshooting() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(40, 32)).unwrap();
+        partition.append("XX".as_bytes()).unwrap();
+        partition.append("XX".as_bytes()).unwrap();
+        assert_eq!(partition.segments.len(), 0, "28 bytes fit in 40");
+        partition.append("XX".as_bytes()).unwrap();
+        assert_eq!(partition.segments.len(), 1, "42 bytes don't");
+        assert_eq!(partition.segments[0].current_position(), 28, "first segment didn't overshoot");
+        assert_eq!(partition.active_segment.base_offset, 2);
+    }
+
+    #[test]
+    fn it_rejects_oversized_messages() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(28, 32)).unwrap();
+        partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        let err = partition.append("YELLOW SUBMARINES".as_bytes()).unwrap_err();
+        assert!(matches!(err, Error::MessageTooLarge { size: 29, max: 28 }));
+        assert_eq!(partition.segments.len(), 0, "nothing rolled for the rejected message");
+        assert_eq!(partition.active_segment.newest_offset(), 1);
+    }
+
+    #[test]
+    fn it_splits_when_old() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let config = PartitionConfig {
+            segment_ms: Some(60_000),
+            ..PartitionConfig::from(MaxBytes(1024, 1024))
+        };
+        let path = {
+            let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), config).unwrap();
+            partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+            partition.append("XX".as_bytes()).unwrap();
+            assert_eq!(partition.segments.len(), 0, "young segment isn't rolled");
+            partition.path.clone()
+        };
+        {
+            // age the segment by an hour while the partition is closed
+            let mut ts = path.clone();
+            ts.push("00000000000000000000.timestamp");
+            fs::write(&ts, (now_ms() - 3_600_000).to_string()).unwrap();
+        }
+
+        let mut partition = Partition::load(&path, MaxBytes(1024, 1024)).unwrap();
+        partition.append("PURPLE PRESIDENT".as_bytes()).unwrap();
+        assert_eq!(partition.segments.len(), 1, "old segment rolled after restart");
+        assert_eq!(partition.active_segment.base_offset, 2);
+        partition.append("XX".as_bytes()).unwrap();
+        assert_eq!(partition.segments.len(), 1, "new segment is young");
+    }
+
+    #[test]
+    fn it_rejects_invalid_config() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let res = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(64, 12));
+        assert!(matches!(res, Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn it_locks_partition_dir_for_writers() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(64, 32)).unwrap();
+
+        let path = partition.path.clone();
+        let err = Partition::load(&path, MaxBytes(64, 32)).err().unwrap();
+        assert!(matches!(err, Error::Locked(_)), "second writer is refused");
+
+        drop(partition);
+        assert!(Partition::load(&path, MaxBytes(64, 32)).is_ok(), "lock released on drop");
+    }
+
+    #[test]
+    fn it_opens_read_only_beside_writer() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let mut writer = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(64, 32)).unwrap();
+        writer.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        writer.append("XX".as_bytes()).unwrap();
+
+        let path = writer.path.clone();
+        let mut reader = Partition::open_read_only(&path, MaxBytes(64, 32)).unwrap();
+        assert_eq!(reader.active_segment.newest_offset(), 2, "reader sees the writer's log");
+        let err = reader.append("XX".as_bytes()).unwrap_err();
+        assert!(matches!(err, Error::ReadOnly), "read-only refuses append");
+        assert_eq!(reader.fetch(0, 1024).unwrap().unwrap().length, 42);
+    }
+
+    #[test]
+    fn it_fetches_file_slices() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(28, 16)).unwrap();
+        partition.append("YELLOW SUBMARINE".as_bytes()).unwrap();
+        partition.append("XX".as_bytes()).unwrap();
+        partition.append("XX".as_bytes()).unwrap();
+
+        let slice = partition.fetch(0, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (0, 28), "first segment only");
+        let slice = partition.fetch(1, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (0, 28), "active segment");
+        let slice = partition.fetch(2, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (14, 14));
+        let mut buf = vec![];
+        slice.copy_to(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 14, 88, 88]);
+        assert!(partition.fetch(3, 1024).unwrap().is_none(), "nothing at the log end");
+        assert!(matches!(partition.fetch(4, 1024), Err(Error::OffsetOutOfRange(4))), "past the log end");
+        let slice = partition.fetch_upto(1, 2, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length, slice.next_offset), (0, 14, 2), "bounded by upto");
+        assert!(partition.fetch_upto(2, 2, 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_reads_messages() {
+        let tmp = tempdir().unwrap().path().to_path_buf();
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(128, 64)).unwrap();
+        for ts in 1..4 {
+            let record = Record::new(ts * 10, None, Some("XX".as_bytes()));
+            partition.append(&record.to_vec().unwrap()).unwrap();
+        }
+
+        let messages = partition.read(1, 1024).unwrap();
+        assert_eq!(messages.iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![1, 2]);
+        assert_eq!(Record::from_slice(&messages[0].payload).unwrap().timestamp, 20);
+        assert_eq!(partition.read(0, 1).unwrap().len(), 1, "always at least one message");
+        assert!(partition.read(3, 1024).unwrap().is_empty());
+
+        assert_eq!(partition.offset_for_timestamp(15).unwrap(), Some((1, 20)));
+        assert_eq!(partition.offset_for_timestamp(31).unwrap(), None);
+    }
+
+    #[test]
+    fn it_deduplicates_producer_batches() {
+        let tmp = tempdir().unwrap();
+        let batch = |sequence: i32| -> Vec<Record> {
+            (0..2).map(|i| Record::new(0, None, Some("XX".as_bytes())).with_producer(7, 0, sequence + i)).collect()
+        };
+        {
+            let mut partition = Partition::create(String::from("topic"), &mut tmp.path().to_path_buf(), MaxBytes(128, 64)).unwrap();
+            assert_eq!(partition.append_batch(&batch(0)).unwrap(), 0);
+            assert_eq!(partition.append_batch(&batch(2)).unwrap(), 2);
+            assert_eq!(partition.append_batch(&batch(2)).unwrap(), 2, "a retry isn't appended");
+            assert!(matches!(partition.append_batch(&batch(6)), Err(Error::OutOfOrderSequence{ expected: 4, .. })));
+            assert_eq!(partition.log_end_offset(), 4);
+            assert!(tmp.path().join("topic").join(format!("{:0>20}.snapshot", 2)).exists(), "snapshot at the roll");
+        }
+
+        let mut partition = Partition::load(&tmp.path().join("topic"), MaxBytes(128, 64)).unwrap();
+        assert_eq!(partition.producer_state().last_sequence(7), Some(3));
+        assert_eq!(partition.append_batch(&batch(0)).unwrap(), 0, "restored from the snapshot");
+        assert_eq!(partition.append_batch(&batch(2)).unwrap(), 2, "replayed after the snapshot");
+        assert_eq!(partition.append_batch(&batch(4)).unwrap(), 4);
+
+        // a batch with a record too large for the log leaves none of it behind
+        let mut oversized = batch(6);
+        oversized[1].value = Some(vec![0; 128]);
+        assert!(matches!(partition.append_batch(&oversized), Err(Error::MessageTooLarge{ .. })));
+        assert_eq!(partition.log_end_offset(), 6);
+        assert_eq!(partition.append_batch(&batch(6)).unwrap(), 6, "the retry isn't a duplicate");
+        assert_eq!(partition.log_end_offset(), 8);
+    }
+
+    #[test]
+    fn it_tracks_transactions() {
+        let tmp = tempdir().unwrap();
+        let txn = |producer_id: i64, sequence: i32| -> Vec<Record> {
+            let mut record = Record::new(0, None, Some("XX".as_bytes())).with_producer(producer_id, 0, sequence);
+            record.attributes = TRANSACTIONAL;
+            vec![record]
+        };
+        {
+            let mut partition = Partition::create(String::from("topic"), &mut tmp.path().to_path_buf(), MaxBytes(1024, 1024)).unwrap();
+            partition.append_batch(&txn(7, 0)).unwrap();
+            partition.append_batch(&txn(8, 0)).unwrap();
+            partition.append_batch(&txn(7, 1)).unwrap();
+            assert_eq!(partition.last_stable_offset(), 0, "both transactions are open");
+            assert_eq!(partition.write_marker(7, 0, Marker::Abort).unwrap(), 3);
+            assert_eq!(partition.last_stable_offset(), 1);
+            assert!(matches!(partition.write_marker(8, -1, Marker::Commit), Err(Error::ProducerFenced{ .. })));
+            partition.write_marker(8, 0, Marker::Commit).unwrap();
+            assert_eq!(partition.last_stable_offset(), 5);
+            let (messages, next) = partition.read_committed(0, 1024).unwrap();
+            assert_eq!(messages.iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![1]);
+            assert_eq!(next, 5);
+        }
+
+        let mut partition = Partition::load(&tmp.path().join("topic"), MaxBytes(1024, 1024)).unwrap();
+        assert_eq!(partition.aborted_transactions(0, 5).unwrap(), vec![AbortedTxn{ producer_id: 7, first_offset: 0, last_offset: 3 }]);
+        partition.append_batch(&txn(8, 1)).unwrap();
+        assert_eq!(partition.last_stable_offset(), 5);
+        let (messages, next) = partition.read_committed(5, 1024).unwrap();
+        assert!(messages.is_empty() && next == 5, "nothing stable past the open transaction");
+    }
+
+    #[test]
+    fn it_appends_replicated_messages() {
+        let tmp = tempdir().unwrap();
+        let mut leader = Partition::create(String::from("leader"), &mut tmp.path().to_path_buf(), MaxBytes(1024, 1024)).unwrap();
+        let mut record = Record::new(0, None, Some("XX".as_bytes())).with_producer(7, 0, 0);
+        record.attributes = TRANSACTIONAL;
+        leader.append_batch(&[record]).unwrap();
+        leader.append_batch(&[Record::new(0, Some("k".as_bytes()), Some("YY".as_bytes()))]).unwrap();
+        leader.write_marker(7, 0, Marker::Abort).unwrap();
+        let mut messages = leader.read(0, 1024).unwrap();
+        // what compaction leaves behind, offsets 3 and 4 are gone
+        messages.push(Message::new(5, 0, &Record::new(0, None, Some("ZZ".as_bytes())).to_vec().unwrap()));
+
+        let mut replica = Partition::create(String::from("replica"), &mut tmp.path().to_path_buf(), MaxBytes(1024, 1024)).unwrap();
+        assert_eq!(replica.append_replica(&messages[..2]).unwrap(), 2);
+        assert_eq!(replica.append_replica(&messages[2..]).unwrap(), 6);
+        assert!(matches!(replica.append_replica(&messages[..1]), Err(Error::InvalidRequest(_))), "already there");
+        assert_eq!(replica.read(0, 1024).unwrap().iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![0, 1, 2, 5]);
+        assert_eq!(replica.read(5, 1024).unwrap()[0].payload, messages[3].payload);
+        assert_eq!(replica.segments_len(), 0, "the gap stays in the active segment");
+        assert_eq!(replica.aborted_transactions(0, 6).unwrap(), leader.aborted_transactions(0, 3).unwrap());
+        assert_eq!(replica.producer_state().epoch(7), Some(0));
+    }
+
+    fn read_all(partition: &Partition) -> Vec<Message> {
+        let mut messages = vec![];
+        let mut offset = partition.log_start_offset();
+        while offset < partition.log_end_offset() {
+            let read = partition.read(offset, 1024).unwrap();
+            offset = read.last().unwrap().offset + 1;
+            messages.extend(read);
+        }
+        messages
+    }
+
+    #[test]
+    fn it_replicates_a_compacted_log_into_as_many_segments() {
+        let tmp = tempdir().unwrap();
+        let config = PartitionConfig {
+            cleanup_policy: CleanupPolicy::Compact,
+            ..PartitionConfig::from(MaxBytes(96, 64))
+        };
+        let mut leader = Partition::create(String::from("leader"), &mut tmp.path().to_path_buf(), config).unwrap();
+        for i in 0..12 {
+            let key = if i % 3 == 0 { "b" } else { "a" };
+            leader.append(&Record::new(0, Some(key.as_bytes()), Some("1".as_bytes())).to_vec().unwrap()).unwrap();
+        }
+        let messages = read_all(&leader);
+        let mut uncompacted = Partition::create(String::from("uncompacted"), &mut tmp.path().to_path_buf(), MaxBytes(1024, 1024)).unwrap();
+        uncompacted.append_replica(&messages).unwrap();
+
+        assert!(leader.compact().unwrap() > 0);
+        let messages = read_all(&leader);
+        let mut replica = Partition::create(String::from("replica"), &mut tmp.path().to_path_buf(), MaxBytes(1024, 1024)).unwrap();
+        assert_eq!(replica.append_replica(&messages).unwrap(), 12);
+        assert_eq!(replica.segments_len(), uncompacted.segments_len(), "no segment per hole");
+        assert_eq!(replica.segments_len(), 0);
+        assert_eq!(replica.log_start_offset(), messages[0].offset);
+
+        // a reload finds the gaps where they were
+        let path = replica.path.clone();
+        drop(replica);
+        let replica = Partition::load(&path, MaxBytes(1024, 1024)).unwrap();
+        let reloaded: Vec<Offset> = read_all(&replica).iter().map(|m| m.offset).collect();
+        assert_eq!(reloaded, messages.iter().map(|m| m.offset).collect::<Vec<Offset>>());
+    }
+
+    #[test]
+    fn it_truncates_to_an_offset() {
+        let tmp = tempdir().unwrap();
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.path().to_path_buf(), MaxBytes(28, 16)).unwrap();
+        partition.assign_leader_epoch(1, 0).unwrap();
+        for _ in 0..3 { partition.append("XX".as_bytes()).unwrap(); }
+        partition.assign_leader_epoch(2, 3).unwrap();
+        for _ in 0..2 { partition.append("YY".as_bytes()).unwrap(); }
+        assert_eq!(partition.log_end_offset(), 5);
+
+        partition.truncate_to(3).unwrap();
+        assert_eq!(partition.log_end_offset(), 3);
+        assert_eq!(partition.leader_epochs().latest_epoch(), Some(1));
+        assert!(matches!(partition.truncate_to(7), Ok(())), "nothing past the end to drop");
+        assert_eq!(partition.append("ZZ".as_bytes()).unwrap(), 4);
+        assert_eq!(partition.read(2, 1024).unwrap().iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![2, 3]);
+
+        let path = partition.path.clone();
+        drop(partition);
+        let mut partition = Partition::load(&path, MaxBytes(28, 16)).unwrap();
+        assert_eq!(partition.log_end_offset(), 4);
+        assert_eq!(partition.read(3, 1024).unwrap()[0].payload, "ZZ".as_bytes());
+        assert_eq!(partition.leader_epochs().latest_epoch(), Some(1));
+
+        // a snapshot up to 3 makes the first segment redundant, one that
+        // covers more than the log restarts it
+        assert_eq!(partition.delete_segments_before(3).unwrap(), 1);
+        assert_eq!(partition.log_start_offset(), 2);
+        partition.truncate_fully_and_start_at(10).unwrap();
+        assert_eq!(partition.append("AA".as_bytes()).unwrap(), 11);
+        drop(partition);
+        let partition = Partition::load(&path, MaxBytes(28, 16)).unwrap();
+        assert_eq!((partition.log_start_offset(), partition.log_end_offset()), (10, 11));
+        assert_eq!(partition.leader_epochs().latest_epoch(), None);
+    }
+
+    #[test]
+    fn it_tells_missing_from_corrupt_partitions() {
+        let mut tmp = tempdir().unwrap().path().to_path_buf();
+        tmp.push("topic");
+        let err = Partition::load(&tmp, MaxBytes(64, 32)).err().unwrap();
+        assert!(matches!(err, Error::PartitionNotFound(_)), "no directory");
+
+        {
+            let mut path = tmp.clone();
+            fs::create_dir_all(&path).unwrap();
+            path.push("00000000000000000000.index");
+            let mut idx = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+            idx.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14]).unwrap();
+            path.pop();
+            path.push("00000000000000000000.log");
+            OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+        }
+        let err = Partition::load(&tmp, MaxBytes(64, 32)).err().unwrap();
+        assert!(matches!(err, Error::CorruptRecord(_)), "index points past an empty log");
+    }
 }
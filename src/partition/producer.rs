@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use crate::{Error, Offset, Result};
+use crate::partition::record::{Record};
+use crate::partition::transaction::{AbortedTxn, Marker, TRANSACTIONAL};
+
+pub const SNAPSHOT_EXT: &str = "snapshot";
+const SNAPSHOT_VERSION: u8 = 2;
+// the last few batches of a producer are remembered so a retry of one of
+// them gets its original offset back instead of being appended twice
+const CACHED_BATCHES: usize = 5;
+const SNAPSHOTS_KEPT: usize = 2;
+
+
+// The producer metadata of a batch about to be appended, its records carry
+// sequences base_sequence..base_sequence + count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerBatch {
+    pub producer_id: i64,
+    pub epoch: i16,
+    pub base_sequence: i32,
+    pub count: i32,
+    pub transactional: bool,
+}
+
+impl ProducerBatch {
+    pub fn last_sequence(&self) -> i32 { next_sequence(self.base_sequence, self.count - 1) }
+}
+
+// sequences wrap around to 0 after i32::MAX, same as kafka's
+pub fn next_sequence(sequence: i32, delta: i32) -> i32 {
+    ((sequence as i64 + delta as i64) % (i32::MAX as i64 + 1)) as i32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BatchMeta {
+    first_sequence: i32,
+    last_sequence: i32,
+    base_offset: Offset,
+}
+
+impl BatchMeta {
+    fn covers(&self, batch: &ProducerBatch) -> Option<Offset> {
+        // a retried batch lies inside one we appended, no wrap around inside a batch
+        if self.first_sequence > self.last_sequence { return None }
+        let last = batch.last_sequence();
+        if batch.base_sequence >= self.first_sequence && last <= self.last_sequence && batch.base_sequence <= last {
+            return Some(self.base_offset + (batch.base_sequence - self.first_sequence) as Offset)
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProducerEntry {
+    epoch: i16,
+    batches: VecDeque<BatchMeta>,
+    // where the producer's open transaction started in this partition
+    txn_first_offset: Option<Offset>,
+}
+
+impl ProducerEntry {
+    fn new(epoch: i16) -> ProducerEntry {
+        ProducerEntry{ epoch, batches: VecDeque::new(), txn_first_offset: None }
+    }
+
+    fn bump(&mut self, epoch: i16) {
+        // a new epoch starts its sequences over
+        if self.epoch != epoch {
+            self.epoch = epoch;
+            self.batches.clear();
+        }
+    }
+
+    fn last_sequence(&self) -> Option<i32> { self.batches.back().map(|b| b.last_sequence) }
+
+    fn push(&mut self, meta: BatchMeta) {
+        self.batches.push_back(meta);
+        if self.batches.len() > CACHED_BATCHES { self.batches.pop_front(); }
+    }
+}
+
+
+// Who has been writing to a partition and which sequence each producer is
+// up to. Appends are checked against it so retries can't duplicate records
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProducerState {
+    producers: BTreeMap<i64, ProducerEntry>,
+}
+
+impl ProducerState {
+    pub fn new() -> ProducerState { ProducerState::default() }
+
+    pub fn len(&self) -> usize { self.producers.len() }
+    pub fn is_empty(&self) -> bool { self.producers.is_empty() }
+
+    pub fn epoch(&self, producer_id: i64) -> Option<i16> {
+        self.producers.get(&producer_id).map(|e| e.epoch)
+    }
+
+    pub fn last_sequence(&self, producer_id: i64) -> Option<i32> {
+        self.producers.get(&producer_id).and_then(|e| e.last_sequence())
+    }
+
+    // Ok(Some(offset)) means the batch was already appended at offset
+    pub fn check(&self, batch: &ProducerBatch) -> Result<Option<Offset>> {
+        let entry = match self.producers.get(&batch.producer_id) {
+            Some(entry) => entry,
+            // a producer we don't know (new, or its records were deleted) starts anywhere
+            None => return Ok(None),
+        };
+        if batch.epoch < entry.epoch {
+            return Err(Error::ProducerFenced{ producer_id: batch.producer_id, epoch: batch.epoch })
+        }
+        if batch.epoch > entry.epoch {
+            if batch.base_sequence != 0 {
+                return Err(Error::OutOfOrderSequence{ producer_id: batch.producer_id, expected: 0, got: batch.base_sequence })
+            }
+            return Ok(None)
+        }
+        if let Some(offset) = entry.batches.iter().find_map(|meta| meta.covers(batch)) {
+            return Ok(Some(offset))
+        }
+        let expected = entry.last_sequence().map_or(0, |last| next_sequence(last, 1));
+        if batch.base_sequence != expected {
+            return Err(Error::OutOfOrderSequence{ producer_id: batch.producer_id, expected, got: batch.base_sequence })
+        }
+        Ok(None)
+    }
+
+    pub fn update(&mut self, batch: &ProducerBatch, base_offset: Offset) {
+        let entry = self.producers.entry(batch.producer_id).or_insert_with(|| ProducerEntry::new(batch.epoch));
+        entry.bump(batch.epoch);
+        entry.push(BatchMeta{ first_sequence: batch.base_sequence, last_sequence: batch.last_sequence(), base_offset });
+        if batch.transactional && entry.txn_first_offset.is_none() {
+            entry.txn_first_offset = Some(base_offset);
+        }
+    }
+
+    // closes the producer's open transaction for a marker, returning where it
+    // started. Markers come from the coordinator, which may have bumped the epoch
+    pub fn end_transaction(&mut self, producer_id: i64, epoch: i16) -> Result<Option<Offset>> {
+        let entry = self.producers.entry(producer_id).or_insert_with(|| ProducerEntry::new(epoch));
+        if epoch < entry.epoch {
+            return Err(Error::ProducerFenced{ producer_id, epoch })
+        }
+        entry.bump(epoch);
+        Ok(entry.txn_first_offset.take())
+    }
+
+    // the first offset of the oldest open transaction, the last stable offset
+    pub fn first_unstable_offset(&self) -> Option<Offset> {
+        self.producers.values().filter_map(|e| e.txn_first_offset).min()
+    }
+
+    // rebuilds the state from records read back off the log. Batch boundaries
+    // aren't stored, consecutive sequences fold into one batch, which `check`
+    // still recognises retries against. Returns the transaction an abort marker ended
+    pub fn replay(&mut self, offset: Offset, record: &Record) -> Option<AbortedTxn> {
+        if !record.has_producer() { return None }
+        let entry = self.producers.entry(record.producer_id).or_insert_with(|| ProducerEntry::new(record.producer_epoch));
+        entry.bump(record.producer_epoch);
+        if let Some(marker) = Marker::from_record(record) {
+            let first_offset = entry.txn_first_offset.take()?;
+            if marker == Marker::Commit { return None }
+            return Some(AbortedTxn{ producer_id: record.producer_id, first_offset, last_offset: offset })
+        }
+        if record.attributes & TRANSACTIONAL != 0 && entry.txn_first_offset.is_none() {
+            entry.txn_first_offset = Some(offset);
+        }
+        if let Some(last) = entry.batches.back_mut() {
+            let next_offset = last.base_offset + (last.last_sequence - last.first_sequence) as Offset + 1;
+            if record.sequence == next_sequence(last.last_sequence, 1) && record.sequence > last.last_sequence && offset == next_offset {
+                last.last_sequence = record.sequence;
+                return None
+            }
+        }
+        entry.push(BatchMeta{ first_sequence: record.sequence, last_sequence: record.sequence, base_offset: offset });
+        None
+    }
+
+    // Snapshots are named by the log end offset they were taken at:
+    //
+    //   version u8 | count u32 | [producer_id i64 | epoch i16 | txn_first_offset i64 |
+    //                              n u8 | [first_seq i32 | last_seq i32 | base_offset u64]]
+    pub fn snapshot(&self, dir: &Path, offset: Offset) -> Result<PathBuf> {
+        let mut buf = vec![SNAPSHOT_VERSION];
+        buf.write_u32::<BigEndian>(self.producers.len() as u32)?;
+        for (id, entry) in &self.producers {
+            buf.write_i64::<BigEndian>(*id)?;
+            buf.write_i16::<BigEndian>(entry.epoch)?;
+            buf.write_i64::<BigEndian>(entry.txn_first_offset.map_or(-1, |offset| offset as i64))?;
+            buf.push(entry.batches.len() as u8);
+            for meta in &entry.batches {
+                buf.write_i32::<BigEndian>(meta.first_sequence)?;
+                buf.write_i32::<BigEndian>(meta.last_sequence)?;
+                buf.write_u64::<BigEndian>(meta.base_offset)?;
+            }
+        }
+        let path = snapshot_path(dir, offset);
+        let tmp = path.with_extension("snapshot.tmp");
+        fs::write(&tmp, &buf)?;
+        fs::rename(&tmp, &path)?;
+
+        let snapshots = list_snapshots(dir)?;
+        if snapshots.len() > SNAPSHOTS_KEPT {
+            for (_, old) in &snapshots[..snapshots.len() - SNAPSHOTS_KEPT] {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(path)
+    }
+
+    pub fn from_slice(raw: &[u8]) -> Result<ProducerState> {
+        let corrupt = || Error::CorruptRecord(String::from("short producer snapshot"));
+        if raw.len() < 5 || raw[0] != SNAPSHOT_VERSION {
+            return Err(Error::CorruptRecord(String::from("not a producer snapshot")))
+        }
+        let count = BigEndian::read_u32(&raw[1..5]);
+        let mut rest = &raw[5..];
+        let mut state = ProducerState::new();
+        for _ in 0..count {
+            if rest.len() < 19 { return Err(corrupt()) }
+            let id = BigEndian::read_i64(&rest[0..8]);
+            let mut entry = ProducerEntry::new(BigEndian::read_i16(&rest[8..10]));
+            let txn_first_offset = BigEndian::read_i64(&rest[10..18]);
+            if txn_first_offset >= 0 { entry.txn_first_offset = Some(txn_first_offset as Offset) }
+            let n = rest[18] as usize;
+            rest = &rest[19..];
+            if rest.len() < n * 16 { return Err(corrupt()) }
+            for chunk in rest[..n * 16].chunks(16) {
+                entry.batches.push_back(BatchMeta{
+                    first_sequence: BigEndian::read_i32(&chunk[0..4]),
+                    last_sequence: BigEndian::read_i32(&chunk[4..8]),
+                    base_offset: BigEndian::read_u64(&chunk[8..16]),
+                });
+            }
+            rest = &rest[n * 16..];
+            state.producers.insert(id, entry);
+        }
+        Ok(state)
+    }
+
+    // snapshots past a truncated log end describe records that are gone
+    pub fn delete_snapshots_after(dir: &Path, offset: Offset) -> Result<()> {
+        for (taken_at, path) in list_snapshots(dir)? {
+            if taken_at > offset { fs::remove_file(path)? }
+        }
+        Ok(())
+    }
+
+    // the newest readable snapshot taken at or below max_offset
+    pub fn load_latest(dir: &Path, max_offset: Offset) -> Result<Option<(Offset, ProducerState)>> {
+        for (offset, path) in list_snapshots(dir)?.into_iter().rev() {
+            if offset > max_offset { continue }
+            // a torn snapshot only costs a longer replay
+            if let Ok(state) = ProducerState::from_slice(&fs::read(&path)?) {
+                return Ok(Some((offset, state)))
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn snapshot_path(dir: &Path, offset: Offset) -> PathBuf {
+    dir.join(format!("{:0>20}.{}", offset, SNAPSHOT_EXT))
+}
+
+fn list_snapshots(dir: &Path) -> Result<Vec<(Offset, PathBuf)>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != SNAPSHOT_EXT) { continue }
+        let offset = path.file_stem().and_then(|s| s.to_string_lossy().parse::<Offset>().ok());
+        if let Some(offset) = offset {
+            snapshots.push((offset, path));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+
+    fn batch(producer_id: i64, epoch: i16, base_sequence: i32, count: i32) -> ProducerBatch {
+        ProducerBatch{ producer_id, epoch, base_sequence, count, transactional: false }
+    }
+
+    #[test]
+    fn it_checks_sequences() {
+        let mut state = ProducerState::new();
+        assert_eq!(state.check(&batch(7, 0, 0, 3)).unwrap(), None);
+        state.update(&batch(7, 0, 0, 3), 10);
+        assert_eq!(state.last_sequence(7), Some(2));
+
+        assert_eq!(state.check(&batch(7, 0, 0, 3)).unwrap(), Some(10), "retry of the last batch");
+        assert_eq!(state.check(&batch(7, 0, 1, 2)).unwrap(), Some(11));
+        assert_eq!(state.check(&batch(7, 0, 3, 1)).unwrap(), None);
+        assert!(matches!(state.check(&batch(7, 0, 5, 1)), Err(Error::OutOfOrderSequence{ expected: 3, got: 5, .. })));
+        assert!(matches!(state.check(&batch(7, 1, 3, 1)), Err(Error::OutOfOrderSequence{ expected: 0, .. })));
+
+        state.update(&batch(7, 1, 0, 1), 13);
+        assert!(matches!(state.check(&batch(7, 0, 3, 1)), Err(Error::ProducerFenced{ epoch: 0, .. })));
+        assert_eq!(state.check(&batch(8, 0, 42, 1)).unwrap(), None, "unknown producers start anywhere");
+    }
+
+    #[test]
+    fn it_wraps_sequences() {
+        let mut state = ProducerState::new();
+        state.update(&batch(7, 0, i32::MAX - 1, 2), 0);
+        assert_eq!(batch(7, 0, i32::MAX, 2).last_sequence(), 0);
+        assert_eq!(state.check(&batch(7, 0, 0, 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn it_replays_records() {
+        let mut state = ProducerState::new();
+        for (offset, sequence) in (0..3).enumerate() {
+            state.replay(offset as Offset, &Record::new(0, None, None).with_producer(7, 0, sequence));
+        }
+        state.replay(3, &Record::new(0, None, None));
+        assert_eq!(state.len(), 1);
+        assert_eq!(state.last_sequence(7), Some(2));
+        assert_eq!(state.check(&batch(7, 0, 1, 2)).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn it_tracks_open_transactions() {
+        let mut state = ProducerState::new();
+        let txn = |producer_id, base_sequence| ProducerBatch{ transactional: true, ..batch(producer_id, 0, base_sequence, 1) };
+        state.update(&batch(6, 0, 0, 1), 3);
+        state.update(&txn(7, 0), 4);
+        state.update(&txn(8, 0), 5);
+        state.update(&txn(7, 1), 6);
+        assert_eq!(state.first_unstable_offset(), Some(4));
+        assert_eq!(state.end_transaction(7, 0).unwrap(), Some(4));
+        assert_eq!(state.first_unstable_offset(), Some(5));
+        assert_eq!(state.end_transaction(8, 1).unwrap(), Some(5), "the coordinator bumped the epoch");
+        assert!(matches!(state.end_transaction(8, 0), Err(Error::ProducerFenced{ .. })));
+        assert_eq!(state.first_unstable_offset(), None);
+
+        let mut replayed = ProducerState::new();
+        let mut record = Record::new(0, None, None).with_producer(7, 0, 0);
+        record.attributes = TRANSACTIONAL;
+        assert_eq!(replayed.replay(4, &record), None);
+        assert_eq!(replayed.first_unstable_offset(), Some(4));
+        let aborted = replayed.replay(5, &Marker::Abort.record(0, 7, 0));
+        assert_eq!(aborted, Some(AbortedTxn{ producer_id: 7, first_offset: 4, last_offset: 5 }));
+        assert_eq!(replayed.first_unstable_offset(), None);
+    }
+
+    #[test]
+    fn it_snapshots() {
+        let tmp = tempdir().unwrap();
+        let mut state = ProducerState::new();
+        state.update(&ProducerBatch{ transactional: true, ..batch(7, 2, 0, 3) }, 10);
+        state.update(&batch(9, 0, 5, 1), 13);
+        for offset in &[14, 20, 30] {
+            state.snapshot(tmp.path(), *offset).unwrap();
+        }
+        assert_eq!(list_snapshots(tmp.path()).unwrap().len(), SNAPSHOTS_KEPT, "old snapshots are pruned");
+
+        let (offset, loaded) = ProducerState::load_latest(tmp.path(), 29).unwrap().unwrap();
+        assert_eq!((offset, &loaded), (20, &state));
+        assert!(ProducerState::load_latest(tmp.path(), 19).unwrap().is_none());
+    }
+}
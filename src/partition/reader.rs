@@ -2,9 +2,12 @@ use std::{io};
 use std::io::{Read, SeekFrom, Seek};
 use std::path::PathBuf;
 // use std::sync::{Arc, Mutex}; // TODO: ADD THESE :X
+use crate::{Error, Result};
 use crate::partition::{Offset};
 use crate::partition::Partition;
+use crate::partition::message::{Message};
 use crate::partition::segment::{SegmentMeta, MaxBytes};
+use crate::partition::transaction::{self as txn, AbortedTxn, Isolation};
 
 
 pub struct Reader {
@@ -13,11 +16,15 @@ pub struct Reader {
     max_bytes: MaxBytes,
     offset: Offset,
     relative_position: u64,
+    isolation: Isolation,
+    // read_committed stops here
+    last_stable_offset: Offset,
+    aborted: Vec<AbortedTxn>,
 }
 
 impl Reader {
-    pub fn new(offset: Offset, path: PathBuf, max_bytes: MaxBytes) -> Option<Reader> {
-        let mut segments = Partition::scan(path, max_bytes).ok()?;
+    pub fn new(offset: Offset, path: PathBuf, max_bytes: MaxBytes) -> Result<Reader> {
+        let mut segments = Partition::scan(path, max_bytes)?;
         segments.reverse();  // largest -> smallest
         let mut cursor: Option<SegmentMeta> = None;
         loop {
@@ -25,23 +32,62 @@ impl Reader {
                 Some(seg) => seg,
                 None => break,
             };
-            if offset < segment.base_offset { break };
+            if offset < segment.base_offset {
+                segments.push(segment);
+                break
+            };
             cursor = Some(segment);
         }
         if let Some(mut active) = cursor {
-            let entry = active.read_index_entry(offset).ok()?;
+            let entry = active.read_index_entry(offset)?;
             let _ = active.seek(SeekFrom::Start(entry.position));
-            return Some(
+            return Ok(
                 Reader{
                     segments: segments,
                     active_segment: active,
                     max_bytes: max_bytes,
                     offset: offset,
                     relative_position: entry.position,
+                    isolation: Isolation::ReadUncommitted,
+                    last_stable_offset: Offset::MAX,
+                    aborted: vec![],
                 }
             );
         }
-        None
+        Err(Error::OffsetOutOfRange(offset))
+    }
+
+    pub fn read_committed(offset: Offset, path: PathBuf, max_bytes: MaxBytes, last_stable_offset: Offset) -> Result<Reader> {
+        // the reader can't tell open transactions from the log files alone, the
+        // partition's last stable offset comes from whoever opened it
+        let mut reader = Reader::new(offset, path, max_bytes)?;
+        for segment in reader.segments.iter().chain(std::iter::once(&reader.active_segment)) {
+            reader.aborted.extend(txn::read_aborted(&segment.txn_index_path())?);
+        }
+        reader.isolation = Isolation::ReadCommitted;
+        reader.last_stable_offset = last_stable_offset;
+        Ok(reader)
+    }
+
+    pub fn messages(&mut self, max_len: u64) -> Result<Vec<Message>> {
+        // the next messages from the reader's offset, about max_len bytes of them.
+        // read_committed leaves out markers and aborted records
+        let mut messages = loop {
+            let messages = self.active_segment.read_from(self.offset, max_len)?;
+            if !messages.is_empty() { break messages }
+            match self.segments.pop() {
+                Some(segment) => self.active_segment = segment,
+                None => return Ok(vec![]),
+            }
+        };
+        if self.isolation == Isolation::ReadCommitted {
+            messages.retain(|m| m.offset < self.last_stable_offset);
+        }
+        if let Some(last) = messages.last() { self.offset = last.offset + 1 }
+        match self.isolation {
+            Isolation::ReadCommitted => Ok(txn::filter_committed(messages, &self.aborted)),
+            Isolation::ReadUncommitted => Ok(messages),
+        }
     }
 }
 
@@ -101,7 +147,7 @@ mod tests {
         fs::create_dir_all(&tmp).unwrap();
         let reader = Reader::new(0, tmp, MaxBytes(64, 32));
 
-        assert!(reader.is_none(), "there should be no reader");
+        assert!(matches!(reader, Err(Error::OffsetOutOfRange(0))), "there should be no reader");
     }
 
 
@@ -112,7 +158,7 @@ mod tests {
         tmp.push("topic/");
 
         let reader = Reader::new(0, tmp, MaxBytes(128, 64));
-        assert!(reader.is_some(), "reader is some");
+        assert!(reader.is_ok(), "reader is ok");
         let actual = reader.unwrap();
         assert_eq!(actual.relative_position, 0);
         assert_eq!(actual.offset, 0);
@@ -138,7 +184,7 @@ mod tests {
     #[test]
     fn it_can_read_from_a_second_segment_in_same_buffer() {
         let mut tmp = tempdir().unwrap().path().to_path_buf();
-        write_partition(tmp.clone(), MaxBytes(24, 32));
+        write_partition(tmp.clone(), MaxBytes(28, 32));
         tmp.push("topic/");
         let mut reader = Reader::new(0, tmp, MaxBytes(128, 64)).unwrap();
 
@@ -149,10 +195,40 @@ mod tests {
         assert_eq!(n, 28);
     }
 
+    #[test]
+    fn it_reads_committed_messages() {
+        use crate::partition::record::{Record};
+        use crate::partition::transaction::{Marker, TRANSACTIONAL};
+        let mut tmp = tempdir().unwrap().path().to_path_buf();
+        let txn = |producer_id: i64, sequence: i32| {
+            let mut record = Record::new(0, None, Some(b"XX")).with_producer(producer_id, 0, sequence);
+            record.attributes = TRANSACTIONAL;
+            vec![record]
+        };
+        let mut partition = Partition::create(String::from("topic"), &mut tmp.clone(), MaxBytes(96, 64)).unwrap();
+        partition.append_batch(&txn(7, 0)).unwrap();
+        partition.append_batch(&txn(8, 0)).unwrap();
+        partition.write_marker(7, 0, Marker::Abort).unwrap();
+        partition.write_marker(8, 0, Marker::Commit).unwrap();
+        partition.append_batch(&txn(9, 0)).unwrap();
+        tmp.push("topic/");
+
+        let read_all = |mut reader: Reader| {
+            let mut offsets = vec![];
+            for _ in 0..5 {
+                offsets.extend(reader.messages(1024).unwrap().iter().map(|m| m.offset));
+            }
+            offsets
+        };
+        assert_eq!(read_all(Reader::new(0, tmp.clone(), MaxBytes(96, 64)).unwrap()), vec![0, 1, 2, 3, 4]);
+        let reader = Reader::read_committed(0, tmp, MaxBytes(96, 64), partition.last_stable_offset()).unwrap();
+        assert_eq!(read_all(reader), vec![1], "only the committed record");
+    }
+
     #[test]
     fn it_can_read_into_giant_buffer() {
         let mut tmp = tempdir().unwrap().path().to_path_buf();
-        write_partition(tmp.clone(), MaxBytes(64, 32));
+        write_partition(tmp.clone(), MaxBytes(128, 32));
         tmp.push("topic/");
         let mut reader = Reader::new(0, tmp, MaxBytes(128, 64)).unwrap();
 
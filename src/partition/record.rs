@@ -0,0 +1,206 @@
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use crate::{Error, Result};
+
+pub const RECORD_MAGIC: u8 = 1;
+// records from idempotent producers carry who wrote them
+pub const PRODUCER_RECORD_MAGIC: u8 = 2;
+pub const NO_PRODUCER_ID: i64 = -1;
+const RECORD_HEADER_LEN: usize = 1 + 1 + 8;
+const PRODUCER_HEADER_LEN: usize = 8 + 2 + 4;
+
+
+// A Record is the keyed payload topics and producers store in a Message:
+//
+//   magic u8 | attributes u8 | timestamp u64 | key_len i32 | key | value_len i32 | value
+//
+// a length of -1 is a null key or value, a null value is a tombstone for
+// compacted partitions. Magic 2 adds `producer_id i64 | producer_epoch i16 |
+// sequence i32` after the timestamp, so producer state can be rebuilt from the log.
+// Records with headers end in `count i32 | [name_len i32 | name | value_len i32 | value]`,
+// those without stop at the value as they always did
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Record {
+    pub attributes: u8,
+    pub timestamp: u64,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub sequence: i32,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub headers: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl Record {
+    pub fn new(timestamp: u64, key: Option<&[u8]>, value: Option<&[u8]>) -> Record {
+        Record{
+            attributes: 0,
+            timestamp,
+            producer_id: NO_PRODUCER_ID,
+            producer_epoch: -1,
+            sequence: -1,
+            key: key.map(|k| k.to_vec()),
+            value: value.map(|v| v.to_vec()),
+            headers: vec![],
+        }
+    }
+
+    pub fn with_producer(mut self, producer_id: i64, producer_epoch: i16, sequence: i32) -> Record {
+        self.producer_id = producer_id;
+        self.producer_epoch = producer_epoch;
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn is_tombstone(&self) -> bool { self.value.is_none() }
+
+    pub fn has_producer(&self) -> bool { self.producer_id != NO_PRODUCER_ID }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.size());
+        buf.push(if self.has_producer() { PRODUCER_RECORD_MAGIC } else { RECORD_MAGIC });
+        buf.push(self.attributes);
+        buf.write_u64::<BigEndian>(self.timestamp)?;
+        if self.has_producer() {
+            buf.write_i64::<BigEndian>(self.producer_id)?;
+            buf.write_i16::<BigEndian>(self.producer_epoch)?;
+            buf.write_i32::<BigEndian>(self.sequence)?;
+        }
+        write_bytes(&mut buf, &self.key)?;
+        write_bytes(&mut buf, &self.value)?;
+        if !self.headers.is_empty() {
+            buf.write_i32::<BigEndian>(self.headers.len() as i32)?;
+            for (name, value) in &self.headers {
+                write_bytes(&mut buf, &Some(name.as_bytes().to_vec()))?;
+                write_bytes(&mut buf, value)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn from_slice(raw: &[u8]) -> Result<Record> {
+        let header_len = match raw.first() {
+            Some(&RECORD_MAGIC) => RECORD_HEADER_LEN,
+            Some(&PRODUCER_RECORD_MAGIC) => RECORD_HEADER_LEN + PRODUCER_HEADER_LEN,
+            _ => return Err(Error::CorruptRecord(String::from("not a record"))),
+        };
+        if raw.len() < header_len {
+            return Err(Error::CorruptRecord(String::from("not a record")))
+        }
+        let attributes = raw[1];
+        let timestamp = BigEndian::read_u64(&raw[2..10]);
+        let mut record = Record::new(timestamp, None, None);
+        record.attributes = attributes;
+        if raw[0] == PRODUCER_RECORD_MAGIC {
+            record.producer_id = BigEndian::read_i64(&raw[10..18]);
+            record.producer_epoch = BigEndian::read_i16(&raw[18..20]);
+            record.sequence = BigEndian::read_i32(&raw[20..24]);
+        }
+        let (key, rest) = read_bytes(&raw[header_len..])?;
+        let (value, mut rest) = read_bytes(rest)?;
+        if rest.len() >= 4 {
+            let count = BigEndian::read_i32(&rest[0..4]);
+            if count < 0 { return Err(Error::CorruptRecord(format!("{} headers", count))) }
+            rest = &rest[4..];
+            for _ in 0..count {
+                let (name, after_name) = read_bytes(rest)?;
+                let name = name.ok_or_else(|| Error::CorruptRecord(String::from("null header name")))?;
+                let (value, after_value) = read_bytes(after_name)?;
+                record.headers.push((String::from_utf8_lossy(&name).to_string(), value));
+                rest = after_value;
+            }
+        }
+        if !rest.is_empty() {
+            return Err(Error::CorruptRecord(format!("{} trailing bytes after record", rest.len())))
+        }
+        record.key = key;
+        record.value = value;
+        Ok(record)
+    }
+
+    pub fn size(&self) -> usize {
+        let len = |b: &Option<Vec<u8>>| b.as_ref().map_or(0, |b| b.len());
+        let producer = if self.has_producer() { PRODUCER_HEADER_LEN } else { 0 };
+        let headers = match self.headers.is_empty() {
+            true => 0,
+            false => 4 + self.headers.iter().map(|(name, value)| 4 + name.len() + 4 + len(value)).sum::<usize>(),
+        };
+        RECORD_HEADER_LEN + producer + 4 + len(&self.key) + 4 + len(&self.value) + headers
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &Option<Vec<u8>>) -> Result<()> {
+    match bytes {
+        Some(bytes) => {
+            buf.write_i32::<BigEndian>(bytes.len() as i32)?;
+            buf.extend_from_slice(bytes);
+        },
+        None => buf.write_i32::<BigEndian>(-1)?,
+    }
+    Ok(())
+}
+
+fn read_bytes(raw: &[u8]) -> Result<(Option<Vec<u8>>, &[u8])> {
+    if raw.len() < 4 {
+        return Err(Error::CorruptRecord(String::from("record is missing a length")))
+    }
+    let len = BigEndian::read_i32(&raw[0..4]);
+    let raw = &raw[4..];
+    if len < 0 { return Ok((None, raw)) }
+    let len = len as usize;
+    if raw.len() < len {
+        return Err(Error::CorruptRecord(format!("record wants {} bytes, {} left", len, raw.len())))
+    }
+    Ok((Some(raw[..len].to_vec()), &raw[len..]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_to_vec() {
+        let record = Record::new(2, Some(&[7]), Some(&[8, 9]));
+        assert_eq!(record.to_vec().unwrap(), vec![
+            1, 0, 0, 0, 0, 0, 0, 0, 0, 2,
+            0, 0, 0, 1, 7,
+            0, 0, 0, 2, 8, 9,
+        ]);
+        assert_eq!(record.size(), 21);
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let record = Record::new(1234, None, Some("YELLOW SUBMARINE".as_bytes()));
+        assert_eq!(Record::from_slice(&record.to_vec().unwrap()).unwrap(), record);
+        let tombstone = Record::new(1234, Some("key".as_bytes()), None);
+        let decoded = Record::from_slice(&tombstone.to_vec().unwrap()).unwrap();
+        assert!(decoded.is_tombstone());
+        assert_eq!(decoded.key, Some("key".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn producer_record_round_trips() {
+        let record = Record::new(1234, Some(&[7]), Some(&[8])).with_producer(42, 3, 17);
+        let raw = record.to_vec().unwrap();
+        assert_eq!((raw[0], raw.len(), record.size()), (PRODUCER_RECORD_MAGIC, 34, 34));
+        assert_eq!(Record::from_slice(&raw).unwrap(), record);
+    }
+
+    #[test]
+    fn record_with_headers_round_trips() {
+        let mut record = Record::new(1234, Some(&[7]), Some(&[8])).with_producer(42, 3, 17);
+        record.headers = vec![(String::from("trace"), Some(b"abc".to_vec())), (String::from("empty"), None)];
+        let raw = record.to_vec().unwrap();
+        assert_eq!(raw.len(), record.size());
+        assert_eq!(Record::from_slice(&raw).unwrap(), record);
+        assert!(matches!(Record::from_slice(&raw[..raw.len() - 1]), Err(Error::CorruptRecord(_))));
+    }
+
+    #[test]
+    fn record_from_garbage() {
+        assert!(matches!(Record::from_slice("YELLOW SUBMARINE".as_bytes()), Err(Error::CorruptRecord(_))));
+        assert!(matches!(Record::from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 9]), Err(Error::CorruptRecord(_))));
+    }
+}
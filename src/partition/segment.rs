@@ -2,16 +2,35 @@ use std::{io};
 use std::cmp::{Ord, Ordering, PartialOrd, PartialEq};
 use std::fs::{OpenOptions, File};
 use std::io::{BufWriter, Write, Read, SeekFrom, Seek};
+use std::fs;
 use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 // use std::sync::{Arc, Mutex};
 
+use crate::{Error, Result};
 use crate::partition::{Offset};
 use crate::partition::index::{Index};
-use crate::partition::entry::{Entry};
+use crate::partition::entry::{Entry, ENTRY_WIDTH};
+use crate::partition::message::{Message, MSG_HEADER_LEN};
+use crate::partition::slice::{FileSlice};
+use crate::partition::transaction::{TXN_INDEX_EXT};
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub struct MaxBytes(pub u64, pub u64);
 
+pub fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+}
+
 #[derive(Debug)]
 pub struct OpenSegment {
     log_reader: File,
@@ -43,63 +62,117 @@ impl Seek for OpenSegment {
 pub struct SegmentMeta {
     segment_path: PathBuf,
     index_path: PathBuf,
+    timestamp_path: PathBuf,
     pub base_offset: Offset,
     next_offset: Offset,
     position: Offset,
     max_bytes: MaxBytes,
+    access: Access,
+    first_write_ms: Option<u64>, // persisted in `.timestamp` so segment age survives restarts
+    // the newest write, persisted beside the first once the segment is rolled
+    last_write_ms: Option<u64>,
 }
 
 
 impl SegmentMeta {
-    pub fn load(path: PathBuf, max_bytes: MaxBytes) -> Option<SegmentMeta> {
-        if path.is_dir() { return None }
+    pub fn load(path: PathBuf, max_bytes: MaxBytes) -> Result<Option<SegmentMeta>> {
+        SegmentMeta::load_as(path, max_bytes, Access::ReadWrite)
+    }
+
+    pub fn load_as(path: PathBuf, max_bytes: MaxBytes, access: Access) -> Result<Option<SegmentMeta>> {
+        // Ok(None) means the path isn't a segment at all, errors are real ones
+        if path.is_dir() { return Ok(None) }
         let ext = match path.extension() {
             Some(ext) => {
                 ext.to_string_lossy()
             },
-            None => { return None }
+            None => { return Ok(None) }
         };
-        if !ext.contains("log") { return None }
+        if !ext.contains("log") { return Ok(None) }
         let stem = match path.file_stem() {
             Some(stem) => { stem.to_string_lossy() },
-            None => { return None }
+            None => { return Ok(None) }
         };
         let offset = match stem.parse::<Offset>() {
             Ok(off) => off,
-            _ => { return None },
+            _ => { return Ok(None) },
         };
 
         let mut base_path = path.clone();
         base_path.pop();
         let mut meta = SegmentMeta::new(base_path, offset, max_bytes);
-        let mut open_segment = meta.open().ok()?;
-        meta.position = meta.size();
-        let entry = open_segment.log_index.find_latest_entry().ok()?;
+        meta.access = access;
+        let mut open_segment = meta.open()?;
+        meta.position = open_segment.log_reader.metadata()?.len();
+        let entry = open_segment.log_index.find_latest_entry()?;
+        // a lone message at the base offset indexes as all zeroes, same as an
+        // empty index, so a non-empty log is what says there is an entry
+        let indexed = !open_segment.log_index.is_empty() || meta.position > 0;
+        if indexed && entry.position + MSG_HEADER_LEN as u64 > meta.position {
+            return Err(Error::CorruptRecord(format!(
+                "{} indexes offset {} at position {} past the log end {}",
+                meta.index_path.display(), entry.offset, entry.position, meta.position,
+            )))
+        }
 
-        meta.next_offset = if open_segment.log_index.is_empty() {
-            entry.offset
-        } else {
+        meta.next_offset = if indexed {
             entry.offset + 1
+        } else {
+            entry.offset
         };
-        Some(meta)
+        if meta.timestamp_path.exists() {
+            // `first [last]`, the last only once the segment was rolled
+            let raw = fs::read_to_string(&meta.timestamp_path)?;
+            let parsed: std::result::Result<Vec<u64>, _> = raw.split_whitespace().map(|ms| ms.parse::<u64>()).collect();
+            match parsed.as_deref() {
+                Ok([first]) => meta.first_write_ms = Some(*first),
+                Ok([first, last]) => {
+                    meta.first_write_ms = Some(*first);
+                    meta.last_write_ms = Some(*last);
+                },
+                _ => return Err(Error::CorruptRecord(format!(
+                    "{} isn't a timestamp", meta.timestamp_path.display()))),
+            }
+        } else if meta.position > 0 {
+            // segments written before timestamps were kept start aging now
+            meta.first_write_ms = Some(now_ms());
+        }
+        Ok(Some(meta))
     }
 
     pub fn new(base_path: PathBuf, base_offset: Offset, max_bytes: MaxBytes) -> SegmentMeta {
         let mut log_path = base_path.clone();
         let mut index_path = base_path.clone();
+        let mut timestamp_path = base_path.clone();
         log_path.push(format!("{:0>20}.log", base_offset));
         index_path.push(format!("{:0>20}.index", base_offset));
+        timestamp_path.push(format!("{:0>20}.timestamp", base_offset));
         SegmentMeta{
             segment_path: log_path,
             index_path: index_path,
+            timestamp_path,
             base_offset: base_offset,
             next_offset: base_offset,
             position: 0,
             max_bytes: max_bytes,
+            access: Access::ReadWrite,
+            first_write_ms: None,
+            last_write_ms: None,
         }
     }
 
-    pub fn open(&self) -> io::Result<OpenSegment> {
+    pub fn read_only(mut self) -> SegmentMeta {
+        self.access = Access::ReadOnly;
+        self
+    }
+
+    pub fn open(&self) -> Result<OpenSegment> {
+        if self.access == Access::ReadOnly {
+            let log_reader = OpenOptions::new().read(true).open(self.segment_path.clone())?;
+            let log_writer = log_reader.try_clone()?;
+            let log_index = Index::open_read_only(self.index_path.clone(), self.base_offset, self.max_bytes.1)?;
+            return Ok(OpenSegment{log_reader, log_writer, log_index})
+        }
         let log_writer = OpenOptions::new().create(true).write(true)
             .append(true).open(self.segment_path.clone())?;
         let log_reader = OpenOptions::new().read(true).open(self.segment_path.clone())?;
@@ -108,24 +181,263 @@ impl SegmentMeta {
         Ok(OpenSegment{log_reader: log_reader, log_writer: log_writer, log_index: log_index})
     }
 
-    pub fn size(&self) -> u64 {
-        match self.open().ok() {
-            Some(seg) => seg.log_writer.metadata().unwrap().len(),
-            None => 0,
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.open()?.log_writer.metadata()?.len())
+    }
+
+    pub fn read_index_entry(&mut self, offset: Offset) -> Result<Entry> {
+        if offset < self.base_offset { return Err(Error::OffsetOutOfRange(offset)) }
+        self.open()?.log_index.read_log_entry(offset - self.base_offset)
+    }
+
+    pub fn slice(&self, offset: Offset, upto: Offset, max_len: u64) -> Result<Option<FileSlice>> {
+        // returns the byte range of whole messages from offset onwards and
+        // before upto, at most max_len long unless the first message alone is
+        // bigger (so a fetch always progresses)
+        if offset < self.base_offset || offset > self.next_offset {
+            return Err(Error::OffsetOutOfRange(offset))
+        }
+        if offset == self.next_offset || offset >= upto { return Ok(None) }
+        let mut open_segment = self.open()?;
+        let start = open_segment.log_index.read_log_entry(offset - self.base_offset)?.position;
+        let mut end = start;
+        let mut sizes = vec![];
+        let mut next_offset = offset;
+        for off in offset..self.next_offset.min(upto) {
+            let message_end = if off + 1 < self.next_offset {
+                open_segment.log_index.read_log_entry(off + 1 - self.base_offset)?.position
+            } else {
+                self.position
+            };
+            if message_end - start > max_len && end > start { break }
+            // compaction leaves holes that take no bytes
+            if message_end > end { sizes.push((message_end - end) as u32) }
+            end = message_end;
+            next_offset = off + 1;
+        }
+        let slice = FileSlice::new(open_segment.log_reader, start, end - start);
+        Ok(Some(slice.with_messages(sizes, next_offset)))
+    }
+
+    pub fn read_from(&self, offset: Offset, max_len: u64) -> Result<Vec<Message>> {
+        // the messages slice() would hand out, decoded
+        let slice = match self.slice(offset, self.next_offset, max_len)? {
+            Some(slice) => slice,
+            None => return Ok(vec![]),
+        };
+        let mut open_segment = self.open()?;
+        let mut raw = vec![0; slice.length as usize];
+        open_segment.log_reader.seek(SeekFrom::Start(slice.position))?;
+        open_segment.log_reader.read_exact(&mut raw)?;
+        let slice_end = slice.position + slice.length;
+        let mut messages = vec![];
+        for off in offset..self.next_offset {
+            let start = open_segment.log_index.read_log_entry(off - self.base_offset)?.position;
+            if start >= slice_end { break }
+            let end = if off + 1 < self.next_offset {
+                open_segment.log_index.read_log_entry(off + 1 - self.base_offset)?.position
+            } else {
+                self.position
+            };
+            if end == start { continue }
+            if start < slice.position || end > slice_end {
+                return Err(Error::CorruptRecord(format!(
+                    "{} offset {} spans {}..{}", self.segment_path.display(), off, start, end)))
+            }
+            let mut message = raw[(start - slice.position) as usize..(end - slice.position) as usize].to_vec();
+            messages.push(Message::from_vec(&mut message)?);
+        }
+        Ok(messages)
+    }
+
+    pub fn read_messages(&self) -> Result<Vec<Message>> {
+        // every message in the segment, offsets compacted away are skipped
+        let mut open_segment = self.open()?;
+        let mut raw = vec![];
+        open_segment.log_reader.read_to_end(&mut raw)?;
+        let size = raw.len() as u64;
+        let mut messages = vec![];
+        for off in self.base_offset..self.next_offset {
+            let start = open_segment.log_index.read_log_entry(off - self.base_offset)?.position;
+            let end = if off + 1 < self.next_offset {
+                open_segment.log_index.read_log_entry(off + 1 - self.base_offset)?.position
+            } else {
+                size
+            };
+            if end == start { continue }
+            if end < start || end > size {
+                return Err(Error::CorruptRecord(format!(
+                    "{} offset {} spans {}..{}", self.segment_path.display(), off, start, end)))
+            }
+            let mut message = raw[start as usize..end as usize].to_vec();
+            messages.push(Message::from_vec(&mut message)?);
+        }
+        Ok(messages)
+    }
+
+    pub fn rewrite<F: FnMut(&Message) -> bool>(&self, mut keep: F) -> Result<SegmentMeta> {
+        // Rewrites the segment with only the messages `keep` says yes to, offsets
+        // are preserved. The index stays dense: an offset that's gone points at
+        // the next message kept, so fetching it returns the next one. The last
+        // message is always kept, it's what tells a reload where the segment ends.
+        let messages = self.read_messages()?;
+        let last = self.next_offset.saturating_sub(1);
+        let clean_log = self.segment_path.with_extension("log.cleaned");
+        let clean_index = self.index_path.with_extension("index.cleaned");
+        {
+            let mut log = OpenOptions::new().create(true).truncate(true).write(true).open(&clean_log)?;
+            let _ = fs::remove_file(&clean_index);
+            let mut index = Index::open(clean_index.clone(), self.base_offset, self.max_bytes.1)?;
+            let mut position: u64 = 0;
+            let mut pending: Vec<Offset> = vec![];
+            let mut messages = messages.into_iter().peekable();
+            for off in self.base_offset..self.next_offset {
+                let message = match messages.peek() {
+                    Some(message) if message.offset == off => messages.next(),
+                    _ => None,
+                };
+                let message = match message {
+                    Some(message) if off == last || keep(&message) => message,
+                    _ => {
+                        pending.push(off);
+                        continue
+                    },
+                };
+                let rewritten = Message::new(off, position as u32, &message.payload).to_vec()?;
+                log.write_all(&rewritten)?;
+                for hole in pending.drain(..) {
+                    index.write_entry(Entry::new(hole, position))?;
+                }
+                index.write_entry(Entry::new(off, position))?;
+                position += rewritten.len() as u64;
+            }
+            log.sync_data()?;
+            index.flush()?;
+        }
+        fs::rename(&clean_log, &self.segment_path)?;
+        fs::rename(&clean_index, &self.index_path)?;
+        match SegmentMeta::load_as(self.segment_path.clone(), self.max_bytes, self.access)? {
+            Some(segment) => Ok(segment),
+            None => Err(Error::CorruptRecord(format!("{} vanished", self.segment_path.display()))),
+        }
+    }
+
+    pub fn truncate_to(&mut self, offset: Offset) -> Result<()> {
+        // cuts the segment back to the messages before offset
+        if offset >= self.next_offset { return Ok(()) }
+        if offset < self.base_offset { return Err(Error::OffsetOutOfRange(offset)) }
+        let mut open_segment = self.open()?;
+        let position = match offset == self.base_offset {
+            true => 0,
+            false => open_segment.log_index.read_log_entry(offset - self.base_offset)?.position,
+        };
+        open_segment.log_writer.set_len(position)?;
+        open_segment.log_writer.sync_data()?;
+        open_segment.log_index.clear_from(offset - self.base_offset)?;
+        self.next_offset = offset;
+        self.position = position;
+        Ok(())
+    }
+
+    pub fn last_write_ms(&self) -> Result<u64> {
+        // what retention ages the segment by. Segments rolled before the
+        // last write was kept, or an active one since a restart, go by the
+        // log file's mtime
+        match self.last_write_ms {
+            Some(ms) => Ok(ms),
+            None => self.last_modified_ms(),
         }
     }
 
-    pub fn read_index_entry(&mut self, offset: Offset) -> io::Result<Entry> {
-        self.open()?.log_index.read_log_entry(offset)
+    pub fn seal(&mut self) -> Result<()> {
+        // the segment is rolled, nothing is written to it again
+        let first = match self.first_write_ms {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let last = self.last_write_ms()?;
+        fs::write(&self.timestamp_path, format!("{} {}", first, last))?;
+        self.last_write_ms = Some(last);
+        Ok(())
+    }
+
+    pub fn last_modified_ms(&self) -> Result<u64> {
+        let modified = fs::metadata(&self.segment_path)?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0))
+    }
+
+    // transactions aborted by markers in this segment, see partition::transaction
+    pub fn txn_index_path(&self) -> PathBuf { self.segment_path.with_extension(TXN_INDEX_EXT) }
+
+    pub fn delete(&self) -> Result<()> {
+        for path in &[&self.segment_path, &self.index_path, &self.timestamp_path, &self.txn_index_path()] {
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        if self.access == Access::ReadOnly { return Ok(()) }
+        let open_segment = self.open()?;
+        open_segment.log_writer.sync_data()?;
+        open_segment.log_index.flush()
+    }
+
+    pub fn append_at(&mut self, offset: Offset, payload: &[u8]) -> Result<()> {
+        // writes the message at offset, past the segment's end leaves a gap.
+        // The offsets skipped index at the message's position, the same holes
+        // a rewrite leaves
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
+        if offset < self.next_offset { return Err(Error::OffsetOutOfRange(offset)) }
+        if !self.can_index(offset) { return Err(Error::IndexFull) }
+        let position = self.position;
+        if position > u32::MAX as u64 { return Err(Error::SegmentFull) }
+        let message = Message::new(offset, position as u32, payload).to_vec()?;
+        let mut open_segment = self.open()?;
+        for hole in self.next_offset..offset {
+            open_segment.log_index.write_entry(Entry::new(hole, position))?;
+        }
+        let next_offset = self.next_offset;
+        self.next_offset = offset;
+        if let Err(e) = self.write(&message) {
+            self.next_offset = next_offset;
+            return Err(e.into())
+        }
+        open_segment.log_index.write_entry(Entry::new(offset, position))
     }
 
-    pub fn write_index_entry(&mut self, entry: Entry) -> io::Result<()> {
+    pub fn write_index_entry(&mut self, entry: Entry) -> Result<()> {
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly) }
         self.open()?.log_index.write_entry(entry)
     }
 
     pub fn is_full(&self) -> bool {
-        return self.position >= self.max_bytes.0
+        // full when either the log or the index can't take the next message
+        let next_entry_end = (self.next_offset - self.base_offset + 1) * ENTRY_WIDTH as u64;
+        self.position >= self.max_bytes.0 || next_entry_end > self.max_bytes.1
+    }
+    pub fn can_index(&self, offset: Offset) -> bool {
+        // the index has a slot for every offset from the base up to this one
+        match offset.checked_sub(self.base_offset) {
+            Some(relative) => relative <= u32::MAX as u64 && (relative + 1) * ENTRY_WIDTH as u64 <= self.max_bytes.1,
+            None => false,
+        }
+    }
+    pub fn has_room_for(&self, size: u64) -> bool {
+        // an empty segment takes anything, max.message.bytes is checked upstream
+        self.position == 0 || self.position + size <= self.max_bytes.0
+    }
+    pub fn is_older_than(&self, age_ms: u64, now_ms: u64) -> bool {
+        // empty segments never age, there is nothing in them to roll
+        match self.first_write_ms {
+            Some(first) => now_ms.saturating_sub(first) >= age_ms,
+            None => false,
+        }
     }
+    pub fn first_write_ms(&self) -> Option<u64> { self.first_write_ms }
     pub fn newest_offset(&self) -> u64 {self.next_offset}
     pub fn current_position(&self) -> u64 { self.position }
 }
@@ -133,6 +445,13 @@ impl SegmentMeta {
 
 impl Write for SegmentMeta {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.access == Access::ReadOnly { return Err(Error::ReadOnly.into()) }
+        let now = now_ms();
+        if self.first_write_ms.is_none() {
+            fs::write(&self.timestamp_path, now.to_string())?;
+            self.first_write_ms = Some(now);
+        }
+        self.last_write_ms = Some(now);
         let open_segment = self.open()?;
         let mut buf_writer = BufWriter::new(&open_segment.log_writer);
         let n = buf_writer.write(buf)?;
@@ -207,7 +526,7 @@ mod tests {
     #[test]
     fn it_returns_none_loading_wrong_path() {
         let tmp = tempdir().unwrap().path().to_path_buf().clone();
-        let root_dir = SegmentMeta::load(tmp, MaxBytes(64, 64));
+        let root_dir = SegmentMeta::load(tmp, MaxBytes(64, 64)).unwrap();
 
         assert!(root_dir.is_none(), "directory isn't a segment");
     }
@@ -218,7 +537,7 @@ mod tests {
         fs::create_dir_all(&tmp).unwrap();
         tmp.push("00000000000000000000.log");
 
-        let segment = SegmentMeta::load(tmp, MaxBytes(32, 16)).unwrap();
+        let segment = SegmentMeta::load(tmp, MaxBytes(32, 16)).unwrap().unwrap();
         assert_eq!(segment.position, 0, "position");
         assert_eq!(segment.base_offset, 0, "base_offset");
         assert_eq!(segment.max_bytes, MaxBytes(32, 16), "max_bytes");
@@ -243,10 +562,139 @@ mod tests {
 
 
         tmp.push("00000000000000000000.log");
-        let segment = SegmentMeta::load(tmp, MaxBytes(32, 16)).unwrap();
+        let segment = SegmentMeta::load(tmp, MaxBytes(32, 16)).unwrap().unwrap();
         assert_eq!(segment.position, 28, "position");
         assert_eq!(segment.base_offset, 0, "base_offset");
         assert_eq!(segment.max_bytes, MaxBytes(32, 16), "max_bytes");
         assert_eq!(segment.next_offset, 2, "next_offset");
     }
+
+    #[test]
+    fn it_keeps_first_write_time() {
+        let tmp = tempdir().unwrap().path().to_path_buf().clone();
+        fs::create_dir_all(&tmp).unwrap();
+        let mut segment = SegmentMeta::new(tmp.clone(), 0, MaxBytes(64, 32));
+        assert!(segment.first_write_ms().is_none(), "nothing written yet");
+        segment.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 88, 88]).unwrap();
+        segment.write_index_entry(Entry::new(0, 0)).unwrap();
+        segment.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14, 88, 88]).unwrap();
+        segment.write_index_entry(Entry::new(1, 14)).unwrap();
+        let first = segment.first_write_ms().unwrap();
+        assert!(!segment.is_older_than(60_000, first + 1));
+        assert!(segment.is_older_than(60_000, first + 60_000));
+
+        let mut path = tmp.clone();
+        path.push("00000000000000000000.timestamp");
+        fs::write(&path, "1000").unwrap();
+        path.set_extension("log");
+        let segment = SegmentMeta::load(path.clone(), MaxBytes(64, 32)).unwrap().unwrap();
+        assert_eq!(segment.first_write_ms(), Some(1000), "survives a restart");
+        assert!(segment.last_write_ms().unwrap() >= first, "unsealed, the log's mtime");
+
+        let mut segment = segment;
+        segment.seal().unwrap();
+        path.set_extension("timestamp");
+        fs::write(&path, "1000 2000").unwrap();
+        path.set_extension("log");
+        let segment = SegmentMeta::load(path, MaxBytes(64, 32)).unwrap().unwrap();
+        assert_eq!((segment.first_write_ms(), segment.last_write_ms().unwrap()), (Some(1000), 2000));
+    }
+
+    #[test]
+    fn it_loads_read_only_segment_meta() {
+        let tmp = tempdir().unwrap().path().to_path_buf().clone();
+        fs::create_dir_all(&tmp).unwrap();
+        {
+            let mut segment = SegmentMeta::new(tmp.clone(), 0, MaxBytes(32, 16));
+            segment.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 88, 88]).unwrap();
+            segment.write_index_entry(Entry::new(0, 0)).unwrap();
+        }
+        let mut path = tmp.clone();
+        path.push("00000000000000000000.log");
+
+        let mut segment = SegmentMeta::load_as(path, MaxBytes(32, 16), Access::ReadOnly).unwrap().unwrap();
+        assert_eq!(segment.position, 14, "position");
+        assert_eq!(segment.next_offset, 1, "a lone message is found");
+        assert!(segment.write("XX".as_bytes()).is_err(), "log writes are refused");
+        assert!(matches!(segment.write_index_entry(Entry::new(1, 14)), Err(Error::ReadOnly)), "index writes are refused");
+        assert_eq!(segment.size().unwrap(), 14, "log untouched");
+    }
+
+    #[test]
+    fn it_rewrites_without_dropped_messages() {
+        let tmp = tempdir().unwrap().path().to_path_buf().clone();
+        fs::create_dir_all(&tmp).unwrap();
+        let mut segment = SegmentMeta::new(tmp.clone(), 10, MaxBytes(1024, 64));
+        for (i, payload) in ["A", "BB", "C", "DD"].iter().enumerate() {
+            let position = segment.current_position();
+            let message = Message::new(10 + i as u64, position as u32, payload.as_bytes());
+            segment.write_all(&message.to_vec().unwrap()).unwrap();
+            segment.write_index_entry(Entry::new(10 + i as u64, position)).unwrap();
+        }
+
+        let mut segment = segment.rewrite(|m| m.payload.len() == 1).unwrap();
+        let messages = segment.read_messages().unwrap();
+        let kept: Vec<(Offset, Vec<u8>)> = messages.into_iter().map(|m| (m.offset, m.payload)).collect();
+        assert_eq!(kept, vec![
+            (10, "A".as_bytes().to_vec()),
+            (12, "C".as_bytes().to_vec()),
+            (13, "DD".as_bytes().to_vec()),
+        ], "last message kept regardless");
+        assert_eq!(segment.newest_offset(), 14, "offsets preserved");
+        assert_eq!(segment.read_index_entry(11).unwrap().position, 13, "hole points at the next message");
+        let slice = segment.slice(11, 14, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (13, 27));
+        assert_eq!((slice.sizes, slice.next_offset), (vec![13, 14], 14), "the hole takes no bytes");
+    }
+
+    #[test]
+    fn it_slices_whole_messages() {
+        let mut tmp = tempdir().unwrap().path().to_path_buf().clone();
+        {
+            let mut path = tmp.clone();
+            fs::create_dir_all(&path).unwrap();
+            path.push("00000000000000000000.index");
+            let mut index = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+            path.pop();
+            path.push("00000000000000000000.log");
+            let mut log = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+            log.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 88, 88,
+                            0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14, 88, 88]).unwrap();
+            index.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14]).unwrap();
+        }
+        tmp.push("00000000000000000000.log");
+        let segment = SegmentMeta::load(tmp, MaxBytes(32, 16)).unwrap().unwrap();
+
+        let slice = segment.slice(0, 2, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (0, 28), "both messages");
+        assert_eq!((slice.sizes.clone(), slice.next_offset), (vec![14, 14], 2));
+        let slice = segment.slice(0, 1, 1024).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length, slice.next_offset), (0, 14, 1), "stops before upto");
+        let slice = segment.slice(0, 2, 20).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (0, 14), "only the first message fits");
+        let slice = segment.slice(1, 2, 4).unwrap().unwrap();
+        assert_eq!((slice.position, slice.length), (14, 14), "oversized message still returned");
+        assert!(segment.slice(2, 2, 1024).unwrap().is_none(), "nothing at the log end");
+        assert!(matches!(segment.slice(3, 3, 1024), Err(Error::OffsetOutOfRange(3))), "past the log end");
+    }
+
+    #[test]
+    fn it_errors_loading_corrupt_segment_meta() {
+        let mut tmp = tempdir().unwrap().path().to_path_buf().clone();
+        {
+            let mut path = tmp.clone();
+            fs::create_dir_all(&path).unwrap();
+            path.push("00000000000000000000.index");
+            let mut index = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+            index.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 14]).unwrap();
+            path.pop();
+            path.push("00000000000000000000.log");
+            let mut log = OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+            log.write_all(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 88, 88]).unwrap();
+        }
+        tmp.push("00000000000000000000.log");
+
+        let res = SegmentMeta::load(tmp, MaxBytes(32, 16));
+        assert!(matches!(res, Err(Error::CorruptRecord(_))), "index points past the log end");
+    }
 }
@@ -0,0 +1,67 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+// Counts appends so fetches can sleep until there's something new to read.
+// A LogManager shares one signal across its partitions so a fetch spanning
+// several of them waits on a single condvar.
+#[derive(Debug, Default)]
+pub struct AppendSignal {
+    appends: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl AppendSignal {
+    pub fn new() -> AppendSignal { AppendSignal::default() }
+
+    pub fn appends(&self) -> u64 { *self.appends.lock().unwrap() }
+
+    pub fn notify(&self) {
+        *self.appends.lock().unwrap() += 1;
+        self.cond.notify_all();
+    }
+
+    pub fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        // returns once the count moves past `seen` or the timeout is up
+        let deadline = Instant::now() + timeout;
+        let mut appends = self.appends.lock().unwrap();
+        while *appends == seen {
+            let now = Instant::now();
+            if now >= deadline { break }
+            appends = self.cond.wait_timeout(appends, deadline - now).unwrap().0;
+        }
+        *appends
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn it_wakes_waiters() {
+        let signal = Arc::new(AppendSignal::new());
+        let seen = signal.appends();
+        let notifier = signal.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            notifier.notify();
+        });
+        let start = Instant::now();
+        assert_eq!(signal.wait(seen, Duration::from_secs(10)), seen + 1);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn it_times_out() {
+        let signal = AppendSignal::new();
+        let start = Instant::now();
+        assert_eq!(signal.wait(0, Duration::from_millis(20)), 0);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(signal.wait(1, Duration::from_secs(10)), 0, "already past what was seen");
+    }
+}
@@ -0,0 +1,137 @@
+use std::{io};
+use std::fs::{File};
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use crate::{Error, Offset, Result};
+use crate::partition::message::{Message};
+
+
+// A FileSlice is a byte range of a segment's `.log` file, handed to the
+// network layer so fetches can be served without copying through userspace
+#[derive(Debug)]
+pub struct FileSlice {
+    pub file: File,
+    pub position: u64,
+    pub length: u64,
+    // the bytes of each message in the range, what a reader splits it by,
+    // and the offset after the last one
+    pub sizes: Vec<u32>,
+    pub next_offset: Offset,
+}
+
+impl FileSlice {
+    pub fn new(file: File, position: u64, length: u64) -> FileSlice {
+        FileSlice{ file, position, length, sizes: vec![], next_offset: 0 }
+    }
+
+    pub fn with_messages(self, sizes: Vec<u32>, next_offset: Offset) -> FileSlice {
+        FileSlice{ sizes, next_offset, ..self }
+    }
+
+    pub fn is_empty(&self) -> bool { self.length == 0 }
+
+    // sendfile(2) straight from the page cache into the socket
+    #[cfg(target_os = "linux")]
+    pub fn transfer_to<W: Write + AsRawFd>(&self, out: &mut W) -> Result<u64> {
+        let mut offset = self.position as libc::off_t;
+        let mut remaining = self.length as usize;
+        while remaining > 0 {
+            let n = unsafe {
+                libc::sendfile(out.as_raw_fd(), self.file.as_raw_fd(), &mut offset, remaining)
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted { continue }
+                return Err(err.into())
+            }
+            // the log was truncated underneath us, the reader was promised more
+            if n == 0 { return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))) }
+            remaining -= n as usize;
+        }
+        Ok(self.length)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn transfer_to<W: Write>(&self, out: &mut W) -> Result<u64> {
+        self.copy_to(out)
+    }
+
+    // plain userspace copy, for writers that aren't file descriptors
+    pub fn copy_to<W: Write>(&self, out: &mut W) -> Result<u64> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(self.position))?;
+        let copied = io::copy(&mut file.take(self.length), out)?;
+        if copied < self.length { return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))) }
+        Ok(copied)
+    }
+}
+
+pub fn split_messages(raw: &[u8], sizes: &[u32]) -> Result<Vec<Message>> {
+    // the messages of a slice sent on, the way the reader gets them
+    let mut messages = Vec::with_capacity(sizes.len());
+    let mut start = 0;
+    for size in sizes {
+        let end = start + *size as usize;
+        if end > raw.len() {
+            return Err(Error::CorruptRecord(format!("{} bytes of messages, {} more sized", raw.len(), end - raw.len())))
+        }
+        messages.push(Message::from_vec(&mut raw[start..end].to_vec())?);
+        start = end;
+    }
+    Ok(messages)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::net::{TcpListener, TcpStream};
+    use tempfile::tempdir;
+    use super::*;
+
+    fn log_file() -> File {
+        let mut path = tempdir().unwrap().path().to_path_buf();
+        fs::create_dir_all(&path).unwrap();
+        path.push("00000000000000000000.log");
+        let mut file = OpenOptions::new().create(true).truncate(true).read(true).write(true)
+            .open(&path).unwrap();
+        file.write_all("YELLOW SUBMARINE".as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn it_copies_slice() {
+        let slice = FileSlice::new(log_file(), 7, 3);
+        let mut buf = vec![];
+        let n = slice.copy_to(&mut buf).unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(buf, "SUB".as_bytes());
+    }
+
+    #[test]
+    fn it_splits_sliced_messages() {
+        let mut raw = Message::new(3, 0, b"XX").to_vec().unwrap();
+        raw.extend(Message::new(4, 14, b"YYY").to_vec().unwrap());
+        let messages = split_messages(&raw, &[14, 15]).unwrap();
+        assert_eq!(messages.iter().map(|m| (m.offset, m.payload.clone())).collect::<Vec<_>>(), vec![(3, b"XX".to_vec()), (4, b"YYY".to_vec())]);
+        assert!(matches!(split_messages(&raw, &[14, 16]), Err(Error::CorruptRecord(_))));
+    }
+
+    #[test]
+    fn it_transfers_slice_to_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let slice = FileSlice::new(log_file(), 0, 6);
+        let n = slice.transfer_to(&mut server).unwrap();
+        drop(server);
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(n, 6);
+        assert_eq!(buf, "YELLOW".as_bytes());
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use crate::{Offset, Result};
+use crate::partition::message::{Message};
+use crate::partition::record::{Record};
+
+// record attributes, the same bits kafka uses on a batch
+pub const TRANSACTIONAL: u8 = 0x10;
+pub const CONTROL: u8 = 0x20;
+
+pub const TXN_INDEX_EXT: &str = "txnindex";
+const ABORTED_TXN_LEN: usize = 8 + 8 + 8;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    // everything up to the log end, open and aborted transactions included
+    ReadUncommitted,
+    // up to the last stable offset, without aborted records or markers
+    ReadCommitted,
+}
+
+impl Isolation {
+    pub fn from_i8(level: i8) -> Isolation {
+        if level == 1 { Isolation::ReadCommitted } else { Isolation::ReadUncommitted }
+    }
+
+    pub fn as_i8(self) -> i8 {
+        match self {
+            Isolation::ReadUncommitted => 0,
+            Isolation::ReadCommitted => 1,
+        }
+    }
+}
+
+
+// The marker a transaction's outcome is written as into each of its partitions.
+// Key and value are laid out like kafka's control records:
+//
+//   key: version i16 | type i16 (0 abort, 1 commit)    value: version i16 | coordinator epoch i32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    Abort,
+    Commit,
+}
+
+impl Marker {
+    pub fn record(self, timestamp: u64, producer_id: i64, epoch: i16) -> Record {
+        let kind = match self { Marker::Abort => 0, Marker::Commit => 1 };
+        let key = [0, 0, 0, kind];
+        let mut record = Record::new(timestamp, Some(&key), Some(&[0; 6])).with_producer(producer_id, epoch, -1);
+        record.attributes = TRANSACTIONAL | CONTROL;
+        record
+    }
+
+    pub fn from_record(record: &Record) -> Option<Marker> {
+        if record.attributes & CONTROL == 0 { return None }
+        match record.key.as_deref() {
+            Some([0, 0, 0, 0]) => Some(Marker::Abort),
+            Some([0, 0, 0, 1]) => Some(Marker::Commit),
+            _ => None,
+        }
+    }
+}
+
+
+// An aborted transaction, from its producer's first record to its abort marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbortedTxn {
+    pub producer_id: i64,
+    pub first_offset: Offset,
+    pub last_offset: Offset,
+}
+
+impl AbortedTxn {
+    pub fn covers(&self, producer_id: i64, offset: Offset) -> bool {
+        self.producer_id == producer_id && offset >= self.first_offset && offset <= self.last_offset
+    }
+}
+
+// Each segment has a `.txnindex` of the transactions aborted by markers in it,
+// read_committed fetches look up which records to leave out there:
+//
+//   [producer_id i64 | first_offset u64 | last_offset u64]
+pub fn append_aborted(path: &Path, txn: &AbortedTxn) -> Result<()> {
+    let mut buf = Vec::with_capacity(ABORTED_TXN_LEN);
+    buf.write_i64::<BigEndian>(txn.producer_id)?;
+    buf.write_u64::<BigEndian>(txn.first_offset)?;
+    buf.write_u64::<BigEndian>(txn.last_offset)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+pub fn read_aborted(path: &Path) -> Result<Vec<AbortedTxn>> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    // a torn last entry is left out, its marker is replayed on the next load
+    Ok(raw.chunks_exact(ABORTED_TXN_LEN).map(|entry| AbortedTxn{
+        producer_id: BigEndian::read_i64(&entry[0..8]),
+        first_offset: BigEndian::read_u64(&entry[8..16]),
+        last_offset: BigEndian::read_u64(&entry[16..24]),
+    }).collect())
+}
+
+// drops markers and records of aborted transactions, what a read_committed
+// consumer gets to see
+pub fn filter_committed(messages: Vec<Message>, aborted: &[AbortedTxn]) -> Vec<Message> {
+    messages.into_iter().filter(|message| {
+        let record = match Record::from_slice(&message.payload) {
+            Ok(record) => record,
+            Err(_) => return true,
+        };
+        if record.attributes & CONTROL != 0 { return false }
+        record.attributes & TRANSACTIONAL == 0
+            || !aborted.iter().any(|txn| txn.covers(record.producer_id, message.offset))
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn it_round_trips_markers() {
+        let record = Marker::Commit.record(1, 7, 2);
+        let decoded = Record::from_slice(&record.to_vec().unwrap()).unwrap();
+        assert_eq!(Marker::from_record(&decoded), Some(Marker::Commit));
+        assert_eq!((decoded.producer_id, decoded.producer_epoch), (7, 2));
+        assert_eq!(Marker::from_record(&Record::new(1, Some(&[0, 0, 0, 0]), None)), None, "not a control record");
+    }
+
+    #[test]
+    fn it_indexes_aborted_transactions() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("00000000000000000000.txnindex");
+        assert!(read_aborted(&path).unwrap().is_empty());
+        let txn = AbortedTxn{ producer_id: 7, first_offset: 3, last_offset: 9 };
+        append_aborted(&path, &txn).unwrap();
+        append_aborted(&path, &AbortedTxn{ producer_id: 8, first_offset: 10, last_offset: 12 }).unwrap();
+        assert_eq!(read_aborted(&path).unwrap()[0], txn);
+        assert_eq!(read_aborted(&path).unwrap().len(), 2);
+        assert!(txn.covers(7, 3) && txn.covers(7, 9));
+        assert!(!txn.covers(8, 5) && !txn.covers(7, 10));
+    }
+
+    #[test]
+    fn it_filters_aborted_records() {
+        let txn_record = |offset: Offset, producer_id: i64| {
+            let mut record = Record::new(0, None, Some(b"XX")).with_producer(producer_id, 0, offset as i32);
+            record.attributes = TRANSACTIONAL;
+            Message::new(offset, 0, &record.to_vec().unwrap())
+        };
+        let messages = vec![
+            txn_record(0, 7),
+            txn_record(1, 8),
+            Message::new(2, 0, &Marker::Abort.record(0, 7, 0).to_vec().unwrap()),
+            Message::new(3, 0, &Record::new(0, None, Some(b"XX")).to_vec().unwrap()),
+        ];
+        let aborted = [AbortedTxn{ producer_id: 7, first_offset: 0, last_offset: 2 }];
+        let kept = filter_committed(messages, &aborted);
+        assert_eq!(kept.iter().map(|m| m.offset).collect::<Vec<Offset>>(), vec![1, 3]);
+    }
+}
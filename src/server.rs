@@ -0,0 +1,772 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::{Error, Result};
+use crate::cluster::Broker;
+use crate::cluster::controller::{Controller};
+use crate::cluster::manager::{BackgroundTasks, LogManager};
+use crate::cluster::group::{GroupCoordinator};
+use crate::cluster::lifecycle::{BrokerLifecycle};
+use crate::cluster::metadata::{MetadataImage};
+use crate::cluster::offsets::{OffsetStore};
+use crate::cluster::raft::{RaftConfig, RaftNode, TcpTransport, METADATA_LOG_DIR};
+use crate::cluster::replica::{ReplicaManager};
+use crate::cluster::transaction::{TransactionCoordinator};
+use crate::kafka::handler::{Handler};
+use crate::native;
+
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // partitions given to topics created by a metadata request
+    pub num_partitions: u32,
+    pub auto_create_topics: bool,
+    pub cluster_id: String,
+    pub max_request_bytes: usize,
+    // group.min/max.session.timeout.ms, the session timeouts members may ask for
+    pub group_min_session_timeout: Duration,
+    pub group_max_session_timeout: Duration,
+    // replica.fetch.wait.max.ms, how long a follower's fetch waits for new messages
+    pub replica_fetch_max_wait: Duration,
+    // replica.fetch.max.bytes, per partition in each follower fetch
+    pub replica_fetch_max_bytes: u32,
+    // replica.fetch.backoff.ms, the pause after a failed fetch
+    pub replica_fetch_backoff: Duration,
+    // replica.lag.time.max.ms, a follower that hasn't caught up for this long drops out of the isr
+    pub replica_lag_time_max: Duration,
+    // leader.replication.throttled.rate, the bytes a second a leader sends
+    // followers outside the isr, those catching up or a reassignment is
+    // moving the partition onto. None doesn't throttle
+    pub replication_throttled_rate: Option<u64>,
+    // replica.high.watermark.checkpoint.interval.ms
+    pub replica_high_watermark_checkpoint_interval: Duration,
+    // how long an acks=all native produce waits for the isr, kafka
+    // produce requests bring their own timeout
+    pub produce_timeout: Duration,
+    // whether this broker runs a controller, which elects partition leaders
+    pub controller: bool,
+    // controller.quorum.bootstrap.servers, the controllers brokers register
+    // and heartbeat with, comma separated. A controller's own broker goes to
+    // the voters when there's none
+    pub controller_addr: Option<String>,
+    // controller.quorum.voters, the id and address of every controller. The
+    // one leading their quorum is the active one. None leaves a controller
+    // on its own
+    pub controller_quorum_voters: Vec<(u32, String)>,
+    // controller.quorum.election.timeout.ms
+    pub controller_quorum_election_timeout: Duration,
+    // controller.quorum.request.timeout.ms, how long a controller waits on
+    // another, and on a change to be committed
+    pub controller_quorum_request_timeout: Duration,
+    // broker.session.timeout.ms, a broker the controller can't reach for this long is dead
+    pub broker_session_timeout: Duration,
+    // broker.heartbeat.interval.ms, how often brokers heartbeat and the
+    // controller checks on every broker
+    pub broker_heartbeat_interval: Duration,
+    // auto.leader.rebalance.enable and leader.imbalance.check.interval.seconds,
+    // leadership moving back to the preferred replica
+    pub auto_leader_rebalance: bool,
+    pub leader_imbalance_check_interval: Duration,
+    // unclean.leader.election.enable, a replica out of the isr may lead when
+    // nothing in it is left. What it never copied is lost
+    pub unclean_leader_election: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig{
+            num_partitions: 1,
+            auto_create_topics: true,
+            cluster_id: String::from("latka"),
+            max_request_bytes: 100 * 1024 * 1024,
+            group_min_session_timeout: Duration::from_secs(6),
+            group_max_session_timeout: Duration::from_secs(30 * 60),
+            replica_fetch_max_wait: Duration::from_millis(500),
+            replica_fetch_max_bytes: 1024 * 1024,
+            replica_fetch_backoff: Duration::from_secs(1),
+            replica_lag_time_max: Duration::from_secs(30),
+            replication_throttled_rate: None,
+            replica_high_watermark_checkpoint_interval: Duration::from_secs(5),
+            produce_timeout: Duration::from_secs(30),
+            controller: false,
+            controller_addr: None,
+            controller_quorum_voters: vec![],
+            controller_quorum_election_timeout: Duration::from_secs(1),
+            controller_quorum_request_timeout: Duration::from_secs(2),
+            broker_session_timeout: Duration::from_secs(9),
+            broker_heartbeat_interval: Duration::from_secs(2),
+            auto_leader_rebalance: true,
+            leader_imbalance_check_interval: Duration::from_secs(300),
+            unclean_leader_election: false,
+        }
+    }
+}
+
+
+// Listens on the broker's address and serves each connection on its own thread,
+// speaking kafka or the native protocol depending on how the client opens
+pub struct Server {
+    listener: TcpListener,
+    handler: Arc<Handler>,
+    native: Arc<native::handler::Handler>,
+    replicas: Arc<ReplicaManager>,
+    // isr expiry and high watermark checkpoints, stopped with the server
+    _replica_tasks: BackgroundTasks,
+    controller: Option<Arc<Controller>>,
+    _quorum_tasks: Option<BackgroundTasks>,
+    _controller_tasks: Option<BackgroundTasks>,
+    _lifecycle_tasks: Option<BackgroundTasks>,
+    max_request_bytes: usize,
+}
+
+impl Server {
+    pub fn bind(broker: Broker, logs: Arc<LogManager>, config: ServerConfig) -> Result<Server> {
+        let listener = TcpListener::bind(broker.addr())?;
+        // port 0 picks a free port, metadata has to advertise the real one
+        let port = listener.local_addr()?.port();
+        let bound = Broker::new(broker.id(), broker.host(), port);
+        let broker = match broker.rack() {
+            Some(rack) => bound.with_rack(rack),
+            None => bound,
+        };
+        let max_request_bytes = config.max_request_bytes;
+        let transactions = Arc::new(TransactionCoordinator::open(logs.clone())?);
+        let offsets = Arc::new(OffsetStore::open(logs.clone())?);
+        let groups = Arc::new(GroupCoordinator::new(config.group_min_session_timeout, config.group_max_session_timeout));
+        let replicas = Arc::new(ReplicaManager::new(&broker, logs.clone(), config.clone()));
+        // a lone controller votes for itself, the others' are given
+        let voters = match config.controller_quorum_voters.is_empty() {
+            true => vec![(broker.id(), broker.addr())],
+            false => config.controller_quorum_voters.clone(),
+        };
+        let controller = match config.controller {
+            true => {
+                let quorum = Arc::new(open_quorum(broker.id(), &voters, &logs, &config)?);
+                Some(Arc::new(Controller::new(broker.id(), quorum, config.clone())))
+            },
+            false => None,
+        };
+        let native = Arc::new(native::handler::Handler::new(
+            logs.clone(), transactions.clone(), offsets.clone(), groups.clone(), replicas.clone(), controller.clone(), config.clone()));
+        // a controller's own broker registers like any other
+        let controllers: Vec<String> = match (&config.controller_addr, config.controller) {
+            (Some(addrs), _) => addrs.split(',').map(String::from).collect(),
+            (None, true) => voters.into_iter().map(|(_, addr)| addr).collect(),
+            (None, false) => vec![],
+        };
+        let lifecycle = match controllers.is_empty() {
+            true => None,
+            false => Some(Arc::new(BrokerLifecycle::new(broker.clone(), controllers, replicas.clone(), config.clone()))),
+        };
+        let handler = Arc::new(Handler::new(broker, logs, transactions, offsets, groups, replicas.clone(), config));
+        let _replica_tasks = ReplicaManager::start(replicas.clone());
+        let _quorum_tasks = controller.as_ref().map(|c| RaftNode::start(c.quorum().clone()));
+        let _controller_tasks = controller.clone().map(Controller::start);
+        let _lifecycle_tasks = lifecycle.map(BrokerLifecycle::start);
+        Ok(Server{
+            listener, handler, native, replicas, _replica_tasks, controller, _quorum_tasks, _controller_tasks, _lifecycle_tasks,
+            max_request_bytes,
+        })
+    }
+
+    pub fn broker(&self) -> &Broker { self.handler.broker() }
+    pub fn replicas(&self) -> &Arc<ReplicaManager> { &self.replicas }
+    pub fn controller(&self) -> Option<&Arc<Controller>> { self.controller.as_ref() }
+    pub fn local_addr(&self) -> Result<SocketAddr> { Ok(self.listener.local_addr()?) }
+
+    pub fn serve(&self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let handler = self.handler.clone();
+            let native = self.native.clone();
+            let max_request_bytes = self.max_request_bytes;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                if let Err(e) = serve_connection(stream, &handler, &native, max_request_bytes) {
+                    eprintln!("latka: closing connection from {}: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn open_quorum(id: u32, voters: &[(u32, String)], logs: &LogManager, config: &ServerConfig) -> Result<RaftNode<MetadataImage>> {
+    // the metadata log goes in the first log dir, its segments sized like
+    // the partitions'. Leaders heartbeat ten times per election timeout,
+    // as raft's defaults do
+    let ids: Vec<u32> = voters.iter().map(|(id, _)| *id).collect();
+    let peers: Vec<(u32, String)> = voters.iter().filter(|(voter, _)| *voter != id).cloned().collect();
+    let transport = Arc::new(TcpTransport::new(&peers, config.controller_quorum_request_timeout));
+    let raft = RaftConfig{
+        election_timeout: config.controller_quorum_election_timeout,
+        heartbeat_interval: config.controller_quorum_election_timeout / 10,
+        log: logs.defaults().clone(),
+        ..RaftConfig::default()
+    };
+    RaftNode::open(id, &ids, &logs.log_dirs()[0].join(METADATA_LOG_DIR), MetadataImage::new(), transport, raft)
+}
+
+fn serve_connection(
+    mut stream: TcpStream,
+    handler: &Handler,
+    native: &native::handler::Handler,
+    max_request_bytes: usize,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut first = true;
+    let mut is_native = false;
+    loop {
+        let mut size = [0; 4];
+        match stream.read_exact(&mut size) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if first && size == native::MAGIC {
+            first = false;
+            is_native = true;
+            continue
+        }
+        first = false;
+        let size = i32::from_be_bytes(size);
+        if size < 0 || size as usize > max_request_bytes {
+            return Err(Error::InvalidRequest(format!("request of {} bytes", size)))
+        }
+        let mut frame = vec![0; size as usize];
+        stream.read_exact(&mut frame)?;
+        if is_native {
+            if let Some(response) = native.handle(&frame)? { response.write_to(&mut stream)? }
+            continue
+        }
+        if let Some(response) = handler.handle(&frame)? {
+            stream.write_i32::<BigEndian>(response.len() as i32)?;
+            stream.write_all(&response)?;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use byteorder::{ReadBytesExt};
+    use tempfile::tempdir;
+    use super::*;
+    use crate::kafka::{self, errors, RequestHeader};
+    use crate::kafka::batch::{BatchRecord, RecordBatch};
+    use crate::kafka::codec::{Decoder, Encoder};
+    use crate::partition::segment::{MaxBytes};
+    use crate::partition::transaction::{TRANSACTIONAL};
+
+    fn start(log_dir: &Path) -> SocketAddr {
+        let logs = Arc::new(LogManager::open(vec![log_dir.to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+        let server = Server::bind(Broker::new(7, "127.0.0.1", 0), logs, ServerConfig::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+        addr
+    }
+
+    fn request(stream: &mut TcpStream, api_key: i16, api_version: i16, body: Encoder) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        RequestHeader{ api_key, api_version, correlation_id: 42, client_id: Some(String::from("test")) }
+            .encode(&mut enc);
+        enc.raw(body.as_slice());
+        stream.write_i32::<BigEndian>(enc.len() as i32).unwrap();
+        stream.write_all(enc.as_slice()).unwrap();
+
+        let size = stream.read_i32::<BigEndian>().unwrap();
+        let mut response = vec![0; size as usize];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(&response[..4], &[0, 0, 0, 42], "correlation id");
+        response.split_off(4)
+    }
+
+    #[test]
+    fn it_negotiates_api_versions() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+
+        let response = request(&mut stream, kafka::API_VERSIONS, 3, Encoder::new());
+        let mut dec = Decoder::new(&response);
+        assert_eq!(dec.i16().unwrap(), errors::UNSUPPORTED_VERSION, "v3 is flexible");
+
+        let response = request(&mut stream, kafka::API_VERSIONS, 2, Encoder::new());
+        let mut dec = Decoder::new(&response);
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        assert_eq!(dec.array_len().unwrap(), Some(kafka::SUPPORTED_APIS.len()));
+    }
+
+    #[test]
+    fn it_produces_fetches_and_lists_offsets() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+
+        // metadata v1 auto-creates the topic
+        let mut body = Encoder::new();
+        body.array_len(1);
+        body.string("events");
+        let response = request(&mut stream, kafka::METADATA, 1, body);
+        let mut dec = Decoder::new(&response);
+        assert_eq!(dec.array_len().unwrap(), Some(1));
+        assert_eq!((dec.i32().unwrap(), dec.string().unwrap()), (7, String::from("127.0.0.1")));
+        dec.i32().unwrap();
+        dec.nullable_string().unwrap();
+        assert_eq!(dec.i32().unwrap(), 7, "controller");
+        assert_eq!(dec.array_len().unwrap(), Some(1));
+        assert_eq!((dec.i16().unwrap(), dec.string().unwrap()), (errors::NONE, String::from("events")));
+        assert!(tmp.path().join("events-0").is_dir());
+
+        let mut with_headers = BatchRecord::new(1, 2_000, None, Some(b"XX"));
+        with_headers.headers.push((String::from("trace"), Some(b"abc".to_vec())));
+        let batch = RecordBatch::new(0, vec![
+            BatchRecord::new(0, 1_000, Some(b"k"), Some(b"YELLOW SUBMARINE")),
+            with_headers.clone(),
+        ]);
+        let mut body = Encoder::new();
+        body.nullable_string(None);
+        body.i16(1);
+        body.i32(1_000);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        body.bytes(Some(&batch.to_vec()));
+        let response = request(&mut stream, kafka::PRODUCE, 3, body);
+        let mut dec = Decoder::new(&response);
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        assert_eq!(dec.i32().unwrap(), 0);
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        assert_eq!(dec.i64().unwrap(), 0, "base offset");
+
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.i32(0);
+        body.i32(1);
+        body.i32(1024 * 1024);
+        body.i8(0);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        body.i64(1);
+        body.i32(1024 * 1024);
+        let response = request(&mut stream, kafka::FETCH, 4, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        assert_eq!(dec.i32().unwrap(), 0);
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        assert_eq!(dec.i64().unwrap(), 2, "high watermark");
+        dec.i64().unwrap();
+        assert_eq!(dec.array_len().unwrap(), None, "no aborted transactions");
+        let fetched = RecordBatch::decode_all(dec.bytes().unwrap().unwrap()).unwrap();
+        assert_eq!(fetched[0].base_offset, 1);
+        with_headers.offset_delta = 0;
+        assert_eq!(fetched[0].records, vec![with_headers], "headers kept");
+
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(2);
+        for timestamp in &[-1, 1_500] {
+            body.i32(0);
+            body.i64(*timestamp);
+        }
+        let response = request(&mut stream, kafka::LIST_OFFSETS, 1, body);
+        let mut dec = Decoder::new(&response);
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        let mut offsets = vec![];
+        for _ in 0..2 {
+            dec.i32().unwrap();
+            assert_eq!(dec.i16().unwrap(), errors::NONE);
+            offsets.push((dec.i64().unwrap(), dec.i64().unwrap()));
+        }
+        assert_eq!(offsets, vec![(-1, 2), (2_000, 1)], "latest, then by timestamp");
+    }
+
+    fn produce(stream: &mut TcpStream, topic: &str, batch: &RecordBatch) -> (i16, i64) {
+        let mut body = Encoder::new();
+        body.nullable_string(None);
+        body.i16(-1);
+        body.i32(1_000);
+        body.array_len(1);
+        body.string(topic);
+        body.array_len(1);
+        body.i32(0);
+        body.bytes(Some(&batch.to_vec()));
+        let response = request(stream, kafka::PRODUCE, 3, body);
+        let mut dec = Decoder::new(&response);
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        (dec.i16().unwrap(), dec.i64().unwrap())
+    }
+
+    #[test]
+    fn it_deduplicates_idempotent_produce() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+        let mut body = Encoder::new();
+        body.array_len(1);
+        body.string("events");
+        request(&mut stream, kafka::METADATA, 1, body);
+
+        let mut body = Encoder::new();
+        body.nullable_string(None);
+        body.i32(60_000);
+        let response = request(&mut stream, kafka::INIT_PRODUCER_ID, 0, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        let (producer_id, epoch) = (dec.i64().unwrap(), dec.i16().unwrap());
+
+        let mut batch = RecordBatch::new(0, vec![
+            BatchRecord::new(0, 1_000, None, Some(b"one")),
+            BatchRecord::new(1, 1_000, None, Some(b"two")),
+        ]);
+        batch.producer_id = producer_id;
+        batch.producer_epoch = epoch;
+        batch.base_sequence = 0;
+        assert_eq!(produce(&mut stream, "events", &batch), (errors::NONE, 0));
+        assert_eq!(produce(&mut stream, "events", &batch), (errors::NONE, 0), "a retry isn't appended twice");
+
+        batch.base_sequence = 5;
+        assert_eq!(produce(&mut stream, "events", &batch).0, errors::OUT_OF_ORDER_SEQUENCE_NUMBER);
+        batch.base_sequence = 2;
+        assert_eq!(produce(&mut stream, "events", &batch), (errors::NONE, 2));
+    }
+
+    fn fetch_committed(stream: &mut TcpStream, topic: &str) -> (i64, Vec<RecordBatch>) {
+        // the last stable offset and the batches a read_committed fetch from 0 gets
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.i32(0);
+        body.i32(0);
+        body.i32(1024 * 1024);
+        body.i8(1);
+        body.array_len(1);
+        body.string(topic);
+        body.array_len(1);
+        body.i32(0);
+        body.i64(0);
+        body.i32(1024 * 1024);
+        let response = request(stream, kafka::FETCH, 4, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        dec.i64().unwrap();
+        let last_stable_offset = dec.i64().unwrap();
+        for _ in 0..dec.array_len().unwrap().unwrap_or(0) {
+            dec.i64().unwrap();
+            dec.i64().unwrap();
+        }
+        (last_stable_offset, RecordBatch::decode_all(dec.bytes().unwrap().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn it_commits_transactions() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+        let mut body = Encoder::new();
+        body.array_len(1);
+        body.string("events");
+        request(&mut stream, kafka::METADATA, 1, body);
+
+        let mut body = Encoder::new();
+        body.string("tx");
+        body.i8(1);
+        let response = request(&mut stream, kafka::FIND_COORDINATOR, 1, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        dec.nullable_string().unwrap();
+        assert_eq!(dec.i32().unwrap(), 7, "this broker");
+
+        let mut body = Encoder::new();
+        body.nullable_string(Some("tx"));
+        body.i32(60_000);
+        let response = request(&mut stream, kafka::INIT_PRODUCER_ID, 0, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        let (producer_id, epoch) = (dec.i64().unwrap(), dec.i16().unwrap());
+
+        let mut body = Encoder::new();
+        body.string("tx");
+        body.i64(producer_id);
+        body.i16(epoch);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        let response = request(&mut stream, kafka::ADD_PARTITIONS_TO_TXN, 0, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+
+        let mut batch = RecordBatch::new(0, vec![BatchRecord::new(0, 1_000, None, Some(b"one"))]);
+        batch.producer_id = producer_id;
+        batch.producer_epoch = epoch;
+        batch.base_sequence = 0;
+        batch.attributes = TRANSACTIONAL as i16;
+        assert_eq!(produce(&mut stream, "events", &batch), (errors::NONE, 0));
+        let (last_stable_offset, batches) = fetch_committed(&mut stream, "events");
+        assert_eq!(last_stable_offset, 0);
+        assert!(batches.is_empty(), "not committed yet");
+
+        let mut body = Encoder::new();
+        body.string("tx");
+        body.i64(producer_id);
+        body.i16(epoch);
+        body.boolean(true);
+        let response = request(&mut stream, kafka::END_TXN, 0, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        let (last_stable_offset, batches) = fetch_committed(&mut stream, "events");
+        assert_eq!(last_stable_offset, 2, "past the commit marker");
+        assert_eq!(batches.len(), 2, "the records, then the marker in its own batch");
+        assert_eq!(batches[0].records[0].value.as_deref(), Some(&b"one"[..]));
+    }
+
+    #[test]
+    fn it_commits_and_fetches_offsets() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+        let mut body = Encoder::new();
+        body.array_len(1);
+        body.string("events");
+        request(&mut stream, kafka::METADATA, 1, body);
+
+        let mut body = Encoder::new();
+        body.string("workers");
+        body.i32(-1);
+        body.string("");
+        body.i64(-1);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(2);
+        body.i32(0);
+        body.i64(42);
+        body.nullable_string(Some("checkpoint"));
+        body.i32(9);
+        body.i64(1);
+        body.nullable_string(None);
+        let response = request(&mut stream, kafka::OFFSET_COMMIT, 3, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        assert_eq!((dec.i32().unwrap(), dec.i16().unwrap()), (0, errors::NONE));
+        assert_eq!((dec.i32().unwrap(), dec.i16().unwrap()), (9, errors::UNKNOWN_TOPIC_OR_PARTITION));
+
+        let mut body = Encoder::new();
+        body.string("workers");
+        body.null_array();
+        let response = request(&mut stream, kafka::OFFSET_FETCH, 3, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        assert_eq!(dec.array_len().unwrap(), Some(1), "every topic the group committed in");
+        assert_eq!(dec.string().unwrap(), "events");
+        assert_eq!(dec.array_len().unwrap(), Some(1));
+        assert_eq!((dec.i32().unwrap(), dec.i64().unwrap()), (0, 42));
+        assert_eq!(dec.nullable_string().unwrap(), Some(String::from("checkpoint")));
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+
+        let mut body = Encoder::new();
+        body.string("nobody");
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        let response = request(&mut stream, kafka::OFFSET_FETCH, 1, body);
+        let mut dec = Decoder::new(&response);
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        assert_eq!((dec.i32().unwrap(), dec.i64().unwrap()), (0, -1), "nothing committed");
+    }
+
+    #[test]
+    fn it_holds_fetches_until_min_bytes() {
+        let tmp = tempdir().unwrap();
+        let addr = start(tmp.path());
+        let mut producer = crate::client::Producer::connect(addr).unwrap();
+        producer.partitions("events").unwrap();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            producer.send_to("events", 0, None, b"late").unwrap();
+        });
+
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.i32(30_000);
+        body.i32(1);
+        body.i32(1024 * 1024);
+        body.i8(0);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        body.i64(0);
+        body.i32(1024 * 1024);
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let response = request(&mut stream, kafka::FETCH, 4, body);
+        assert!(start.elapsed() < Duration::from_secs(30), "woken by the append");
+        handle.join().unwrap();
+
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::NONE);
+        assert_eq!(dec.i64().unwrap(), 1, "high watermark");
+    }
+
+    #[test]
+    fn it_reports_the_isr_and_fences_stale_leader_epochs() {
+        let tmp = tempdir().unwrap();
+        let logs = Arc::new(LogManager::open(vec![tmp.path().to_path_buf()], MaxBytes(64 * 1024, 64 * 1024).into()).unwrap());
+        let config = ServerConfig{ replica_lag_time_max: Duration::from_millis(100), ..ServerConfig::default() };
+        let server = Server::bind(Broker::new(7, "127.0.0.1", 0), logs, config).unwrap();
+        let (addr, replicas) = (server.local_addr().unwrap(), server.replicas().clone());
+        thread::spawn(move || server.serve());
+        replicas.become_leader("events", 0, &[7, 8], 3).unwrap();
+        // broker 8 never fetches, so it drops out of the isr
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while replicas.state("events", 0).unwrap().isr != vec![7] {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let mut body = Encoder::new();
+        body.array_len(1);
+        body.string("events");
+        body.boolean(false);
+        let response = request(&mut stream, kafka::METADATA, 7, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        for _ in 0..dec.array_len().unwrap().unwrap() {
+            dec.i32().unwrap();
+            dec.string().unwrap();
+            dec.i32().unwrap();
+            dec.nullable_string().unwrap();
+        }
+        dec.nullable_string().unwrap();
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        assert_eq!((dec.i16().unwrap(), dec.string().unwrap(), dec.boolean().unwrap()), (errors::NONE, String::from("events"), false));
+        dec.array_len().unwrap();
+        assert_eq!((dec.i16().unwrap(), dec.i32().unwrap(), dec.i32().unwrap()), (errors::NONE, 0, 7));
+        assert_eq!(dec.i32().unwrap(), 3, "leader epoch");
+        let mut brokers = vec![];
+        for _ in 0..2 {
+            brokers.push((0..dec.array_len().unwrap().unwrap()).map(|_| dec.i32().unwrap()).collect::<Vec<_>>());
+        }
+        assert_eq!(brokers, vec![vec![7, 8], vec![7]], "replicas, then isr");
+
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.i8(0);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(3);
+        for epoch in &[2, 3, 4] {
+            body.i32(0);
+            body.i32(*epoch);
+            body.i64(-1);
+        }
+        let response = request(&mut stream, kafka::LIST_OFFSETS, 4, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        let mut answers = vec![];
+        for _ in 0..3 {
+            dec.i32().unwrap();
+            let error = dec.i16().unwrap();
+            dec.i64().unwrap();
+            dec.i64().unwrap();
+            answers.push((error, dec.i32().unwrap()));
+        }
+        assert_eq!(answers, vec![(errors::FENCED_LEADER_EPOCH, 3), (errors::NONE, 3), (errors::UNKNOWN_LEADER_EPOCH, 3)]);
+
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.i32(0);
+        body.i32(0);
+        body.i32(1024);
+        body.i8(0);
+        body.i32(0);
+        body.i32(-1);
+        body.array_len(1);
+        body.string("events");
+        body.array_len(1);
+        body.i32(0);
+        body.i32(2);
+        body.i64(0);
+        body.i64(-1);
+        body.i32(1024);
+        body.array_len(0);
+        let response = request(&mut stream, kafka::FETCH, 9, body);
+        let mut dec = Decoder::new(&response);
+        dec.i32().unwrap();
+        dec.i16().unwrap();
+        dec.i32().unwrap();
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::FENCED_LEADER_EPOCH);
+    }
+
+    #[test]
+    fn it_reports_unknown_partitions() {
+        let tmp = tempdir().unwrap();
+        let mut stream = TcpStream::connect(start(tmp.path())).unwrap();
+        let mut body = Encoder::new();
+        body.i32(-1);
+        body.array_len(1);
+        body.string("nope");
+        body.array_len(1);
+        body.i32(3);
+        body.i64(-2);
+        let response = request(&mut stream, kafka::LIST_OFFSETS, 1, body);
+        let mut dec = Decoder::new(&response);
+        dec.array_len().unwrap();
+        dec.string().unwrap();
+        dec.array_len().unwrap();
+        dec.i32().unwrap();
+        assert_eq!(dec.i16().unwrap(), errors::UNKNOWN_TOPIC_OR_PARTITION);
+    }
+}